@@ -69,13 +69,8 @@ fn calculate_slot_for_hash(
             calculate_slot_with_strategy(key, strategy)
         }
         NamespaceDistributionStrategy::HashTagAware => {
-            // Check if key has hash tag, otherwise use key
-            let combined_key = if key.contains('{') && key.contains('}') {
-                key
-            } else {
-                key
-            };
-            calculate_slot_with_strategy(combined_key, strategy)
+            // Hash tags in the key are honoured by calculate_slot_with_strategy
+            calculate_slot_with_strategy(key, strategy)
         }
     }
 }
@@ -0,0 +1,351 @@
+//! Permissions of users: the commands they may run and the namespaces they
+//! may run them on.
+//!
+//! Permissions are written as rules modelled on Redis ACLs. They are used in
+//! the `commands` of `[[users]]` entries and by `ACL SETUSER`, and printed by
+//! `ACL LIST`:
+//!
+//! - `+<command>` and `-<command>` allow or deny a command, `+@<category>`
+//!   and `-@<category>` every command of a category (`read`, `write`,
+//!   `admin`, or `all`). Rules apply in order, so `+@read -keys` allows every
+//!   read but `KEYS`. `allcommands` is `+@all` and `nocommands` denies all.
+//! - `~<pattern>` allows reads and writes on the namespaces matching a glob
+//!   pattern, `%R~<pattern>` only reads and `%W~<pattern>` only writes.
+//!   `allnamespaces` is `~*` and `resetnamespaces` drops every pattern.
+//!
+//! Commands on plain keys, such as `GET` and `SCAN`, work on the default
+//! keyspace, which patterns see as the empty namespace: `~*` covers it but
+//! `~team_a_*` does not. Connection commands like `AUTH`, `PING` and
+//! `ACL WHOAMI`, and the cluster commands clients route with, are always
+//! allowed.
+
+use crate::redis::{CommandCategory, RedisCommand};
+use crate::scan::glob_match;
+
+/// How a command uses a namespace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Why a command was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    Command,
+    Namespace,
+}
+
+impl Denied {
+    /// Error reply sent to the client, worded like Redis
+    pub fn message(self, user: &str, command: &str) -> String {
+        match self {
+            Denied::Command => format!(
+                "NOPERM User {} has no permissions to run the '{}' command",
+                user,
+                acl_name(command)
+            ),
+            Denied::Namespace => {
+                "NOPERM No permissions to access a namespace used by this command".to_string()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CommandRule {
+    All,
+    Category(CommandCategory),
+    /// Lowercase command name, with `|` between a command and its subcommand
+    Command(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NamespacePattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+/// Commands and namespaces a user may use
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Permissions {
+    /// Applied in order, the last rule matching a command decides
+    commands: Vec<(bool, CommandRule)>,
+    namespaces: Vec<NamespacePattern>,
+}
+
+impl Permissions {
+    /// Every command on every namespace
+    pub fn all() -> Self {
+        let mut permissions = Self::default();
+        permissions.apply("allcommands").unwrap();
+        permissions.apply("allnamespaces").unwrap();
+        permissions
+    }
+
+    /// Apply a command or namespace rule
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_ascii_lowercase().as_str() {
+            "allcommands" => {
+                self.commands = vec![(true, CommandRule::All)];
+                return Ok(());
+            }
+            "nocommands" => {
+                self.commands.clear();
+                return Ok(());
+            }
+            "allnamespaces" => {
+                self.namespaces = vec![NamespacePattern {
+                    pattern: "*".to_string(),
+                    read: true,
+                    write: true,
+                }];
+                return Ok(());
+            }
+            "resetnamespaces" => {
+                self.namespaces.clear();
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let allow = match rule.as_bytes().first() {
+            Some(b'+') => Some(true),
+            Some(b'-') => Some(false),
+            _ => None,
+        };
+        if let Some(allow) = allow {
+            let name = &rule[1..];
+            let command = match name.strip_prefix('@') {
+                Some(category) if category.eq_ignore_ascii_case("all") => CommandRule::All,
+                Some(category) => match CommandCategory::from_name(category) {
+                    Some(category) => CommandRule::Category(category),
+                    None => return Err(format!("unknown command category '{}'", category)),
+                },
+                None if is_command_name(name) => CommandRule::Command(name.to_ascii_lowercase()),
+                None => return Err(format!("invalid command name '{}'", name)),
+            };
+            self.commands.push((allow, command));
+            return Ok(());
+        }
+
+        let (read, write, pattern) = if let Some(pattern) = rule.strip_prefix('~') {
+            (true, true, pattern)
+        } else if let Some((flags, pattern)) =
+            rule.strip_prefix('%').and_then(|rest| rest.split_once('~'))
+        {
+            let flags = flags.to_ascii_uppercase();
+            if flags.is_empty() || flags.chars().any(|c| c != 'R' && c != 'W') {
+                return Err(format!("invalid namespace permission '{}'", flags));
+            }
+            (flags.contains('R'), flags.contains('W'), pattern)
+        } else {
+            return Err("Syntax error".to_string());
+        };
+        self.namespaces.push(NamespacePattern {
+            pattern: pattern.to_string(),
+            read,
+            write,
+        });
+        Ok(())
+    }
+
+    /// Check that a command may run
+    pub fn check(&self, command: &RedisCommand) -> Result<(), Denied> {
+        let category = command.category();
+        if category == CommandCategory::Connection {
+            return Ok(());
+        }
+        if !self.allows_command(&command.name(), category) {
+            return Err(Denied::Command);
+        }
+        let access = match category {
+            CommandCategory::Read => Access::Read,
+            CommandCategory::Write => Access::Write,
+            _ => return Ok(()),
+        };
+        // Plain keys are in the default keyspace, the empty namespace
+        if !self.allows_namespace(command.namespace().unwrap_or(""), access) {
+            return Err(Denied::Namespace);
+        }
+        Ok(())
+    }
+
+    fn allows_command(&self, name: &str, category: CommandCategory) -> bool {
+        let name = acl_name(name);
+        self.commands
+            .iter()
+            .rev()
+            .find(|(_, rule)| match rule {
+                CommandRule::All => true,
+                CommandRule::Category(c) => *c == category,
+                CommandRule::Command(command) => *command == name,
+            })
+            .is_some_and(|(allow, _)| *allow)
+    }
+
+    /// Whether the namespace may be read or written
+    pub fn allows_namespace(&self, namespace: &str, access: Access) -> bool {
+        self.namespaces.iter().any(|p| {
+            let granted = match access {
+                Access::Read => p.read,
+                Access::Write => p.write,
+            };
+            granted && glob_match(p.pattern.as_bytes(), namespace.as_bytes())
+        })
+    }
+
+    /// The permissions as rules, in the form `ACL LIST` shows them
+    pub fn rules(&self) -> Vec<String> {
+        let mut rules: Vec<String> = self
+            .namespaces
+            .iter()
+            .map(|p| match (p.read, p.write) {
+                (true, true) => format!("~{}", p.pattern),
+                (true, false) => format!("%R~{}", p.pattern),
+                _ => format!("%W~{}", p.pattern),
+            })
+            .collect();
+        if self.namespaces.is_empty() {
+            rules.push("resetnamespaces".to_string());
+        }
+        if self.commands.is_empty() {
+            rules.push("-@all".to_string());
+        }
+        for (allow, rule) in &self.commands {
+            let sign = if *allow { '+' } else { '-' };
+            rules.push(match rule {
+                CommandRule::All => format!("{}@all", sign),
+                CommandRule::Category(category) => format!("{}@{}", sign, category.name()),
+                CommandRule::Command(name) => format!("{}{}", sign, name),
+            });
+        }
+        rules
+    }
+}
+
+/// Name of a command in rules, such as `hget` or `acl|setuser`
+fn acl_name(name: &str) -> String {
+    name.to_ascii_lowercase().replace(' ', "|")
+}
+
+fn is_command_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'|' || b == b'-' || b == b'_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    fn permissions(rules: &[&str]) -> Permissions {
+        let mut permissions = Permissions::default();
+        for rule in rules {
+            permissions.apply(rule).unwrap();
+        }
+        permissions
+    }
+
+    fn hget(namespace: &str) -> RedisCommand {
+        RedisCommand::HGet {
+            namespace: namespace.to_string(),
+            key: Bytes::from_static(b"k"),
+        }
+    }
+
+    fn hset(namespace: &str) -> RedisCommand {
+        RedisCommand::HSet {
+            namespace: namespace.to_string(),
+            key: Bytes::from_static(b"k"),
+            value: Bytes::from_static(b"v"),
+        }
+    }
+
+    #[test]
+    fn test_all_permissions() {
+        let all = Permissions::all();
+        assert!(all.check(&hset("anything")).is_ok());
+        assert!(
+            all.check(&RedisCommand::Get {
+                key: Bytes::from_static(b"k")
+            })
+            .is_ok()
+        );
+        assert!(all.check(&RedisCommand::AclList).is_ok());
+        assert_eq!(all.rules(), vec!["~*", "+@all"]);
+    }
+
+    #[test]
+    fn test_single_command_on_namespace_pattern() {
+        let p = permissions(&["~team_a_*", "+hget"]);
+        assert!(p.check(&hget("team_a_users")).is_ok());
+        assert_eq!(p.check(&hget("team_b_users")), Err(Denied::Namespace));
+        assert_eq!(p.check(&hset("team_a_users")), Err(Denied::Command));
+        // The default keyspace is not covered by the pattern
+        let get = RedisCommand::Get {
+            key: Bytes::from_static(b"k"),
+        };
+        assert_eq!(
+            permissions(&["~team_a_*", "+@read"]).check(&get),
+            Err(Denied::Namespace)
+        );
+        // Connection commands need no permission
+        assert!(
+            Permissions::default()
+                .check(&RedisCommand::AclWhoami)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_read_only_namespaces() {
+        let p = permissions(&["%R~shared_*", "~team_a_*", "+@all", "-@admin"]);
+        assert!(p.check(&hget("shared_config")).is_ok());
+        assert_eq!(p.check(&hset("shared_config")), Err(Denied::Namespace));
+        assert!(p.check(&hset("team_a_users")).is_ok());
+        assert_eq!(p.check(&RedisCommand::AclList), Err(Denied::Command));
+        assert_eq!(
+            p.rules(),
+            vec!["%R~shared_*", "~team_a_*", "+@all", "-@admin"]
+        );
+    }
+
+    #[test]
+    fn test_later_rules_win() {
+        let p = permissions(&["~*", "+@read", "-hgetall", "+@write", "-@write"]);
+        assert!(p.check(&hget("ns")).is_ok());
+        assert_eq!(
+            p.check(&RedisCommand::HGetAll {
+                namespace: "ns".to_string()
+            }),
+            Err(Denied::Command)
+        );
+        assert_eq!(p.check(&hset("ns")), Err(Denied::Command));
+
+        let p = permissions(&["~*", "+@all", "nocommands", "+acl|list"]);
+        assert!(p.check(&RedisCommand::AclList).is_ok());
+        assert_eq!(p.check(&hget("ns")), Err(Denied::Command));
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        let mut p = Permissions::default();
+        assert!(p.apply("+@nope").is_err());
+        assert!(p.apply("%X~ns").is_err());
+        assert!(p.apply("team_a_*").is_err());
+        assert!(p.apply("+").is_err());
+        assert_eq!(p, Permissions::default());
+    }
+
+    #[test]
+    fn test_denied_message() {
+        assert_eq!(
+            Denied::Command.message("app", "ACL SETUSER"),
+            "NOPERM User app has no permissions to run the 'acl|setuser' command"
+        );
+    }
+}
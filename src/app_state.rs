@@ -1,23 +1,46 @@
 use futures::future::join_all;
 use miette::Result;
-use moka::future::Cache;
 use mpchash::HashRing;
 use sqlx::SqlitePool;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
 use std::fs;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
-use crate::compression::{self, Compressor};
+use crate::auth::Users;
+use crate::compression::{CompressionPool, DictionaryStore, ValueCodec};
+use crate::inflight::{InflightHashes, InflightValues};
+use crate::journal::{self, Journal};
 // Import ShardWriteOperation from shard_manager
 use crate::{
-    cluster::ClusterManager, config::Cfg, metrics::Metrics, shard_manager::ShardWriteOperation,
+    chunks,
+    cluster::ClusterManager,
+    config::Cfg,
+    metrics::Metrics,
+    migration,
+    shard_manager::{self, ShardWriteOperation},
 };
 use bytes::Bytes;
 
 #[derive(Hash)]
 struct ShardNode(u64);
 
+/// A key as placed on the hash ring. Keys used to be hashed as `str`, so raw
+/// bytes are hashed the same way to keep every existing key on its shard.
+pub struct ShardKey<'a>(pub &'a [u8]);
+
+impl Hash for ShardKey<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // Mirrors `impl Hash for str`
+        state.write(self.0);
+        state.write_u8(0xff);
+    }
+}
+
 pub struct AppState {
     pub cfg: Cfg,
     pub shard_senders: Vec<mpsc::Sender<ShardWriteOperation>>,
@@ -26,17 +49,31 @@ pub struct AppState {
     /// When async_write=true, SET operations return OK immediately but the actual
     /// database write happens asynchronously. This cache stores the key-value pairs
     /// for pending writes so that GET requests can return the correct data even
-    /// before the write completes, preventing race conditions.
-    pub inflight_cache: Cache<String, Bytes>,
-    /// Cache for inflight namespaced write operations (namespace:key -> data).
-    /// Same as inflight_cache but for HSET/HGET operations. The key format is
-    /// "namespace:key" to avoid collisions between namespaces.
-    pub inflight_hcache: Cache<String, Bytes>,
+    /// before the write completes, preventing race conditions. Entries are
+    /// never evicted, only removed once their write is committed.
+    pub inflight_cache: InflightValues,
+    /// Inflight namespaced write operations ((namespace, key) -> data).
+    /// Same as inflight_cache but for HSET/HGET operations, keyed by
+    /// `namespaced_key` and grouped by namespace so DEL and namespace drops
+    /// find a namespace's pending writes without scanning them all.
+    pub inflight_hcache: InflightHashes,
+    /// Per-shard journals of asynchronous writes, when enabled
+    pub journals: Option<Vec<Arc<Journal>>>,
     /// Cluster manager for Redis cluster protocol
     pub cluster_manager: Option<ClusterManager>,
-    pub compressor: Option<Box<dyn Compressor>>,
+    /// Encodes stored values and decodes them with the codec that wrote them
+    pub codec: Arc<ValueCodec>,
+    /// Threads that encode and decode large values
+    pub compression_pool: CompressionPool,
+    /// Users clients authenticate as, changed at runtime by `ACL SETUSER`
+    pub users: RwLock<Users>,
     /// Metrics collector
     pub metrics: Metrics,
+    /// Cancelled when the server shuts down; connections stop reading new
+    /// commands once the ones already received have been answered
+    pub shutdown: CancellationToken,
+    /// Tasks serving client connections, awaited on shutdown
+    pub connections: TaskTracker,
 
     ring: HashRing<ShardNode>,
 }
@@ -62,29 +99,19 @@ impl AppState {
         // Validate the number of shards in the data directory
         validate_shard_count(&cfg.data_dir, cfg.num_shards)?;
 
+        let storage = cfg.storage();
         let mut db_pools_futures = vec![];
         for i in 0..cfg.num_shards {
             let data_dir = cfg.data_dir.clone();
             let db_path = format!("{}/shard_{}.db", data_dir, i);
 
-            let mut connect_options =
-                SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path))
-                    .expect(&format!(
-                        "Failed to parse connection string for shard {}",
-                        i
-                    ))
-                    .create_if_missing(true)
-                    .journal_mode(SqliteJournalMode::Wal)
-                    .busy_timeout(std::time::Duration::from_millis(5000));
-
-            // These PRAGMAs are often set for performance with WAL mode.
-            // `synchronous = OFF` is safe except for power loss.
-            // `cache_size` is negative to indicate KiB, so -4000 is 4MB.
-            // `temp_store = MEMORY` avoids disk I/O for temporary tables.
-            connect_options = connect_options
-                .pragma("synchronous", "OFF")
-                .pragma("cache_size", "-100000") // 4MB cache per shard
-                .pragma("temp_store", "MEMORY");
+            let connect_options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path))
+                .unwrap_or_else(|e| {
+                    panic!("Failed to parse connection string for shard {}: {}", i, e)
+                })
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal);
+            let connect_options = storage.apply(connect_options);
 
             db_pools_futures.push(sqlx::SqlitePool::connect_with(connect_options))
         }
@@ -102,7 +129,7 @@ impl AppState {
         for (i, pool) in db_pools.iter().enumerate() {
             sqlx::query(
                 "CREATE TABLE IF NOT EXISTS blobs (
-                    key TEXT PRIMARY KEY,
+                    key BLOB PRIMARY KEY,
                     data BLOB,
                     created_at INTEGER NOT NULL,
                     updated_at INTEGER NOT NULL,
@@ -121,12 +148,75 @@ impl AppState {
             .execute(pool)
             .await
             .unwrap_or_else(|e| panic!("Failed to create expires_at index in shard {} DB: {}", i, e));
+
+            // Shard files written by older versions store keys as TEXT
+            let upgraded = migration::upgrade_text_keys(pool)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to upgrade keys in shard {} DB: {}", i, e));
+            if upgraded > 0 {
+                tracing::info!("Converted {} tables of shard {} to BLOB keys", upgraded, i);
+            }
+
+            // Large values are stored as chunks next to `blobs`. The triggers
+            // are created after the upgrade, which rebuilds `blobs`.
+            chunks::create_chunk_table(pool).await.unwrap_or_else(|e| {
+                panic!("Failed to create chunk table in shard {} DB: {}", i, e)
+            });
         }
 
-        // Create caches for inflight operations
-        // Use a reasonable capacity - adjust based on expected load
-        let inflight_cache = Cache::new(10_000);
-        let inflight_hcache = Cache::new(10_000);
+        // Pending async writes; the shard writer channels bound their number
+        let inflight_cache = InflightValues::new();
+        let inflight_hcache = InflightHashes::new();
+
+        // Writes acknowledged by an earlier run but never committed are
+        // applied before any client can read
+        let journals = match cfg.journal() {
+            Some(journal_cfg) => {
+                let mut journals = Vec::with_capacity(cfg.num_shards);
+                for (i, pool) in db_pools.iter().enumerate() {
+                    journal::create_watermark_table(pool).await.map_err(|e| {
+                        miette::miette!("Failed to create journal table in shard {}: {}", i, e)
+                    })?;
+                    let watermark = journal::load_watermark(pool).await.map_err(|e| {
+                        miette::miette!("Failed to read journal watermark of shard {}: {}", i, e)
+                    })?;
+                    let records = Journal::recover(&cfg.data_dir, i, watermark)
+                        .await
+                        .map_err(|e| {
+                            miette::miette!("Failed to read journal of shard {}: {}", i, e)
+                        })?;
+                    let last_seq = records.last().map_or(watermark, |(seq, _)| *seq);
+                    if !records.is_empty() {
+                        tracing::info!(
+                            "Replaying {} journaled writes of shard {}",
+                            records.len(),
+                            i
+                        );
+                        shard_manager::replay_journal(
+                            i,
+                            pool,
+                            records
+                                .into_iter()
+                                .map(|(_, operation)| operation)
+                                .collect(),
+                            last_seq,
+                            &inflight_cache,
+                            &inflight_hcache,
+                        )
+                        .await
+                        .map_err(|e| miette::miette!("Failed to replay journal: {}", e))?;
+                    }
+                    let journal = Journal::create(&cfg.data_dir, i, journal_cfg, last_seq)
+                        .await
+                        .map_err(|e| {
+                            miette::miette!("Failed to open journal of shard {}: {}", i, e)
+                        })?;
+                    journals.push(Arc::new(journal));
+                }
+                Some(journals)
+            }
+            None => None,
+        };
 
         // Initialize cluster manager if clustering is enabled
         let cluster_manager = if let Some(ref cluster_config) = cfg.cluster {
@@ -159,14 +249,34 @@ impl AppState {
             None
         };
 
-        let compressor = match &cfg.storage_compression {
-            Some(comp) if comp.enabled => Some(compression::init_compression(comp.clone())),
-            _ => None,
-        };
+        // Values compressed with any trained dictionary must stay readable
+        let dictionaries = DictionaryStore::load(&cfg.data_dir)
+            .map_err(|e| miette::miette!("Failed to load zstd dictionaries: {}", e))?;
+        let codec = ValueCodec::with_dictionaries(cfg.storage_compression.as_ref(), &dictionaries);
+        if let Some(id) = codec.dictionary() {
+            tracing::info!("Compressing values with zstd dictionary {}", id);
+        }
+
+        let users = Users::from_config(&cfg).map_err(|e| miette::miette!(e))?;
 
         // Initialize metrics
         let metrics = Metrics::new();
 
+        let pool_cfg = cfg.compression_pool();
+        let compression_pool = CompressionPool::new(
+            pool_cfg.threads(),
+            pool_cfg.queue_size(),
+            pool_cfg.inline_threshold(),
+            metrics.compression_queue_seconds.clone(),
+        )
+        .map_err(|e| miette::miette!("Failed to start compression threads: {}", e))?;
+        tracing::info!(
+            "Compression pool started (threads={}, queue_size={}, inline_threshold={})",
+            compression_pool.threads(),
+            pool_cfg.queue_size(),
+            pool_cfg.inline_threshold()
+        );
+
         let ring = HashRing::new();
         for i in 0..cfg.num_shards {
             ring.add(ShardNode(i as u64));
@@ -178,21 +288,43 @@ impl AppState {
             db_pools,
             inflight_cache,
             inflight_hcache,
+            journals,
             cluster_manager,
-            compressor,
+            codec: Arc::new(codec),
+            compression_pool,
+            users: RwLock::new(users),
             metrics,
+            shutdown: CancellationToken::new(),
+            connections: TaskTracker::new(),
             ring,
         })
     }
 
-    pub fn get_shard(&self, key: &str) -> usize {
-        let token = self.ring.node(&key).unwrap();
+    pub fn get_shard(&self, key: &[u8]) -> usize {
+        let token = self.ring.node(&ShardKey(key)).unwrap();
         token.node().0 as usize
     }
 
+    /// Queue an asynchronous write for a shard, appending it to the shard's
+    /// journal first when the journal is enabled
+    pub async fn send_async(
+        &self,
+        shard_index: usize,
+        operation: ShardWriteOperation,
+    ) -> Result<(), String> {
+        let sender = &self.shard_senders[shard_index];
+        match self.journals {
+            Some(ref journals) => journals[shard_index].send(sender, operation).await,
+            None => sender
+                .send(operation)
+                .await
+                .map_err(|_| "shard writer stopped".to_string()),
+        }
+    }
+
     /// Get a namespaced cache key for HGET/HSET operations
-    pub fn namespaced_key(&self, namespace: &str, key: &str) -> String {
-        format!("{}:{}", namespace, key)
+    pub fn namespaced_key(&self, namespace: &str, key: &Bytes) -> (String, Bytes) {
+        (namespace.to_string(), key.clone())
     }
 }
 
@@ -208,10 +340,10 @@ fn validate_shard_count(data_dir: &str, expected_shards: usize) -> Result<()> {
     }
 
     // If the directory is empty, that's also fine - no validation needed
-    if let Ok(entries) = fs::read_dir(data_path) {
-        if entries.count() == 0 {
-            return Ok(());
-        }
+    if let Ok(entries) = fs::read_dir(data_path)
+        && entries.count() == 0
+    {
+        return Ok(());
     }
 
     // Count existing shard files
@@ -230,17 +362,13 @@ fn validate_shard_count(data_dir: &str, expected_shards: usize) -> Result<()> {
         for entry in entries.flatten() {
             let file_name = entry.file_name();
             let file_name_str = file_name.to_string_lossy();
-            if file_name_str.starts_with("shard_") && file_name_str.ends_with(".db") {
-                if let Some(num_str) = file_name_str
-                    .strip_prefix("shard_")
-                    .and_then(|s| s.strip_suffix(".db"))
-                {
-                    if let Ok(shard_num) = num_str.parse::<usize>() {
-                        if shard_num >= expected_shards {
-                            extra_shards.push(shard_num);
-                        }
-                    }
-                }
+            if let Some(num_str) = file_name_str
+                .strip_prefix("shard_")
+                .and_then(|s| s.strip_suffix(".db"))
+                && let Ok(shard_num) = num_str.parse::<usize>()
+                && shard_num >= expected_shards
+            {
+                extra_shards.push(shard_num);
             }
         }
     }
@@ -0,0 +1,466 @@
+//! Password authentication of client connections.
+//!
+//! Passwords are never stored in the configuration in clear text: both
+//! `requirepass` and the `password` of each `[[users]]` entry hold the hex
+//! encoded SHA-256 digest of the password, as printed by
+//! `echo -n 'password' | sha256sum`.
+//!
+//! Like Redis, every server has a `default` user that connections are
+//! authenticated as from the start. Without `requirepass` and `[[users]]` it
+//! accepts any password, so authentication is effectively off. With
+//! `requirepass` it needs that password, and when only `[[users]]` are
+//! configured it does not exist, so every connection must `AUTH` as one of
+//! the configured users. What each user may do once authenticated is
+//! described in [`crate::acl`].
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::acl::Permissions;
+use crate::config::{Cfg, UserConfig};
+
+/// User connections are authenticated as unless they `AUTH` as another
+pub const DEFAULT_USER: &str = "default";
+
+/// A user clients may authenticate as
+#[derive(Debug, Clone)]
+pub struct User {
+    pub name: String,
+    /// SHA-256 digests of the passwords the user accepts
+    passwords: Vec<[u8; 32]>,
+    /// Any password is accepted
+    nopass: bool,
+    pub enabled: bool,
+    pub permissions: Permissions,
+}
+
+impl User {
+    /// A user without passwords or permissions, as created by `ACL SETUSER`
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            passwords: Vec::new(),
+            nopass: false,
+            enabled: false,
+            permissions: Permissions::default(),
+        }
+    }
+
+    /// Apply an `ACL SETUSER` rule
+    fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_ascii_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.passwords.clear();
+                self.nopass = true;
+            }
+            "resetpass" => {
+                self.passwords.clear();
+                self.nopass = false;
+            }
+            "reset" => *self = User::new(&self.name),
+            _ => {
+                if let Some(password) = rule.strip_prefix('>') {
+                    self.add_password(hash_password(password.as_bytes()));
+                } else if let Some(password) = rule.strip_prefix('<') {
+                    self.remove_password(&hash_password(password.as_bytes()))?;
+                } else if let Some(digest) = rule.strip_prefix('#') {
+                    self.add_password(parse_digest(digest).ok_or(
+                        "The password hash must be exactly 64 characters and contain only lowercase hexadecimal characters",
+                    )?);
+                } else if let Some(digest) = rule.strip_prefix('!') {
+                    let digest = parse_digest(digest).ok_or("Syntax error")?;
+                    self.remove_password(&digest)?;
+                } else {
+                    self.permissions.apply(rule)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, digest: [u8; 32]) {
+        self.nopass = false;
+        if !self.passwords.contains(&digest) {
+            self.passwords.push(digest);
+        }
+    }
+
+    fn remove_password(&mut self, digest: &[u8; 32]) -> Result<(), String> {
+        let before = self.passwords.len();
+        self.passwords.retain(|p| p != digest);
+        if self.passwords.len() == before {
+            return Err("no such password".to_string());
+        }
+        Ok(())
+    }
+
+    /// The user as `ACL LIST` shows it, with passwords as digests
+    pub fn describe(&self) -> String {
+        let mut rules = vec![
+            "user".to_string(),
+            self.name.clone(),
+            if self.enabled { "on" } else { "off" }.to_string(),
+        ];
+        if self.nopass {
+            rules.push("nopass".to_string());
+        }
+        for digest in &self.passwords {
+            let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+            rules.push(format!("#{}", hex));
+        }
+        rules.extend(self.permissions.rules());
+        rules.join(" ")
+    }
+}
+
+/// Why an `AUTH` attempt was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// `AUTH <password>` while the default user has no password
+    NoPassword,
+    /// Unknown or disabled user, or a wrong password
+    WrongPass,
+}
+
+impl AuthError {
+    /// Error reply sent to the client, worded like Redis
+    pub fn message(self) -> &'static str {
+        match self {
+            AuthError::NoPassword => {
+                "ERR AUTH <password> called without any password configured for the default user. Are you sure your configuration is correct?"
+            }
+            AuthError::WrongPass => "WRONGPASS invalid username-password pair or user is disabled.",
+        }
+    }
+}
+
+/// Users configured with `requirepass` and `[[users]]`, and changed at
+/// runtime with `ACL SETUSER`
+#[derive(Debug, Clone)]
+pub struct Users {
+    users: HashMap<String, User>,
+}
+
+impl Users {
+    pub fn from_config(cfg: &Cfg) -> Result<Self, String> {
+        let mut users = HashMap::new();
+
+        let configured = cfg.users.as_deref().unwrap_or_default();
+        if cfg.requirepass.is_some() || configured.is_empty() {
+            let mut user = User::new(DEFAULT_USER);
+            user.enabled = true;
+            user.permissions = Permissions::all();
+            match cfg.requirepass {
+                Some(ref digest) => user.add_password(
+                    parse_digest(digest)
+                        .ok_or("requirepass must be a hex encoded SHA-256 digest")?,
+                ),
+                None => user.nopass = true,
+            }
+            users.insert(DEFAULT_USER.to_string(), user);
+        }
+
+        for config in configured {
+            let user = user_from_config(config)?;
+            if users.contains_key(&user.name) {
+                return Err(if user.name == DEFAULT_USER {
+                    "the default user is configured with requirepass and in users".to_string()
+                } else {
+                    format!("user '{}' is configured more than once", user.name)
+                });
+            }
+            users.insert(user.name.clone(), user);
+        }
+
+        Ok(Self { users })
+    }
+
+    /// Whether connections must authenticate before running commands
+    pub fn required(&self) -> bool {
+        self.implicit_user().is_none()
+    }
+
+    /// User a new connection is authenticated as, the default user if it
+    /// accepts any password
+    pub fn implicit_user(&self) -> Option<&User> {
+        self.users
+            .get(DEFAULT_USER)
+            .filter(|user| user.enabled && user.nopass)
+    }
+
+    /// Check a password. Without a user name the password is checked
+    /// against the default user, as `AUTH <password>` does.
+    pub fn authenticate(
+        &self,
+        username: Option<&str>,
+        password: &[u8],
+    ) -> Result<&User, AuthError> {
+        let user = self
+            .users
+            .get(username.unwrap_or(DEFAULT_USER))
+            .filter(|user| user.enabled)
+            .ok_or(AuthError::WrongPass)?;
+        if user.nopass {
+            return match username {
+                Some(_) => Ok(user),
+                None => Err(AuthError::NoPassword),
+            };
+        }
+        if user.passwords.contains(&hash_password(password)) {
+            Ok(user)
+        } else {
+            Err(AuthError::WrongPass)
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    /// Users sorted by name
+    pub fn list(&self) -> Vec<&User> {
+        let mut users: Vec<_> = self.users.values().collect();
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        users
+    }
+
+    /// Create or change a user with `ACL SETUSER` rules. The rules are
+    /// applied together: if one is invalid the user is left unchanged.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> Result<(), String> {
+        validate_name(name)?;
+        let mut user = self
+            .users
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|e| format!("Error in ACL SETUSER modifier '{}': {}", rule, e))?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+}
+
+/// Build a user from its `[[users]]` entry. Users get every permission
+/// unless `namespaces`, `read_namespaces` or `commands` restrict them.
+fn user_from_config(config: &UserConfig) -> Result<User, String> {
+    validate_name(&config.name)?;
+    let mut user = User::new(&config.name);
+    user.enabled = config.enabled.unwrap_or(true);
+    user.add_password(parse_digest(&config.password).ok_or_else(|| {
+        format!(
+            "password of user '{}' must be a hex encoded SHA-256 digest",
+            config.name
+        )
+    })?);
+
+    let mut rules = Vec::new();
+    if config.namespaces.is_none() && config.read_namespaces.is_none() {
+        rules.push("allnamespaces".to_string());
+    }
+    for pattern in config.namespaces.iter().flatten() {
+        rules.push(format!("~{}", pattern));
+    }
+    for pattern in config.read_namespaces.iter().flatten() {
+        rules.push(format!("%R~{}", pattern));
+    }
+    match config.commands {
+        Some(ref commands) => rules.extend(commands.iter().cloned()),
+        None => rules.push("allcommands".to_string()),
+    }
+    for rule in &rules {
+        user.permissions
+            .apply(rule)
+            .map_err(|e| format!("invalid rule '{}' for user '{}': {}", rule, config.name, e))?;
+    }
+    Ok(user)
+}
+
+fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.bytes().any(|b| !(b'!'..=b'~').contains(&b)) {
+        return Err(format!("invalid user name '{}'", name));
+    }
+    Ok(())
+}
+
+/// SHA-256 digest of a password
+pub fn hash_password(password: &[u8]) -> [u8; 32] {
+    Sha256::digest(password).into()
+}
+
+/// Parse a hex encoded SHA-256 digest
+fn parse_digest(hex: &str) -> Option<[u8; 32]> {
+    let hex = hex.trim();
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::RedisCommand;
+    use bytes::Bytes;
+
+    /// sha256("secret")
+    const SECRET: &str = "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b";
+
+    fn cfg(requirepass: Option<&str>, users: Vec<UserConfig>) -> Cfg {
+        let mut cfg: Cfg = serde_json::from_value(serde_json::json!({
+            "data_dir": "/tmp",
+            "num_shards": 1,
+        }))
+        .unwrap();
+        cfg.requirepass = requirepass.map(str::to_string);
+        cfg.users = Some(users);
+        cfg
+    }
+
+    fn user(name: &str, enabled: bool) -> UserConfig {
+        UserConfig {
+            name: name.to_string(),
+            password: SECRET.to_string(),
+            enabled: Some(enabled),
+            namespaces: None,
+            read_namespaces: None,
+            commands: None,
+        }
+    }
+
+    #[test]
+    fn test_no_password_configured() {
+        let users = Users::from_config(&cfg(None, vec![])).unwrap();
+        assert!(!users.required());
+        assert_eq!(users.implicit_user().unwrap().name, DEFAULT_USER);
+        assert_eq!(
+            users.authenticate(None, b"anything").unwrap_err(),
+            AuthError::NoPassword
+        );
+        assert!(users.authenticate(Some("default"), b"anything").is_ok());
+        assert_eq!(
+            users.authenticate(Some("app"), b"secret").unwrap_err(),
+            AuthError::WrongPass
+        );
+    }
+
+    #[test]
+    fn test_requirepass() {
+        let users = Users::from_config(&cfg(Some(SECRET), vec![])).unwrap();
+        assert!(users.required());
+        assert_eq!(users.authenticate(None, b"secret").unwrap().name, "default");
+        assert_eq!(
+            users.authenticate(Some("default"), b"secret").unwrap().name,
+            "default"
+        );
+        assert_eq!(
+            users.authenticate(None, b"wrong").unwrap_err(),
+            AuthError::WrongPass
+        );
+    }
+
+    #[test]
+    fn test_named_users() {
+        let users =
+            Users::from_config(&cfg(None, vec![user("app", true), user("old", false)])).unwrap();
+        assert!(users.required());
+        assert!(users.authenticate(Some(DEFAULT_USER), b"").is_err());
+        assert_eq!(
+            users.authenticate(Some("app"), b"secret").unwrap().name,
+            "app"
+        );
+        assert_eq!(
+            users.authenticate(Some("old"), b"secret").unwrap_err(),
+            AuthError::WrongPass
+        );
+        assert_eq!(
+            users.authenticate(None, b"secret").unwrap_err(),
+            AuthError::WrongPass
+        );
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Users::from_config(&cfg(Some("secret"), vec![])).is_err());
+        assert!(Users::from_config(&cfg(Some(SECRET), vec![user("default", true)])).is_err());
+        assert!(
+            Users::from_config(&cfg(None, vec![user("app", true), user("app", true)])).is_err()
+        );
+        assert!(Users::from_config(&cfg(None, vec![user("my app", true)])).is_err());
+
+        let mut bad = user("app", true);
+        bad.password = "2bb80d53".to_string();
+        assert!(Users::from_config(&cfg(None, vec![bad])).is_err());
+    }
+
+    #[test]
+    fn test_configured_permissions() {
+        let mut team = user("team_a", true);
+        team.namespaces = Some(vec!["team_a_*".to_string()]);
+        team.read_namespaces = Some(vec!["shared".to_string()]);
+        team.commands = Some(vec!["+@read".to_string(), "+hset".to_string()]);
+        let users = Users::from_config(&cfg(None, vec![team, user("admin", true)])).unwrap();
+
+        let team = users.get("team_a").unwrap();
+        assert_eq!(
+            team.describe(),
+            format!(
+                "user team_a on #{} ~team_a_* %R~shared +@read +hset",
+                SECRET
+            )
+        );
+        let hset = RedisCommand::HSet {
+            namespace: "shared".to_string(),
+            key: Bytes::from_static(b"k"),
+            value: Bytes::from_static(b"v"),
+        };
+        assert!(team.permissions.check(&hset).is_err());
+        assert!(users.get("admin").unwrap().permissions.check(&hset).is_ok());
+
+        let mut bad = user("app", true);
+        bad.commands = Some(vec!["+@nope".to_string()]);
+        assert!(Users::from_config(&cfg(None, vec![bad])).is_err());
+    }
+
+    #[test]
+    fn test_set_user() {
+        let mut users = Users::from_config(&cfg(Some(SECRET), vec![])).unwrap();
+        let rules = |rules: &[&str]| rules.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+
+        users.set_user("team_b", &rules(&[">pw"])).unwrap();
+        // New users are disabled until switched on
+        assert!(users.authenticate(Some("team_b"), b"pw").is_err());
+        users
+            .set_user("team_b", &rules(&["on", "~team_b_*", "+@all"]))
+            .unwrap();
+        assert!(users.authenticate(Some("team_b"), b"pw").is_ok());
+        assert!(users.authenticate(Some("team_b"), b"other").is_err());
+
+        let err = users
+            .set_user("team_b", &rules(&["off", "+@nope"]))
+            .unwrap_err();
+        assert!(err.starts_with("Error in ACL SETUSER modifier '+@nope'"));
+        assert!(users.get("team_b").unwrap().enabled);
+
+        users
+            .set_user("team_b", &rules(&["<pw", "nopass"]))
+            .unwrap();
+        assert!(users.authenticate(Some("team_b"), b"anything").is_ok());
+        assert_eq!(
+            users
+                .list()
+                .iter()
+                .map(|u| u.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["default", "team_b"]
+        );
+        assert!(users.set_user("bad name", &[]).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! Storage of large values as chunks.
+//!
+//! A value larger than `chunk_size` is split into chunks that are encoded with
+//! the value codec one by one and stored in the `blob_chunks` table of its
+//! shard. The `blobs` row then holds a small manifest instead of the value:
+//!
+//! ```text
+//! +-------------+-----+----------------+------------------+----------------+
+//! | b1 0b 5a 01 | 06  | length (u64le) | chunk size (u32) | generation u64 |
+//! +-------------+-----+----------------+------------------+----------------+
+//! ```
+//!
+//! The manifest keeps the chunk size the value was written with, so changing
+//! `chunk_size` only affects values written afterwards. The generation is
+//! picked anew whenever a value is written as a whole, which lets APPEND and
+//! SETRANGE detect that a value was replaced while they were reading it.
+//!
+//! Triggers on `blobs` delete the chunks of a key whenever its row is deleted
+//! or overwritten with a value that is not chunked, so DEL, expiry and plain
+//! SETs never leave chunks behind.
+
+use crate::compression::codec::{CHUNKED_TAG, FRAME_MAGIC};
+use bytes::Bytes;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Table holding the chunks of large values, next to `blobs` in every shard.
+/// Its name must not start with `blobs` so it is never taken for a namespace.
+pub const CHUNK_TABLE: &str = "blob_chunks";
+
+/// Length of an encoded manifest
+pub const MANIFEST_LEN: usize = FRAME_MAGIC.len() + 1 + 8 + 4 + 8;
+
+/// Describes a value stored as chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkManifest {
+    /// Length of the whole value
+    pub len: u64,
+    /// Length of every chunk but the last
+    pub chunk_size: u32,
+    pub generation: u64,
+}
+
+impl ChunkManifest {
+    /// Manifest of a value of `len` bytes written as a whole
+    pub fn new(len: u64, chunk_size: u32) -> Self {
+        Self {
+            len,
+            chunk_size,
+            generation: next_generation(),
+        }
+    }
+
+    /// Parse a stored value, `None` unless it is a manifest
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() != MANIFEST_LEN {
+            return None;
+        }
+        let rest = data.strip_prefix(&FRAME_MAGIC)?;
+        let (&tag, rest) = rest.split_first()?;
+        if tag != CHUNKED_TAG {
+            return None;
+        }
+        let (len, rest) = rest.split_first_chunk::<8>()?;
+        let (chunk_size, rest) = rest.split_first_chunk::<4>()?;
+        let (generation, _) = rest.split_first_chunk::<8>()?;
+        let chunk_size = u32::from_le_bytes(*chunk_size);
+        if chunk_size == 0 {
+            return None;
+        }
+        Some(Self {
+            len: u64::from_le_bytes(*len),
+            chunk_size,
+            generation: u64::from_le_bytes(*generation),
+        })
+    }
+
+    pub fn encode(&self) -> Bytes {
+        let mut data = Vec::with_capacity(MANIFEST_LEN);
+        data.extend_from_slice(&FRAME_MAGIC);
+        data.push(CHUNKED_TAG);
+        data.extend_from_slice(&self.len.to_le_bytes());
+        data.extend_from_slice(&self.chunk_size.to_le_bytes());
+        data.extend_from_slice(&self.generation.to_le_bytes());
+        Bytes::from(data)
+    }
+
+    pub fn chunk_count(&self) -> u32 {
+        self.len.div_ceil(self.chunk_size as u64) as u32
+    }
+
+    /// Byte range of the value held by chunk `idx`
+    pub fn chunk_range(&self, idx: u32) -> Range<u64> {
+        let start = idx as u64 * self.chunk_size as u64;
+        start..(start + self.chunk_size as u64).min(self.len)
+    }
+
+    /// Chunks holding the bytes `start..end` of the value
+    pub fn chunks_for(&self, start: u64, end: u64) -> Range<u32> {
+        if start >= end {
+            return 0..0;
+        }
+        let chunk_size = self.chunk_size as u64;
+        (start / chunk_size) as u32..end.div_ceil(chunk_size) as u32
+    }
+}
+
+/// Whether a stored value is a manifest
+pub fn is_chunked(data: &[u8]) -> bool {
+    ChunkManifest::parse(data).is_some()
+}
+
+// Generations only need to differ between writes of the same key, so the
+// clock at startup plus a counter is enough
+fn next_generation() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    if NEXT.load(Ordering::Relaxed) == 0 {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(1);
+        let _ = NEXT.compare_exchange(0, now, Ordering::Relaxed, Ordering::Relaxed);
+    }
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Create the chunk table of a shard and the triggers keeping it in step
+/// with `blobs`
+pub async fn create_chunk_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    let not_chunked = format!(
+        "NEW.data IS NULL OR substr(NEW.data, 1, 5) != x'{}'",
+        FRAME_MAGIC
+            .iter()
+            .chain(std::iter::once(&CHUNKED_TAG))
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+    let statements = [
+        format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                key BLOB NOT NULL,
+                idx INTEGER NOT NULL,
+                data BLOB NOT NULL,
+                PRIMARY KEY (key, idx)
+            ) WITHOUT ROWID",
+            CHUNK_TABLE
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS blob_chunks_on_delete AFTER DELETE ON blobs
+             BEGIN DELETE FROM {} WHERE key = OLD.key; END",
+            CHUNK_TABLE
+        ),
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS blob_chunks_on_update AFTER UPDATE OF data ON blobs
+             WHEN {}
+             BEGIN DELETE FROM {} WHERE key = NEW.key; END",
+            not_chunked, CHUNK_TABLE
+        ),
+        // Also covers INSERT OR REPLACE over an expired chunked value, since
+        // REPLACE does not fire delete triggers
+        format!(
+            "CREATE TRIGGER IF NOT EXISTS blob_chunks_on_insert AFTER INSERT ON blobs
+             WHEN {}
+             BEGIN DELETE FROM {} WHERE key = NEW.key; END",
+            not_chunked, CHUNK_TABLE
+        ),
+    ];
+    for statement in &statements {
+        sqlx::query(statement).execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Store encoded chunks of a value and drop any chunk past its end
+pub async fn write_chunks(
+    conn: &mut SqliteConnection,
+    key: &[u8],
+    manifest: &ChunkManifest,
+    chunks: &[(u32, Bytes)],
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM blob_chunks WHERE key = ? AND idx >= ?")
+        .bind(key)
+        .bind(manifest.chunk_count() as i64)
+        .execute(&mut *conn)
+        .await?;
+    for (idx, data) in chunks {
+        sqlx::query("INSERT OR REPLACE INTO blob_chunks (key, idx, data) VALUES (?, ?, ?)")
+            .bind(key)
+            .bind(*idx as i64)
+            .bind(&data[..])
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Read the encoded chunks `range` of a value, in order. Fails if one is
+/// missing.
+pub async fn read_chunks(
+    conn: &mut SqliteConnection,
+    key: &[u8],
+    range: Range<u32>,
+) -> Result<Vec<Bytes>, sqlx::Error> {
+    if range.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query_as::<_, (i64, Vec<u8>)>(
+        "SELECT idx, data FROM blob_chunks WHERE key = ? AND idx >= ? AND idx < ? ORDER BY idx",
+    )
+    .bind(key)
+    .bind(range.start as i64)
+    .bind(range.end as i64)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if rows.len() != range.len()
+        || rows
+            .iter()
+            .zip(range.clone())
+            .any(|((idx, _), expected)| *idx != expected as i64)
+    {
+        return Err(sqlx::Error::Protocol(format!(
+            "chunks {:?} of key {:?} are incomplete",
+            range,
+            String::from_utf8_lossy(key)
+        )));
+    }
+    Ok(rows
+        .into_iter()
+        .map(|(_, data)| Bytes::from(data))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let manifest = ChunkManifest::new(10 * 1024 * 1024 + 1, 1024 * 1024);
+        let encoded = manifest.encode();
+        assert_eq!(encoded.len(), MANIFEST_LEN);
+        assert_eq!(ChunkManifest::parse(&encoded), Some(manifest));
+        assert!(is_chunked(&encoded));
+        assert_eq!(manifest.chunk_count(), 11);
+
+        // Every full write gets a new generation
+        assert_ne!(
+            ChunkManifest::new(1, 1).generation,
+            ChunkManifest::new(1, 1).generation
+        );
+
+        assert!(!is_chunked(b"plain value"));
+        assert!(!is_chunked(&encoded[..MANIFEST_LEN - 1]));
+        let mut other_tag = encoded.to_vec();
+        other_tag[FRAME_MAGIC.len()] = 0;
+        assert!(!is_chunked(&other_tag));
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        let manifest = ChunkManifest::new(25, 10);
+        assert_eq!(manifest.chunk_count(), 3);
+        assert_eq!(manifest.chunk_range(0), 0..10);
+        assert_eq!(manifest.chunk_range(2), 20..25);
+        assert_eq!(manifest.chunks_for(0, 25), 0..3);
+        assert_eq!(manifest.chunks_for(9, 11), 0..2);
+        assert_eq!(manifest.chunks_for(10, 20), 1..2);
+        assert_eq!(manifest.chunks_for(5, 5), 0..0);
+    }
+}
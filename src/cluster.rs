@@ -10,23 +10,18 @@ use tokio::time::interval;
 use tracing::{debug, error, info, warn};
 
 /// Strategy for distributing namespace operations across cluster nodes
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub enum NamespaceDistributionStrategy {
     /// Use namespace for slot calculation (legacy behavior - confines namespace to one node)
     #[allow(dead_code)]
     NamespaceBased,
     /// Use key for slot calculation (distributes namespace across nodes)
+    #[default]
     KeyBased,
     /// Use hash tags when present, fallback to key-based
     HashTagAware,
 }
 
-impl Default for NamespaceDistributionStrategy {
-    fn default() -> Self {
-        Self::KeyBased
-    }
-}
-
 use crate::config::ClusterConfig;
 
 /// Redis cluster has 16384 hash slots
@@ -265,6 +260,12 @@ impl ClusterManager {
 
                     debug!("Processing node: {}", chitchat_id.node_id);
 
+                    // Nodes that are shutting down no longer serve their slots
+                    if node_state.get("node_status") == Some("shutting_down") {
+                        debug!("Node {} is shutting down", chitchat_id.node_id);
+                        continue;
+                    }
+
                     if let Some(node_info_value) = node_state.get("node_info") {
                         debug!("Node {} has gossip data", chitchat_id.node_id);
                         match serde_json::from_str::<NodeGossipData>(node_info_value) {
@@ -352,12 +353,15 @@ impl ClusterManager {
     }
 
     /// Calculate Redis hash slot for a key
-    pub fn calculate_slot(key: &str) -> u16 {
+    pub fn calculate_slot(key: &[u8]) -> u16 {
         Self::calculate_slot_with_strategy(key, NamespaceDistributionStrategy::HashTagAware)
     }
 
     /// Calculate slot with specific distribution strategy
-    pub fn calculate_slot_with_strategy(key: &str, strategy: NamespaceDistributionStrategy) -> u16 {
+    pub fn calculate_slot_with_strategy(
+        key: &[u8],
+        strategy: NamespaceDistributionStrategy,
+    ) -> u16 {
         let hash_key = match strategy {
             NamespaceDistributionStrategy::NamespaceBased => {
                 // Legacy behavior - use full key
@@ -369,8 +373,8 @@ impl ClusterManager {
             }
             NamespaceDistributionStrategy::HashTagAware => {
                 // Extract hash tag if present (e.g., "user:{123}:profile" -> "123")
-                if let Some(start) = key.find('{') {
-                    if let Some(end) = key[start + 1..].find('}') {
+                if let Some(start) = key.iter().position(|&b| b == b'{') {
+                    if let Some(end) = key[start + 1..].iter().position(|&b| b == b'}') {
                         let tag = &key[start + 1..start + 1 + end];
                         if !tag.is_empty() { tag } else { key }
                     } else {
@@ -382,41 +386,36 @@ impl ClusterManager {
             }
         };
 
-        crc16::State::<crc16::XMODEM>::calculate(hash_key.as_bytes()) % REDIS_CLUSTER_SLOTS
+        crc16::State::<crc16::XMODEM>::calculate(hash_key) % REDIS_CLUSTER_SLOTS
     }
 
     /// Calculate slot for hash operations (HGET, HSET, etc.)
-    pub fn calculate_slot_for_hash(&self, namespace: &str, key: &str) -> u16 {
+    pub fn calculate_slot_for_hash(&self, namespace: &str, key: &[u8]) -> u16 {
         match self.namespace_strategy {
             NamespaceDistributionStrategy::NamespaceBased => {
                 // Use namespace as the hash key (legacy behavior)
-                Self::calculate_slot_with_strategy(namespace, self.namespace_strategy)
+                Self::calculate_slot_with_strategy(namespace.as_bytes(), self.namespace_strategy)
             }
             NamespaceDistributionStrategy::KeyBased => {
                 // Use individual key for distribution
                 Self::calculate_slot_with_strategy(key, self.namespace_strategy)
             }
             NamespaceDistributionStrategy::HashTagAware => {
-                // Check if key has hash tag, otherwise use key
-                let combined_key = if key.contains('{') && key.contains('}') {
-                    key
-                } else {
-                    key
-                };
-                Self::calculate_slot_with_strategy(combined_key, self.namespace_strategy)
+                // Hash tags in the key are honoured by calculate_slot_with_strategy
+                Self::calculate_slot_with_strategy(key, self.namespace_strategy)
             }
         }
     }
 
     /// Check if we should handle a hash operation locally
-    pub async fn should_handle_hash_locally(&self, namespace: &str, key: &str) -> bool {
+    pub async fn should_handle_hash_locally(&self, namespace: &str, key: &[u8]) -> bool {
         let slot = self.calculate_slot_for_hash(namespace, key);
         let local_slots = self.local_slots.read().await;
         local_slots.contains(&slot)
     }
 
     /// Get redirect response for a hash operation
-    pub async fn get_hash_redirect_response(&self, namespace: &str, key: &str) -> Option<String> {
+    pub async fn get_hash_redirect_response(&self, namespace: &str, key: &[u8]) -> Option<String> {
         let slot = self.calculate_slot_for_hash(namespace, key);
         if let Some(node) = self.get_node_for_slot(slot).await {
             Some(format!("MOVED {} {}", slot, node.addr))
@@ -533,14 +532,14 @@ impl ClusterManager {
     }
 
     /// Check if we should handle a key locally or redirect
-    pub async fn should_handle_locally(&self, key: &str) -> bool {
+    pub async fn should_handle_locally(&self, key: &[u8]) -> bool {
         let slot = Self::calculate_slot(key);
         let local_slots = self.local_slots.read().await;
         local_slots.contains(&slot)
     }
 
     /// Get redirect response for a key
-    pub async fn get_redirect_response(&self, key: &str) -> Option<String> {
+    pub async fn get_redirect_response(&self, key: &[u8]) -> Option<String> {
         let slot = Self::calculate_slot(key);
         if let Some(node) = self.get_node_for_slot(slot).await {
             Some(format!(
@@ -628,8 +627,8 @@ impl ClusterManager {
         self.local_slots.read().await.clone()
     }
 
-    /// Shutdown the cluster manager
-    #[allow(dead_code)]
+    /// Announce that this node is leaving, so peers stop redirecting clients
+    /// to it, and give the announcement one gossip round to spread.
     pub async fn shutdown(&self) {
         if let Some(handle) = self.chitchat_handle.as_ref() {
             let chitchat_arc = handle.chitchat();
@@ -639,6 +638,10 @@ impl ClusterManager {
                 .set("node_status", "shutting_down");
             drop(chitchat_guard);
             // Note: We don't call handle.shutdown() as it would consume the handle
+            tokio::time::sleep(Duration::from_millis(
+                self.config.gossip_interval_ms.unwrap_or(1000),
+            ))
+            .await;
         }
         info!("Shutting down cluster manager");
     }
@@ -668,29 +671,29 @@ mod tests {
     #[test]
     fn test_calculate_slot_basic_keys() {
         // Test basic key hashing
-        let slot1 = ClusterManager::calculate_slot("mykey");
-        let slot2 = ClusterManager::calculate_slot("anotherkey");
+        let slot1 = ClusterManager::calculate_slot(b"mykey");
+        let slot2 = ClusterManager::calculate_slot(b"anotherkey");
 
         // Slots should be within valid range
         assert!(slot1 < REDIS_CLUSTER_SLOTS);
         assert!(slot2 < REDIS_CLUSTER_SLOTS);
 
         // Same key should always produce same slot
-        assert_eq!(slot1, ClusterManager::calculate_slot("mykey"));
+        assert_eq!(slot1, ClusterManager::calculate_slot(b"mykey"));
     }
 
     #[test]
     fn test_calculate_slot_with_hash_tags() {
         // Keys with same hash tag should go to same slot
-        let slot1 = ClusterManager::calculate_slot("user:{1000}:profile");
-        let slot2 = ClusterManager::calculate_slot("user:{1000}:settings");
-        let slot3 = ClusterManager::calculate_slot("user:{1000}:data");
+        let slot1 = ClusterManager::calculate_slot(b"user:{1000}:profile");
+        let slot2 = ClusterManager::calculate_slot(b"user:{1000}:settings");
+        let slot3 = ClusterManager::calculate_slot(b"user:{1000}:data");
 
         assert_eq!(slot1, slot2);
         assert_eq!(slot2, slot3);
 
         // Different hash tags should likely go to different slots
-        let slot4 = ClusterManager::calculate_slot("user:{2000}:profile");
+        let slot4 = ClusterManager::calculate_slot(b"user:{2000}:profile");
         // Note: Different hash tags *might* collide, but it's very unlikely
         assert!(slot4 < REDIS_CLUSTER_SLOTS);
     }
@@ -698,8 +701,8 @@ mod tests {
     #[test]
     fn test_calculate_slot_empty_hash_tag() {
         // Empty hash tag should use whole key
-        let slot1 = ClusterManager::calculate_slot("user:{}:profile");
-        let slot2 = ClusterManager::calculate_slot("user:{}:profile");
+        let slot1 = ClusterManager::calculate_slot(b"user:{}:profile");
+        let slot2 = ClusterManager::calculate_slot(b"user:{}:profile");
 
         assert_eq!(slot1, slot2);
         assert!(slot1 < REDIS_CLUSTER_SLOTS);
@@ -708,14 +711,14 @@ mod tests {
     #[test]
     fn test_calculate_slot_no_hash_tag() {
         // Keys without hash tags should use full key
-        let slot1 = ClusterManager::calculate_slot("simple_key");
-        let slot2 = ClusterManager::calculate_slot("another_simple_key");
+        let slot1 = ClusterManager::calculate_slot(b"simple_key");
+        let slot2 = ClusterManager::calculate_slot(b"another_simple_key");
 
         assert!(slot1 < REDIS_CLUSTER_SLOTS);
         assert!(slot2 < REDIS_CLUSTER_SLOTS);
 
         // Same key should produce same slot
-        assert_eq!(slot1, ClusterManager::calculate_slot("simple_key"));
+        assert_eq!(slot1, ClusterManager::calculate_slot(b"simple_key"));
     }
 
     #[test]
@@ -724,13 +727,13 @@ mod tests {
         // These values are based on Redis cluster slot calculation
 
         // "key" should map to slot 12539 in Redis
-        assert_eq!(ClusterManager::calculate_slot("key"), 12539);
+        assert_eq!(ClusterManager::calculate_slot(b"key"), 12539);
 
         // "foo" should map to slot 12182 in Redis
-        assert_eq!(ClusterManager::calculate_slot("foo"), 12182);
+        assert_eq!(ClusterManager::calculate_slot(b"foo"), 12182);
 
         // "bar" should map to slot 5061 in Redis
-        assert_eq!(ClusterManager::calculate_slot("bar"), 5061);
+        assert_eq!(ClusterManager::calculate_slot(b"bar"), 5061);
     }
 
     #[test]
@@ -778,8 +781,7 @@ mod tests {
         let cluster_manager = ClusterManager::new(&config, gossip_bind_addr, redis_addr).await;
 
         // Note: This test might fail in CI due to UDP binding issues
-        if cluster_manager.is_ok() {
-            let manager = cluster_manager.unwrap();
+        if let Ok(manager) = cluster_manager {
             assert_eq!(manager.node_id, "test-node");
 
             // Check local slots were set
@@ -815,8 +817,8 @@ mod tests {
                 // Find a key that maps to this slot
                 for i in 0..1000 {
                     let test_key = format!("testkey{}", i);
-                    if ClusterManager::calculate_slot(&test_key) == slot {
-                        assert!(manager.should_handle_locally(&test_key).await);
+                    if ClusterManager::calculate_slot(test_key.as_bytes()) == slot {
+                        assert!(manager.should_handle_locally(test_key.as_bytes()).await);
                         break;
                     }
                 }
@@ -824,7 +826,7 @@ mod tests {
 
             // Test a key that definitely won't be in slots 0, 1, 2
             // We'll use a key that we know maps to a different slot
-            let remote_key = "key"; // This maps to slot 12539
+            let remote_key = b"key"; // This maps to slot 12539
             assert!(!manager.should_handle_locally(remote_key).await);
         }
     }
@@ -0,0 +1,547 @@
+//! Self-describing stored values.
+//!
+//! Every value written to a shard starts with a small header naming the
+//! algorithm it was compressed with, so it can be decoded no matter how
+//! `storage_compression` is configured when it is read. Values written before
+//! the header existed have no marker: they are decoded with the configured
+//! compressor as they always were, and returned as stored if that fails or
+//! compression is disabled.
+//!
+//! Values the [`CompressionPolicy`] decides not to compress are stored with the
+//! `none` tag, so they cost nothing to decode whatever is configured.
+//!
+//! ```text
+//! +---------------------+-----------+------------------------+
+//! | magic (4 bytes)     | codec tag | payload                |
+//! | b1 0b 5a 01         | 1 byte    | compressed value bytes |
+//! +---------------------+-----------+------------------------+
+//! ```
+//!
+//! Values compressed with a trained zstd dictionary use their own tag, followed
+//! by the id of the dictionary as a little endian `u32` before the payload.
+
+use super::{
+    CompressionOutcome, CompressionPolicy, Compressor, DictionaryStore, ZstdCompressor,
+    init_compression,
+};
+use crate::config::{CompressionConfig, CompressionType};
+use std::collections::HashMap;
+use std::io;
+
+/// Marks a value that starts with a codec header. The last byte is the format
+/// version.
+pub const FRAME_MAGIC: [u8; 4] = [0xb1, 0x0b, 0x5a, 0x01];
+
+/// Length of the header in front of every framed value
+pub const HEADER_LEN: usize = FRAME_MAGIC.len() + 1;
+
+/// Tag of values compressed with zstd and a trained dictionary, whose id
+/// follows the tag
+pub const ZSTD_DICTIONARY_TAG: u8 = 5;
+
+/// Tag of the manifest of a value stored as chunks (see `crate::chunks`).
+/// Manifests are not values and cannot be decoded.
+pub const CHUNKED_TAG: u8 = 6;
+
+/// Tag stored in the header for an algorithm. Tags are part of the on-disk
+/// format and must never be reused.
+pub fn codec_tag(algorithm: CompressionType) -> u8 {
+    match algorithm {
+        CompressionType::None => 0,
+        CompressionType::Gzip => 1,
+        CompressionType::Zstd => 2,
+        CompressionType::Lz4 => 3,
+        CompressionType::Brotli => 4,
+    }
+}
+
+/// Algorithm of a header tag, `None` for tags this version does not know
+pub fn codec_from_tag(tag: u8) -> Option<CompressionType> {
+    match tag {
+        0 => Some(CompressionType::None),
+        1 => Some(CompressionType::Gzip),
+        2 => Some(CompressionType::Zstd),
+        3 => Some(CompressionType::Lz4),
+        4 => Some(CompressionType::Brotli),
+        _ => None,
+    }
+}
+
+/// A stored value split into its header fields and payload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub algorithm: CompressionType,
+    /// Id of the zstd dictionary the payload was compressed with
+    pub dictionary: Option<u32>,
+    pub payload: &'a [u8],
+}
+
+/// Split a stored value into its header fields and payload. Returns `None`
+/// for values written before values were framed.
+pub fn parse_frame(data: &[u8]) -> Option<Frame<'_>> {
+    let rest = data.strip_prefix(&FRAME_MAGIC)?;
+    let (&tag, payload) = rest.split_first()?;
+    if tag == ZSTD_DICTIONARY_TAG {
+        let (id, payload) = payload.split_first_chunk::<4>()?;
+        return Some(Frame {
+            algorithm: CompressionType::Zstd,
+            dictionary: Some(u32::from_le_bytes(*id)),
+            payload,
+        });
+    }
+    Some(Frame {
+        algorithm: codec_from_tag(tag)?,
+        dictionary: None,
+        payload,
+    })
+}
+
+/// Encodes values with the configured algorithm and decodes values written
+/// with any of them.
+pub struct ValueCodec {
+    algorithm: CompressionType,
+    compressor: Box<dyn Compressor>,
+    policy: CompressionPolicy,
+    /// Dictionary `compressor` compresses with
+    dictionary: Option<u32>,
+    /// Decompressors for the values of every known dictionary
+    dictionaries: HashMap<u32, ZstdCompressor>,
+    /// Whether unframed values are decompressed with `compressor`
+    enabled: bool,
+}
+
+impl ValueCodec {
+    /// Codec for the `storage_compression` section, which also decodes
+    /// values compressed with the dictionaries of `store`. Values are stored
+    /// uncompressed when the section is missing or disabled, and compressed
+    /// with the latest dictionary when it asks for one.
+    pub fn with_dictionaries(config: Option<&CompressionConfig>, store: &DictionaryStore) -> Self {
+        let dictionaries = store
+            .iter()
+            .map(|(id, dictionary)| (id, ZstdCompressor::with_dictionary(None, dictionary)))
+            .collect();
+
+        match config {
+            Some(config) if config.enabled => {
+                let (compressor, dictionary): (Box<dyn Compressor>, _) = match (
+                    config.use_dictionary.unwrap_or(false),
+                    store.latest(),
+                ) {
+                    (true, Some((id, dictionary))) => (
+                        Box::new(ZstdCompressor::with_dictionary(config.level, dictionary)),
+                        Some(id),
+                    ),
+                    (true, None) => {
+                        tracing::warn!(
+                            "use_dictionary is set but no dictionary has been trained, compressing without one"
+                        );
+                        (init_compression(config.clone()), None)
+                    }
+                    (false, _) => (init_compression(config.clone()), None),
+                };
+                ValueCodec {
+                    algorithm: config.algorithm,
+                    compressor,
+                    policy: CompressionPolicy::new(config),
+                    dictionary,
+                    dictionaries,
+                    enabled: true,
+                }
+            }
+            _ => ValueCodec {
+                algorithm: CompressionType::None,
+                compressor: decoder(CompressionType::None),
+                policy: CompressionPolicy {
+                    min_size: 0,
+                    min_savings_percent: 0,
+                },
+                dictionary: None,
+                dictionaries,
+                enabled: false,
+            },
+        }
+    }
+
+    /// Whether new values are compressed at all
+    pub fn compresses(&self) -> bool {
+        self.algorithm != CompressionType::None
+    }
+
+    /// Id of the dictionary new values are compressed with
+    pub fn dictionary(&self) -> Option<u32> {
+        self.dictionary
+    }
+
+    /// Compress a value and put the codec header in front of it
+    pub async fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(self.encode_with_outcome(data).await?.0)
+    }
+
+    /// Like [`ValueCodec::encode`], also reporting whether the value was
+    /// compressed or why it was not
+    pub async fn encode_with_outcome(
+        &self,
+        data: &[u8],
+    ) -> io::Result<(Vec<u8>, CompressionOutcome)> {
+        if self.algorithm == CompressionType::None {
+            return Ok((
+                frame(CompressionType::None, data),
+                CompressionOutcome::Disabled,
+            ));
+        }
+        if !self.policy.should_compress(data.len()) {
+            return Ok((
+                frame(CompressionType::None, data),
+                CompressionOutcome::TooSmall,
+            ));
+        }
+
+        let compressed = self.compressor.compress(data).await?;
+        if !self.policy.accepts(data.len(), compressed.len()) {
+            return Ok((
+                frame(CompressionType::None, data),
+                CompressionOutcome::Incompressible,
+            ));
+        }
+        let framed = match self.dictionary {
+            Some(id) => frame_with_dictionary(id, &compressed),
+            None => frame(self.algorithm, &compressed),
+        };
+        Ok((framed, CompressionOutcome::Compressed))
+    }
+
+    /// Decode a stored value with the algorithm that wrote it
+    pub async fn decode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.get(..HEADER_LEN).is_some_and(|header| {
+            header.starts_with(&FRAME_MAGIC) && header[FRAME_MAGIC.len()] == CHUNKED_TAG
+        }) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "value is stored as chunks",
+            ));
+        }
+        match parse_frame(data) {
+            Some(Frame {
+                dictionary: Some(id),
+                payload,
+                ..
+            }) => match self.dictionaries.get(&id) {
+                Some(decompressor) => decompressor.decompress(payload).await,
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("zstd dictionary {} is not in the data directory", id),
+                )),
+            },
+            Some(Frame {
+                algorithm, payload, ..
+            }) if algorithm == self.algorithm && self.dictionary.is_none() => {
+                self.compressor.decompress(payload).await
+            }
+            Some(Frame {
+                algorithm, payload, ..
+            }) => decoder(algorithm).decompress(payload).await,
+            None if self.enabled => {
+                // Legacy values were never compressed with a dictionary
+                let decompressed = match self.dictionary {
+                    Some(_) => decoder(self.algorithm).decompress(data).await,
+                    None => self.compressor.decompress(data).await,
+                };
+                match decompressed {
+                    Ok(decompressed) => Ok(decompressed),
+                    // Stored before compression was enabled
+                    Err(_) => Ok(data.to_vec()),
+                }
+            }
+            None => Ok(data.to_vec()),
+        }
+    }
+}
+
+/// Put the header for `algorithm` in front of a payload
+fn frame(algorithm: CompressionType, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(HEADER_LEN + payload.len());
+    framed.extend_from_slice(&FRAME_MAGIC);
+    framed.push(codec_tag(algorithm));
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// Put the header of a value compressed with dictionary `id` in front of a
+/// payload
+fn frame_with_dictionary(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(HEADER_LEN + 4 + payload.len());
+    framed.extend_from_slice(&FRAME_MAGIC);
+    framed.push(ZSTD_DICTIONARY_TAG);
+    framed.extend_from_slice(&id.to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// A compressor used to decompress values of `algorithm`. The level only
+/// matters when compressing, so the algorithm's default is used.
+fn decoder(algorithm: CompressionType) -> Box<dyn Compressor> {
+    init_compression(CompressionConfig {
+        enabled: true,
+        algorithm,
+        level: None,
+        min_size: None,
+        min_savings_percent: None,
+        use_dictionary: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALGORITHMS: [CompressionType; 5] = [
+        CompressionType::None,
+        CompressionType::Gzip,
+        CompressionType::Zstd,
+        CompressionType::Lz4,
+        CompressionType::Brotli,
+    ];
+
+    fn codec(algorithm: Option<CompressionType>) -> ValueCodec {
+        let config = algorithm.map(|algorithm| CompressionConfig {
+            enabled: true,
+            algorithm,
+            level: None,
+            min_size: None,
+            min_savings_percent: None,
+            use_dictionary: None,
+        });
+        ValueCodec::with_dictionaries(config.as_ref(), &DictionaryStore::default())
+    }
+
+    #[test]
+    fn test_tags_round_trip() {
+        for algorithm in ALGORITHMS {
+            assert_eq!(codec_from_tag(codec_tag(algorithm)), Some(algorithm));
+        }
+        assert_eq!(codec_from_tag(0xff), None);
+    }
+
+    #[tokio::test]
+    async fn test_values_decode_with_their_writer() {
+        let data = b"some value some value some value".repeat(10);
+        let mut stored = Vec::new();
+        for algorithm in ALGORITHMS {
+            let encoded = codec(Some(algorithm)).encode(&data).await.unwrap();
+            assert_eq!(parse_frame(&encoded).map(|f| f.algorithm), Some(algorithm));
+            stored.push(encoded);
+        }
+        stored.push(codec(None).encode(&data).await.unwrap());
+
+        // Whatever is configured now, every stored value reads back
+        for reader in ALGORITHMS.map(Some).into_iter().chain([None]) {
+            let reader = codec(reader);
+            for encoded in &stored {
+                assert_eq!(reader.decode(encoded).await.unwrap(), data);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_legacy_values() {
+        let data = b"written before values were framed".to_vec();
+
+        // Uncompressed legacy values are returned as stored
+        for reader in ALGORITHMS.map(Some).into_iter().chain([None]) {
+            assert_eq!(codec(reader).decode(&data).await.unwrap(), data);
+        }
+
+        // Compressed legacy values decode with the configured algorithm
+        let gzip = init_compression(CompressionConfig {
+            enabled: true,
+            algorithm: CompressionType::Gzip,
+            level: Some(6),
+            min_size: None,
+            min_savings_percent: None,
+            use_dictionary: None,
+        });
+        let legacy = gzip.compress(&data).await.unwrap();
+        let reader = codec(Some(CompressionType::Gzip));
+        assert_eq!(reader.decode(&legacy).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_chunk_manifest_is_not_a_value() {
+        let mut manifest = FRAME_MAGIC.to_vec();
+        manifest.push(CHUNKED_TAG);
+        manifest.extend_from_slice(&[0; 20]);
+        for reader in ALGORITHMS.map(Some).into_iter().chain([None]) {
+            assert!(codec(reader).decode(&manifest).await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_frame() {
+        assert_eq!(parse_frame(b""), None);
+        assert_eq!(parse_frame(&FRAME_MAGIC), None);
+        let mut unknown = FRAME_MAGIC.to_vec();
+        unknown.push(0xff);
+        assert_eq!(parse_frame(&unknown), None);
+        let mut empty = FRAME_MAGIC.to_vec();
+        empty.push(codec_tag(CompressionType::Zstd));
+        assert_eq!(
+            parse_frame(&empty),
+            Some(Frame {
+                algorithm: CompressionType::Zstd,
+                dictionary: None,
+                payload: b"",
+            })
+        );
+
+        let mut truncated = FRAME_MAGIC.to_vec();
+        truncated.extend_from_slice(&[ZSTD_DICTIONARY_TAG, 7, 0]);
+        assert_eq!(parse_frame(&truncated), None);
+        let mut dictionary = FRAME_MAGIC.to_vec();
+        dictionary.extend_from_slice(&[ZSTD_DICTIONARY_TAG, 7, 0, 0, 0, 42]);
+        assert_eq!(
+            parse_frame(&dictionary),
+            Some(Frame {
+                algorithm: CompressionType::Zstd,
+                dictionary: Some(7),
+                payload: &[42],
+            })
+        );
+    }
+
+    /// Bytes that no algorithm can shrink
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545f4914f6cdd1du64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_policy_stores_raw() {
+        let text = b"some value some value some value".repeat(10);
+        for algorithm in ALGORITHMS.into_iter().skip(1) {
+            let codec = codec(Some(algorithm));
+
+            let (encoded, outcome) = codec.encode_with_outcome(&text).await.unwrap();
+            assert_eq!(outcome, CompressionOutcome::Compressed);
+            assert_eq!(parse_frame(&encoded).map(|f| f.algorithm), Some(algorithm));
+
+            // Small and incompressible values keep their bytes behind a `none` header
+            for (data, expected) in [
+                (b"tiny".to_vec(), CompressionOutcome::TooSmall),
+                (noise(4096), CompressionOutcome::Incompressible),
+            ] {
+                let (encoded, outcome) = codec.encode_with_outcome(&data).await.unwrap();
+                assert_eq!(outcome, expected, "{:?}", algorithm);
+                let frame = parse_frame(&encoded).unwrap();
+                assert_eq!(frame.algorithm, CompressionType::None);
+                assert_eq!(frame.payload, &data[..]);
+                assert_eq!(codec.decode(&encoded).await.unwrap(), data);
+            }
+        }
+
+        let (_, outcome) = codec(None).encode_with_outcome(&text).await.unwrap();
+        assert_eq!(outcome, CompressionOutcome::Disabled);
+    }
+
+    #[tokio::test]
+    async fn test_policy_thresholds() {
+        let text = b"some value some value some value".repeat(10);
+        let codec = |min_size, min_savings_percent| {
+            ValueCodec::with_dictionaries(
+                Some(&CompressionConfig {
+                    enabled: true,
+                    algorithm: CompressionType::Zstd,
+                    level: Some(3),
+                    min_size,
+                    min_savings_percent,
+                    use_dictionary: None,
+                }),
+                &DictionaryStore::default(),
+            )
+        };
+
+        let (_, outcome) = codec(Some(1024), None)
+            .encode_with_outcome(&text)
+            .await
+            .unwrap();
+        assert_eq!(outcome, CompressionOutcome::TooSmall);
+
+        // Half random, half repetitive: saves some but not 90%
+        let mut mixed = noise(2048);
+        mixed.extend_from_slice(&[b'a'; 2048]);
+        let (_, outcome) = codec(None, Some(90))
+            .encode_with_outcome(&mixed)
+            .await
+            .unwrap();
+        assert_eq!(outcome, CompressionOutcome::Incompressible);
+        let (_, outcome) = codec(None, Some(20))
+            .encode_with_outcome(&mixed)
+            .await
+            .unwrap();
+        assert_eq!(outcome, CompressionOutcome::Compressed);
+    }
+
+    #[tokio::test]
+    async fn test_dictionary_values() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| format!(r#"{{"id":{},"kind":"document","tags":["a","b"]}}"#, i).into_bytes())
+            .collect();
+        let mut store = DictionaryStore::default();
+        let dictionary = super::super::train_dictionary(&samples, 4096).unwrap();
+        let id = store.save(temp_dir.path(), dictionary).unwrap();
+
+        let config = CompressionConfig {
+            enabled: true,
+            algorithm: CompressionType::Zstd,
+            level: Some(3),
+            min_size: Some(0),
+            min_savings_percent: None,
+            use_dictionary: Some(true),
+        };
+        let writer = ValueCodec::with_dictionaries(Some(&config), &store);
+        assert_eq!(writer.dictionary(), Some(id));
+
+        let data = br#"{"id":5000,"kind":"document","tags":["a","b"]}"#;
+        let encoded = writer.encode(data).await.unwrap();
+        let frame = parse_frame(&encoded).unwrap();
+        assert_eq!(frame.algorithm, CompressionType::Zstd);
+        assert_eq!(frame.dictionary, Some(id));
+
+        // Any codec with the dictionaries decodes it, whatever is configured
+        let store = DictionaryStore::load(temp_dir.path()).unwrap();
+        for reader in ALGORITHMS.map(Some).into_iter().chain([None]) {
+            let config = reader.map(|algorithm| CompressionConfig {
+                enabled: true,
+                algorithm,
+                level: None,
+                min_size: None,
+                min_savings_percent: None,
+                use_dictionary: None,
+            });
+            let reader = ValueCodec::with_dictionaries(config.as_ref(), &store);
+            assert_eq!(reader.decode(&encoded).await.unwrap(), data);
+        }
+
+        // Values written without a dictionary still decode
+        let plain = codec(Some(CompressionType::Zstd))
+            .encode(data)
+            .await
+            .unwrap();
+        assert_eq!(writer.decode(&plain).await.unwrap(), data);
+
+        // Without the dictionary the value cannot be decoded
+        assert!(
+            codec(Some(CompressionType::Zstd))
+                .decode(&encoded)
+                .await
+                .is_err()
+        );
+    }
+}
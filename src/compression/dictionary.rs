@@ -0,0 +1,118 @@
+//! Versioned zstd dictionaries kept in the data directory.
+//!
+//! Dictionaries are trained offline with `blobasaur shard train-dictionary`
+//! and written to `<data_dir>/dictionaries/<id>.dict`. Ids only ever grow and
+//! a dictionary is never rewritten once saved, because values compressed with
+//! it carry its id and need exactly those bytes to be decoded.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directory of the data dir holding the dictionaries
+pub const DICTIONARY_DIR: &str = "dictionaries";
+
+/// Default maximum size of a trained dictionary, as recommended by zstd
+pub const DEFAULT_DICTIONARY_SIZE: usize = 112_640;
+
+/// The dictionaries of a data directory, by id
+#[derive(Debug, Clone, Default)]
+pub struct DictionaryStore {
+    dictionaries: BTreeMap<u32, Arc<Vec<u8>>>,
+}
+
+impl DictionaryStore {
+    /// Load every dictionary of a data directory. A missing directory simply
+    /// has no dictionaries.
+    pub fn load(data_dir: impl AsRef<Path>) -> io::Result<Self> {
+        let dir = dictionary_dir(data_dir);
+        let mut dictionaries = BTreeMap::new();
+
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if let Some(id) = dictionary_id(&path) {
+                dictionaries.insert(id, Arc::new(std::fs::read(&path)?));
+            }
+        }
+
+        Ok(Self { dictionaries })
+    }
+
+    /// The most recently trained dictionary
+    pub fn latest(&self) -> Option<(u32, &Arc<Vec<u8>>)> {
+        self.dictionaries
+            .last_key_value()
+            .map(|(id, dictionary)| (*id, dictionary))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &Arc<Vec<u8>>)> {
+        self.dictionaries
+            .iter()
+            .map(|(id, dictionary)| (*id, dictionary))
+    }
+
+    /// Save a new dictionary under the next id and return that id
+    pub fn save(&mut self, data_dir: impl AsRef<Path>, dictionary: Vec<u8>) -> io::Result<u32> {
+        let dir = dictionary_dir(data_dir);
+        std::fs::create_dir_all(&dir)?;
+
+        let id = self.latest().map_or(1, |(id, _)| id + 1);
+        let path = dir.join(format!("{}.dict", id));
+        // Write to a temporary file first so a crash never leaves a truncated dictionary
+        let tmp_path = dir.join(format!("{}.dict.tmp", id));
+        std::fs::write(&tmp_path, &dictionary)?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        self.dictionaries.insert(id, Arc::new(dictionary));
+        Ok(id)
+    }
+}
+
+/// Train a dictionary of at most `max_size` bytes from sample values
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> io::Result<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+}
+
+fn dictionary_dir(data_dir: impl AsRef<Path>) -> PathBuf {
+    data_dir.as_ref().join(DICTIONARY_DIR)
+}
+
+/// Id of a dictionary file, `None` for any other file
+fn dictionary_id(path: &Path) -> Option<u32> {
+    if path.extension()? != "dict" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_save_and_load() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut store = DictionaryStore::load(temp_dir.path()).unwrap();
+        assert!(store.latest().is_none());
+
+        assert_eq!(store.save(temp_dir.path(), b"first".to_vec()).unwrap(), 1);
+        assert_eq!(store.save(temp_dir.path(), b"second".to_vec()).unwrap(), 2);
+
+        // Unrelated files are ignored
+        std::fs::write(temp_dir.path().join(DICTIONARY_DIR).join("notes.txt"), "x").unwrap();
+
+        let store = DictionaryStore::load(temp_dir.path()).unwrap();
+        let saved: Vec<_> = store.iter().map(|(id, d)| (id, d.to_vec())).collect();
+        assert_eq!(saved, [(1, b"first".to_vec()), (2, b"second".to_vec())]);
+        let (id, latest) = store.latest().unwrap();
+        assert_eq!(id, 2);
+        assert_eq!(latest.as_slice(), b"second");
+    }
+}
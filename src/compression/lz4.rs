@@ -18,7 +18,7 @@ impl Lz4Compressor {
     }
     pub fn validate(config: &CompressionConfig) -> Result<(), miette::Error> {
         let level = config.level.unwrap_or(0);
-        if level < 1 || level > 16 {
+        if !(1..=16).contains(&level) {
             return Err(miette::miette!(
                 "Compression level must be between 1 and 16"
             ));
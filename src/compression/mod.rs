@@ -2,15 +2,21 @@ use async_trait::async_trait;
 use std::io;
 
 pub mod brotli;
+pub mod codec;
+pub mod dictionary;
 pub mod gzip;
 pub mod lz4;
 pub mod none;
+pub mod pool;
 pub mod zstd;
 
 pub use brotli::BrotliCompressor;
+pub use codec::ValueCodec;
+pub use dictionary::{DictionaryStore, train_dictionary};
 pub use gzip::GzipCompressor;
 pub use lz4::Lz4Compressor;
 pub use none::NoneCompressor;
+pub use pool::CompressionPool;
 pub use zstd::ZstdCompressor;
 
 use crate::config::{CompressionConfig, CompressionType};
@@ -31,7 +37,66 @@ pub fn init_compression(config: CompressionConfig) -> Box<dyn Compressor> {
     }
 }
 
+/// Values smaller than this are stored uncompressed unless `min_size` is set
+pub const DEFAULT_MIN_SIZE: usize = 64;
+
+/// What the policy decided for a value being stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionOutcome {
+    /// Stored compressed
+    Compressed,
+    /// Below the minimum size, stored without trying to compress it
+    TooSmall,
+    /// Compression did not save enough, stored uncompressed
+    Incompressible,
+    /// Compression is disabled
+    Disabled,
+}
+
+/// Decides which values are worth compressing. Tiny values cost more CPU than
+/// they save and already compressed data such as images often grows, so both
+/// are stored uncompressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    pub min_size: usize,
+    pub min_savings_percent: u8,
+}
+
+impl CompressionPolicy {
+    pub fn new(config: &CompressionConfig) -> Self {
+        Self {
+            min_size: config.min_size.unwrap_or(DEFAULT_MIN_SIZE),
+            min_savings_percent: config.min_savings_percent.unwrap_or(0),
+        }
+    }
+
+    /// Whether a value of `len` bytes should be compressed at all
+    pub fn should_compress(&self, len: usize) -> bool {
+        len >= self.min_size
+    }
+
+    /// Whether compressing `original` bytes down to `compressed` bytes saves
+    /// enough to store the compressed form
+    pub fn accepts(&self, original: usize, compressed: usize) -> bool {
+        if compressed >= original {
+            return false;
+        }
+        let saved = (original - compressed) as u128 * 100;
+        saved >= original as u128 * self.min_savings_percent as u128
+    }
+}
+
 pub fn validate_config(config: &CompressionConfig) -> Result<(), miette::Error> {
+    if config.min_savings_percent.is_some_and(|p| p > 99) {
+        return Err(miette::miette!(
+            "min_savings_percent must be between 0 and 99"
+        ));
+    }
+    if config.use_dictionary.unwrap_or(false) && config.algorithm != CompressionType::Zstd {
+        return Err(miette::miette!(
+            "use_dictionary is only supported with the zstd algorithm"
+        ));
+    }
     match config.algorithm {
         CompressionType::None => NoneCompressor::validate(config),
         CompressionType::Gzip => GzipCompressor::validate(config),
@@ -40,3 +105,58 @@ pub fn validate_config(config: &CompressionConfig) -> Result<(), miette::Error>
         CompressionType::Brotli => BrotliCompressor::validate(config),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_size: Option<usize>, min_savings_percent: Option<u8>) -> CompressionPolicy {
+        CompressionPolicy::new(&CompressionConfig {
+            enabled: true,
+            algorithm: CompressionType::Zstd,
+            level: Some(3),
+            min_size,
+            min_savings_percent,
+            use_dictionary: None,
+        })
+    }
+
+    #[test]
+    fn test_policy_min_size() {
+        let default = policy(None, None);
+        assert!(!default.should_compress(DEFAULT_MIN_SIZE - 1));
+        assert!(default.should_compress(DEFAULT_MIN_SIZE));
+
+        let all = policy(Some(0), None);
+        assert!(all.should_compress(0));
+    }
+
+    #[test]
+    fn test_policy_savings() {
+        // By default anything smaller is kept
+        let default = policy(None, None);
+        assert!(default.accepts(100, 99));
+        assert!(!default.accepts(100, 100));
+        assert!(!default.accepts(100, 120));
+
+        let strict = policy(None, Some(10));
+        assert!(strict.accepts(1000, 900));
+        assert!(!strict.accepts(1000, 901));
+        assert!(!strict.accepts(0, 0));
+    }
+
+    #[test]
+    fn test_validate_min_savings_percent() {
+        let mut config = CompressionConfig {
+            enabled: true,
+            algorithm: CompressionType::Zstd,
+            level: Some(3),
+            min_size: None,
+            min_savings_percent: Some(99),
+            use_dictionary: None,
+        };
+        assert!(validate_config(&config).is_ok());
+        config.min_savings_percent = Some(100);
+        assert!(validate_config(&config).is_err());
+    }
+}
@@ -4,6 +4,7 @@ use super::Compressor;
 use async_trait::async_trait;
 use std::io;
 
+#[derive(Default)]
 pub struct NoneCompressor;
 
 impl NoneCompressor {
@@ -11,7 +12,7 @@ impl NoneCompressor {
         Self
     }
     pub fn validate(_: &CompressionConfig) -> Result<(), miette::Error> {
-        return Ok(());
+        Ok(())
     }
 }
 
@@ -0,0 +1,157 @@
+//! Dedicated threads for compressing and decompressing large values.
+//!
+//! The compressors run their codecs over in-memory buffers, which is pure CPU
+//! work: a multi-megabyte brotli value would hold a tokio worker for as long
+//! as it takes and stall every connection scheduled on it. Large values are
+//! therefore handed to a fixed set of threads through a bounded queue. When the
+//! queue is full, callers wait for room instead of piling up more work.
+
+use metrics::Histogram;
+use std::io;
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+pub struct CompressionPool {
+    sender: mpsc::Sender<Job>,
+    threads: usize,
+    /// Values smaller than this are cheap enough to handle on the caller's task
+    inline_threshold: usize,
+    /// Time jobs spend between being submitted and starting on a thread
+    queue_time: Histogram,
+}
+
+impl CompressionPool {
+    /// Start `threads` threads sharing a queue of `queue_size` jobs
+    pub fn new(
+        threads: usize,
+        queue_size: usize,
+        inline_threshold: usize,
+        queue_time: Histogram,
+    ) -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Job>(queue_size.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            std::thread::Builder::new()
+                .name(format!("compression-{}", i))
+                .spawn(move || {
+                    loop {
+                        // The lock is only held while waiting for the next job
+                        let job = match receiver.lock() {
+                            Ok(mut receiver) => receiver.blocking_recv(),
+                            Err(_) => None,
+                        };
+                        let Some(job) = job else {
+                            // The pool was dropped
+                            return;
+                        };
+                        // A panicking codec fails its own job, not the thread
+                        let _ = catch_unwind(AssertUnwindSafe(job));
+                    }
+                })?;
+        }
+
+        Ok(Self {
+            sender,
+            threads: threads.max(1),
+            inline_threshold,
+            queue_time,
+        })
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Whether a value of `len` bytes should be handled on the pool
+    pub fn offloads(&self, len: usize) -> bool {
+        len >= self.inline_threshold
+    }
+
+    /// Run `f` on one of the pool's threads and wait for its result
+    pub async fn run<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let queue_time = self.queue_time.clone();
+        let submitted = Instant::now();
+        let job: Job = Box::new(move || {
+            queue_time.record(submitted.elapsed().as_secs_f64());
+            let _ = result_tx.send(f());
+        });
+
+        self.sender
+            .send(job)
+            .await
+            .map_err(|_| io::Error::other("compression pool is shut down"))?;
+        result_rx
+            .await
+            .map_err(|_| io::Error::other("compression task panicked"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_runs_on_pool_threads() {
+        let pool = CompressionPool::new(2, 4, 0, Histogram::noop()).unwrap();
+        let name = pool
+            .run(|| std::thread::current().name().map(str::to_string))
+            .await
+            .unwrap();
+        assert!(name.unwrap().starts_with("compression-"));
+    }
+
+    #[test]
+    fn test_offloads() {
+        let pool = CompressionPool::new(1, 1, 1024, Histogram::noop()).unwrap();
+        assert!(!pool.offloads(1023));
+        assert!(pool.offloads(1024));
+    }
+
+    #[tokio::test]
+    async fn test_bounded_concurrency() {
+        let pool = Arc::new(CompressionPool::new(2, 1, 0, Histogram::noop()).unwrap());
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for _ in 0..8 {
+            let pool = pool.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            tasks.push(tokio::spawn(async move {
+                pool.run(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[tokio::test]
+    async fn test_panic_fails_only_its_job() {
+        let pool = CompressionPool::new(1, 1, 0, Histogram::noop()).unwrap();
+        let result = pool.run(|| -> usize { panic!("codec bug") }).await;
+        assert!(result.is_err());
+        assert_eq!(pool.run(|| 42).await.unwrap(), 42);
+    }
+}
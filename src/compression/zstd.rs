@@ -3,22 +3,45 @@ use crate::config::CompressionConfig;
 use super::Compressor;
 use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
 use async_trait::async_trait;
-use std::io;
+use std::io::{self, Read};
 use tokio::io::{AsyncReadExt, BufReader};
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
 
 pub struct ZstdCompressor {
     level: i32,
+    dictionary: Option<Dictionary>,
+}
+
+/// A trained dictionary, prepared once for compression and decompression
+struct Dictionary {
+    encoder: EncoderDictionary<'static>,
+    decoder: DecoderDictionary<'static>,
 }
 
 impl ZstdCompressor {
     pub fn new(level: Option<u32>) -> Self {
         Self {
             level: level.map(|l| l as i32).unwrap_or(3),
+            dictionary: None,
+        }
+    }
+
+    /// Compressor that compresses and decompresses with a trained dictionary.
+    /// Values compressed with it can only be decompressed with the same
+    /// dictionary.
+    pub fn with_dictionary(level: Option<u32>, dictionary: &[u8]) -> Self {
+        let level = level.map(|l| l as i32).unwrap_or(3);
+        Self {
+            level,
+            dictionary: Some(Dictionary {
+                encoder: EncoderDictionary::copy(dictionary, level),
+                decoder: DecoderDictionary::copy(dictionary),
+            }),
         }
     }
     pub fn validate(config: &CompressionConfig) -> Result<(), miette::Error> {
         let level = config.level.unwrap_or(0);
-        if level < 1 || level > 22 {
+        if !(1..=22).contains(&level) {
             return Err(miette::miette!(
                 "Compression level must be between 1 and 22"
             ));
@@ -30,6 +53,12 @@ impl ZstdCompressor {
 #[async_trait]
 impl Compressor for ZstdCompressor {
     async fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if let Some(dictionary) = &self.dictionary {
+            let mut compressor =
+                zstd::bulk::Compressor::with_prepared_dictionary(&dictionary.encoder)?;
+            return compressor.compress(data);
+        }
+
         let cursor = std::io::Cursor::new(data);
         let buf_reader = BufReader::new(cursor);
 
@@ -42,6 +71,14 @@ impl Compressor for ZstdCompressor {
     }
 
     async fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if let Some(dictionary) = &self.dictionary {
+            let mut decoder =
+                zstd::stream::read::Decoder::with_prepared_dictionary(data, &dictionary.decoder)?;
+            let mut decompressed = Vec::new();
+            decoder.read_to_end(&mut decompressed)?;
+            return Ok(decompressed);
+        }
+
         let cursor = std::io::Cursor::new(data);
         let buf_reader = BufReader::new(cursor);
 
@@ -84,4 +121,33 @@ mod tests {
 
         assert_eq!(data.as_slice(), decompressed);
     }
+
+    #[tokio::test]
+    async fn test_dictionary_roundtrip() {
+        let samples: Vec<Vec<u8>> = (0..200)
+            .map(|i| {
+                format!(
+                    r#"{{"id":{},"name":"user {}","email":"user{}@example.com","active":true}}"#,
+                    i, i, i
+                )
+                .into_bytes()
+            })
+            .collect();
+        let dictionary = zstd::dict::from_samples(&samples, 4096).unwrap();
+
+        let with_dictionary = ZstdCompressor::with_dictionary(Some(3), &dictionary);
+        let plain = ZstdCompressor::new(Some(3));
+        let data =
+            br#"{"id":1000,"name":"user 1000","email":"user1000@example.com","active":true}"#;
+
+        let compressed = with_dictionary.compress(data).await.unwrap();
+        assert!(compressed.len() < plain.compress(data).await.unwrap().len());
+        assert_eq!(
+            with_dictionary.decompress(&compressed).await.unwrap(),
+            data.as_slice()
+        );
+
+        // The dictionary is needed to decompress
+        assert!(plain.decompress(&compressed).await.is_err());
+    }
 }
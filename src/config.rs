@@ -1,7 +1,10 @@
 use config::Config;
 use miette::{IntoDiagnostic, Result};
+use sqlx::sqlite::{SqliteAutoVacuum, SqliteConnectOptions, SqliteSynchronous};
 
+use crate::auth;
 use crate::compression;
+use crate::redis::ProtocolLimits;
 
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Cfg {
@@ -14,6 +17,27 @@ pub struct Cfg {
     pub addr: Option<String>,
     pub cluster: Option<ClusterConfig>,
     pub metrics: Option<MetricsConfig>,
+    pub expiry: Option<ExpiryConfig>,
+    pub compression_pool: Option<CompressionPoolConfig>,
+    /// Largest value a single SET, SETRANGE or APPEND may store
+    pub max_value_size: Option<usize>,
+    /// Values larger than this are stored as chunks of this size
+    pub chunk_size: Option<usize>,
+    /// Longest bulk string a request may carry
+    pub max_bulk_len: Option<usize>,
+    /// Most arguments a single command may have
+    pub max_multibulk_len: Option<usize>,
+    /// Longest inline command or protocol header line
+    pub max_inline_len: Option<usize>,
+    /// Hex encoded SHA-256 digest of the default user's password
+    pub requirepass: Option<String>,
+    pub users: Option<Vec<UserConfig>>,
+    pub tls: Option<TlsConfig>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    pub journal: Option<JournalConfig>,
+    pub storage: Option<StorageConfig>,
+    /// Longest a graceful shutdown may take before the process exits anyway
+    pub shutdown_timeout_ms: Option<u64>,
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -36,7 +60,7 @@ pub struct ClusterConfig {
     pub advertise_addr: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq)]
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum CompressionType {
     None,
@@ -46,11 +70,103 @@ pub enum CompressionType {
     Brotli,
 }
 
+impl std::str::FromStr for CompressionType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(CompressionType::None),
+            "gzip" => Ok(CompressionType::Gzip),
+            "zstd" => Ok(CompressionType::Zstd),
+            "lz4" => Ok(CompressionType::Lz4),
+            "brotli" => Ok(CompressionType::Brotli),
+            other => Err(format!(
+                "unknown compression algorithm '{}', expected none, gzip, zstd, lz4 or brotli",
+                other
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct CompressionConfig {
     pub enabled: bool,
     pub algorithm: CompressionType,
     pub level: Option<u32>,
+    /// Values smaller than this many bytes are stored uncompressed
+    pub min_size: Option<usize>,
+    /// Values are stored uncompressed unless compression saves at least this
+    /// percentage of their size
+    pub min_savings_percent: Option<u8>,
+    /// Compress with the latest dictionary trained with
+    /// `shard train-dictionary` (zstd only)
+    pub use_dictionary: Option<bool>,
+}
+
+/// A user clients may authenticate as with `AUTH`
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UserConfig {
+    pub name: String,
+    /// Hex encoded SHA-256 digest of the password
+    pub password: String,
+    /// Disabled users cannot authenticate (defaults to true)
+    pub enabled: Option<bool>,
+    /// Namespace patterns the user may read and write
+    pub namespaces: Option<Vec<String>>,
+    /// Namespace patterns the user may only read
+    pub read_namespaces: Option<Vec<String>>,
+    /// ACL command rules such as `+@read` or `-keys`
+    pub commands: Option<Vec<String>>,
+}
+
+/// TLS for the Redis listener. Without `addr` the listener on the main
+/// `addr` serves TLS only, with it plaintext and TLS run side by side.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct TlsConfig {
+    /// Address of a separate TLS listener
+    pub addr: Option<String>,
+    /// PEM file with the server certificate chain
+    pub cert_file: String,
+    /// PEM file with the server private key
+    pub key_file: String,
+    /// PEM file with the CAs client certificates are verified against
+    pub client_ca_file: Option<String>,
+    /// Whether clients must present a certificate when `client_ca_file` is
+    /// set (defaults to required)
+    pub client_auth: Option<ClientAuth>,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    Required,
+    Optional,
+}
+
+impl TlsConfig {
+    pub fn client_auth(&self) -> ClientAuth {
+        self.client_auth.unwrap_or(ClientAuth::Required)
+    }
+}
+
+/// Unix domain socket listener, for clients on the same host
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct UnixSocketConfig {
+    pub path: String,
+    /// Mode of the socket file, such as `0o770` (defaults to `0o700`)
+    pub permissions: Option<u32>,
+    /// Only listen on the socket, not on `addr` (defaults to false)
+    pub disable_tcp: Option<bool>,
+}
+
+impl UnixSocketConfig {
+    pub fn permissions(&self) -> u32 {
+        self.permissions.unwrap_or(0o700)
+    }
+
+    pub fn disable_tcp(&self) -> bool {
+        self.disable_tcp.unwrap_or(false)
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -59,6 +175,214 @@ pub struct MetricsConfig {
     pub addr: Option<String>,
 }
 
+/// Background reaper that deletes expired rows from the shard databases
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct ExpiryConfig {
+    pub enabled: bool,
+    /// Time between reaper passes (defaults to 1000ms)
+    pub interval_ms: Option<u64>,
+    /// Rows deleted per statement (defaults to 500)
+    pub batch_size: Option<usize>,
+    /// Upper bound of rows deleted per shard in a single pass (defaults to 10000)
+    pub max_keys_per_pass: Option<usize>,
+}
+
+impl ExpiryConfig {
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.interval_ms.unwrap_or(1000))
+    }
+
+    pub fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(500)
+    }
+
+    pub fn max_keys_per_pass(&self) -> usize {
+        self.max_keys_per_pass.unwrap_or(10_000)
+    }
+}
+
+/// SQLite settings of the shard databases, used by the server and by
+/// shard migrations
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct StorageConfig {
+    /// How safely commits reach the disk (defaults to fast)
+    pub durability: Option<Durability>,
+    /// Page cache of each connection in KiB (defaults to 100000)
+    pub cache_size_kib: Option<u64>,
+    /// Bytes of each database read through memory mapping (defaults to 0,
+    /// which disables it)
+    pub mmap_size: Option<u64>,
+    /// Page size of new shard databases (defaults to 4096)
+    pub page_size: Option<u32>,
+    /// Whether freed pages are returned to the file system (defaults to none)
+    pub auto_vacuum: Option<AutoVacuum>,
+    /// Pages the WAL may grow to before it is checkpointed (defaults to 1000)
+    pub wal_autocheckpoint: Option<u32>,
+    /// How long to wait for a locked database (defaults to 5000ms)
+    pub busy_timeout_ms: Option<u64>,
+}
+
+/// Named `synchronous` settings
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// `synchronous = OFF`: commits survive a crash of the server, but an
+    /// OS crash or power loss can lose the last ones and corrupt the database
+    Fast,
+    /// `synchronous = NORMAL`: the database cannot be corrupted by a power
+    /// loss, but the last commits can still be rolled back
+    Normal,
+    /// `synchronous = FULL`: every commit is on disk before it returns
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AutoVacuum {
+    None,
+    Full,
+    Incremental,
+}
+
+impl Durability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Durability::Fast => "fast",
+            Durability::Normal => "normal",
+            Durability::Full => "full",
+        }
+    }
+}
+
+impl AutoVacuum {
+    pub fn name(self) -> &'static str {
+        match self {
+            AutoVacuum::None => "none",
+            AutoVacuum::Full => "full",
+            AutoVacuum::Incremental => "incremental",
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn durability(&self) -> Durability {
+        self.durability.unwrap_or(Durability::Fast)
+    }
+
+    pub fn cache_size_kib(&self) -> u64 {
+        self.cache_size_kib.unwrap_or(100_000)
+    }
+
+    pub fn mmap_size(&self) -> u64 {
+        self.mmap_size.unwrap_or(0)
+    }
+
+    pub fn page_size(&self) -> u32 {
+        self.page_size.unwrap_or(4096)
+    }
+
+    pub fn auto_vacuum(&self) -> AutoVacuum {
+        self.auto_vacuum.unwrap_or(AutoVacuum::None)
+    }
+
+    pub fn wal_autocheckpoint(&self) -> u32 {
+        self.wal_autocheckpoint.unwrap_or(1000)
+    }
+
+    pub fn busy_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.busy_timeout_ms.unwrap_or(5000))
+    }
+
+    /// Apply the settings to the options of a shard connection. The page
+    /// size and auto-vacuum mode of an existing database only change when
+    /// it is vacuumed.
+    pub fn apply(&self, options: SqliteConnectOptions) -> SqliteConnectOptions {
+        let synchronous = match self.durability() {
+            Durability::Fast => SqliteSynchronous::Off,
+            Durability::Normal => SqliteSynchronous::Normal,
+            Durability::Full => SqliteSynchronous::Full,
+        };
+        let auto_vacuum = match self.auto_vacuum() {
+            AutoVacuum::None => SqliteAutoVacuum::None,
+            AutoVacuum::Full => SqliteAutoVacuum::Full,
+            AutoVacuum::Incremental => SqliteAutoVacuum::Incremental,
+        };
+        options
+            .busy_timeout(self.busy_timeout())
+            .page_size(self.page_size())
+            .auto_vacuum(auto_vacuum)
+            .synchronous(synchronous)
+            // Negative sizes are in KiB rather than pages
+            .pragma("cache_size", format!("-{}", self.cache_size_kib()))
+            .pragma("mmap_size", self.mmap_size().to_string())
+            .pragma("wal_autocheckpoint", self.wal_autocheckpoint().to_string())
+            .pragma("temp_store", "MEMORY")
+    }
+}
+
+/// Write-ahead journal that makes `async_write` acknowledgements durable
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct JournalConfig {
+    pub enabled: bool,
+    /// Sync every append to disk before replying (defaults to true); without
+    /// it writes survive a crash of the process but not of the machine
+    pub fsync: Option<bool>,
+    /// Size in bytes at which a journal segment is closed and a new one
+    /// started (defaults to 64 MiB)
+    pub segment_size: Option<u64>,
+}
+
+impl JournalConfig {
+    pub fn fsync(&self) -> bool {
+        self.fsync.unwrap_or(true)
+    }
+
+    pub fn segment_size(&self) -> u64 {
+        self.segment_size.unwrap_or(64 * 1024 * 1024)
+    }
+}
+
+/// Threads that compress and decompress large values off the connection tasks
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+pub struct CompressionPoolConfig {
+    /// Number of threads (defaults to the number of CPUs)
+    pub threads: Option<usize>,
+    /// Values waiting for a thread before callers wait too (defaults to 256)
+    pub queue_size: Option<usize>,
+    /// Values smaller than this many bytes are handled on the connection task
+    /// (defaults to 16384)
+    pub inline_threshold: Option<usize>,
+}
+
+impl CompressionPoolConfig {
+    pub fn threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1)
+        })
+    }
+
+    pub fn queue_size(&self) -> usize {
+        self.queue_size.unwrap_or(256)
+    }
+
+    pub fn inline_threshold(&self) -> usize {
+        self.inline_threshold.unwrap_or(16 * 1024)
+    }
+}
+
+impl Default for ExpiryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            interval_ms: None,
+            batch_size: None,
+            max_keys_per_pass: None,
+        }
+    }
+}
+
 impl Cfg {
     pub fn load(cfg_path: &str) -> Result<Self> {
         let settings = Config::builder()
@@ -80,6 +404,83 @@ impl Cfg {
             compression::validate_config(comp)?;
         }
 
+        let pool = cfg.compression_pool();
+        if pool.threads() == 0 {
+            return Err(miette::miette!(
+                "compression_pool.threads must be greater than 0"
+            ));
+        }
+        if pool.queue_size() == 0 {
+            return Err(miette::miette!(
+                "compression_pool.queue_size must be greater than 0"
+            ));
+        }
+
+        if cfg.max_value_size == Some(0) || cfg.chunk_size == Some(0) {
+            return Err(miette::miette!(
+                "max_value_size and chunk_size must be greater than 0"
+            ));
+        }
+        if cfg.chunk_size() > u32::MAX as usize {
+            return Err(miette::miette!("chunk_size must be less than 4 GiB"));
+        }
+
+        if cfg.max_bulk_len == Some(0)
+            || cfg.max_multibulk_len == Some(0)
+            || cfg.max_inline_len == Some(0)
+        {
+            return Err(miette::miette!(
+                "max_bulk_len, max_multibulk_len and max_inline_len must be greater than 0"
+            ));
+        }
+
+        let users = auth::Users::from_config(&cfg).map_err(|e| miette::miette!(e))?;
+        if users.required() {
+            println!("Authentication is required");
+        }
+
+        if let Some(ref tls) = cfg.tls {
+            if tls.client_auth.is_some() && tls.client_ca_file.is_none() {
+                return Err(miette::miette!(
+                    "tls.client_auth requires tls.client_ca_file"
+                ));
+            }
+            match tls.addr {
+                Some(ref tls_addr) if *tls_addr == cfg.addr() => {
+                    return Err(miette::miette!("tls.addr must differ from addr"));
+                }
+                Some(ref tls_addr) => match cfg.plaintext_addr() {
+                    Some(addr) => println!("TLS enabled on {}, plaintext on {}", tls_addr, addr),
+                    None => println!("TLS enabled on {}", tls_addr),
+                },
+                None => println!("TLS enabled on {}", cfg.addr()),
+            }
+        }
+
+        if let Some(ref unix_socket) = cfg.unix_socket {
+            if unix_socket.path.is_empty() {
+                return Err(miette::miette!("unix_socket.path cannot be empty"));
+            }
+            if unix_socket.permissions() > 0o777 {
+                return Err(miette::miette!(
+                    "unix_socket.permissions must be a file mode such as 0o770"
+                ));
+            }
+            if unix_socket.disable_tcp() && cfg.tls.as_ref().is_some_and(|t| t.addr.is_none()) {
+                return Err(miette::miette!(
+                    "unix_socket.disable_tcp requires tls.addr when tls is enabled"
+                ));
+            }
+            println!("Unix socket: {}", unix_socket.path);
+        }
+
+        let page_size = cfg.storage().page_size();
+        if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+            return Err(miette::miette!(
+                "storage.page_size must be a power of two between 512 and 65536"
+            ));
+        }
+
         if cfg.async_write.is_some_and(|v| v) {
             println!("Async write is enabled");
         }
@@ -98,25 +499,174 @@ impl Cfg {
             return Err(miette::miette!("batch_size must be greater than 0"));
         }
 
+        if let Some(ref expiry) = cfg.expiry {
+            if expiry.interval_ms == Some(0) {
+                return Err(miette::miette!("expiry.interval_ms must be greater than 0"));
+            }
+            if expiry.batch_size == Some(0) || expiry.max_keys_per_pass == Some(0) {
+                return Err(miette::miette!(
+                    "expiry.batch_size and expiry.max_keys_per_pass must be greater than 0"
+                ));
+            }
+        }
+
         println!("Data directory: {}", cfg.data_dir);
         println!("Number of shards: {}", cfg.num_shards);
 
-        if let Some(ref metrics) = cfg.metrics {
-            if metrics.enabled {
-                println!(
-                    "Metrics enabled on: {}",
-                    metrics.addr.as_deref().unwrap_or("0.0.0.0:9090")
-                );
-            }
+        if let Some(ref metrics) = cfg.metrics
+            && metrics.enabled
+        {
+            println!(
+                "Metrics enabled on: {}",
+                metrics.addr.as_deref().unwrap_or("0.0.0.0:9090")
+            );
         }
 
         Ok(cfg)
     }
 
-    pub fn is_compression(&self) -> bool {
-        match &self.storage_compression {
-            Some(comp) if comp.enabled => true,
-            _ => false,
+    /// Address of the Redis listener
+    pub fn addr(&self) -> String {
+        self.addr
+            .clone()
+            .unwrap_or_else(|| "0.0.0.0:6379".to_string())
+    }
+
+    /// Address of the plaintext TCP listener, `None` when TLS takes `addr`
+    /// or TCP is disabled in favour of the Unix socket
+    pub fn plaintext_addr(&self) -> Option<String> {
+        let disabled = self.unix_socket.as_ref().is_some_and(|u| u.disable_tcp());
+        let tls_on_addr = self.tls.as_ref().is_some_and(|t| t.addr.is_none());
+        (!disabled && !tls_on_addr).then(|| self.addr())
+    }
+
+    /// Address of the TLS listener, when TLS is configured
+    pub fn tls_addr(&self) -> Option<String> {
+        self.tls
+            .as_ref()
+            .map(|t| t.addr.clone().unwrap_or_else(|| self.addr()))
+    }
+
+    /// Time allowed to drain connections and shard writers on shutdown,
+    /// 30 seconds by default
+    pub fn shutdown_timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.shutdown_timeout_ms.unwrap_or(30_000))
+    }
+
+    /// SQLite settings; defaults apply when the section is absent
+    pub fn storage(&self) -> StorageConfig {
+        self.storage.clone().unwrap_or_default()
+    }
+
+    /// Journal settings when the journal is enabled and writes are
+    /// asynchronous, since synchronous writes are committed before replying
+    pub fn journal(&self) -> Option<&JournalConfig> {
+        self.journal
+            .as_ref()
+            .filter(|j| j.enabled && self.async_write.unwrap_or(false))
+    }
+
+    /// Expiry reaper settings; the reaper runs with defaults when the section is absent
+    pub fn expiry(&self) -> ExpiryConfig {
+        self.expiry.clone().unwrap_or_default()
+    }
+
+    /// Largest value a client may store, 512 MiB by default like Redis
+    pub fn max_value_size(&self) -> usize {
+        self.max_value_size.unwrap_or(512 * 1024 * 1024)
+    }
+
+    /// Whether a value of `len` bytes may be stored. Values of a namespace
+    /// are never split into chunks, so they must also fit in `chunk_size`.
+    pub fn value_fits(&self, len: u64, namespaced: bool) -> bool {
+        let max = if namespaced {
+            self.max_value_size().min(self.chunk_size())
+        } else {
+            self.max_value_size()
+        };
+        len <= max as u64
+    }
+
+    /// Size of the chunks large values are split into, 1 MiB by default
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size.unwrap_or(1024 * 1024)
+    }
+
+    /// Limits checked against request headers, Redis' defaults unless set
+    pub fn protocol_limits(&self) -> ProtocolLimits {
+        ProtocolLimits {
+            max_bulk_len: self.max_bulk_len.unwrap_or(512 * 1024 * 1024),
+            max_multibulk_len: self.max_multibulk_len.unwrap_or(1024 * 1024),
+            max_inline_len: self.max_inline_len.unwrap_or(64 * 1024),
         }
     }
+
+    /// Compression pool settings; defaults apply when the section is absent
+    pub fn compression_pool(&self) -> CompressionPoolConfig {
+        self.compression_pool.clone().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(extra: serde_json::Value) -> Cfg {
+        let mut value = serde_json::json!({
+            "data_dir": "/tmp",
+            "num_shards": 1,
+            "addr": "127.0.0.1:6379",
+        });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().unwrap().clone());
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_listener_addresses() {
+        let tls = serde_json::json!({
+            "addr": "127.0.0.1:6380",
+            "cert_file": "cert.pem",
+            "key_file": "key.pem",
+        });
+
+        let plain = cfg(serde_json::json!({}));
+        assert_eq!(plain.plaintext_addr().as_deref(), Some("127.0.0.1:6379"));
+        assert_eq!(plain.tls_addr(), None);
+
+        // TLS on its own address runs next to plaintext
+        let both = cfg(serde_json::json!({ "tls": tls }));
+        assert_eq!(both.plaintext_addr().as_deref(), Some("127.0.0.1:6379"));
+        assert_eq!(both.tls_addr().as_deref(), Some("127.0.0.1:6380"));
+
+        // disable_tcp only turns off plaintext, TLS keeps its listener
+        let tls_only = cfg(serde_json::json!({
+            "tls": tls,
+            "unix_socket": { "path": "/tmp/blobasaur.sock", "disable_tcp": true },
+        }));
+        assert_eq!(tls_only.plaintext_addr(), None);
+        assert_eq!(tls_only.tls_addr().as_deref(), Some("127.0.0.1:6380"));
+
+        // Without tls.addr, TLS replaces plaintext on addr
+        let tls_on_addr = cfg(serde_json::json!({
+            "tls": { "cert_file": "cert.pem", "key_file": "key.pem" },
+        }));
+        assert_eq!(tls_on_addr.plaintext_addr(), None);
+        assert_eq!(tls_on_addr.tls_addr().as_deref(), Some("127.0.0.1:6379"));
+    }
+
+    #[test]
+    fn test_value_fits() {
+        let chunked = cfg(serde_json::json!({ "max_value_size": 100, "chunk_size": 10 }));
+        assert!(chunked.value_fits(100, false));
+        assert!(!chunked.value_fits(101, false));
+        // Values of a namespace are never split into chunks
+        assert!(chunked.value_fits(10, true));
+        assert!(!chunked.value_fits(11, true));
+
+        let small = cfg(serde_json::json!({ "max_value_size": 5, "chunk_size": 10 }));
+        assert!(!small.value_fits(6, true));
+    }
 }
@@ -0,0 +1,120 @@
+//! State of a client connection.
+//!
+//! Wraps the socket together with what a client negotiated on it, so replies
+//! are written in the protocol version the client asked for with `HELLO`
+//! and commands run as the user it authenticated as.
+//! Replies are buffered and written once per batch of pipelined commands, or
+//! whenever the buffer fills up.
+
+use crate::redis::{ProtocolVersion, serialize_map, serialize_reply};
+use bytes::BytesMut;
+use redis_protocol::resp2::types::BytesFrame;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Buffered replies are written out once they reach this size
+const FLUSH_SIZE: usize = 64 * 1024;
+
+/// Write half of a client's socket, whichever kind of stream it is
+pub type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+pub struct Connection {
+    /// `None` for a detached connection, which only collects replies
+    writer: Option<Writer>,
+    replies: BytesMut,
+    /// Set on a detached connection whose command must run again on the
+    /// real one, see [`Connection::defer`]
+    deferred: bool,
+    /// Unique id of the connection, reported by `HELLO`
+    pub id: u64,
+    /// Protocol version replies are written in
+    pub protocol: ProtocolVersion,
+    /// Name given with `HELLO ... SETNAME`
+    pub name: Option<String>,
+    /// User the connection is authenticated as, `None` until it
+    /// authenticates
+    pub user: Option<String>,
+}
+
+impl Connection {
+    pub fn new(writer: Writer) -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        Self {
+            writer: Some(writer),
+            replies: BytesMut::new(),
+            deferred: false,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            protocol: ProtocolVersion::default(),
+            name: None,
+            user: None,
+        }
+    }
+
+    /// A connection with the same state that keeps its replies in memory, so
+    /// a command can run alongside others of its pipeline. Its replies are
+    /// added back in order with [`Connection::append`].
+    pub fn detached(&self) -> Self {
+        Self {
+            writer: None,
+            replies: BytesMut::new(),
+            deferred: false,
+            id: self.id,
+            protocol: self.protocol,
+            name: self.name.clone(),
+            user: self.user.clone(),
+        }
+    }
+
+    /// Whether the connection only collects its replies in memory
+    pub fn is_detached(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// Give up on replying from a detached connection, for a reply too
+    /// large to collect in memory. The command is run again on the real
+    /// connection, in its turn.
+    pub fn defer(&mut self) {
+        self.deferred = true;
+    }
+
+    pub fn is_deferred(&self) -> bool {
+        self.deferred
+    }
+
+    /// Queue the replies collected by a detached connection
+    pub async fn append(&mut self, detached: Connection) -> std::io::Result<()> {
+        self.write_all(&detached.replies).await
+    }
+
+    /// Write a reply in the connection's protocol version
+    pub async fn write_frame(&mut self, frame: &BytesFrame) -> std::io::Result<()> {
+        let data = serialize_reply(frame, self.protocol);
+        self.write_all(&data).await
+    }
+
+    /// Write a map reply, a flat array of keys and values for RESP2 clients
+    pub async fn write_map(&mut self, entries: &[(BytesFrame, BytesFrame)]) -> std::io::Result<()> {
+        let data = serialize_map(entries, self.protocol);
+        self.write_all(&data).await
+    }
+
+    /// Write raw protocol data, for replies streamed in parts
+    pub async fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.replies.extend_from_slice(data);
+        if self.replies.len() >= FLUSH_SIZE {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Send the buffered replies to the client
+    pub async fn flush(&mut self) -> std::io::Result<()> {
+        if let Some(writer) = &mut self.writer
+            && !self.replies.is_empty()
+        {
+            writer.write_all(&self.replies).await?;
+            self.replies.clear();
+        }
+        Ok(())
+    }
+}
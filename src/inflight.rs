@@ -0,0 +1,208 @@
+//! Values of asynchronous writes that are queued but not yet committed.
+//!
+//! With `async_write` a client is answered before its write reaches SQLite,
+//! so reads look here first. Every entry is tagged with the sequence number
+//! of the write that made it, which the write carries to the shard writer.
+//! Once the write is committed its entries are removed, unless a later write
+//! of the same key has replaced them in the meantime.
+
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Pending values by key, with the sequence number of the write that made them
+type Entries = HashMap<Bytes, (u64, Bytes)>;
+
+/// Pending SET values of the plain keyspace. Unlike a cache, nothing is
+/// evicted: an entry stays until its write is committed, and the bounded
+/// shard writer channels limit how many can be pending.
+#[derive(Clone, Default)]
+pub struct InflightValues {
+    last_seq: Arc<AtomicU64>,
+    values: Arc<Mutex<Entries>>,
+}
+
+impl InflightValues {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number for the entries of a new write, never 0
+    pub fn next_seq(&self) -> u64 {
+        self.last_seq.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        let values = self.values.lock().unwrap();
+        values.get(key).map(|(_, data)| data.clone())
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.values.lock().unwrap().contains_key(key)
+    }
+
+    pub fn insert(&self, key: Bytes, data: Bytes, seq: u64) {
+        self.values.lock().unwrap().insert(key, (seq, data));
+    }
+
+    pub fn invalidate(&self, key: &[u8]) {
+        self.values.lock().unwrap().remove(key);
+    }
+
+    /// Remove the entry of `key` if it is still the one of write `seq`
+    pub fn remove_committed(&self, key: &[u8], seq: u64) {
+        let mut values = self.values.lock().unwrap();
+        if values
+            .get(key)
+            .is_some_and(|(entry_seq, _)| *entry_seq == seq)
+        {
+            values.remove(key);
+        }
+    }
+}
+
+/// Pending HSET values, grouped by namespace so the writes pending in a
+/// namespace are found without walking every pending write
+#[derive(Clone, Default)]
+pub struct InflightHashes {
+    last_seq: Arc<AtomicU64>,
+    namespaces: Arc<Mutex<HashMap<String, Entries>>>,
+}
+
+impl InflightHashes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sequence number for the entries of a new write, never 0
+    pub fn next_seq(&self) -> u64 {
+        self.last_seq.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn get(&self, (namespace, key): &(String, Bytes)) -> Option<Bytes> {
+        let namespaces = self.namespaces.lock().unwrap();
+        let (_, data) = namespaces.get(namespace)?.get(key)?;
+        Some(data.clone())
+    }
+
+    pub fn contains_key(&self, key: &(String, Bytes)) -> bool {
+        self.get(key).is_some()
+    }
+
+    pub fn insert(&self, (namespace, key): (String, Bytes), data: Bytes, seq: u64) {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        namespaces
+            .entry(namespace)
+            .or_default()
+            .insert(key, (seq, data));
+    }
+
+    pub fn invalidate(&self, key: &(String, Bytes)) {
+        self.remove_if(key, |_| true);
+    }
+
+    /// Remove the entry of `key` if it is still the one of write `seq`
+    pub fn remove_committed(&self, key: &(String, Bytes), seq: u64) {
+        self.remove_if(key, |entry_seq| entry_seq == seq);
+    }
+
+    fn remove_if(&self, (namespace, key): &(String, Bytes), matches: impl Fn(u64) -> bool) {
+        let mut namespaces = self.namespaces.lock().unwrap();
+        if let Some(fields) = namespaces.get_mut(namespace) {
+            if fields.get(key).is_some_and(|(seq, _)| matches(*seq)) {
+                fields.remove(key);
+            }
+            if fields.is_empty() {
+                namespaces.remove(namespace);
+            }
+        }
+    }
+
+    /// Fields of `namespace` with a pending write
+    pub fn fields(&self, namespace: &str) -> Vec<Bytes> {
+        let namespaces = self.namespaces.lock().unwrap();
+        namespaces
+            .get(namespace)
+            .map(|fields| fields.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Fields of `namespace` with a pending write, with their values
+    pub fn entries(&self, namespace: &str) -> Vec<(Bytes, Bytes)> {
+        let namespaces = self.namespaces.lock().unwrap();
+        namespaces
+            .get(namespace)
+            .map(|fields| {
+                fields
+                    .iter()
+                    .map(|(key, (_, data))| (key.clone(), data.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forget the pending writes of a dropped namespace
+    pub fn invalidate_namespace(&self, namespace: &str) {
+        self.namespaces.lock().unwrap().remove(namespace);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(namespace: &str, field: &'static str) -> (String, Bytes) {
+        (namespace.to_string(), Bytes::from_static(field.as_bytes()))
+    }
+
+    #[test]
+    fn test_values_are_removed_by_their_write() {
+        let values = InflightValues::new();
+        let first = values.next_seq();
+        let second = values.next_seq();
+        assert!(first != 0 && second != first);
+
+        values.insert(Bytes::from_static(b"k"), Bytes::from_static(b"v1"), first);
+        values.insert(Bytes::from_static(b"k"), Bytes::from_static(b"v2"), second);
+        values.remove_committed(b"k", first);
+        assert_eq!(values.get(b"k").unwrap(), "v2");
+        values.remove_committed(b"k", second);
+        assert!(!values.contains_key(b"k"));
+    }
+
+    #[test]
+    fn test_hashes_by_namespace() {
+        let hashes = InflightHashes::new();
+        hashes.insert(key("a", "f1"), Bytes::from_static(b"1"), 1);
+        hashes.insert(key("a", "f2"), Bytes::from_static(b"2"), 1);
+        hashes.insert(key("b", "f1"), Bytes::from_static(b"3"), 1);
+
+        assert_eq!(hashes.get(&key("a", "f1")).unwrap(), "1");
+        assert_eq!(hashes.get(&key("b", "f1")).unwrap(), "3");
+        let mut fields = hashes.fields("a");
+        fields.sort();
+        assert_eq!(fields, vec!["f1", "f2"]);
+        assert!(hashes.fields("c").is_empty());
+
+        hashes.invalidate(&key("a", "f1"));
+        assert!(!hashes.contains_key(&key("a", "f1")));
+        assert_eq!(hashes.fields("a"), vec!["f2"]);
+        assert_eq!(
+            hashes.entries("a"),
+            vec![(Bytes::from_static(b"f2"), Bytes::from_static(b"2"))]
+        );
+
+        // A committed write leaves the entry of a later write alone
+        hashes.insert(key("a", "f2"), Bytes::from_static(b"4"), 2);
+        hashes.remove_committed(&key("a", "f2"), 1);
+        assert_eq!(hashes.get(&key("a", "f2")).unwrap(), "4");
+        hashes.remove_committed(&key("a", "f2"), 2);
+        assert!(hashes.fields("a").is_empty());
+
+        hashes.insert(key("a", "f2"), Bytes::from_static(b"2"), 3);
+        hashes.invalidate_namespace("a");
+        assert!(hashes.fields("a").is_empty());
+        assert!(hashes.contains_key(&key("b", "f1")));
+    }
+}
@@ -0,0 +1,644 @@
+//! Write-ahead journal of asynchronous writes.
+//!
+//! With `async_write` a client is answered as soon as its write is queued for
+//! the shard writer. When the journal is enabled every such write is first
+//! appended to a journal file of its shard, so writes that were acknowledged
+//! but not yet committed to SQLite survive a crash: they are replayed when the
+//! server starts.
+//!
+//! A shard's journal is a series of segment files named
+//! `shard_<n>.<segment>.journal` in the data directory. Each record is
+//!
+//! ```text
+//! +------------------------+--------------------------+---------+
+//! | payload length (u32le) | crc32 of payload (u32le) | payload |
+//! +------------------------+--------------------------+---------+
+//! ```
+//!
+//! where the payload is the record's sequence number (u64le), a tag byte
+//! naming the operation and its fields, each as a u32le length and the
+//! bytes. Records are numbered in the order the writes are queued. The shard
+//! writer stores the number of the last journaled write of every batch in
+//! the `blob_journal` table, in the same transaction as the batch, and only
+//! records after it are replayed. A replayed write can then never overwrite
+//! a newer write, journaled or not, that was already committed.
+//!
+//! After each commit the open segment is emptied if every record in it is
+//! committed, and otherwise closed; closed segments are deleted once their
+//! last record is committed. A segment is also closed when it reaches
+//! `segment_size`.
+//!
+//! A record cut short by a crash ends the replay of its segment.
+
+use bytes::Bytes;
+use sqlx::{SqliteConnection, SqlitePool};
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, mpsc};
+
+use crate::config::JournalConfig;
+use crate::shard_manager::ShardWriteOperation;
+
+const HEADER_LEN: usize = 8;
+
+/// Table of a shard holding the sequence number of the last committed
+/// journaled write. Its name must not start with `blobs` so it is never
+/// taken for a namespace.
+pub const WATERMARK_TABLE: &str = "blob_journal";
+
+const TAG_SET: u8 = 1;
+const TAG_DELETE: u8 = 2;
+const TAG_MSET: u8 = 3;
+const TAG_DELETE_MANY: u8 = 4;
+const TAG_HSET: u8 = 5;
+const TAG_HDELETE: u8 = 6;
+const TAG_HMSET: u8 = 7;
+
+/// Journal of one shard
+pub struct Journal {
+    shard_id: usize,
+    data_dir: PathBuf,
+    fsync: bool,
+    segment_size: u64,
+    /// Sequence number of the last write committed before this run
+    start_seq: u64,
+    inner: Mutex<Segments>,
+}
+
+struct Segments {
+    file: File,
+    segment: u64,
+    /// Bytes in the open segment
+    size: u64,
+    /// Sequence number of the first record of the open segment
+    first_seq: u64,
+    /// Sequence number of the last record appended
+    last_seq: u64,
+    /// Closed segments, with the sequence number of their last record
+    closed: VecDeque<(u64, u64)>,
+}
+
+impl Journal {
+    /// Start an empty journal for a shard, deleting any segment left from an
+    /// earlier run. Call `recover` first to replay them. `last_seq` is the
+    /// watermark of the shard once they are replayed.
+    pub async fn create(
+        data_dir: &str,
+        shard_id: usize,
+        cfg: &JournalConfig,
+        last_seq: u64,
+    ) -> io::Result<Self> {
+        let data_dir = PathBuf::from(data_dir);
+        for (_, path) in segments(&data_dir, shard_id)? {
+            tokio::fs::remove_file(path).await?;
+        }
+        let file = open_segment(&data_dir, shard_id, 0).await?;
+        Ok(Self {
+            shard_id,
+            data_dir,
+            fsync: cfg.fsync(),
+            segment_size: cfg.segment_size(),
+            start_seq: last_seq,
+            inner: Mutex::new(Segments {
+                file,
+                segment: 0,
+                size: 0,
+                first_seq: last_seq + 1,
+                last_seq,
+                closed: VecDeque::new(),
+            }),
+        })
+    }
+
+    /// Read the writes journaled by an earlier run after the `watermark`
+    /// stored in the shard, oldest first, with their sequence numbers
+    pub async fn recover(
+        data_dir: &str,
+        shard_id: usize,
+        watermark: u64,
+    ) -> io::Result<Vec<(u64, ShardWriteOperation)>> {
+        let mut operations = Vec::new();
+        for (_, path) in segments(Path::new(data_dir), shard_id)? {
+            let contents = tokio::fs::read(&path).await?;
+            let mut rest = &contents[..];
+            while !rest.is_empty() {
+                match read_record(&mut rest) {
+                    Some((seq, _)) if seq <= watermark => {}
+                    Some(record) => operations.push(record),
+                    None => {
+                        tracing::warn!(
+                            "Ignoring {} bytes of incomplete or corrupt records at the end of {}",
+                            rest.len(),
+                            path.display()
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(operations)
+    }
+
+    /// Sequence number of the last write committed before this run. The
+    /// shard writer numbers the asynchronous writes it receives from here.
+    pub fn start_seq(&self) -> u64 {
+        self.start_seq
+    }
+
+    /// Append an asynchronous write to the journal, then queue it for the
+    /// shard writer. Returns once the record is written, and synced if
+    /// `fsync` is on.
+    pub async fn send(
+        &self,
+        sender: &mpsc::Sender<ShardWriteOperation>,
+        operation: ShardWriteOperation,
+    ) -> Result<(), String> {
+        // Queueing happens under the lock so the writer receives operations
+        // in sequence order. The permit is taken before the lock, so a full
+        // queue never keeps the writer from truncating the journal.
+        let permit = sender
+            .reserve()
+            .await
+            .map_err(|_| "shard writer stopped".to_string())?;
+
+        let mut inner = self.inner.lock().await;
+        if inner.size >= self.segment_size {
+            self.rotate(&mut inner)
+                .await
+                .map_err(|e| format!("rotating journal: {}", e))?;
+        }
+        let seq = inner.last_seq + 1;
+        let record = encode(seq, &operation)
+            .ok_or_else(|| "only asynchronous writes are journaled".to_string())?;
+        if let Err(e) = write_record(&mut inner.file, &record, self.fsync).await {
+            // Drop a partly written record, it would hide the records after it
+            let size = inner.size;
+            let _ = inner.file.set_len(size).await;
+            return Err(format!("writing journal: {}", e));
+        }
+        inner.size += record.len() as u64;
+        inner.last_seq = seq;
+        permit.send(operation);
+        Ok(())
+    }
+
+    /// Drop the records up to `seq`, which the writer has just committed
+    /// along with the watermark
+    pub async fn committed(&self, seq: u64) {
+        let mut inner = self.inner.lock().await;
+        while let Some(&(segment, last)) = inner.closed.front() {
+            if last > seq {
+                break;
+            }
+            inner.closed.pop_front();
+            let path = segment_path(&self.data_dir, self.shard_id, segment);
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                tracing::error!("Failed to remove {}: {}", path.display(), e);
+            }
+        }
+
+        if inner.size == 0 || inner.first_seq > seq {
+            return;
+        }
+        if inner.last_seq <= seq {
+            match inner.file.set_len(0).await {
+                Ok(()) => {
+                    inner.size = 0;
+                    inner.first_seq = inner.last_seq + 1;
+                }
+                Err(e) => tracing::error!(
+                    "[Shard {}] Failed to truncate journal: {}",
+                    self.shard_id,
+                    e
+                ),
+            }
+        } else if let Err(e) = self.rotate(&mut inner).await {
+            // The committed records are deleted with the rest of the segment
+            tracing::error!("[Shard {}] Failed to rotate journal: {}", self.shard_id, e);
+        }
+    }
+
+    async fn rotate(&self, inner: &mut Segments) -> io::Result<()> {
+        let segment = inner.segment + 1;
+        inner.file = open_segment(&self.data_dir, self.shard_id, segment).await?;
+        let closed = (inner.segment, inner.last_seq);
+        inner.closed.push_back(closed);
+        inner.segment = segment;
+        inner.size = 0;
+        inner.first_seq = inner.last_seq + 1;
+        Ok(())
+    }
+}
+
+/// Create the watermark table of a shard
+pub async fn create_watermark_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            seq INTEGER NOT NULL
+        )",
+        WATERMARK_TABLE
+    ))
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Sequence number of the last journaled write committed to a shard
+pub async fn load_watermark(pool: &SqlitePool) -> Result<u64, sqlx::Error> {
+    let seq: Option<i64> =
+        sqlx::query_scalar(&format!("SELECT seq FROM {} WHERE id = 0", WATERMARK_TABLE))
+            .fetch_optional(pool)
+            .await?;
+    Ok(seq.unwrap_or(0) as u64)
+}
+
+/// Store the watermark of a shard, in the transaction of the batch that
+/// committed the write
+pub async fn store_watermark(conn: &mut SqliteConnection, seq: u64) -> Result<(), sqlx::Error> {
+    sqlx::query(&format!(
+        "INSERT INTO {} (id, seq) VALUES (0, ?) ON CONFLICT (id) DO UPDATE SET seq = excluded.seq",
+        WATERMARK_TABLE
+    ))
+    .bind(seq as i64)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn write_record(file: &mut File, record: &[u8], fsync: bool) -> io::Result<()> {
+    file.write_all(record).await?;
+    // Hand the record to the OS before the client is answered
+    file.flush().await?;
+    if fsync {
+        file.sync_data().await?;
+    }
+    Ok(())
+}
+
+fn segment_path(data_dir: &Path, shard_id: usize, segment: u64) -> PathBuf {
+    data_dir.join(format!("shard_{}.{:06}.journal", shard_id, segment))
+}
+
+async fn open_segment(data_dir: &Path, shard_id: usize, segment: u64) -> io::Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(segment_path(data_dir, shard_id, segment))
+        .await
+}
+
+/// Segment files of a shard, in the order they were written
+fn segments(data_dir: &Path, shard_id: usize) -> io::Result<Vec<(u64, PathBuf)>> {
+    let prefix = format!("shard_{}.", shard_id);
+    let mut segments = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if let Some(segment) = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(&prefix))
+            .and_then(|name| name.strip_suffix(".journal"))
+            .and_then(|segment| segment.parse::<u64>().ok())
+        {
+            segments.push((segment, entry.path()));
+        }
+    }
+    segments.sort_unstable_by_key(|(segment, _)| *segment);
+    Ok(segments)
+}
+
+/// The record of an asynchronous write, `None` for other operations
+fn encode(seq: u64, operation: &ShardWriteOperation) -> Option<Vec<u8>> {
+    let mut payload = seq.to_le_bytes().to_vec();
+    match operation {
+        ShardWriteOperation::SetAsync { key, data, .. } => {
+            payload.push(TAG_SET);
+            put_field(&mut payload, key);
+            put_field(&mut payload, data);
+        }
+        ShardWriteOperation::DeleteAsync { key } => {
+            payload.push(TAG_DELETE);
+            put_field(&mut payload, key);
+        }
+        ShardWriteOperation::MSetAsync { entries, .. } => {
+            payload.push(TAG_MSET);
+            put_entries(&mut payload, entries);
+        }
+        ShardWriteOperation::DeleteManyAsync { keys } => {
+            payload.push(TAG_DELETE_MANY);
+            payload.extend_from_slice(&(keys.len() as u32).to_le_bytes());
+            for key in keys {
+                put_field(&mut payload, key);
+            }
+        }
+        ShardWriteOperation::HSetAsync {
+            namespace,
+            key,
+            data,
+            ..
+        } => {
+            payload.push(TAG_HSET);
+            put_field(&mut payload, namespace.as_bytes());
+            put_field(&mut payload, key);
+            put_field(&mut payload, data);
+        }
+        ShardWriteOperation::HDeleteAsync { namespace, key } => {
+            payload.push(TAG_HDELETE);
+            put_field(&mut payload, namespace.as_bytes());
+            put_field(&mut payload, key);
+        }
+        ShardWriteOperation::HMSetAsync {
+            namespace, entries, ..
+        } => {
+            payload.push(TAG_HMSET);
+            put_field(&mut payload, namespace.as_bytes());
+            put_entries(&mut payload, entries);
+        }
+        _ => return None,
+    }
+
+    let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    Some(record)
+}
+
+fn put_field(payload: &mut Vec<u8>, field: &[u8]) {
+    payload.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    payload.extend_from_slice(field);
+}
+
+fn put_entries(payload: &mut Vec<u8>, entries: &[(Bytes, Bytes)]) {
+    payload.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (key, data) in entries {
+        put_field(payload, key);
+        put_field(payload, data);
+    }
+}
+
+/// Read the record at the start of `input` and advance past it. `None` if it
+/// is incomplete or does not match its checksum.
+fn read_record(input: &mut &[u8]) -> Option<(u64, ShardWriteOperation)> {
+    let len = u32::from_le_bytes(input.get(..4)?.try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(input.get(4..HEADER_LEN)?.try_into().ok()?);
+    let payload = input.get(HEADER_LEN..HEADER_LEN + len)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    let record = decode(payload)?;
+    *input = &input[HEADER_LEN + len..];
+    Some(record)
+}
+
+fn decode(payload: &[u8]) -> Option<(u64, ShardWriteOperation)> {
+    let (seq, payload) = payload.split_first_chunk::<8>()?;
+    let (&tag, mut rest) = payload.split_first()?;
+    let input = &mut rest;
+    let operation = match tag {
+        TAG_SET => ShardWriteOperation::SetAsync {
+            key: take_field(input)?,
+            data: take_field(input)?,
+            inflight_seq: 0,
+        },
+        TAG_DELETE => ShardWriteOperation::DeleteAsync {
+            key: take_field(input)?,
+        },
+        TAG_MSET => ShardWriteOperation::MSetAsync {
+            entries: take_entries(input)?,
+            inflight_seq: 0,
+        },
+        TAG_DELETE_MANY => {
+            let count = take_u32(input)?;
+            let keys = (0..count)
+                .map(|_| take_field(input))
+                .collect::<Option<_>>()?;
+            ShardWriteOperation::DeleteManyAsync { keys }
+        }
+        TAG_HSET => ShardWriteOperation::HSetAsync {
+            namespace: take_namespace(input)?,
+            key: take_field(input)?,
+            data: take_field(input)?,
+            inflight_seq: 0,
+        },
+        TAG_HDELETE => ShardWriteOperation::HDeleteAsync {
+            namespace: take_namespace(input)?,
+            key: take_field(input)?,
+        },
+        TAG_HMSET => ShardWriteOperation::HMSetAsync {
+            namespace: take_namespace(input)?,
+            entries: take_entries(input)?,
+            inflight_seq: 0,
+        },
+        _ => return None,
+    };
+    input
+        .is_empty()
+        .then_some((u64::from_le_bytes(*seq), operation))
+}
+
+fn take_u32(input: &mut &[u8]) -> Option<u32> {
+    let (value, rest) = input.split_first_chunk::<4>()?;
+    *input = rest;
+    Some(u32::from_le_bytes(*value))
+}
+
+fn take_field(input: &mut &[u8]) -> Option<Bytes> {
+    let len = take_u32(input)? as usize;
+    let field = input.get(..len)?;
+    *input = &input[len..];
+    Some(Bytes::copy_from_slice(field))
+}
+
+fn take_namespace(input: &mut &[u8]) -> Option<String> {
+    String::from_utf8(take_field(input)?.to_vec()).ok()
+}
+
+fn take_entries(input: &mut &[u8]) -> Option<Vec<(Bytes, Bytes)>> {
+    let count = take_u32(input)?;
+    (0..count)
+        .map(|_| Some((take_field(input)?, take_field(input)?)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cfg(segment_size: u64) -> JournalConfig {
+        JournalConfig {
+            enabled: true,
+            fsync: Some(false),
+            segment_size: Some(segment_size),
+        }
+    }
+
+    fn set(key: &str) -> ShardWriteOperation {
+        ShardWriteOperation::SetAsync {
+            key: Bytes::copy_from_slice(key.as_bytes()),
+            data: Bytes::from_static(b"value"),
+            inflight_seq: 0,
+        }
+    }
+
+    fn keys(records: &[(u64, ShardWriteOperation)]) -> Vec<String> {
+        records
+            .iter()
+            .map(|(_, operation)| match operation {
+                ShardWriteOperation::SetAsync { key, .. } => {
+                    String::from_utf8(key.to_vec()).unwrap()
+                }
+                _ => panic!("unexpected operation"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let operations = vec![
+            set("k"),
+            ShardWriteOperation::DeleteAsync {
+                key: Bytes::from_static(b"k"),
+            },
+            ShardWriteOperation::MSetAsync {
+                entries: vec![(Bytes::from_static(b"a"), Bytes::from_static(b"1"))],
+                inflight_seq: 0,
+            },
+            ShardWriteOperation::DeleteManyAsync {
+                keys: vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")],
+            },
+            ShardWriteOperation::HSetAsync {
+                namespace: "ns".to_string(),
+                key: Bytes::from_static(b"k"),
+                data: Bytes::new(),
+                inflight_seq: 0,
+            },
+            ShardWriteOperation::HDeleteAsync {
+                namespace: "ns".to_string(),
+                key: Bytes::from_static(b"k"),
+            },
+            ShardWriteOperation::HMSetAsync {
+                namespace: "ns".to_string(),
+                entries: vec![(Bytes::from_static(b"k"), Bytes::from_static(b"v"))],
+                inflight_seq: 0,
+            },
+        ];
+        for (seq, operation) in (1..).zip(operations) {
+            let record = encode(seq, &operation).unwrap();
+            let mut input = &record[..];
+            let (decoded_seq, decoded) = read_record(&mut input).unwrap();
+            assert!(input.is_empty());
+            assert_eq!(decoded_seq, seq);
+            assert_eq!(encode(seq, &decoded).unwrap(), record);
+        }
+    }
+
+    #[test]
+    fn test_damaged_records_are_rejected() {
+        let record = encode(1, &set("k")).unwrap();
+        assert!(read_record(&mut &record[..record.len() - 1]).is_none());
+
+        let mut corrupt = record.clone();
+        *corrupt.last_mut().unwrap() ^= 1;
+        assert!(read_record(&mut &corrupt[..]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_recover_uncommitted_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let (sender, mut receiver) = mpsc::channel(16);
+
+        let journal = Journal::create(data_dir, 0, &cfg(1024), 0).await.unwrap();
+        for key in ["a", "b", "c"] {
+            journal.send(&sender, set(key)).await.unwrap();
+        }
+        receiver.recv().await.unwrap();
+        journal.committed(1).await;
+        drop(journal);
+
+        // A torn record at the end is ignored
+        let segment = segment_path(dir.path(), 0, 0);
+        let mut contents = std::fs::read(&segment).unwrap();
+        contents.extend_from_slice(&encode(4, &set("d")).unwrap()[..5]);
+        std::fs::write(&segment, contents).unwrap();
+
+        // Records up to the watermark are skipped
+        let recovered = Journal::recover(data_dir, 0, 1).await.unwrap();
+        assert_eq!(keys(&recovered), vec!["b", "c"]);
+        assert_eq!(recovered[0].0, 2);
+
+        // Starting afresh discards the old segments and numbers on
+        let journal = Journal::create(data_dir, 0, &cfg(1024), 3).await.unwrap();
+        assert!(Journal::recover(data_dir, 0, 0).await.unwrap().is_empty());
+        journal.send(&sender, set("e")).await.unwrap();
+        assert_eq!(Journal::recover(data_dir, 0, 0).await.unwrap()[0].0, 4);
+    }
+
+    #[tokio::test]
+    async fn test_committed_segments_are_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let (sender, _receiver) = mpsc::channel(16);
+
+        // Every record fills a segment, so each write opens a new one
+        let journal = Journal::create(data_dir, 0, &cfg(1), 0).await.unwrap();
+        for key in ["a", "b", "c"] {
+            journal.send(&sender, set(key)).await.unwrap();
+        }
+        assert_eq!(segments(dir.path(), 0).unwrap().len(), 3);
+
+        journal.committed(2).await;
+        assert_eq!(
+            keys(&Journal::recover(data_dir, 0, 0).await.unwrap()),
+            vec!["c"]
+        );
+
+        journal.committed(3).await;
+        assert!(Journal::recover(data_dir, 0, 0).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_partly_committed_segment_is_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let data_dir = dir.path().to_str().unwrap();
+        let (sender, _receiver) = mpsc::channel(16);
+
+        let journal = Journal::create(data_dir, 0, &cfg(1024), 0).await.unwrap();
+        for key in ["a", "b"] {
+            journal.send(&sender, set(key)).await.unwrap();
+        }
+        journal.committed(1).await;
+        journal.send(&sender, set("c")).await.unwrap();
+        assert_eq!(segments(dir.path(), 0).unwrap().len(), 2);
+
+        // The closed segment goes once its last record is committed
+        journal.committed(2).await;
+        assert_eq!(segments(dir.path(), 0).unwrap().len(), 1);
+        assert_eq!(
+            keys(&Journal::recover(data_dir, 0, 0).await.unwrap()),
+            vec!["c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = sqlx::sqlite::SqliteConnectOptions::new()
+            .filename(dir.path().join("shard_0.db"))
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        create_watermark_table(&pool).await.unwrap();
+        assert_eq!(load_watermark(&pool).await.unwrap(), 0);
+
+        let mut conn = pool.acquire().await.unwrap();
+        store_watermark(&mut conn, 7).await.unwrap();
+        store_watermark(&mut conn, 9).await.unwrap();
+        drop(conn);
+        assert_eq!(load_watermark(&pool).await.unwrap(), 9);
+    }
+}
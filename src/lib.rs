@@ -3,21 +3,32 @@
 //! This library provides a Redis-compatible interface for storing and retrieving
 //! binary data with features like:
 //! - Consistent hashing-based sharding
-//! - Storage compression
+//! - Storage compression, optionally with trained zstd dictionaries
 //! - Namespaced operations
 //! - Cluster support
 //! - Shard migration capabilities
 
+pub mod acl;
 pub mod app_state;
+pub mod auth;
+pub mod chunks;
 pub mod cluster;
 pub mod compression;
 pub mod config;
+pub mod connection;
 pub mod http_server;
+pub mod inflight;
+pub mod journal;
 pub mod metrics;
 pub mod migration;
+pub mod namespace;
+pub mod recompress;
 pub mod redis;
+pub mod scan;
 pub mod server;
 pub mod shard_manager;
+pub mod tls;
+pub mod train;
 
 pub use app_state::AppState;
 pub use config::Cfg;
@@ -1,17 +1,31 @@
 use std::sync::Arc;
 
+use futures::FutureExt;
+use futures::future::LocalBoxFuture;
 use miette::{Context, Result};
+use tokio_util::sync::CancellationToken;
 
+mod acl;
 mod app_state;
+mod auth;
+mod chunks;
 mod cluster;
 mod compression;
 mod config;
+mod connection;
 mod http_server;
+mod inflight;
+mod journal;
 mod metrics;
 mod migration;
+mod namespace;
+mod recompress;
 mod redis;
+mod scan;
 mod server;
 mod shard_manager;
+mod tls;
+mod train;
 
 use app_state::AppState;
 use gumdrop::Options;
@@ -49,6 +63,12 @@ struct ServeOptions {}
 enum ShardCommand {
     #[options(help = "Migrate data from old shard configuration to new")]
     Migrate(MigrateOptions),
+
+    #[options(help = "Rewrite stored values with another compression algorithm")]
+    Recompress(RecompressOptions),
+
+    #[options(help = "Train a zstd dictionary from stored values")]
+    TrainDictionary(TrainDictionaryOptions),
 }
 
 #[derive(Options, Debug)]
@@ -71,6 +91,53 @@ struct MigrateOptions {
     verify: bool,
 }
 
+#[derive(Options, Debug)]
+struct RecompressOptions {
+    #[options(
+        help = "Target algorithm (none, gzip, zstd, lz4, brotli)",
+        required,
+        meta = "ALGORITHM"
+    )]
+    to: Option<config::CompressionType>,
+
+    #[options(help = "Target compression level", meta = "LEVEL")]
+    level: Option<u32>,
+
+    #[options(
+        help = "Data directory path",
+        short = "d",
+        long = "data-dir",
+        meta = "DIR"
+    )]
+    data_dir: Option<String>,
+
+    #[options(help = "Rows rewritten per transaction (default 500)", meta = "N")]
+    batch_size: Option<usize>,
+
+    #[options(help = "Compress with the latest trained dictionary (zstd only)")]
+    dictionary: bool,
+}
+
+#[derive(Options, Debug)]
+struct TrainDictionaryOptions {
+    #[options(
+        help = "Data directory path",
+        short = "d",
+        long = "data-dir",
+        meta = "DIR"
+    )]
+    data_dir: Option<String>,
+
+    #[options(help = "Values sampled across all shards (default 10000)", meta = "N")]
+    samples: Option<usize>,
+
+    #[options(
+        help = "Maximum dictionary size in bytes (default 112640)",
+        meta = "BYTES"
+    )]
+    max_size: Option<usize>,
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     // initialize tracing
@@ -91,19 +158,26 @@ async fn main() -> Result<()> {
 async fn handle_shard_command(shard_cmd: ShardCommand, config_path: &str) -> Result<()> {
     match shard_cmd {
         ShardCommand::Migrate(migrate_opts) => {
-            let data_dir = if let Some(dir) = migrate_opts.data_dir {
-                dir
+            // The shards are opened with the configured storage settings
+            // when the config can be loaded
+            let (data_dir, storage) = if let Some(dir) = migrate_opts.data_dir {
+                let storage = config::Cfg::load(config_path)
+                    .map(|cfg| cfg.storage())
+                    .unwrap_or_default();
+                (dir, storage)
             } else {
                 // Load config to get data_dir
                 let cfg = config::Cfg::load(config_path).wrap_err("loading config")?;
-                cfg.data_dir
+                let storage = cfg.storage();
+                (cfg.data_dir, storage)
             };
 
             let migration_manager = migration::MigrationManager::new(
                 migrate_opts.old_shard_count,
                 migrate_opts.new_shard_count,
                 data_dir,
-            )?;
+            )?
+            .with_storage(storage);
 
             migration_manager.run_migration().await?;
 
@@ -111,6 +185,62 @@ async fn handle_shard_command(shard_cmd: ShardCommand, config_path: &str) -> Res
                 migration_manager.verify_migration().await?;
             }
 
+            Ok(())
+        }
+        ShardCommand::Recompress(recompress_opts) => {
+            // The configured compression decodes values stored before they were tagged
+            let cfg = config::Cfg::load(config_path).wrap_err("loading config")?;
+            let algorithm = recompress_opts
+                .to
+                .ok_or_else(|| miette::miette!("--to is required"))?;
+
+            let recompress_manager = recompress::RecompressManager::new(
+                cfg.num_shards,
+                recompress_opts.data_dir.unwrap_or(cfg.data_dir),
+                cfg.storage_compression.as_ref(),
+                config::CompressionConfig {
+                    enabled: true,
+                    algorithm,
+                    level: recompress_opts.level,
+                    // Skip the same small and incompressible values as the server
+                    min_size: cfg.storage_compression.as_ref().and_then(|c| c.min_size),
+                    min_savings_percent: cfg
+                        .storage_compression
+                        .as_ref()
+                        .and_then(|c| c.min_savings_percent),
+                    use_dictionary: Some(recompress_opts.dictionary),
+                },
+                recompress_opts
+                    .batch_size
+                    .unwrap_or(recompress::DEFAULT_BATCH_SIZE),
+            )?;
+
+            recompress_manager.run().await?;
+
+            Ok(())
+        }
+        ShardCommand::TrainDictionary(train_opts) => {
+            // The configured compression decodes values stored before they were tagged
+            let cfg = config::Cfg::load(config_path).wrap_err("loading config")?;
+
+            let trainer = train::DictionaryTrainer::new(
+                cfg.num_shards,
+                train_opts.data_dir.unwrap_or(cfg.data_dir),
+                cfg.storage_compression.as_ref(),
+                train_opts.samples.unwrap_or(train::DEFAULT_SAMPLES),
+                train_opts
+                    .max_size
+                    .unwrap_or(compression::dictionary::DEFAULT_DICTIONARY_SIZE),
+            )?;
+
+            let trained = trainer.run().await?;
+            tracing::info!(
+                "Trained zstd dictionary {} ({} bytes) from {} values",
+                trained.id,
+                trained.size,
+                trained.samples
+            );
+
             Ok(())
         }
     }
@@ -120,7 +250,7 @@ async fn run_server(config_path: &str) -> Result<()> {
     let cfg = config::Cfg::load(config_path).wrap_err("loading config")?;
 
     // Initialize metrics if enabled
-    let prometheus_handle = if cfg.metrics.as_ref().map_or(false, |m| m.enabled) {
+    let prometheus_handle = if cfg.metrics.as_ref().is_some_and(|m| m.enabled) {
         Some(metrics::init_metrics_exporter().wrap_err("initializing metrics exporter")?)
     } else {
         None
@@ -139,6 +269,11 @@ async fn run_server(config_path: &str) -> Result<()> {
     // Initialize metrics
     shared_state.metrics.record_server_startup();
 
+    // Writers are stopped separately from connections, after the last
+    // connection has queued its writes
+    let writers_shutdown = CancellationToken::new();
+    let mut background_tasks = Vec::new();
+
     // Spawn shard writer tasks using the receivers populated by AppState::new
     for (i, receiver) in shard_receivers.into_iter().enumerate() {
         let pool = shared_state.db_pools[i].clone();
@@ -147,8 +282,12 @@ async fn run_server(config_path: &str) -> Result<()> {
         let inflight_cache = shared_state.inflight_cache.clone();
         let inflight_hcache = shared_state.inflight_hcache.clone();
         let metrics = shared_state.metrics.clone();
+        let journal = shared_state
+            .journals
+            .as_ref()
+            .map(|journals| journals[i].clone());
         // Pass the receiver to the spawned task
-        tokio::spawn(shard_manager::shard_writer_task(
+        background_tasks.push(tokio::spawn(shard_manager::shard_writer_task(
             i,
             pool,
             receiver,
@@ -157,7 +296,25 @@ async fn run_server(config_path: &str) -> Result<()> {
             inflight_cache,
             inflight_hcache,
             metrics,
-        ));
+            writers_shutdown.clone(),
+            journal,
+        )));
+    }
+
+    // Spawn one expiry reaper per shard to reclaim space used by expired keys
+    let expiry = cfg.expiry();
+    if expiry.enabled {
+        for (i, pool) in shared_state.db_pools.iter().enumerate() {
+            background_tasks.push(tokio::spawn(shard_manager::expiry_reaper_task(
+                i,
+                pool.clone(),
+                expiry.interval(),
+                expiry.batch_size(),
+                expiry.max_keys_per_pass(),
+                shared_state.metrics.clone(),
+                writers_shutdown.clone(),
+            )));
+        }
     }
 
     // Start HTTP metrics server if enabled
@@ -179,15 +336,133 @@ async fn run_server(config_path: &str) -> Result<()> {
         });
     }
 
-    // Run Redis server
-    if let Err(e) = server::run_redis_server(
-        shared_state,
-        &cfg.addr.unwrap_or("0.0.0.0:6379".to_string()),
-    )
-    .await
-    {
-        return Err(miette::miette!("Failed to run Redis server: {}", e));
+    let tls = match cfg.tls {
+        Some(ref tls_cfg) => {
+            let tls = Arc::new(
+                tls::Tls::load(tls_cfg)
+                    .map_err(|e| miette::miette!("Failed to load TLS certificates: {}", e))?,
+            );
+            #[cfg(unix)]
+            tokio::spawn(tls::reload_on_sighup(tls.clone()));
+            Some(tls)
+        }
+        None => None,
+    };
+
+    // Run Redis server on every configured listener
+    let plaintext_addr = cfg.plaintext_addr();
+    let tls_addr = cfg.tls_addr();
+    let mut listeners: Vec<LocalBoxFuture<Result<(), Box<dyn std::error::Error>>>> = Vec::new();
+    if let Some(ref addr) = plaintext_addr {
+        listeners.push(server::run_redis_server(shared_state.clone(), addr, None).boxed_local());
+    }
+    if let (Some(tls), Some(tls_addr)) = (tls, &tls_addr) {
+        listeners.push(
+            server::run_redis_server(shared_state.clone(), tls_addr, Some(tls)).boxed_local(),
+        );
+    }
+    #[cfg(unix)]
+    if let Some(ref unix_socket) = cfg.unix_socket {
+        listeners.push(
+            server::run_unix_server(
+                shared_state.clone(),
+                &unix_socket.path,
+                unix_socket.permissions(),
+            )
+            .boxed_local(),
+        );
+    }
+    // Listeners are dropped as soon as a signal arrives, so no new
+    // connections are accepted while the server drains
+    tokio::select! {
+        result = futures::future::try_join_all(listeners) => {
+            if let Err(e) = result {
+                return Err(miette::miette!("Failed to run Redis server: {}", e));
+            }
+        }
+        signal = shutdown_signal() => {
+            tracing::info!("Received {}, shutting down", signal);
+        }
     }
 
+    let shutdown_timeout = cfg.shutdown_timeout();
+    let drain = shutdown(&shared_state, writers_shutdown, background_tasks);
+    if tokio::time::timeout(shutdown_timeout, drain).await.is_err() {
+        return Err(miette::miette!(
+            "Shutdown did not finish within {}ms, queued writes may be lost",
+            shutdown_timeout.as_millis()
+        ));
+    }
+    tracing::info!("Shutdown complete");
+
     Ok(())
 }
+
+/// Wait for SIGINT, or SIGTERM on Unix, and return its name
+async fn shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+                _ = terminate.recv() => "SIGTERM",
+            },
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "Ctrl-C"
+    }
+}
+
+/// Let connections answer the commands they have read, write everything the
+/// shard writers have queued, checkpoint the shards and leave the cluster
+async fn shutdown(
+    state: &AppState,
+    writers_shutdown: CancellationToken,
+    background_tasks: Vec<tokio::task::JoinHandle<()>>,
+) {
+    state.shutdown.cancel();
+    state.connections.close();
+    tracing::info!(
+        "Waiting for {} connections to finish",
+        state.connections.len()
+    );
+    state.connections.wait().await;
+
+    writers_shutdown.cancel();
+    for task in background_tasks {
+        if let Err(e) = task.await {
+            tracing::error!("Shard task failed during shutdown: {}", e);
+        }
+    }
+
+    for (i, pool) in state.db_pools.iter().enumerate() {
+        if let Err(e) = sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(pool)
+            .await
+        {
+            tracing::error!("Failed to checkpoint shard {}: {}", i, e);
+        }
+        pool.close().await;
+    }
+
+    #[cfg(unix)]
+    if let Some(ref unix_socket) = state.cfg.unix_socket
+        && let Err(e) = std::fs::remove_file(&unix_socket.path)
+    {
+        tracing::warn!("Failed to remove {}: {}", unix_socket.path, e);
+    }
+
+    if let Some(ref cluster_manager) = state.cluster_manager {
+        cluster_manager.shutdown().await;
+    }
+}
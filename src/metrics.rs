@@ -4,6 +4,9 @@ use metrics::{Counter, Gauge, Histogram};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use miette::Result;
 
+use crate::compression::CompressionOutcome;
+use crate::redis::LimitExceeded;
+
 /// Metrics collector for the blobasaur Redis server
 #[derive(Clone)]
 pub struct Metrics {
@@ -13,10 +16,22 @@ pub struct Metrics {
     pub commands_set_total: Counter,
     pub commands_del_total: Counter,
     pub commands_exists_total: Counter,
+    pub commands_mget_total: Counter,
+    pub commands_mset_total: Counter,
+    pub commands_expire_total: Counter,
+    pub commands_ttl_total: Counter,
     pub commands_hget_total: Counter,
     pub commands_hset_total: Counter,
     pub commands_hdel_total: Counter,
     pub commands_hexists_total: Counter,
+    pub commands_hmget_total: Counter,
+    pub commands_hmset_total: Counter,
+    pub commands_hlen_total: Counter,
+    pub commands_hkeys_total: Counter,
+    pub commands_hgetall_total: Counter,
+    pub commands_hexpire_total: Counter,
+    pub commands_httl_total: Counter,
+    pub commands_scan_total: Counter,
     pub commands_ping_total: Counter,
     pub commands_info_total: Counter,
     pub commands_cluster_total: Counter,
@@ -36,6 +51,11 @@ pub struct Metrics {
     pub connection_errors_total: Counter,
     pub protocol_errors_total: Counter,
     pub storage_errors_total: Counter,
+    pub bulk_len_exceeded_total: Counter,
+    pub multibulk_len_exceeded_total: Counter,
+    pub inline_len_exceeded_total: Counter,
+    pub auth_failures_total: Counter,
+    pub permission_denied_total: Counter,
 
     // Connection metrics
     pub connections_active: Gauge,
@@ -53,6 +73,18 @@ pub struct Metrics {
     pub batch_operations_total: Counter,
     pub batch_size: Histogram,
     pub batch_duration_seconds: Histogram,
+
+    // Expiry metrics
+    pub expired_keys_total: Counter,
+    pub expired_bytes_total: Counter,
+    pub expiry_pass_duration_seconds: Histogram,
+
+    // Compression metrics
+    pub compression_bytes_in_total: Counter,
+    pub compression_bytes_out_total: Counter,
+    pub compression_skipped_small_total: Counter,
+    pub compression_skipped_incompressible_total: Counter,
+    pub compression_queue_seconds: Histogram,
 }
 
 impl Metrics {
@@ -65,10 +97,22 @@ impl Metrics {
             commands_set_total: metrics::counter!("blobasaur_commands_set_total"),
             commands_del_total: metrics::counter!("blobasaur_commands_del_total"),
             commands_exists_total: metrics::counter!("blobasaur_commands_exists_total"),
+            commands_mget_total: metrics::counter!("blobasaur_commands_mget_total"),
+            commands_mset_total: metrics::counter!("blobasaur_commands_mset_total"),
+            commands_expire_total: metrics::counter!("blobasaur_commands_expire_total"),
+            commands_ttl_total: metrics::counter!("blobasaur_commands_ttl_total"),
             commands_hget_total: metrics::counter!("blobasaur_commands_hget_total"),
             commands_hset_total: metrics::counter!("blobasaur_commands_hset_total"),
             commands_hdel_total: metrics::counter!("blobasaur_commands_hdel_total"),
             commands_hexists_total: metrics::counter!("blobasaur_commands_hexists_total"),
+            commands_hmget_total: metrics::counter!("blobasaur_commands_hmget_total"),
+            commands_hmset_total: metrics::counter!("blobasaur_commands_hmset_total"),
+            commands_hlen_total: metrics::counter!("blobasaur_commands_hlen_total"),
+            commands_hkeys_total: metrics::counter!("blobasaur_commands_hkeys_total"),
+            commands_hgetall_total: metrics::counter!("blobasaur_commands_hgetall_total"),
+            commands_hexpire_total: metrics::counter!("blobasaur_commands_hexpire_total"),
+            commands_httl_total: metrics::counter!("blobasaur_commands_httl_total"),
+            commands_scan_total: metrics::counter!("blobasaur_commands_scan_total"),
             commands_ping_total: metrics::counter!("blobasaur_commands_ping_total"),
             commands_info_total: metrics::counter!("blobasaur_commands_info_total"),
             commands_cluster_total: metrics::counter!("blobasaur_commands_cluster_total"),
@@ -88,6 +132,13 @@ impl Metrics {
             connection_errors_total: metrics::counter!("blobasaur_connection_errors_total"),
             protocol_errors_total: metrics::counter!("blobasaur_protocol_errors_total"),
             storage_errors_total: metrics::counter!("blobasaur_storage_errors_total"),
+            bulk_len_exceeded_total: metrics::counter!("blobasaur_bulk_len_exceeded_total"),
+            multibulk_len_exceeded_total: metrics::counter!(
+                "blobasaur_multibulk_len_exceeded_total"
+            ),
+            inline_len_exceeded_total: metrics::counter!("blobasaur_inline_len_exceeded_total"),
+            auth_failures_total: metrics::counter!("blobasaur_auth_failures_total"),
+            permission_denied_total: metrics::counter!("blobasaur_permission_denied_total"),
 
             // Connection metrics
             connections_active: metrics::gauge!("blobasaur_connections_active"),
@@ -105,6 +156,24 @@ impl Metrics {
             batch_operations_total: metrics::counter!("blobasaur_batch_operations_total"),
             batch_size: metrics::histogram!("blobasaur_batch_size"),
             batch_duration_seconds: metrics::histogram!("blobasaur_batch_duration_seconds"),
+
+            // Expiry metrics
+            expired_keys_total: metrics::counter!("blobasaur_expired_keys_total"),
+            expired_bytes_total: metrics::counter!("blobasaur_expired_bytes_total"),
+            expiry_pass_duration_seconds: metrics::histogram!(
+                "blobasaur_expiry_pass_duration_seconds"
+            ),
+
+            // Compression metrics
+            compression_bytes_in_total: metrics::counter!("blobasaur_compression_bytes_in_total"),
+            compression_bytes_out_total: metrics::counter!("blobasaur_compression_bytes_out_total"),
+            compression_skipped_small_total: metrics::counter!(
+                "blobasaur_compression_skipped_small_total"
+            ),
+            compression_skipped_incompressible_total: metrics::counter!(
+                "blobasaur_compression_skipped_incompressible_total"
+            ),
+            compression_queue_seconds: metrics::histogram!("blobasaur_compression_queue_seconds"),
         };
 
         // Initialize baseline metrics to ensure we have data
@@ -141,11 +210,23 @@ impl Metrics {
             "EXISTS" => {
                 self.commands_exists_total.increment(1);
             }
+            "MGET" => {
+                self.commands_mget_total.increment(1);
+            }
+            "MSET" | "MSETNX" => {
+                self.commands_mset_total.increment(1);
+            }
+            "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" | "PERSIST" => {
+                self.commands_expire_total.increment(1);
+            }
+            "TTL" | "PTTL" => {
+                self.commands_ttl_total.increment(1);
+            }
             "HGET" => {
                 self.commands_hget_total.increment(1);
                 self.hget_duration_seconds.record(duration);
             }
-            "HSET" => {
+            "HSET" | "HSETEX" => {
                 self.commands_hset_total.increment(1);
                 self.hset_duration_seconds.record(duration);
             }
@@ -156,6 +237,30 @@ impl Metrics {
             "HEXISTS" => {
                 self.commands_hexists_total.increment(1);
             }
+            "HMGET" => {
+                self.commands_hmget_total.increment(1);
+            }
+            "HMSET" => {
+                self.commands_hmset_total.increment(1);
+            }
+            "HLEN" => {
+                self.commands_hlen_total.increment(1);
+            }
+            "HKEYS" => {
+                self.commands_hkeys_total.increment(1);
+            }
+            "HGETALL" => {
+                self.commands_hgetall_total.increment(1);
+            }
+            "HEXPIRE" | "HPEXPIRE" | "HPERSIST" => {
+                self.commands_hexpire_total.increment(1);
+            }
+            "HTTL" | "HPTTL" => {
+                self.commands_httl_total.increment(1);
+            }
+            "SCAN" | "KEYS" | "HSCAN" => {
+                self.commands_scan_total.increment(1);
+            }
             "PING" => {
                 self.commands_ping_total.increment(1);
             }
@@ -193,6 +298,29 @@ impl Metrics {
         }
     }
 
+    /// Record a request rejected for exceeding a protocol limit
+    pub fn record_limit_exceeded(&self, limit: LimitExceeded) {
+        self.record_error("protocol");
+
+        match limit {
+            LimitExceeded::Bulk => self.bulk_len_exceeded_total.increment(1),
+            LimitExceeded::Multibulk => self.multibulk_len_exceeded_total.increment(1),
+            LimitExceeded::Inline => self.inline_len_exceeded_total.increment(1),
+        }
+    }
+
+    /// Record a rejected `AUTH` or `HELLO ... AUTH`
+    pub fn record_auth_failure(&self) {
+        self.errors_total.increment(1);
+        self.auth_failures_total.increment(1);
+    }
+
+    /// Record a command refused by the user's ACL rules
+    pub fn record_permission_denied(&self) {
+        self.errors_total.increment(1);
+        self.permission_denied_total.increment(1);
+    }
+
     /// Record a new connection
     pub fn record_connection(&self) {
         self.connections_total.increment(1);
@@ -216,6 +344,39 @@ impl Metrics {
         self.batch_size.record(batch_size as f64);
         self.batch_duration_seconds.record(duration.as_secs_f64());
     }
+
+    /// Record a completed expiry reaper pass
+    pub fn record_expiry_pass(&self, keys: u64, bytes: u64, duration: std::time::Duration) {
+        self.expired_keys_total.increment(keys);
+        self.expired_bytes_total.increment(bytes);
+        self.expiry_pass_duration_seconds
+            .record(duration.as_secs_f64());
+    }
+
+    /// Record a value encoded for storage while compression is enabled
+    pub fn record_compression(
+        &self,
+        outcome: CompressionOutcome,
+        bytes_in: usize,
+        bytes_out: usize,
+    ) {
+        match outcome {
+            CompressionOutcome::Disabled => return,
+            CompressionOutcome::TooSmall => self.compression_skipped_small_total.increment(1),
+            CompressionOutcome::Incompressible => {
+                self.compression_skipped_incompressible_total.increment(1)
+            }
+            CompressionOutcome::Compressed => {}
+        }
+        self.compression_bytes_in_total.increment(bytes_in as u64);
+        self.compression_bytes_out_total.increment(bytes_out as u64);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Initialize the Prometheus metrics exporter
@@ -1,3 +1,7 @@
+use crate::app_state::ShardKey;
+use crate::chunks::{self, ChunkManifest};
+use crate::config::StorageConfig;
+use crate::namespace::{self, quote_identifier};
 use miette::{Context, Result};
 use mpchash::HashRing;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
@@ -6,6 +10,63 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::str::FromStr;
 
+/// Convert the tables of a shard whose `key` column was declared TEXT by older
+/// versions to BLOB keys, so that binary keys and existing keys compare equal.
+/// Each table is rebuilt in its own transaction. Returns the number of tables
+/// that were converted.
+pub async fn upgrade_text_keys(pool: &SqlitePool) -> Result<usize, sqlx::Error> {
+    let tables = sqlx::query_as::<_, (String,)>(
+        "SELECT m.name FROM sqlite_master m, pragma_table_info(m.name) p
+         WHERE m.type = 'table' AND (m.name = 'blobs' OR m.name LIKE 'blobs_%')
+         AND p.name = 'key' AND upper(p.type) = 'TEXT'",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for (table_name,) in &tables {
+        let table = quote_identifier(table_name);
+        let staging = quote_identifier(&format!("{}__blob_keys", table_name));
+        let statements = [
+            format!("DROP TABLE IF EXISTS {}", staging),
+            format!(
+                "CREATE TABLE {} (
+                    key BLOB PRIMARY KEY,
+                    data BLOB,
+                    created_at INTEGER NOT NULL,
+                    updated_at INTEGER NOT NULL,
+                    expires_at INTEGER,
+                    version INTEGER NOT NULL DEFAULT 0
+                )",
+                staging
+            ),
+            format!(
+                "INSERT INTO {} (key, data, created_at, updated_at, expires_at, version)
+                 SELECT CAST(key AS BLOB), data, created_at, updated_at, expires_at, version FROM {}",
+                staging, table
+            ),
+            format!("DROP TABLE {}", table),
+            format!("ALTER TABLE {} RENAME TO {}", staging, table),
+            format!(
+                "CREATE INDEX IF NOT EXISTS {} ON {}(expires_at) WHERE expires_at IS NOT NULL",
+                quote_identifier(&namespace::expires_at_index_name(table_name)),
+                table
+            ),
+        ];
+
+        let mut tx = pool.begin().await?;
+        for statement in &statements {
+            sqlx::query(statement).execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        tracing::info!(
+            "Converted the keys of table {} from TEXT to BLOB",
+            table_name
+        );
+    }
+
+    Ok(tables.len())
+}
+
 // Helper function to convert sqlx errors to miette errors
 fn sqlx_to_miette(err: sqlx::Error, context: &str) -> miette::Error {
     miette::miette!("{}: {}", context, err)
@@ -18,6 +79,7 @@ pub struct MigrationManager {
     old_shard_count: usize,
     new_shard_count: usize,
     data_dir: String,
+    storage: StorageConfig,
     new_ring: HashRing<ShardNode>,
 }
 
@@ -43,12 +105,20 @@ impl MigrationManager {
             old_shard_count,
             new_shard_count,
             data_dir,
+            storage: StorageConfig::default(),
             new_ring,
         })
     }
 
-    fn get_new_shard(&self, key: &str) -> usize {
-        let token = self.new_ring.node(&key).unwrap();
+    /// Open the shard databases with these SQLite settings instead of the
+    /// defaults
+    pub fn with_storage(mut self, storage: StorageConfig) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    fn get_new_shard(&self, key: &[u8]) -> usize {
+        let token = self.new_ring.node(&ShardKey(key)).unwrap();
         token.node().0 as usize
     }
 
@@ -58,11 +128,8 @@ impl MigrationManager {
         let connect_options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path))
             .map_err(|e| sqlx_to_miette(e, "Failed to parse connection string"))?
             .create_if_missing(true)
-            .journal_mode(SqliteJournalMode::Wal)
-            .busy_timeout(std::time::Duration::from_millis(5000))
-            .pragma("synchronous", "OFF")
-            .pragma("cache_size", "-100000")
-            .pragma("temp_store", "MEMORY");
+            .journal_mode(SqliteJournalMode::Wal);
+        let connect_options = self.storage.apply(connect_options);
 
         SqlitePool::connect_with(connect_options)
             .await
@@ -84,6 +151,15 @@ impl MigrationManager {
             }
         }
 
+        // Keys are compared as BLOBs, so old shards with TEXT keys are converted first
+        for i in 0..self.old_shard_count {
+            let pool = self.create_connection_pool(i).await?;
+            upgrade_text_keys(&pool).await.map_err(|e| {
+                sqlx_to_miette(e, &format!("Failed to upgrade keys of shard {}", i))
+            })?;
+            pool.close().await;
+        }
+
         // Create new shard databases if they don't exist
         for i in 0..self.new_shard_count {
             let pool = self.create_connection_pool(i).await.wrap_err(format!(
@@ -94,7 +170,7 @@ impl MigrationManager {
             // Create the main blobs table
             sqlx::query(
                 "CREATE TABLE IF NOT EXISTS blobs (
-                    key TEXT PRIMARY KEY,
+                    key BLOB PRIMARY KEY,
                     data BLOB,
                     created_at INTEGER NOT NULL,
                     updated_at INTEGER NOT NULL,
@@ -119,6 +195,13 @@ impl MigrationManager {
             .await
             .map_err(|e| sqlx_to_miette(e, &format!("Failed to create expires_at index in new shard {}", i)))?;
 
+            chunks::create_chunk_table(&pool).await.map_err(|e| {
+                sqlx_to_miette(
+                    e,
+                    &format!("Failed to create chunk table in new shard {}", i),
+                )
+            })?;
+
             pool.close().await;
         }
 
@@ -152,7 +235,10 @@ impl MigrationManager {
                 );
 
                 // Count records before migration
-                let count_query = format!("SELECT COUNT(*) as count FROM {}", table_name);
+                let count_query = format!(
+                    "SELECT COUNT(*) as count FROM {}",
+                    quote_identifier(&table_name)
+                );
                 if let Ok(row) = sqlx::query(&count_query).fetch_one(&old_pool).await {
                     let count: i64 = row.get("count");
                     tracing::info!(
@@ -200,6 +286,30 @@ impl MigrationManager {
         Ok(tables)
     }
 
+    /// Copy the chunks of a value one by one, so large values are never held
+    /// in memory as a whole
+    async fn copy_chunks(
+        &self,
+        old_pool: &SqlitePool,
+        tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+        key: &[u8],
+        manifest: &ChunkManifest,
+    ) -> Result<()> {
+        let mut old_conn = old_pool
+            .acquire()
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to connect to old shard"))?;
+        for idx in 0..manifest.chunk_count() {
+            let chunk = chunks::read_chunks(&mut old_conn, key, idx..idx + 1)
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to read chunk"))?;
+            chunks::write_chunks(tx, key, manifest, &[(idx, chunk[0].clone())])
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to write chunk"))?;
+        }
+        Ok(())
+    }
+
     async fn migrate_table(
         &self,
         old_pool: &SqlitePool,
@@ -207,7 +317,7 @@ impl MigrationManager {
         current_old_shard: usize,
     ) -> Result<()> {
         // First, read only keys to determine which ones need to be migrated
-        let keys_query = format!("SELECT key FROM {}", table_name);
+        let keys_query = format!("SELECT key FROM {}", quote_identifier(table_name));
         let key_rows = sqlx::query(&keys_query)
             .fetch_all(old_pool)
             .await
@@ -223,18 +333,18 @@ impl MigrationManager {
         );
 
         // Identify keys that need to be migrated (those moving to different shards)
-        let mut keys_to_migrate: HashMap<usize, Vec<String>> = HashMap::new();
-        let mut keys_to_delete: Vec<String> = Vec::new();
+        let mut keys_to_migrate: HashMap<usize, Vec<Vec<u8>>> = HashMap::new();
+        let mut keys_to_delete: Vec<Vec<u8>> = Vec::new();
 
         for row in key_rows {
-            let key: String = row.get("key");
+            let key: Vec<u8> = row.get("key");
             let new_shard_id = self.get_new_shard(&key);
 
             if new_shard_id != current_old_shard {
                 // Key needs to be migrated to a different shard
                 keys_to_migrate
                     .entry(new_shard_id)
-                    .or_insert_with(Vec::new)
+                    .or_default()
                     .push(key.clone());
                 keys_to_delete.push(key);
             }
@@ -280,14 +390,14 @@ impl MigrationManager {
             if table_name != "blobs" {
                 let create_query = format!(
                     "CREATE TABLE IF NOT EXISTS {} (
-                        key TEXT PRIMARY KEY,
+                        key BLOB PRIMARY KEY,
                         data BLOB,
                         created_at INTEGER NOT NULL,
                         updated_at INTEGER NOT NULL,
                         expires_at INTEGER,
                         version INTEGER NOT NULL DEFAULT 0
                     )",
-                    table_name
+                    quote_identifier(table_name)
                 );
 
                 sqlx::query(&create_query)
@@ -304,10 +414,10 @@ impl MigrationManager {
                     })?;
 
                 // Create index on expires_at for namespaced tables
-                let namespace = table_name.strip_prefix("blobs_").unwrap_or("");
                 let index_query = format!(
-                    "CREATE INDEX IF NOT EXISTS idx_{}_expires_at ON {}(expires_at) WHERE expires_at IS NOT NULL",
-                    namespace, table_name
+                    "CREATE INDEX IF NOT EXISTS {} ON {}(expires_at) WHERE expires_at IS NOT NULL",
+                    quote_identifier(&namespace::expires_at_index_name(table_name)),
+                    quote_identifier(table_name)
                 );
 
                 sqlx::query(&index_query)
@@ -330,7 +440,8 @@ impl MigrationManager {
                 let placeholders = batch.iter().map(|_| "?").collect::<Vec<_>>().join(",");
                 let batch_query = format!(
                     "SELECT key, data, created_at, updated_at, expires_at, version FROM {} WHERE key IN ({})",
-                    table_name, placeholders
+                    quote_identifier(table_name),
+                    placeholders
                 );
 
                 let mut query = sqlx::query(&batch_query);
@@ -357,11 +468,11 @@ impl MigrationManager {
 
                 let insert_query = format!(
                     "INSERT OR REPLACE INTO {} (key, data, created_at, updated_at, expires_at, version) VALUES (?, ?, ?, ?, ?, ?)",
-                    table_name
+                    quote_identifier(table_name)
                 );
 
                 for record in records {
-                    let key: String = record.get("key");
+                    let key: Vec<u8> = record.get("key");
                     let data: Vec<u8> = record.get("data");
                     let created_at: i64 = record.get("created_at");
                     let updated_at: i64 = record.get("updated_at");
@@ -382,10 +493,25 @@ impl MigrationManager {
                                 e,
                                 &format!(
                                     "Failed to insert record {} into new shard {}",
-                                    key, new_shard_id
+                                    String::from_utf8_lossy(&key),
+                                    new_shard_id
                                 ),
                             )
                         })?;
+
+                    // Chunks move with their manifest, and are deleted from
+                    // the old shard together with it by the chunk triggers
+                    if table_name == "blobs"
+                        && let Some(manifest) = ChunkManifest::parse(&data)
+                    {
+                        self.copy_chunks(old_pool, &mut tx, &key, &manifest)
+                            .await
+                            .wrap_err(format!(
+                                "Failed to copy the chunks of {} to new shard {}",
+                                String::from_utf8_lossy(&key),
+                                new_shard_id
+                            ))?;
+                    }
                 }
 
                 tx.commit().await.map_err(|e| {
@@ -428,7 +554,8 @@ impl MigrationManager {
                     )
                 })?;
 
-                let delete_query = format!("DELETE FROM {} WHERE key = ?", table_name);
+                let delete_query =
+                    format!("DELETE FROM {} WHERE key = ?", quote_identifier(table_name));
 
                 for key in batch {
                     sqlx::query(&delete_query)
@@ -440,7 +567,8 @@ impl MigrationManager {
                                 e,
                                 &format!(
                                     "Failed to delete key {} from shard {}",
-                                    key, current_old_shard
+                                    String::from_utf8_lossy(key),
+                                    current_old_shard
                                 ),
                             )
                         })?;
@@ -492,7 +620,10 @@ impl MigrationManager {
             ))?;
 
             for table_name in tables {
-                let query = format!("SELECT COUNT(*) as count FROM {}", table_name);
+                let query = format!(
+                    "SELECT COUNT(*) as count FROM {}",
+                    quote_identifier(&table_name)
+                );
                 let row = sqlx::query(&query)
                     .fetch_one(&new_pool)
                     .await
@@ -528,7 +659,7 @@ impl MigrationManager {
             ))?;
 
             for table_name in tables {
-                let query = format!("SELECT key FROM {}", table_name);
+                let query = format!("SELECT key FROM {}", quote_identifier(&table_name));
                 let rows = sqlx::query(&query)
                     .fetch_all(&new_pool)
                     .await
@@ -543,13 +674,13 @@ impl MigrationManager {
                     })?;
 
                 for row in rows {
-                    let key: String = row.get("key");
+                    let key: Vec<u8> = row.get("key");
                     let expected_shard = self.get_new_shard(&key);
 
                     if expected_shard != new_shard_id {
                         return Err(miette::miette!(
                             "Migration verification failed: Key '{}' found in shard {} but should be in shard {}",
-                            key,
+                            String::from_utf8_lossy(&key),
                             new_shard_id,
                             expected_shard
                         ));
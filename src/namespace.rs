@@ -0,0 +1,201 @@
+//! Mapping between client supplied namespaces and SQLite table names.
+//!
+//! Every namespace is stored in its own `blobs_{namespace}` table. Namespaces
+//! come straight from clients, so they are never interpolated into SQL as is:
+//! the namespace is encoded into a table name and that name is always quoted.
+//!
+//! The encoding keeps lowercase ASCII letters, digits and `_`, which leaves the
+//! common names readable and unchanged, and writes every other byte as `$XX`
+//! with two uppercase hex digits. It is reversible, and because SQLite compares
+//! table names case-insensitively, uppercase letters are escaped as well so
+//! that `Users` and `users` cannot end up in the same table.
+
+use std::fmt;
+
+/// Prefix of every namespaced table
+const TABLE_PREFIX: &str = "blobs_";
+
+/// Longest namespace accepted from clients, in bytes
+pub const MAX_NAMESPACE_LEN: usize = 256;
+
+/// A namespace rejected by `validate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidNamespace;
+
+impl fmt::Display for InvalidNamespace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid namespace")
+    }
+}
+
+impl std::error::Error for InvalidNamespace {}
+
+/// Check that a client supplied namespace can be used. Any byte is allowed,
+/// but the namespace must not be empty or longer than `MAX_NAMESPACE_LEN`.
+pub fn validate(namespace: &str) -> Result<(), InvalidNamespace> {
+    if namespace.is_empty() || namespace.len() > MAX_NAMESPACE_LEN {
+        return Err(InvalidNamespace);
+    }
+    Ok(())
+}
+
+/// Name of the table holding a namespace. The result is safe to quote with
+/// `quote_identifier` but should not be used in SQL unquoted.
+pub fn table_name(namespace: &str) -> String {
+    let mut table_name = String::with_capacity(TABLE_PREFIX.len() + namespace.len());
+    table_name.push_str(TABLE_PREFIX);
+    for &byte in namespace.as_bytes() {
+        if is_plain(byte) {
+            table_name.push(byte as char);
+        } else {
+            table_name.push_str(&format!("${:02X}", byte));
+        }
+    }
+    table_name
+}
+
+/// Namespace stored in a table, the inverse of `table_name`. Returns `None`
+/// for `blobs` and for tables that were not created by `table_name`.
+pub fn namespace_of(table_name: &str) -> Option<String> {
+    let encoded = table_name.strip_prefix(TABLE_PREFIX)?.as_bytes();
+    let mut namespace = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'$' {
+            let hex = std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok()?;
+            if hex.bytes().any(|b| b.is_ascii_lowercase()) {
+                return None;
+            }
+            namespace.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else if is_plain(encoded[i]) {
+            namespace.push(encoded[i]);
+            i += 1;
+        } else {
+            return None;
+        }
+    }
+    String::from_utf8(namespace).ok()
+}
+
+/// Namespace of a table created before namespaces were encoded, when its name
+/// differs from the encoded one. Such tables hold namespaces with uppercase
+/// letters and are renamed when a shard starts.
+pub fn legacy_namespace_of(table_name: &str) -> Option<&str> {
+    let namespace = table_name.strip_prefix(TABLE_PREFIX)?;
+    let legacy = !namespace.is_empty()
+        && namespace
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_')
+        && namespace.bytes().any(|b| b.is_ascii_uppercase());
+    legacy.then_some(namespace)
+}
+
+/// Name of the partial index on `expires_at` of a table
+pub fn expires_at_index_name(table_name: &str) -> String {
+    match table_name.strip_prefix(TABLE_PREFIX) {
+        Some(suffix) => format!("idx_{}_expires_at", suffix),
+        None => "idx_expires_at".to_string(),
+    }
+}
+
+/// Quote a table or index name for use in SQL
+pub fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+fn is_plain(byte: u8) -> bool {
+    byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plain_namespaces_are_unchanged() {
+        assert_eq!(table_name("users"), "blobs_users");
+        assert_eq!(table_name("user_123"), "blobs_user_123");
+        assert_eq!(expires_at_index_name("blobs_users"), "idx_users_expires_at");
+        assert_eq!(expires_at_index_name("blobs"), "idx_expires_at");
+        assert_eq!(namespace_of("blobs_users").as_deref(), Some("users"));
+        assert_eq!(namespace_of("blobs"), None);
+    }
+
+    #[test]
+    fn test_hostile_namespaces_round_trip() {
+        let hostile = [
+            "users:123",
+            "a b",
+            "x\"; DROP TABLE blobs; --",
+            "x'); DELETE FROM blobs; --",
+            "`[]`",
+            "$41",
+            "\0",
+            "Users",
+            "ünïcødé",
+            "blobs",
+        ];
+        for namespace in hostile {
+            let table_name = table_name(namespace);
+            assert!(
+                table_name
+                    .bytes()
+                    .all(|b| is_plain(b) || b == b'$' || b.is_ascii_uppercase()),
+                "{:?} encoded to {:?}",
+                namespace,
+                table_name
+            );
+            assert_eq!(namespace_of(&table_name).as_deref(), Some(namespace));
+        }
+        assert_eq!(table_name("users:123"), "blobs_users$3A123");
+        assert_eq!(table_name("Users"), "blobs_$55sers");
+    }
+
+    #[test]
+    fn test_encoding_is_case_insensitively_unique() {
+        // SQLite would treat these as the same table if they were not escaped
+        let names = ["users", "Users", "USERS", "$55sers", "$2455sers"];
+        let mut tables: Vec<String> = names
+            .iter()
+            .map(|namespace| table_name(namespace).to_lowercase())
+            .collect();
+        tables.sort();
+        tables.dedup();
+        assert_eq!(tables.len(), names.len());
+    }
+
+    #[test]
+    fn test_namespace_of_rejects_foreign_tables() {
+        assert_eq!(namespace_of("blobs_a$4"), None);
+        assert_eq!(namespace_of("blobs_a$4g"), None);
+        assert_eq!(namespace_of("blobs_a$3a"), None);
+        assert_eq!(namespace_of("blobs_Users"), None);
+        assert_eq!(namespace_of("other"), None);
+    }
+
+    #[test]
+    fn test_legacy_tables() {
+        assert_eq!(legacy_namespace_of("blobs_Users"), Some("Users"));
+        assert_eq!(legacy_namespace_of("blobs_users"), None);
+        assert_eq!(legacy_namespace_of("blobs_$55sers"), None);
+        assert_eq!(legacy_namespace_of("blobs"), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(""), Err(InvalidNamespace));
+        assert_eq!(
+            validate(&"n".repeat(MAX_NAMESPACE_LEN + 1)),
+            Err(InvalidNamespace)
+        );
+        assert!(validate(&"n".repeat(MAX_NAMESPACE_LEN)).is_ok());
+        assert!(validate("x\"; DROP TABLE blobs; --").is_ok());
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("blobs_users"), "\"blobs_users\"");
+        assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+    }
+}
@@ -0,0 +1,460 @@
+//! Offline recompression of existing shards.
+//!
+//! Values carry the codec that wrote them, so changing `storage_compression`
+//! only affects new writes. `RecompressManager` rewrites the values already
+//! stored in every `blobs*` table of every shard with a target algorithm and
+//! level, in batches ordered by key.
+//!
+//! Progress is recorded in a `recompress_progress` table of each shard, in the
+//! same transaction as the batch it describes, so an interrupted run resumes
+//! where it stopped when started again with the same target. The table is
+//! dropped once every shard has been recompressed.
+//!
+//! Values stored as chunks are recompressed one chunk at a time. A chunk is
+//! only replaced while the value still has the manifest it was read under and
+//! the chunk is unchanged, so a concurrent write wins there too.
+
+use crate::chunks::{CHUNK_TABLE, ChunkManifest};
+use crate::compression::{self, DictionaryStore, ValueCodec};
+use crate::config::{CompressionConfig, CompressionType};
+use crate::migration::upgrade_text_keys;
+use crate::namespace::quote_identifier;
+use miette::{Context, Result};
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Rows rewritten per transaction when no batch size is given
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+// Helper function to convert sqlx errors to miette errors
+fn sqlx_to_miette(err: sqlx::Error, context: &str) -> miette::Error {
+    miette::miette!("{}: {}", context, err)
+}
+
+/// Outcome of recompressing one shard, including the batches of earlier
+/// interrupted runs with the same target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShardReport {
+    pub shard_id: usize,
+    /// Rows read, rewritten or not
+    pub rows: i64,
+    /// Stored size of those rows before and after
+    pub bytes_before: i64,
+    pub bytes_after: i64,
+    /// Rows that could not be decoded, or with a chunk that could not be,
+    /// left untouched
+    pub skipped: i64,
+}
+
+impl ShardReport {
+    /// Bytes saved on the shard, negative if the values grew
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before - self.bytes_after
+    }
+}
+
+/// Stored size of the chunks of one value before and after recompression
+struct ChunksOutcome {
+    bytes_before: i64,
+    bytes_after: i64,
+    /// Whether a chunk could not be decoded and was left untouched
+    skipped: bool,
+}
+
+pub struct RecompressManager {
+    shard_count: usize,
+    data_dir: String,
+    /// Decodes the stored values, using the configured compression for
+    /// values written before they were tagged with their codec
+    reader: ValueCodec,
+    writer: ValueCodec,
+    /// Identifies the target in `recompress_progress`
+    target: String,
+    batch_size: usize,
+}
+
+impl RecompressManager {
+    pub fn new(
+        shard_count: usize,
+        data_dir: String,
+        current: Option<&CompressionConfig>,
+        target: CompressionConfig,
+        batch_size: usize,
+    ) -> Result<Self> {
+        if shard_count == 0 {
+            return Err(miette::miette!("Shard count must be greater than 0"));
+        }
+        if batch_size == 0 {
+            return Err(miette::miette!("Batch size must be greater than 0"));
+        }
+        compression::validate_config(&target)?;
+
+        let dictionaries = DictionaryStore::load(&data_dir)
+            .map_err(|e| miette::miette!("Failed to load zstd dictionaries: {}", e))?;
+        let writer = ValueCodec::with_dictionaries(Some(&target), &dictionaries);
+
+        let mut description = match target.algorithm {
+            CompressionType::None => "none".to_string(),
+            algorithm => format!(
+                "{}:{}",
+                format!("{:?}", algorithm).to_lowercase(),
+                target.level.unwrap_or_default()
+            ),
+        };
+        if let Some(id) = writer.dictionary() {
+            description.push_str(&format!(":dict{}", id));
+        }
+
+        Ok(RecompressManager {
+            shard_count,
+            data_dir,
+            reader: ValueCodec::with_dictionaries(current, &dictionaries),
+            writer,
+            target: description,
+            batch_size,
+        })
+    }
+
+    async fn create_connection_pool(&self, shard_id: usize) -> Result<SqlitePool> {
+        let db_path = format!("{}/shard_{}.db", self.data_dir, shard_id);
+        if !Path::new(&db_path).exists() {
+            return Err(miette::miette!("Shard file {} does not exist", db_path));
+        }
+
+        let connect_options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path))
+            .map_err(|e| sqlx_to_miette(e, "Failed to parse connection string"))?
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(std::time::Duration::from_millis(5000))
+            .pragma("synchronous", "NORMAL");
+
+        SqlitePool::connect_with(connect_options)
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to connect to database"))
+    }
+
+    /// Recompress every shard and return what was saved on each of them
+    pub async fn run(&self) -> Result<Vec<ShardReport>> {
+        tracing::info!(
+            "Recompressing {} shards in {} to {}",
+            self.shard_count,
+            self.data_dir,
+            self.target
+        );
+
+        let mut reports = Vec::with_capacity(self.shard_count);
+        for shard_id in 0..self.shard_count {
+            let pool = self.create_connection_pool(shard_id).await?;
+            let report = self
+                .recompress_shard(&pool, shard_id)
+                .await
+                .wrap_err(format!("Failed to recompress shard {}", shard_id))?;
+            pool.close().await;
+
+            tracing::info!(
+                "Shard {}: {} rows, {} -> {} bytes, {} bytes saved, {} rows skipped",
+                shard_id,
+                report.rows,
+                report.bytes_before,
+                report.bytes_after,
+                report.bytes_saved(),
+                report.skipped
+            );
+            reports.push(report);
+        }
+
+        // Every shard is done, so the next run starts from scratch
+        for shard_id in 0..self.shard_count {
+            let pool = self.create_connection_pool(shard_id).await?;
+            sqlx::query("DROP TABLE IF EXISTS recompress_progress")
+                .execute(&pool)
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to drop recompress_progress"))?;
+            pool.close().await;
+        }
+
+        let saved: i64 = reports.iter().map(ShardReport::bytes_saved).sum();
+        tracing::info!("Recompression completed, {} bytes saved in total", saved);
+
+        Ok(reports)
+    }
+
+    async fn recompress_shard(&self, pool: &SqlitePool, shard_id: usize) -> Result<ShardReport> {
+        // Batches are paged by key, which must compare as bytes
+        upgrade_text_keys(pool)
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to upgrade keys"))?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS recompress_progress (
+                table_name TEXT PRIMARY KEY,
+                target TEXT NOT NULL,
+                last_key BLOB,
+                done INTEGER NOT NULL DEFAULT 0,
+                row_count INTEGER NOT NULL DEFAULT 0,
+                bytes_before INTEGER NOT NULL DEFAULT 0,
+                bytes_after INTEGER NOT NULL DEFAULT 0,
+                skipped INTEGER NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(pool)
+        .await
+        .map_err(|e| sqlx_to_miette(e, "Failed to create recompress_progress"))?;
+
+        // Progress towards another target is meaningless for this run
+        sqlx::query("DELETE FROM recompress_progress WHERE target != ?")
+            .bind(&self.target)
+            .execute(pool)
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to reset recompress_progress"))?;
+
+        let tables = sqlx::query_as::<_, (String,)>(
+            "SELECT name FROM sqlite_master WHERE type='table' AND (name = 'blobs' OR name LIKE 'blobs_%') ORDER BY name",
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| sqlx_to_miette(e, "Failed to query table names"))?;
+
+        for (table_name,) in tables {
+            self.recompress_table(pool, shard_id, &table_name)
+                .await
+                .wrap_err(format!("Failed to recompress table {}", table_name))?;
+        }
+
+        let (rows, bytes_before, bytes_after, skipped) = sqlx::query_as::<_, (i64, i64, i64, i64)>(
+            "SELECT COALESCE(SUM(row_count), 0), COALESCE(SUM(bytes_before), 0),
+                        COALESCE(SUM(bytes_after), 0), COALESCE(SUM(skipped), 0)
+                 FROM recompress_progress",
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(|e| sqlx_to_miette(e, "Failed to read recompress_progress"))?;
+
+        Ok(ShardReport {
+            shard_id,
+            rows,
+            bytes_before,
+            bytes_after,
+            skipped,
+        })
+    }
+
+    async fn recompress_table(
+        &self,
+        pool: &SqlitePool,
+        shard_id: usize,
+        table_name: &str,
+    ) -> Result<()> {
+        let progress = sqlx::query_as::<_, (Option<Vec<u8>>, bool)>(
+            "SELECT last_key, done FROM recompress_progress WHERE table_name = ?",
+        )
+        .bind(table_name)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| sqlx_to_miette(e, "Failed to read recompress_progress"))?;
+
+        let mut last_key = match progress {
+            Some((_, true)) => {
+                tracing::info!(
+                    "Table {} of shard {} is already recompressed",
+                    table_name,
+                    shard_id
+                );
+                return Ok(());
+            }
+            Some((last_key, false)) => {
+                if last_key.is_some() {
+                    tracing::info!(
+                        "Resuming table {} of shard {} after an interrupted run",
+                        table_name,
+                        shard_id
+                    );
+                }
+                last_key
+            }
+            None => None,
+        };
+
+        let table = quote_identifier(table_name);
+        let select_query = format!(
+            "SELECT key, data FROM {} WHERE (? IS NULL OR key > ?) AND data IS NOT NULL ORDER BY key LIMIT ?",
+            table
+        );
+        // Only replace the value that was read, a concurrent write wins
+        let update_query = format!("UPDATE {} SET data = ? WHERE key = ? AND data = ?", table);
+
+        loop {
+            let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(&select_query)
+                .bind(&last_key)
+                .bind(&last_key)
+                .bind(self.batch_size as i64)
+                .fetch_all(pool)
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to read rows"))?;
+            let done = rows.len() < self.batch_size;
+
+            let mut rewrites = Vec::with_capacity(rows.len());
+            let (mut bytes_before, mut bytes_after, mut skipped) = (0, 0, 0);
+            for (key, data) in &rows {
+                bytes_before += data.len() as i64;
+                if let Some(manifest) = ChunkManifest::parse(data) {
+                    let outcome = self
+                        .recompress_chunks(pool, shard_id, &table, key, data, &manifest)
+                        .await?;
+                    bytes_before += outcome.bytes_before;
+                    bytes_after += data.len() as i64 + outcome.bytes_after;
+                    skipped += outcome.skipped as i64;
+                    continue;
+                }
+                let decoded = match self.reader.decode(data).await {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        tracing::warn!(
+                            "Skipping key {:?} of table {} in shard {}: {}",
+                            String::from_utf8_lossy(key),
+                            table_name,
+                            shard_id,
+                            e
+                        );
+                        bytes_after += data.len() as i64;
+                        skipped += 1;
+                        continue;
+                    }
+                };
+                let encoded = self
+                    .writer
+                    .encode(&decoded)
+                    .await
+                    .map_err(|e| miette::miette!("Failed to compress a value: {}", e))?;
+                bytes_after += encoded.len() as i64;
+                if encoded != *data {
+                    rewrites.push((key, data, encoded));
+                }
+            }
+            if let Some((key, _)) = rows.last() {
+                last_key = Some(key.clone());
+            }
+
+            let mut tx = pool
+                .begin()
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to begin transaction"))?;
+            for (key, old, new) in &rewrites {
+                sqlx::query(&update_query)
+                    .bind(new)
+                    .bind(*key)
+                    .bind(*old)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(|e| sqlx_to_miette(e, "Failed to rewrite row"))?;
+            }
+            sqlx::query(
+                "INSERT INTO recompress_progress
+                    (table_name, target, last_key, done, row_count, bytes_before, bytes_after, skipped)
+                 VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                 ON CONFLICT(table_name) DO UPDATE SET
+                    last_key = excluded.last_key,
+                    done = excluded.done,
+                    row_count = row_count + excluded.row_count,
+                    bytes_before = bytes_before + excluded.bytes_before,
+                    bytes_after = bytes_after + excluded.bytes_after,
+                    skipped = skipped + excluded.skipped",
+            )
+            .bind(table_name)
+            .bind(&self.target)
+            .bind(&last_key)
+            .bind(done)
+            .bind(rows.len() as i64)
+            .bind(bytes_before)
+            .bind(bytes_after)
+            .bind(skipped)
+            .execute(&mut *tx)
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to record progress"))?;
+            tx.commit()
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to commit batch"))?;
+
+            if done {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Recompress the chunks of the value of `key` in `table`, whose row
+    /// holds `manifest_data`. Chunks are read and rewritten one at a time, each
+    /// rewrite in its own statement, so memory stays bounded by the chunk size.
+    async fn recompress_chunks(
+        &self,
+        pool: &SqlitePool,
+        shard_id: usize,
+        table: &str,
+        key: &[u8],
+        manifest_data: &[u8],
+        manifest: &ChunkManifest,
+    ) -> Result<ChunksOutcome> {
+        let select_query = format!("SELECT data FROM {} WHERE key = ? AND idx = ?", CHUNK_TABLE);
+        // Only replace the chunk that was read, and only while the value has
+        // not been written again as a whole
+        let update_query = format!(
+            "UPDATE {} SET data = ? WHERE key = ? AND idx = ? AND data = ?
+             AND EXISTS (SELECT 1 FROM {} WHERE key = ? AND data = ?)",
+            CHUNK_TABLE, table
+        );
+
+        let mut outcome = ChunksOutcome {
+            bytes_before: 0,
+            bytes_after: 0,
+            skipped: false,
+        };
+        for idx in 0..manifest.chunk_count() {
+            let chunk = sqlx::query_as::<_, (Vec<u8>,)>(&select_query)
+                .bind(key)
+                .bind(idx as i64)
+                .fetch_optional(pool)
+                .await
+                .map_err(|e| sqlx_to_miette(e, "Failed to read chunk"))?;
+            // The value was replaced since its manifest was read
+            let Some((data,)) = chunk else {
+                break;
+            };
+            outcome.bytes_before += data.len() as i64;
+
+            let decoded = match self.reader.decode(&data).await {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    tracing::warn!(
+                        "Skipping chunk {} of key {:?} in shard {}: {}",
+                        idx,
+                        String::from_utf8_lossy(key),
+                        shard_id,
+                        e
+                    );
+                    outcome.bytes_after += data.len() as i64;
+                    outcome.skipped = true;
+                    continue;
+                }
+            };
+            let encoded = self
+                .writer
+                .encode(&decoded)
+                .await
+                .map_err(|e| miette::miette!("Failed to compress a chunk: {}", e))?;
+            outcome.bytes_after += encoded.len() as i64;
+            if encoded != data {
+                sqlx::query(&update_query)
+                    .bind(&encoded)
+                    .bind(key)
+                    .bind(idx as i64)
+                    .bind(&data)
+                    .bind(key)
+                    .bind(manifest_data)
+                    .execute(pool)
+                    .await
+                    .map_err(|e| sqlx_to_miette(e, "Failed to rewrite chunk"))?;
+            }
+        }
+        Ok(outcome)
+    }
+}
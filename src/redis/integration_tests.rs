@@ -1,4 +1,5 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod integration_tests {
     use crate::redis::protocol::*;
     use crate::redis::serialize_frame;
@@ -58,7 +59,7 @@ mod integration_tests {
         assert_eq!(
             parsed_command,
             RedisCommand::Get {
-                key: "mykey".to_string()
+                key: Bytes::from("mykey")
             }
         );
     }
@@ -80,8 +81,9 @@ mod integration_tests {
         assert_eq!(
             parsed_command,
             RedisCommand::Set {
-                key: "mykey".to_string(),
-                value: Bytes::from_static(b"hello world")
+                key: Bytes::from("mykey"),
+                value: Bytes::from_static(b"hello world"),
+                options: SetOptions::default(),
             }
         );
     }
@@ -103,7 +105,7 @@ mod integration_tests {
         assert_eq!(
             parsed_command,
             RedisCommand::Del {
-                key: "mykey".to_string()
+                keys: vec![Bytes::from("mykey")]
             }
         );
     }
@@ -125,7 +127,7 @@ mod integration_tests {
         assert_eq!(
             parsed_command,
             RedisCommand::Exists {
-                key: "mykey".to_string()
+                keys: vec![Bytes::from("mykey")]
             }
         );
     }
@@ -220,11 +222,11 @@ mod integration_tests {
 
             match parsed_command {
                 RedisCommand::Get { key } => assert_eq!(key, "key1"),
-                RedisCommand::Set { key, value } => {
+                RedisCommand::Set { key, value, .. } => {
                     assert_eq!(key, "key2");
                     assert_eq!(value, Bytes::from_static(b"value"));
                 }
-                RedisCommand::Del { key } => assert_eq!(key, "key3"),
+                RedisCommand::Del { keys } => assert_eq!(keys, vec!["key3".to_string()]),
                 _ => panic!("Unexpected command"),
             }
         }
@@ -241,7 +243,7 @@ mod integration_tests {
         let parsed_command = parse_command(parsed_resp).unwrap();
 
         match parsed_command {
-            RedisCommand::Set { key, value } => {
+            RedisCommand::Set { key, value, .. } => {
                 assert_eq!(key, "binary");
                 assert_eq!(value, Bytes::copy_from_slice(binary_data));
             }
@@ -267,7 +269,7 @@ mod integration_tests {
         let parsed_command = parse_command(parsed_resp).unwrap();
 
         match parsed_command {
-            RedisCommand::Set { key, value } => {
+            RedisCommand::Set { key, value, .. } => {
                 assert_eq!(key, large_key);
                 assert_eq!(value, Bytes::from(large_value.into_bytes()));
             }
@@ -283,7 +285,7 @@ mod integration_tests {
         let parsed_command = parse_command(parsed_resp).unwrap();
 
         match parsed_command {
-            RedisCommand::Set { key, value } => {
+            RedisCommand::Set { key, value, .. } => {
                 assert_eq!(key, "empty");
                 assert_eq!(value, Bytes::new());
             }
@@ -2,5 +2,8 @@ mod integration_tests;
 pub mod protocol;
 
 pub use protocol::{
-    ParseError, RedisCommand, parse_command, parse_resp_with_remaining, serialize_frame,
+    CommandCategory, ExpireCondition, LimitExceeded, ParseError, ProtocolLimits, ProtocolVersion,
+    RedisCommand, ScanOptions, SetCondition, SetExpiry, SetOptions, check_limits,
+    check_request_len, parse_command, parse_resp_from_buffer, serialize_frame, serialize_map,
+    serialize_reply,
 };
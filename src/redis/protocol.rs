@@ -1,3 +1,4 @@
+use crate::namespace;
 use bytes::Bytes;
 use redis_protocol::resp2::{decode::decode_bytes_mut, encode::extend_encode, types::BytesFrame};
 
@@ -8,34 +9,149 @@ pub type RespValue = BytesFrame;
 #[derive(Debug, Clone, PartialEq)]
 pub enum RedisCommand {
     Get {
-        key: String,
+        key: Bytes,
     },
     Set {
-        key: String,
+        key: Bytes,
         value: Bytes,
+        options: SetOptions,
+    },
+    GetRange {
+        key: Bytes,
+        start: i64,
+        end: i64,
+    },
+    SetRange {
+        key: Bytes,
+        offset: i64,
+        value: Bytes,
+    },
+    Append {
+        key: Bytes,
+        value: Bytes,
+    },
+    StrLen {
+        key: Bytes,
     },
     Del {
-        key: String,
+        keys: Vec<Bytes>,
     },
     Exists {
-        key: String,
+        keys: Vec<Bytes>,
+    },
+    MGet {
+        keys: Vec<Bytes>,
+    },
+    MSet {
+        entries: Vec<(Bytes, Bytes)>,
+    },
+    MSetNx {
+        entries: Vec<(Bytes, Bytes)>,
+    },
+    Expire {
+        key: Bytes,
+        seconds: i64,
+        condition: Option<ExpireCondition>,
+    },
+    PExpire {
+        key: Bytes,
+        milliseconds: i64,
+        condition: Option<ExpireCondition>,
+    },
+    ExpireAt {
+        key: Bytes,
+        timestamp: i64,
+        condition: Option<ExpireCondition>,
+    },
+    PExpireAt {
+        key: Bytes,
+        timestamp_ms: i64,
+        condition: Option<ExpireCondition>,
+    },
+    Ttl {
+        key: Bytes,
+    },
+    PTtl {
+        key: Bytes,
+    },
+    Persist {
+        key: Bytes,
     },
     HGet {
         namespace: String,
-        key: String,
+        key: Bytes,
     },
     HSet {
         namespace: String,
-        key: String,
+        key: Bytes,
         value: Bytes,
     },
     HDel {
         namespace: String,
-        key: String,
+        key: Bytes,
     },
     HExists {
         namespace: String,
-        key: String,
+        key: Bytes,
+    },
+    HMSet {
+        namespace: String,
+        entries: Vec<(Bytes, Bytes)>,
+    },
+    HMGet {
+        namespace: String,
+        keys: Vec<Bytes>,
+    },
+    HLen {
+        namespace: String,
+    },
+    HKeys {
+        namespace: String,
+    },
+    HGetAll {
+        namespace: String,
+    },
+    HSetEx {
+        namespace: String,
+        fields: Vec<(Bytes, Bytes)>,
+        expiry: Option<SetExpiry>,
+        condition: Option<SetCondition>,
+    },
+    HExpire {
+        namespace: String,
+        seconds: i64,
+        condition: Option<ExpireCondition>,
+        fields: Vec<Bytes>,
+    },
+    HPExpire {
+        namespace: String,
+        milliseconds: i64,
+        condition: Option<ExpireCondition>,
+        fields: Vec<Bytes>,
+    },
+    HTtl {
+        namespace: String,
+        fields: Vec<Bytes>,
+    },
+    HPTtl {
+        namespace: String,
+        fields: Vec<Bytes>,
+    },
+    HPersist {
+        namespace: String,
+        fields: Vec<Bytes>,
+    },
+    Scan {
+        cursor: String,
+        options: ScanOptions,
+    },
+    Keys {
+        pattern: Bytes,
+    },
+    HScan {
+        namespace: String,
+        cursor: String,
+        options: ScanOptions,
     },
     Ping {
         message: Option<String>,
@@ -43,7 +159,22 @@ pub enum RedisCommand {
     Info {
         section: Option<String>,
     },
+    Hello {
+        protover: Option<i64>,
+        auth: Option<(String, String)>,
+        setname: Option<String>,
+    },
+    Auth {
+        username: Option<String>,
+        password: Bytes,
+    },
     Command,
+    AclWhoami,
+    AclList,
+    AclSetUser {
+        username: String,
+        rules: Vec<String>,
+    },
     // Cluster commands
     ClusterNodes,
     ClusterInfo,
@@ -55,7 +186,7 @@ pub enum RedisCommand {
         slots: Vec<u16>,
     },
     ClusterKeySlot {
-        key: String,
+        key: Bytes,
     },
     Quit,
     Unknown(String),
@@ -67,15 +198,48 @@ impl RedisCommand {
         match self {
             RedisCommand::Get { .. } => "GET".to_string(),
             RedisCommand::Set { .. } => "SET".to_string(),
+            RedisCommand::GetRange { .. } => "GETRANGE".to_string(),
+            RedisCommand::SetRange { .. } => "SETRANGE".to_string(),
+            RedisCommand::Append { .. } => "APPEND".to_string(),
+            RedisCommand::StrLen { .. } => "STRLEN".to_string(),
             RedisCommand::Del { .. } => "DEL".to_string(),
             RedisCommand::Exists { .. } => "EXISTS".to_string(),
+            RedisCommand::MGet { .. } => "MGET".to_string(),
+            RedisCommand::MSet { .. } => "MSET".to_string(),
+            RedisCommand::MSetNx { .. } => "MSETNX".to_string(),
+            RedisCommand::Expire { .. } => "EXPIRE".to_string(),
+            RedisCommand::PExpire { .. } => "PEXPIRE".to_string(),
+            RedisCommand::ExpireAt { .. } => "EXPIREAT".to_string(),
+            RedisCommand::PExpireAt { .. } => "PEXPIREAT".to_string(),
+            RedisCommand::Ttl { .. } => "TTL".to_string(),
+            RedisCommand::PTtl { .. } => "PTTL".to_string(),
+            RedisCommand::Persist { .. } => "PERSIST".to_string(),
             RedisCommand::HGet { .. } => "HGET".to_string(),
             RedisCommand::HSet { .. } => "HSET".to_string(),
             RedisCommand::HDel { .. } => "HDEL".to_string(),
             RedisCommand::HExists { .. } => "HEXISTS".to_string(),
+            RedisCommand::HMSet { .. } => "HMSET".to_string(),
+            RedisCommand::HMGet { .. } => "HMGET".to_string(),
+            RedisCommand::HLen { .. } => "HLEN".to_string(),
+            RedisCommand::HKeys { .. } => "HKEYS".to_string(),
+            RedisCommand::HGetAll { .. } => "HGETALL".to_string(),
+            RedisCommand::HSetEx { .. } => "HSETEX".to_string(),
+            RedisCommand::HExpire { .. } => "HEXPIRE".to_string(),
+            RedisCommand::HPExpire { .. } => "HPEXPIRE".to_string(),
+            RedisCommand::HTtl { .. } => "HTTL".to_string(),
+            RedisCommand::HPTtl { .. } => "HPTTL".to_string(),
+            RedisCommand::HPersist { .. } => "HPERSIST".to_string(),
             RedisCommand::Ping { .. } => "PING".to_string(),
+            RedisCommand::Scan { .. } => "SCAN".to_string(),
+            RedisCommand::Keys { .. } => "KEYS".to_string(),
+            RedisCommand::HScan { .. } => "HSCAN".to_string(),
             RedisCommand::Info { .. } => "INFO".to_string(),
+            RedisCommand::Hello { .. } => "HELLO".to_string(),
+            RedisCommand::Auth { .. } => "AUTH".to_string(),
             RedisCommand::Command => "COMMAND".to_string(),
+            RedisCommand::AclWhoami => "ACL WHOAMI".to_string(),
+            RedisCommand::AclList => "ACL LIST".to_string(),
+            RedisCommand::AclSetUser { .. } => "ACL SETUSER".to_string(),
             RedisCommand::ClusterNodes => "CLUSTER NODES".to_string(),
             RedisCommand::ClusterInfo => "CLUSTER INFO".to_string(),
             RedisCommand::ClusterSlots => "CLUSTER SLOTS".to_string(),
@@ -86,6 +250,232 @@ impl RedisCommand {
             RedisCommand::Unknown(cmd) => cmd.clone(),
         }
     }
+
+    /// ACL category of the command
+    pub fn category(&self) -> CommandCategory {
+        match self {
+            RedisCommand::Get { .. }
+            | RedisCommand::GetRange { .. }
+            | RedisCommand::StrLen { .. }
+            | RedisCommand::Exists { .. }
+            | RedisCommand::MGet { .. }
+            | RedisCommand::Ttl { .. }
+            | RedisCommand::PTtl { .. }
+            | RedisCommand::HGet { .. }
+            | RedisCommand::HExists { .. }
+            | RedisCommand::HMGet { .. }
+            | RedisCommand::HLen { .. }
+            | RedisCommand::HKeys { .. }
+            | RedisCommand::HGetAll { .. }
+            | RedisCommand::HTtl { .. }
+            | RedisCommand::HPTtl { .. }
+            | RedisCommand::Scan { .. }
+            | RedisCommand::Keys { .. }
+            | RedisCommand::HScan { .. } => CommandCategory::Read,
+            RedisCommand::Set { .. }
+            | RedisCommand::SetRange { .. }
+            | RedisCommand::Append { .. }
+            | RedisCommand::Del { .. }
+            | RedisCommand::MSet { .. }
+            | RedisCommand::MSetNx { .. }
+            | RedisCommand::Expire { .. }
+            | RedisCommand::PExpire { .. }
+            | RedisCommand::ExpireAt { .. }
+            | RedisCommand::PExpireAt { .. }
+            | RedisCommand::Persist { .. }
+            | RedisCommand::HSet { .. }
+            | RedisCommand::HDel { .. }
+            | RedisCommand::HMSet { .. }
+            | RedisCommand::HSetEx { .. }
+            | RedisCommand::HExpire { .. }
+            | RedisCommand::HPExpire { .. }
+            | RedisCommand::HPersist { .. } => CommandCategory::Write,
+            RedisCommand::Info { .. }
+            | RedisCommand::AclList
+            | RedisCommand::AclSetUser { .. }
+            | RedisCommand::ClusterAddSlots { .. }
+            | RedisCommand::ClusterDelSlots { .. } => CommandCategory::Admin,
+            // Unknown commands are let through so they get their usual error
+            RedisCommand::Ping { .. }
+            | RedisCommand::Hello { .. }
+            | RedisCommand::Auth { .. }
+            | RedisCommand::Command
+            | RedisCommand::AclWhoami
+            | RedisCommand::ClusterNodes
+            | RedisCommand::ClusterInfo
+            | RedisCommand::ClusterSlots
+            | RedisCommand::ClusterKeySlot { .. }
+            | RedisCommand::Quit
+            | RedisCommand::Unknown(_) => CommandCategory::Connection,
+        }
+    }
+
+    /// Namespace a namespaced (hash) command operates on
+    pub fn namespace(&self) -> Option<&str> {
+        match self {
+            RedisCommand::HGet { namespace, .. }
+            | RedisCommand::HSet { namespace, .. }
+            | RedisCommand::HDel { namespace, .. }
+            | RedisCommand::HExists { namespace, .. }
+            | RedisCommand::HMSet { namespace, .. }
+            | RedisCommand::HMGet { namespace, .. }
+            | RedisCommand::HLen { namespace }
+            | RedisCommand::HKeys { namespace }
+            | RedisCommand::HGetAll { namespace }
+            | RedisCommand::HSetEx { namespace, .. }
+            | RedisCommand::HExpire { namespace, .. }
+            | RedisCommand::HPExpire { namespace, .. }
+            | RedisCommand::HTtl { namespace, .. }
+            | RedisCommand::HPTtl { namespace, .. }
+            | RedisCommand::HPersist { namespace, .. }
+            | RedisCommand::HScan { namespace, .. } => Some(namespace),
+            _ => None,
+        }
+    }
+
+    /// Whether the command may run before the connection authenticated
+    pub fn allowed_before_auth(&self) -> bool {
+        matches!(
+            self,
+            RedisCommand::Auth { .. } | RedisCommand::Hello { .. } | RedisCommand::Quit
+        )
+    }
+
+    /// Whether the command only reads keys and touches no connection state,
+    /// so it may run concurrently with the reads next to it in a pipeline.
+    /// Replies of concurrent reads are held in memory until their turn, so
+    /// commands whose replies grow with the dataset, like HGETALL and KEYS,
+    /// are left out.
+    pub fn is_concurrent_read(&self) -> bool {
+        matches!(
+            self,
+            RedisCommand::Get { .. }
+                | RedisCommand::GetRange { .. }
+                | RedisCommand::StrLen { .. }
+                | RedisCommand::Exists { .. }
+                | RedisCommand::MGet { .. }
+                | RedisCommand::Ttl { .. }
+                | RedisCommand::PTtl { .. }
+                | RedisCommand::HGet { .. }
+                | RedisCommand::HExists { .. }
+                | RedisCommand::HMGet { .. }
+                | RedisCommand::HLen { .. }
+                | RedisCommand::HTtl { .. }
+        )
+    }
+}
+
+/// Group of commands ACL rules can allow or deny together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CommandCategory {
+    /// Commands that only read keys
+    Read,
+    /// Commands that write or delete keys
+    Write,
+    /// Server administration, such as `INFO` and `ACL SETUSER`
+    Admin,
+    /// Connection handling and cluster topology lookups, always allowed
+    Connection,
+}
+
+impl CommandCategory {
+    /// Name used in `+@category` rules
+    pub fn name(self) -> &'static str {
+        match self {
+            CommandCategory::Read => "read",
+            CommandCategory::Write => "write",
+            CommandCategory::Admin => "admin",
+            CommandCategory::Connection => "connection",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "read" => Some(CommandCategory::Read),
+            "write" => Some(CommandCategory::Write),
+            "admin" => Some(CommandCategory::Admin),
+            "connection" => Some(CommandCategory::Connection),
+            _ => None,
+        }
+    }
+}
+
+/// Expiration requested by `SET ... EX|PX|EXAT|PXAT|KEEPTTL`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetExpiry {
+    /// Relative expiry in seconds
+    Ex(i64),
+    /// Relative expiry in milliseconds
+    Px(i64),
+    /// Absolute Unix time in seconds
+    ExAt(i64),
+    /// Absolute Unix time in milliseconds
+    PxAt(i64),
+    /// Retain the TTL already associated with the key
+    KeepTtl,
+}
+
+impl SetExpiry {
+    /// Resolve the expiry to an absolute Unix timestamp in milliseconds.
+    /// Returns `None` for `KEEPTTL`, which leaves the stored TTL untouched.
+    pub fn to_unix_millis(self, now_ms: i64) -> Option<i64> {
+        match self {
+            SetExpiry::Ex(s) => Some(now_ms.saturating_add(s.saturating_mul(1000))),
+            SetExpiry::Px(ms) => Some(now_ms.saturating_add(ms)),
+            SetExpiry::ExAt(s) => Some(s.saturating_mul(1000)),
+            SetExpiry::PxAt(ms) => Some(ms),
+            SetExpiry::KeepTtl => None,
+        }
+    }
+}
+
+/// Write condition for `SET ... NX|XX`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    /// Only set the key if it does not already exist
+    Nx,
+    /// Only set the key if it already exists
+    Xx,
+}
+
+/// Optional arguments accepted by `SET`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SetOptions {
+    pub expiry: Option<SetExpiry>,
+    pub condition: Option<SetCondition>,
+    /// Return the previous value (`GET` flag)
+    pub get: bool,
+}
+
+impl SetOptions {
+    /// True when the options change nothing compared to a plain `SET key value`
+    pub fn is_plain(&self) -> bool {
+        self.expiry.is_none() && self.condition.is_none() && !self.get
+    }
+}
+
+/// Optional arguments accepted by `SCAN` and `HSCAN`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanOptions {
+    /// Glob pattern keys must match (`MATCH`)
+    pub pattern: Option<Bytes>,
+    /// Number of keys to examine per call (`COUNT`)
+    pub count: Option<usize>,
+    /// Only return field names (`HSCAN ... NOVALUES`)
+    pub novalues: bool,
+}
+
+/// Condition flags for `EXPIRE` and friends (`NX|XX|GT|LT`)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpireCondition {
+    /// Only set the expiry if the key has none
+    Nx,
+    /// Only set the expiry if the key already has one
+    Xx,
+    /// Only set the expiry if it is greater than the current one
+    Gt,
+    /// Only set the expiry if it is less than the current one
+    Lt,
 }
 
 /// Parse error types
@@ -98,6 +488,7 @@ pub enum ParseError {
 }
 
 /// Parse a single RESP message and return both the parsed value and remaining bytes
+#[allow(dead_code)]
 pub fn parse_resp_with_remaining(input: &[u8]) -> Result<(RespValue, &[u8]), ParseError> {
     let mut bytes_mut = bytes::BytesMut::from(input);
 
@@ -111,6 +502,257 @@ pub fn parse_resp_with_remaining(input: &[u8]) -> Result<(RespValue, &[u8]), Par
     }
 }
 
+/// Parse a single RESP message from the front of a connection buffer and
+/// remove it. The frame shares the buffer's memory, so large values are
+/// neither copied nor parsed again while the rest of them arrives.
+///
+/// As in Redis, anything that does not start with `*` is an inline command:
+/// a line of space separated, optionally quoted, arguments as typed into
+/// telnet or sent by TCP health checks. It is returned as the array a client
+/// would have sent. Empty lines are skipped.
+pub fn parse_resp_from_buffer(buffer: &mut bytes::BytesMut) -> Result<RespValue, ParseError> {
+    while buffer.first().is_some_and(|&b| b != b'*') {
+        if let Some(command) = parse_inline(buffer)? {
+            return Ok(command);
+        }
+    }
+    match decode_bytes_mut(buffer) {
+        Ok(Some((frame, _, _))) => Ok(frame),
+        Ok(None) => Err(ParseError::Incomplete),
+        Err(e) => Err(ParseError::Invalid(e.details().to_string())),
+    }
+}
+
+/// Parse the inline command on the first line of the buffer, `None` if the
+/// line is empty
+fn parse_inline(buffer: &mut bytes::BytesMut) -> Result<Option<RespValue>, ParseError> {
+    let Some(end) = buffer.iter().position(|&b| b == b'\n') else {
+        return Err(ParseError::Incomplete);
+    };
+    let line = buffer.split_to(end + 1);
+    let line = &line[..end];
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let args = split_inline_args(line)
+        .ok_or_else(|| ParseError::Invalid("unbalanced quotes in request".to_string()))?;
+    if args.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(BytesFrame::Array(
+        args.into_iter().map(BytesFrame::BulkString).collect(),
+    )))
+}
+
+/// Split an inline command into arguments the way redis-cli does. Double
+/// quoted arguments support `\n`, `\r`, `\t`, `\b`, `\a` and `\xHH` escapes,
+/// single quoted ones only `\'`. A closing quote must end the argument.
+/// Returns `None` for unbalanced quotes.
+fn split_inline_args(line: &[u8]) -> Option<Vec<Bytes>> {
+    let mut args = Vec::new();
+    let mut rest = line;
+    loop {
+        while rest.first().is_some_and(|b| b.is_ascii_whitespace()) {
+            rest = &rest[1..];
+        }
+        if rest.is_empty() {
+            return Some(args);
+        }
+
+        let mut arg = Vec::new();
+        while let Some(&b) = rest.first() {
+            match b {
+                b if b.is_ascii_whitespace() => break,
+                b'"' => {
+                    rest = &rest[1..];
+                    loop {
+                        match rest {
+                            [b'\\', b'x', high, low, tail @ ..]
+                                if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() =>
+                            {
+                                let hex = [*high, *low];
+                                let hex = std::str::from_utf8(&hex).ok()?;
+                                arg.push(u8::from_str_radix(hex, 16).ok()?);
+                                rest = tail;
+                            }
+                            [b'\\', escaped, tail @ ..] => {
+                                arg.push(match escaped {
+                                    b'n' => b'\n',
+                                    b'r' => b'\r',
+                                    b't' => b'\t',
+                                    b'b' => 0x08,
+                                    b'a' => 0x07,
+                                    other => *other,
+                                });
+                                rest = tail;
+                            }
+                            [b'"', tail @ ..] => {
+                                rest = tail;
+                                break;
+                            }
+                            [other, tail @ ..] => {
+                                arg.push(*other);
+                                rest = tail;
+                            }
+                            [] => return None,
+                        }
+                    }
+                    if rest.first().is_some_and(|b| !b.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    break;
+                }
+                b'\'' => {
+                    rest = &rest[1..];
+                    loop {
+                        match rest {
+                            [b'\\', b'\'', tail @ ..] => {
+                                arg.push(b'\'');
+                                rest = tail;
+                            }
+                            [b'\'', tail @ ..] => {
+                                rest = tail;
+                                break;
+                            }
+                            [other, tail @ ..] => {
+                                arg.push(*other);
+                                rest = tail;
+                            }
+                            [] => return None,
+                        }
+                    }
+                    if rest.first().is_some_and(|b| !b.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    break;
+                }
+                other => {
+                    arg.push(other);
+                    rest = &rest[1..];
+                }
+            }
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+/// Limits on the size of incoming requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolLimits {
+    /// Longest bulk string argument
+    pub max_bulk_len: usize,
+    /// Most arguments in one command
+    pub max_multibulk_len: usize,
+    /// Longest inline command or header line
+    pub max_inline_len: usize,
+}
+
+/// A protocol limit exceeded by a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Bulk,
+    Multibulk,
+    Inline,
+}
+
+impl LimitExceeded {
+    /// Error reply sent before the connection is closed, as Redis does
+    pub fn message(&self) -> &'static str {
+        match self {
+            LimitExceeded::Bulk => "ERR Protocol error: invalid bulk length",
+            LimitExceeded::Multibulk => "ERR Protocol error: invalid multibulk length",
+            LimitExceeded::Inline => "ERR Protocol error: too big inline request",
+        }
+    }
+}
+
+/// Check the headers of the request at the front of a connection buffer
+/// against the limits. Only the part that has arrived is looked at, so an
+/// oversized bulk string is rejected from its `$<len>` header alone, before
+/// its payload is buffered. Anything malformed other than a length is left
+/// to the parser.
+pub fn check_limits(buffer: &[u8], limits: &ProtocolLimits) -> Result<(), LimitExceeded> {
+    let Some(&first) = buffer.first() else {
+        return Ok(());
+    };
+    if first != b'*' {
+        // Inline commands may end with a bare newline
+        let line_len = buffer
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(buffer.len());
+        return if line_len > limits.max_inline_len {
+            Err(LimitExceeded::Inline)
+        } else {
+            Ok(())
+        };
+    }
+    let Some(header_end) = find_crlf(buffer) else {
+        // A line that never ends is rejected once it outgrows the limit
+        return if buffer.len() > limits.max_inline_len {
+            Err(LimitExceeded::Inline)
+        } else {
+            Ok(())
+        };
+    };
+    if header_end > limits.max_inline_len {
+        return Err(LimitExceeded::Inline);
+    }
+
+    let count = parse_length(&buffer[1..header_end]).ok_or(LimitExceeded::Multibulk)?;
+    if count > limits.max_multibulk_len as i64 {
+        return Err(LimitExceeded::Multibulk);
+    }
+
+    let mut pos = header_end + 2;
+    for _ in 0..count.max(0) {
+        if buffer.get(pos) != Some(&b'$') {
+            // Not arrived yet, or not a bulk string
+            return Ok(());
+        }
+        let Some(line_len) = find_crlf(&buffer[pos..]) else {
+            return if buffer.len() - pos > limits.max_inline_len {
+                Err(LimitExceeded::Bulk)
+            } else {
+                Ok(())
+            };
+        };
+        let len = parse_length(&buffer[pos + 1..pos + line_len])
+            .filter(|len| (0..=limits.max_bulk_len as i64).contains(len))
+            .ok_or(LimitExceeded::Bulk)?;
+        pos += line_len + 2 + len as usize + 2;
+    }
+    Ok(())
+}
+
+/// Check that a buffer holding the start of a single request is no longer
+/// than that request can be. For a multibulk request the bound follows from
+/// its argument count, each argument being at most a header line and
+/// `max_bulk_len` bytes.
+pub fn check_request_len(buffer: &[u8], limits: &ProtocolLimits) -> Result<(), LimitExceeded> {
+    let line_max = limits.max_inline_len.saturating_add(2);
+    let (max_len, limit) = match (buffer.first(), find_crlf(buffer)) {
+        (Some(b'*'), Some(header_end)) => {
+            let count = parse_length(&buffer[1..header_end]).unwrap_or(0).max(0) as usize;
+            let arg_max = line_max.saturating_add(limits.max_bulk_len.saturating_add(2));
+            let max_len = count.saturating_mul(arg_max).saturating_add(header_end + 2);
+            (max_len, LimitExceeded::Multibulk)
+        }
+        _ => (line_max, LimitExceeded::Inline),
+    };
+    if buffer.len() > max_len {
+        Err(limit)
+    } else {
+        Ok(())
+    }
+}
+
+fn find_crlf(data: &[u8]) -> Option<usize> {
+    data.windows(2).position(|w| w == b"\r\n")
+}
+
+fn parse_length(digits: &[u8]) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
 /// Parse a Redis command from RESP value
 pub fn parse_command(resp: RespValue) -> Result<RedisCommand, ParseError> {
     match resp {
@@ -139,36 +781,150 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
                     "GET requires exactly 1 argument".to_string(),
                 ));
             }
-            let key = extract_string(&elements[1])?;
+            let key = extract_bytes(&elements[1])?;
             Ok(RedisCommand::Get { key })
         }
         "SET" => {
-            if elements.len() != 3 {
+            if elements.len() < 3 {
                 return Err(ParseError::Invalid(
-                    "SET requires exactly 2 arguments".to_string(),
+                    "SET requires at least 2 arguments".to_string(),
                 ));
             }
-            let key = extract_string(&elements[1])?;
+            let key = extract_bytes(&elements[1])?;
             let value = extract_bytes(&elements[2])?;
-            Ok(RedisCommand::Set { key, value })
+            let options = parse_set_options(&elements[3..])?;
+            Ok(RedisCommand::Set {
+                key,
+                value,
+                options,
+            })
         }
-        "DEL" => {
-            if elements.len() != 2 {
+        "GETRANGE" | "SETRANGE" => {
+            if elements.len() != 4 {
+                return Err(ParseError::Invalid(format!(
+                    "{} requires exactly 3 arguments",
+                    command_name
+                )));
+            }
+            let key = extract_bytes(&elements[1])?;
+            let first = extract_integer(&elements[2])?;
+            Ok(if command_name == "GETRANGE" {
+                RedisCommand::GetRange {
+                    key,
+                    start: first,
+                    end: extract_integer(&elements[3])?,
+                }
+            } else {
+                RedisCommand::SetRange {
+                    key,
+                    offset: first,
+                    value: extract_bytes(&elements[3])?,
+                }
+            })
+        }
+        "APPEND" => {
+            if elements.len() != 3 {
                 return Err(ParseError::Invalid(
-                    "DEL requires exactly 1 argument".to_string(),
+                    "APPEND requires exactly 2 arguments".to_string(),
                 ));
             }
-            let key = extract_string(&elements[1])?;
-            Ok(RedisCommand::Del { key })
+            let key = extract_bytes(&elements[1])?;
+            let value = extract_bytes(&elements[2])?;
+            Ok(RedisCommand::Append { key, value })
         }
-        "EXISTS" => {
+        "STRLEN" => {
             if elements.len() != 2 {
                 return Err(ParseError::Invalid(
-                    "EXISTS requires exactly 1 argument".to_string(),
+                    "STRLEN requires exactly 1 argument".to_string(),
                 ));
             }
-            let key = extract_string(&elements[1])?;
-            Ok(RedisCommand::Exists { key })
+            let key = extract_bytes(&elements[1])?;
+            Ok(RedisCommand::StrLen { key })
+        }
+        // Deletion is cheap enough that UNLINK does not need to be deferred
+        "DEL" | "UNLINK" | "EXISTS" | "MGET" => {
+            if elements.len() < 2 {
+                return Err(ParseError::Invalid(format!(
+                    "{} requires at least 1 argument",
+                    command_name
+                )));
+            }
+            let keys = elements[1..]
+                .iter()
+                .map(extract_bytes)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(match command_name.as_str() {
+                "EXISTS" => RedisCommand::Exists { keys },
+                "MGET" => RedisCommand::MGet { keys },
+                _ => RedisCommand::Del { keys },
+            })
+        }
+        "MSET" | "MSETNX" => {
+            if elements.len() < 3 || elements.len().is_multiple_of(2) {
+                return Err(ParseError::Invalid(format!(
+                    "wrong number of arguments for '{}' command",
+                    command_name.to_lowercase()
+                )));
+            }
+            let mut entries = Vec::with_capacity(elements.len() / 2);
+            for pair in elements[1..].chunks(2) {
+                entries.push((extract_bytes(&pair[0])?, extract_bytes(&pair[1])?));
+            }
+            Ok(if command_name == "MSET" {
+                RedisCommand::MSet { entries }
+            } else {
+                RedisCommand::MSetNx { entries }
+            })
+        }
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+            if elements.len() != 3 && elements.len() != 4 {
+                return Err(ParseError::Invalid(format!(
+                    "{} requires 2 or 3 arguments",
+                    command_name
+                )));
+            }
+            let key = extract_bytes(&elements[1])?;
+            let amount = extract_integer(&elements[2])?;
+            let condition = match elements.get(3) {
+                Some(arg) => Some(parse_expire_condition(arg)?),
+                None => None,
+            };
+            Ok(match command_name.as_str() {
+                "EXPIRE" => RedisCommand::Expire {
+                    key,
+                    seconds: amount,
+                    condition,
+                },
+                "PEXPIRE" => RedisCommand::PExpire {
+                    key,
+                    milliseconds: amount,
+                    condition,
+                },
+                "EXPIREAT" => RedisCommand::ExpireAt {
+                    key,
+                    timestamp: amount,
+                    condition,
+                },
+                _ => RedisCommand::PExpireAt {
+                    key,
+                    timestamp_ms: amount,
+                    condition,
+                },
+            })
+        }
+        "TTL" | "PTTL" | "PERSIST" => {
+            if elements.len() != 2 {
+                return Err(ParseError::Invalid(format!(
+                    "{} requires exactly 1 argument",
+                    command_name
+                )));
+            }
+            let key = extract_bytes(&elements[1])?;
+            Ok(match command_name.as_str() {
+                "TTL" => RedisCommand::Ttl { key },
+                "PTTL" => RedisCommand::PTtl { key },
+                _ => RedisCommand::Persist { key },
+            })
         }
         "PING" => {
             let message = if elements.len() > 1 {
@@ -186,6 +942,59 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
             };
             Ok(RedisCommand::Info { section })
         }
+        "HELLO" => {
+            let protover = match elements.get(1) {
+                Some(value) => Some(extract_integer(value).map_err(|_| {
+                    ParseError::Invalid(
+                        "Protocol version is not an integer or out of range".to_string(),
+                    )
+                })?),
+                None => None,
+            };
+            let mut auth = None;
+            let mut setname = None;
+            let mut i = 2;
+            while i < elements.len() {
+                let option = extract_string(&elements[i])?;
+                match option.to_uppercase().as_str() {
+                    "AUTH" if i + 2 < elements.len() => {
+                        auth = Some((
+                            extract_string(&elements[i + 1])?,
+                            extract_string(&elements[i + 2])?,
+                        ));
+                        i += 3;
+                    }
+                    "SETNAME" if i + 1 < elements.len() => {
+                        setname = Some(extract_string(&elements[i + 1])?);
+                        i += 2;
+                    }
+                    _ => {
+                        return Err(ParseError::Invalid(format!(
+                            "Syntax error in HELLO option '{}'",
+                            option
+                        )));
+                    }
+                }
+            }
+            Ok(RedisCommand::Hello {
+                protover,
+                auth,
+                setname,
+            })
+        }
+        "AUTH" => match elements.len() {
+            2 => Ok(RedisCommand::Auth {
+                username: None,
+                password: extract_bytes(&elements[1])?,
+            }),
+            3 => Ok(RedisCommand::Auth {
+                username: Some(extract_string(&elements[1])?),
+                password: extract_bytes(&elements[2])?,
+            }),
+            _ => Err(ParseError::Invalid(
+                "wrong number of arguments for 'auth' command".to_string(),
+            )),
+        },
         "COMMAND" => Ok(RedisCommand::Command),
         "HGET" => {
             if elements.len() != 3 {
@@ -193,23 +1002,59 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
                     "HGET requires exactly 2 arguments".to_string(),
                 ));
             }
-            let namespace = extract_string(&elements[1])?;
-            let key = extract_string(&elements[2])?;
+            let namespace = extract_namespace(&elements[1])?;
+            let key = extract_bytes(&elements[2])?;
             Ok(RedisCommand::HGet { namespace, key })
         }
-        "HSET" => {
-            if elements.len() != 4 {
+        "HSET" | "HMSET" => {
+            if elements.len() < 4 || !elements.len().is_multiple_of(2) {
+                return Err(ParseError::Invalid(format!(
+                    "wrong number of arguments for '{}' command",
+                    command_name.to_lowercase()
+                )));
+            }
+            let namespace = extract_namespace(&elements[1])?;
+            if command_name == "HSET" && elements.len() == 4 {
+                let key = extract_bytes(&elements[2])?;
+                let value = extract_bytes(&elements[3])?;
+                return Ok(RedisCommand::HSet {
+                    namespace,
+                    key,
+                    value,
+                });
+            }
+            // HSET with several key/value pairs behaves like HMSET
+            let mut entries = Vec::with_capacity(elements.len() / 2 - 1);
+            for pair in elements[2..].chunks(2) {
+                entries.push((extract_bytes(&pair[0])?, extract_bytes(&pair[1])?));
+            }
+            Ok(RedisCommand::HMSet { namespace, entries })
+        }
+        "HMGET" => {
+            if elements.len() < 3 {
                 return Err(ParseError::Invalid(
-                    "HSET requires exactly 3 arguments".to_string(),
+                    "HMGET requires at least 2 arguments".to_string(),
                 ));
             }
-            let namespace = extract_string(&elements[1])?;
-            let key = extract_string(&elements[2])?;
-            let value = extract_bytes(&elements[3])?;
-            Ok(RedisCommand::HSet {
-                namespace,
-                key,
-                value,
+            let namespace = extract_namespace(&elements[1])?;
+            let keys = elements[2..]
+                .iter()
+                .map(extract_bytes)
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(RedisCommand::HMGet { namespace, keys })
+        }
+        "HLEN" | "HKEYS" | "HGETALL" => {
+            if elements.len() != 2 {
+                return Err(ParseError::Invalid(format!(
+                    "{} requires exactly 1 argument",
+                    command_name
+                )));
+            }
+            let namespace = extract_namespace(&elements[1])?;
+            Ok(match command_name.as_str() {
+                "HLEN" => RedisCommand::HLen { namespace },
+                "HKEYS" => RedisCommand::HKeys { namespace },
+                _ => RedisCommand::HGetAll { namespace },
             })
         }
         "HDEL" => {
@@ -218,8 +1063,8 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
                     "HDEL requires exactly 2 arguments".to_string(),
                 ));
             }
-            let namespace = extract_string(&elements[1])?;
-            let key = extract_string(&elements[2])?;
+            let namespace = extract_namespace(&elements[1])?;
+            let key = extract_bytes(&elements[2])?;
             Ok(RedisCommand::HDel { namespace, key })
         }
         "HEXISTS" => {
@@ -228,10 +1073,163 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
                     "HEXISTS requires exactly 2 arguments".to_string(),
                 ));
             }
-            let namespace = extract_string(&elements[1])?;
-            let key = extract_string(&elements[2])?;
+            let namespace = extract_namespace(&elements[1])?;
+            let key = extract_bytes(&elements[2])?;
             Ok(RedisCommand::HExists { namespace, key })
         }
+        "SCAN" => {
+            if elements.len() < 2 {
+                return Err(ParseError::Invalid(
+                    "wrong number of arguments for 'scan' command".to_string(),
+                ));
+            }
+            let cursor = extract_string(&elements[1])?;
+            let options = parse_scan_options(&elements[2..], false)?;
+            Ok(RedisCommand::Scan { cursor, options })
+        }
+        "KEYS" => {
+            if elements.len() != 2 {
+                return Err(ParseError::Invalid(
+                    "KEYS requires exactly 1 argument".to_string(),
+                ));
+            }
+            let pattern = extract_bytes(&elements[1])?;
+            Ok(RedisCommand::Keys { pattern })
+        }
+        "HSCAN" => {
+            if elements.len() < 3 {
+                return Err(ParseError::Invalid(
+                    "wrong number of arguments for 'hscan' command".to_string(),
+                ));
+            }
+            let namespace = extract_namespace(&elements[1])?;
+            let cursor = extract_string(&elements[2])?;
+            let options = parse_scan_options(&elements[3..], true)?;
+            Ok(RedisCommand::HScan {
+                namespace,
+                cursor,
+                options,
+            })
+        }
+        "HEXPIRE" | "HPEXPIRE" => {
+            // HEXPIRE key seconds [NX|XX|GT|LT] FIELDS numfields field [field ...]
+            if elements.len() < 6 {
+                return Err(ParseError::Invalid(format!(
+                    "wrong number of arguments for '{}' command",
+                    command_name.to_lowercase()
+                )));
+            }
+            let namespace = extract_namespace(&elements[1])?;
+            let amount = extract_integer(&elements[2])?;
+            let (condition, fields_at) = if is_keyword(&elements[3], "FIELDS") {
+                (None, 3)
+            } else {
+                (Some(parse_expire_condition(&elements[3])?), 4)
+            };
+            let fields = parse_fields(&elements[fields_at..])?;
+            Ok(if command_name == "HEXPIRE" {
+                RedisCommand::HExpire {
+                    namespace,
+                    seconds: amount,
+                    condition,
+                    fields,
+                }
+            } else {
+                RedisCommand::HPExpire {
+                    namespace,
+                    milliseconds: amount,
+                    condition,
+                    fields,
+                }
+            })
+        }
+        "HTTL" | "HPTTL" | "HPERSIST" => {
+            // HTTL key FIELDS numfields field [field ...]
+            if elements.len() < 5 {
+                return Err(ParseError::Invalid(format!(
+                    "wrong number of arguments for '{}' command",
+                    command_name.to_lowercase()
+                )));
+            }
+            let namespace = extract_namespace(&elements[1])?;
+            let fields = parse_fields(&elements[2..])?;
+            Ok(match command_name.as_str() {
+                "HTTL" => RedisCommand::HTtl { namespace, fields },
+                "HPTTL" => RedisCommand::HPTtl { namespace, fields },
+                _ => RedisCommand::HPersist { namespace, fields },
+            })
+        }
+        "HSETEX" => {
+            // HSETEX key [FNX|FXX] [EX s|PX ms|EXAT ts|PXAT ts|KEEPTTL] FIELDS numfields field value [...]
+            if elements.len() < 6 {
+                return Err(ParseError::Invalid(
+                    "wrong number of arguments for 'hsetex' command".to_string(),
+                ));
+            }
+            let namespace = extract_namespace(&elements[1])?;
+            let fields_at = elements
+                .iter()
+                .skip(2)
+                .position(|e| is_keyword(e, "FIELDS"))
+                .map(|pos| pos + 2)
+                .ok_or_else(|| ParseError::Invalid("syntax error".to_string()))?;
+
+            // The flags share their syntax with SET, except for the FNX/FXX spelling
+            let mut flags = Vec::with_capacity(fields_at - 2);
+            for arg in &elements[2..fields_at] {
+                let flag = extract_string(arg)?.to_uppercase();
+                flags.push(match flag.as_str() {
+                    "FNX" => BytesFrame::BulkString(Bytes::from_static(b"NX")),
+                    "FXX" => BytesFrame::BulkString(Bytes::from_static(b"XX")),
+                    "NX" | "XX" | "GET" => {
+                        return Err(ParseError::Invalid("syntax error".to_string()));
+                    }
+                    _ => arg.clone(),
+                });
+            }
+            let options = parse_set_options(&flags).map_err(|e| match e {
+                ParseError::Invalid(msg) => ParseError::Invalid(msg.replace("'set'", "'hsetex'")),
+                other => other,
+            })?;
+
+            let count = parse_field_count(&elements[fields_at + 1..], 2)?;
+            let mut fields = Vec::with_capacity(count);
+            for pair in elements[fields_at + 2..].chunks(2) {
+                fields.push((extract_bytes(&pair[0])?, extract_bytes(&pair[1])?));
+            }
+            Ok(RedisCommand::HSetEx {
+                namespace,
+                fields,
+                expiry: options.expiry,
+                condition: options.condition,
+            })
+        }
+        "ACL" => {
+            if elements.len() < 2 {
+                return Err(ParseError::Invalid(
+                    "wrong number of arguments for 'acl' command".to_string(),
+                ));
+            }
+            let subcommand = extract_string(&elements[1])?.to_uppercase();
+            match subcommand.as_str() {
+                "WHOAMI" => Ok(RedisCommand::AclWhoami),
+                "LIST" => Ok(RedisCommand::AclList),
+                "SETUSER" => {
+                    if elements.len() < 3 {
+                        return Err(ParseError::Invalid(
+                            "wrong number of arguments for 'acl|setuser' command".to_string(),
+                        ));
+                    }
+                    let username = extract_string(&elements[2])?;
+                    let rules = elements[3..]
+                        .iter()
+                        .map(extract_string)
+                        .collect::<Result<_, _>>()?;
+                    Ok(RedisCommand::AclSetUser { username, rules })
+                }
+                _ => Ok(RedisCommand::Unknown(format!("ACL {}", subcommand))),
+            }
+        }
         "CLUSTER" => {
             if elements.len() < 2 {
                 return Err(ParseError::Invalid(
@@ -281,7 +1279,7 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
                             "CLUSTER KEYSLOT requires exactly 1 argument".to_string(),
                         ));
                     }
-                    let key = extract_string(&elements[2])?;
+                    let key = extract_bytes(&elements[2])?;
                     Ok(RedisCommand::ClusterKeySlot { key })
                 }
                 _ => Ok(RedisCommand::Unknown(format!("CLUSTER {}", subcommand))),
@@ -292,23 +1290,181 @@ fn parse_command_array(elements: Vec<BytesFrame>) -> Result<RedisCommand, ParseE
     }
 }
 
-/// Extract string from RESP value
-fn extract_string(value: &BytesFrame) -> Result<String, ParseError> {
-    match value {
-        BytesFrame::BulkString(data) => Ok(String::from_utf8_lossy(data).to_string()),
-        BytesFrame::SimpleString(data) => Ok(String::from_utf8_lossy(data).to_string()),
-        BytesFrame::Null => Err(ParseError::Invalid(
-            "Cannot use null as string argument".to_string(),
-        )),
-        _ => Err(ParseError::Invalid("Expected string argument".to_string())),
-    }
-}
+/// Parse the optional trailing arguments of `SET key value [...]`
+fn parse_set_options(args: &[BytesFrame]) -> Result<SetOptions, ParseError> {
+    let mut options = SetOptions::default();
+    let mut i = 0;
 
-/// Extract bytes from RESP value
-fn extract_bytes(value: &BytesFrame) -> Result<Bytes, ParseError> {
+    while i < args.len() {
+        let flag = extract_string(&args[i])?.to_uppercase();
+        match flag.as_str() {
+            "NX" | "XX" => {
+                if options.condition.is_some() {
+                    return Err(ParseError::Invalid("syntax error".to_string()));
+                }
+                options.condition = Some(if flag == "NX" {
+                    SetCondition::Nx
+                } else {
+                    SetCondition::Xx
+                });
+            }
+            "GET" => options.get = true,
+            "KEEPTTL" => {
+                if options.expiry.is_some() {
+                    return Err(ParseError::Invalid("syntax error".to_string()));
+                }
+                options.expiry = Some(SetExpiry::KeepTtl);
+            }
+            "EX" | "PX" | "EXAT" | "PXAT" => {
+                if options.expiry.is_some() {
+                    return Err(ParseError::Invalid("syntax error".to_string()));
+                }
+                i += 1;
+                let value = args
+                    .get(i)
+                    .ok_or_else(|| ParseError::Invalid("syntax error".to_string()))?;
+                let amount = extract_integer(value)?;
+                if amount <= 0 {
+                    return Err(ParseError::Invalid(
+                        "invalid expire time in 'set' command".to_string(),
+                    ));
+                }
+                options.expiry = Some(match flag.as_str() {
+                    "EX" => SetExpiry::Ex(amount),
+                    "PX" => SetExpiry::Px(amount),
+                    "EXAT" => SetExpiry::ExAt(amount),
+                    _ => SetExpiry::PxAt(amount),
+                });
+            }
+            _ => return Err(ParseError::Invalid("syntax error".to_string())),
+        }
+        i += 1;
+    }
+
+    Ok(options)
+}
+
+/// Parse the `[MATCH pattern] [COUNT count] [NOVALUES]` arguments of SCAN/HSCAN
+fn parse_scan_options(
+    args: &[BytesFrame],
+    allow_novalues: bool,
+) -> Result<ScanOptions, ParseError> {
+    let syntax_error = || ParseError::Invalid("syntax error".to_string());
+    let mut options = ScanOptions::default();
+    let mut i = 0;
+
+    while i < args.len() {
+        let option = extract_string(&args[i])?.to_uppercase();
+        match option.as_str() {
+            "MATCH" => {
+                let pattern = args.get(i + 1).ok_or_else(syntax_error)?;
+                options.pattern = Some(extract_bytes(pattern)?);
+                i += 2;
+            }
+            "COUNT" => {
+                let count = extract_integer(args.get(i + 1).ok_or_else(syntax_error)?)?;
+                if count < 1 {
+                    return Err(syntax_error());
+                }
+                options.count = Some(count as usize);
+                i += 2;
+            }
+            "NOVALUES" if allow_novalues => {
+                options.novalues = true;
+                i += 1;
+            }
+            _ => return Err(syntax_error()),
+        }
+    }
+
+    Ok(options)
+}
+
+/// Parse `FIELDS numfields field [field ...]` used by the hash field expiry commands
+fn parse_fields(args: &[BytesFrame]) -> Result<Vec<Bytes>, ParseError> {
+    if args.is_empty() || !is_keyword(&args[0], "FIELDS") {
+        return Err(ParseError::Invalid(
+            "Mandatory argument FIELDS is missing or not at the right position".to_string(),
+        ));
+    }
+    parse_field_count(&args[1..], 1)?;
+    args[2..].iter().map(extract_bytes).collect()
+}
+
+/// Validate the `numfields` argument against the number of remaining arguments,
+/// where every field takes `arity` arguments.
+fn parse_field_count(args: &[BytesFrame], arity: usize) -> Result<usize, ParseError> {
+    let count = args
+        .first()
+        .map(extract_integer)
+        .transpose()?
+        .ok_or_else(|| ParseError::Invalid("syntax error".to_string()))?;
+    if count <= 0 || (args.len() - 1) != count as usize * arity {
+        return Err(ParseError::Invalid(
+            "The `numfields` parameter must match the number of arguments".to_string(),
+        ));
+    }
+    Ok(count as usize)
+}
+
+/// Case-insensitive comparison of an argument against a keyword
+fn is_keyword(value: &BytesFrame, keyword: &str) -> bool {
+    match value {
+        BytesFrame::BulkString(data) | BytesFrame::SimpleString(data) => {
+            data.eq_ignore_ascii_case(keyword.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Parse the `NX|XX|GT|LT` flag of the EXPIRE family
+fn parse_expire_condition(value: &BytesFrame) -> Result<ExpireCondition, ParseError> {
+    match extract_string(value)?.to_uppercase().as_str() {
+        "NX" => Ok(ExpireCondition::Nx),
+        "XX" => Ok(ExpireCondition::Xx),
+        "GT" => Ok(ExpireCondition::Gt),
+        "LT" => Ok(ExpireCondition::Lt),
+        other => Err(ParseError::Invalid(format!("Unsupported option {}", other))),
+    }
+}
+
+/// Extract a signed integer from RESP value
+fn extract_integer(value: &BytesFrame) -> Result<i64, ParseError> {
+    match value {
+        BytesFrame::Integer(i) => Ok(*i),
+        _ => extract_string(value)?.parse::<i64>().map_err(|_| {
+            ParseError::Invalid("value is not an integer or out of range".to_string())
+        }),
+    }
+}
+
+/// Extract string from RESP value
+fn extract_string(value: &BytesFrame) -> Result<String, ParseError> {
+    match value {
+        BytesFrame::BulkString(data) => Ok(String::from_utf8_lossy(data).to_string()),
+        BytesFrame::SimpleString(data) => Ok(String::from_utf8_lossy(data).to_string()),
+        BytesFrame::Null => Err(ParseError::Invalid(
+            "Cannot use null as string argument".to_string(),
+        )),
+        _ => Err(ParseError::Invalid("Expected string argument".to_string())),
+    }
+}
+
+/// Extract a namespace, rejecting names that cannot be stored. Unlike keys,
+/// namespaces must be valid UTF-8 rather than being converted lossily, so
+/// that two different namespaces never share a table.
+fn extract_namespace(value: &BytesFrame) -> Result<String, ParseError> {
+    let invalid = || ParseError::Invalid(namespace::InvalidNamespace.to_string());
+    let namespace = String::from_utf8(extract_bytes(value)?.to_vec()).map_err(|_| invalid())?;
+    namespace::validate(&namespace).map_err(|_| invalid())?;
+    Ok(namespace)
+}
+
+/// Extract bytes from RESP value
+fn extract_bytes(value: &BytesFrame) -> Result<Bytes, ParseError> {
     match value {
-        BytesFrame::BulkString(data) => Ok(Bytes::copy_from_slice(data)),
-        BytesFrame::SimpleString(data) => Ok(Bytes::copy_from_slice(data)),
+        BytesFrame::BulkString(data) => Ok(data.clone()),
+        BytesFrame::SimpleString(data) => Ok(data.clone()),
         BytesFrame::Null => Err(ParseError::Invalid(
             "Cannot use null as byte argument".to_string(),
         )),
@@ -325,6 +1481,79 @@ pub fn serialize_frame(frame: &BytesFrame) -> Bytes {
     buf.freeze()
 }
 
+/// Protocol a connection speaks, RESP2 until the client switches with `HELLO 3`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl ProtocolVersion {
+    /// Version for the `protover` argument of `HELLO`
+    pub fn from_number(protover: i64) -> Option<Self> {
+        match protover {
+            2 => Some(ProtocolVersion::Resp2),
+            3 => Some(ProtocolVersion::Resp3),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self) -> i64 {
+        match self {
+            ProtocolVersion::Resp2 => 2,
+            ProtocolVersion::Resp3 => 3,
+        }
+    }
+
+    /// Header of a map of `len` entries, whose keys and values follow it in
+    /// turn. RESP2 has no maps, so they are sent as flat arrays.
+    pub fn map_header(&self, len: usize) -> String {
+        match self {
+            ProtocolVersion::Resp2 => format!("*{}\r\n", len * 2),
+            ProtocolVersion::Resp3 => format!("%{}\r\n", len),
+        }
+    }
+}
+
+/// Serialize a reply for a connection speaking `version`. Replies are built
+/// as RESP2 frames, which RESP3 encodes the same way except for nulls.
+pub fn serialize_reply(frame: &BytesFrame, version: ProtocolVersion) -> Bytes {
+    match version {
+        ProtocolVersion::Resp2 => serialize_frame(frame),
+        ProtocolVersion::Resp3 => {
+            let mut buf = bytes::BytesMut::new();
+            extend_resp3(&mut buf, frame);
+            buf.freeze()
+        }
+    }
+}
+
+fn extend_resp3(buf: &mut bytes::BytesMut, frame: &BytesFrame) {
+    match frame {
+        BytesFrame::Null => buf.extend_from_slice(b"_\r\n"),
+        BytesFrame::Array(elements) => {
+            buf.extend_from_slice(format!("*{}\r\n", elements.len()).as_bytes());
+            for element in elements {
+                extend_resp3(buf, element);
+            }
+        }
+        _ => {
+            extend_encode(buf, frame, false).expect("Failed to encode frame");
+        }
+    }
+}
+
+/// Serialize a map reply for a connection speaking `version`
+pub fn serialize_map(entries: &[(BytesFrame, BytesFrame)], version: ProtocolVersion) -> Bytes {
+    let mut buf = bytes::BytesMut::from(version.map_header(entries.len()).as_bytes());
+    for (key, value) in entries {
+        buf.extend_from_slice(&serialize_reply(key, version));
+        buf.extend_from_slice(&serialize_reply(value, version));
+    }
+    buf.freeze()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -337,11 +1566,44 @@ mod tests {
         assert_eq!(
             command,
             RedisCommand::Get {
-                key: "mykey42".to_string()
+                key: Bytes::from("mykey42")
             }
         );
     }
 
+    #[test]
+    fn test_parse_binary_keys() {
+        let input = b"*3\r\n$3\r\nSET\r\n$4\r\n\x00\xff\xfe\x80\r\n$1\r\nv\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Set {
+                key: Bytes::from_static(b"\x00\xff\xfe\x80"),
+                value: Bytes::from_static(b"v"),
+                options: SetOptions::default(),
+            }
+        );
+
+        let input = b"*4\r\n$4\r\nHSET\r\n$2\r\nns\r\n$2\r\n\xc3\x28\r\n$1\r\nv\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HSet {
+                namespace: "ns".to_string(),
+                key: Bytes::from_static(b"\xc3\x28"),
+                value: Bytes::from_static(b"v"),
+            }
+        );
+
+        // Namespaces become table names and must be valid UTF-8
+        let input = b"*3\r\n$4\r\nHGET\r\n$2\r\n\xc3\x28\r\n$1\r\nk\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(
+            parse_command(resp),
+            Err(ParseError::Invalid(msg)) if msg == "invalid namespace"
+        ));
+    }
+
     #[test]
     fn test_parse_set_command() {
         let input = b"*3\r\n$3\r\nSET\r\n$5\r\nmykey\r\n$11\r\nhello world\r\n";
@@ -350,12 +1612,318 @@ mod tests {
         assert_eq!(
             command,
             RedisCommand::Set {
-                key: "mykey".to_string(),
-                value: Bytes::from_static(b"hello world")
+                key: Bytes::from("mykey"),
+                value: Bytes::from_static(b"hello world"),
+                options: SetOptions::default(),
             }
         );
     }
 
+    #[test]
+    fn test_parse_set_with_options() {
+        let input =
+            b"*6\r\n$3\r\nSET\r\n$5\r\nmykey\r\n$1\r\nv\r\n$2\r\nEX\r\n$2\r\n60\r\n$2\r\nNX\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        let command = parse_command(resp).unwrap();
+        assert_eq!(
+            command,
+            RedisCommand::Set {
+                key: Bytes::from("mykey"),
+                value: Bytes::from_static(b"v"),
+                options: SetOptions {
+                    expiry: Some(SetExpiry::Ex(60)),
+                    condition: Some(SetCondition::Nx),
+                    get: false,
+                },
+            }
+        );
+
+        let input = b"*5\r\n$3\r\nSET\r\n$5\r\nmykey\r\n$1\r\nv\r\n$7\r\nKEEPTTL\r\n$3\r\nGET\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        let command = parse_command(resp).unwrap();
+        assert_eq!(
+            command,
+            RedisCommand::Set {
+                key: Bytes::from("mykey"),
+                value: Bytes::from_static(b"v"),
+                options: SetOptions {
+                    expiry: Some(SetExpiry::KeepTtl),
+                    condition: None,
+                    get: true,
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_set_invalid_options() {
+        // Conflicting expiry flags
+        let input = b"*7\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n1\r\n$2\r\nPX\r\n$1\r\n1\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+
+        // NX and XX together
+        let input = b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nNX\r\n$2\r\nXX\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+
+        // Non-positive expire time
+        let input = b"*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n0\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+
+        // Missing expire value
+        let input = b"*4\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nPX\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_set_expiry_to_unix_millis() {
+        let now = 1_700_000_000_000;
+        assert_eq!(SetExpiry::Ex(60).to_unix_millis(now), Some(now + 60_000));
+        assert_eq!(SetExpiry::Px(1500).to_unix_millis(now), Some(now + 1500));
+        assert_eq!(
+            SetExpiry::ExAt(1_800_000_000).to_unix_millis(now),
+            Some(1_800_000_000_000)
+        );
+        assert_eq!(SetExpiry::PxAt(42).to_unix_millis(now), Some(42));
+        assert_eq!(SetExpiry::KeepTtl.to_unix_millis(now), None);
+    }
+
+    #[test]
+    fn test_parse_expire_commands() {
+        let input = b"*3\r\n$6\r\nEXPIRE\r\n$5\r\nmykey\r\n$2\r\n10\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Expire {
+                key: Bytes::from("mykey"),
+                seconds: 10,
+                condition: None,
+            }
+        );
+
+        let input = b"*4\r\n$7\r\nPEXPIRE\r\n$5\r\nmykey\r\n$4\r\n1500\r\n$2\r\ngt\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::PExpire {
+                key: Bytes::from("mykey"),
+                milliseconds: 1500,
+                condition: Some(ExpireCondition::Gt),
+            }
+        );
+
+        let input = b"*3\r\n$8\r\nEXPIREAT\r\n$5\r\nmykey\r\n$3\r\nabc\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_hexpire_commands() {
+        let input = b"*7\r\n$7\r\nHEXPIRE\r\n$2\r\nns\r\n$2\r\n60\r\n$6\r\nFIELDS\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HExpire {
+                namespace: "ns".to_string(),
+                seconds: 60,
+                condition: None,
+                fields: vec![Bytes::from("a"), Bytes::from("b")],
+            }
+        );
+
+        let input = b"*7\r\n$8\r\nHPEXPIRE\r\n$2\r\nns\r\n$3\r\n500\r\n$2\r\nNX\r\n$6\r\nFIELDS\r\n$1\r\n1\r\n$1\r\na\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HPExpire {
+                namespace: "ns".to_string(),
+                milliseconds: 500,
+                condition: Some(ExpireCondition::Nx),
+                fields: vec![Bytes::from("a")],
+            }
+        );
+
+        // numfields must match the number of fields
+        let input = b"*6\r\n$7\r\nHEXPIRE\r\n$2\r\nns\r\n$2\r\n60\r\n$6\r\nFIELDS\r\n$1\r\n2\r\n$1\r\na\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_httl_and_hpersist_commands() {
+        let input = b"*5\r\n$4\r\nHTTL\r\n$2\r\nns\r\n$6\r\nfields\r\n$1\r\n1\r\n$1\r\na\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HTtl {
+                namespace: "ns".to_string(),
+                fields: vec![Bytes::from("a")],
+            }
+        );
+
+        let input = b"*5\r\n$8\r\nHPERSIST\r\n$2\r\nns\r\n$6\r\nFIELDS\r\n$1\r\n1\r\n$1\r\na\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HPersist {
+                namespace: "ns".to_string(),
+                fields: vec![Bytes::from("a")],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_hsetex_command() {
+        let input = b"*10\r\n$6\r\nHSETEX\r\n$2\r\nns\r\n$3\r\nFNX\r\n$2\r\nEX\r\n$2\r\n60\r\n$6\r\nFIELDS\r\n$1\r\n1\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nx\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        // Trailing argument makes numfields mismatch
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+
+        let input = b"*9\r\n$6\r\nHSETEX\r\n$2\r\nns\r\n$3\r\nFNX\r\n$2\r\nEX\r\n$2\r\n60\r\n$6\r\nFIELDS\r\n$1\r\n1\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HSetEx {
+                namespace: "ns".to_string(),
+                fields: vec![(Bytes::from("a"), Bytes::from_static(b"1"))],
+                expiry: Some(SetExpiry::Ex(60)),
+                condition: Some(SetCondition::Nx),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_scan_commands() {
+        let input = b"*6\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nmatch\r\n$6\r\nuser:*\r\n$5\r\nCOUNT\r\n$3\r\n100\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Scan {
+                cursor: "0".to_string(),
+                options: ScanOptions {
+                    pattern: Some(Bytes::from("user:*")),
+                    count: Some(100),
+                    novalues: false,
+                },
+            }
+        );
+
+        let input = b"*2\r\n$4\r\nKEYS\r\n$1\r\n*\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Keys {
+                pattern: Bytes::from("*"),
+            }
+        );
+
+        let input = b"*4\r\n$5\r\nHSCAN\r\n$2\r\nns\r\n$4\r\n1:6b\r\n$8\r\nNOVALUES\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HScan {
+                namespace: "ns".to_string(),
+                cursor: "1:6b".to_string(),
+                options: ScanOptions {
+                    novalues: true,
+                    ..Default::default()
+                },
+            }
+        );
+
+        // NOVALUES is HSCAN only, COUNT must be positive and MATCH needs a pattern
+        for input in [
+            &b"*3\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$8\r\nNOVALUES\r\n"[..],
+            &b"*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nCOUNT\r\n$1\r\n0\r\n"[..],
+            &b"*3\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nMATCH\r\n"[..],
+        ] {
+            let (resp, _) = parse_resp_with_remaining(input).unwrap();
+            assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn test_parse_ttl_and_persist_commands() {
+        let input = b"*2\r\n$3\r\nTTL\r\n$5\r\nmykey\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Ttl {
+                key: Bytes::from("mykey")
+            }
+        );
+
+        let input = b"*2\r\n$4\r\nPTTL\r\n$5\r\nmykey\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::PTtl {
+                key: Bytes::from("mykey")
+            }
+        );
+
+        let input = b"*2\r\n$7\r\nPERSIST\r\n$5\r\nmykey\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Persist {
+                key: Bytes::from("mykey")
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_string_range_commands() {
+        let input = b"*4\r\n$8\r\nGETRANGE\r\n$1\r\nk\r\n$1\r\n0\r\n$2\r\n-1\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::GetRange {
+                key: Bytes::from("k"),
+                start: 0,
+                end: -1
+            }
+        );
+
+        let input = b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$2\r\n10\r\n$3\r\nabc\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::SetRange {
+                key: Bytes::from("k"),
+                offset: 10,
+                value: Bytes::from("abc")
+            }
+        );
+
+        let input = b"*3\r\n$6\r\nAPPEND\r\n$1\r\nk\r\n$3\r\nabc\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Append {
+                key: Bytes::from("k"),
+                value: Bytes::from("abc")
+            }
+        );
+
+        let input = b"*2\r\n$6\r\nSTRLEN\r\n$1\r\nk\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::StrLen {
+                key: Bytes::from("k")
+            }
+        );
+
+        // Offsets must be integers
+        let input = b"*4\r\n$8\r\nSETRANGE\r\n$1\r\nk\r\n$1\r\nx\r\n$3\r\nabc\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(parse_command(resp).is_err());
+    }
+
     #[test]
     fn test_parse_del_command() {
         let input = b"*2\r\n$3\r\nDEL\r\n$5\r\nmykey\r\n";
@@ -364,9 +1932,23 @@ mod tests {
         assert_eq!(
             command,
             RedisCommand::Del {
-                key: "mykey".to_string()
+                keys: vec![Bytes::from("mykey")]
+            }
+        );
+
+        // DEL and UNLINK accept several keys
+        let input = b"*3\r\n$6\r\nUNLINK\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Del {
+                keys: vec![Bytes::from("a"), Bytes::from("b")]
             }
         );
+
+        let input = b"*1\r\n$3\r\nDEL\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
     }
 
     #[test]
@@ -377,11 +1959,59 @@ mod tests {
         assert_eq!(
             command,
             RedisCommand::Exists {
-                key: "mykey".to_string()
+                keys: vec![Bytes::from("mykey")]
+            }
+        );
+
+        // Repeated keys are kept, they are counted once per occurrence
+        let input = b"*3\r\n$6\r\nEXISTS\r\n$1\r\na\r\n$1\r\na\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Exists {
+                keys: vec![Bytes::from("a"), Bytes::from("a")]
             }
         );
     }
 
+    #[test]
+    fn test_parse_mget_and_mset_commands() {
+        let input = b"*3\r\n$4\r\nMGET\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::MGet {
+                keys: vec![Bytes::from("a"), Bytes::from("b")]
+            }
+        );
+
+        let input = b"*5\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::MSet {
+                entries: vec![
+                    (Bytes::from("a"), Bytes::from_static(b"1")),
+                    (Bytes::from("b"), Bytes::from_static(b"2")),
+                ]
+            }
+        );
+
+        let input = b"*3\r\n$6\r\nMSETNX\r\n$1\r\na\r\n$1\r\n1\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::MSetNx {
+                entries: vec![(Bytes::from("a"), Bytes::from_static(b"1"))]
+            }
+        );
+
+        // A key without a value
+        let input = b"*4\r\n$4\r\nMSET\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+    }
+
     #[test]
     fn test_parse_ping_command() {
         // PING without message
@@ -447,7 +2077,7 @@ mod tests {
             command,
             RedisCommand::HGet {
                 namespace: "namespace".to_string(),
-                key: "mykey".to_string()
+                key: Bytes::from("mykey")
             }
         );
     }
@@ -461,12 +2091,87 @@ mod tests {
             command,
             RedisCommand::HSet {
                 namespace: "namespace".to_string(),
-                key: "mykey".to_string(),
+                key: Bytes::from("mykey"),
                 value: Bytes::from_static(b"hello world")
             }
         );
     }
 
+    #[test]
+    fn test_parse_invalid_namespace() {
+        let long = "n".repeat(crate::namespace::MAX_NAMESPACE_LEN + 1);
+        let inputs = [
+            b"*3\r\n$4\r\nHGET\r\n$0\r\n\r\n$1\r\nk\r\n".to_vec(),
+            format!("*2\r\n$4\r\nHLEN\r\n${}\r\n{}\r\n", long.len(), long).into_bytes(),
+        ];
+        for input in inputs {
+            let (resp, _) = parse_resp_with_remaining(&input).unwrap();
+            assert!(matches!(
+                parse_command(resp),
+                Err(ParseError::Invalid(msg)) if msg == "invalid namespace"
+            ));
+        }
+
+        // Anything else is accepted and encoded when it is turned into a table name
+        let input = b"*3\r\n$4\r\nHGET\r\n$14\r\nx\"; DROP TABLE\r\n$1\r\nk\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HGet {
+                namespace: "x\"; DROP TABLE".to_string(),
+                key: Bytes::from("k")
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_multi_field_hash_commands() {
+        let expected = vec![
+            (Bytes::from("a"), Bytes::from_static(b"1")),
+            (Bytes::from("b"), Bytes::from_static(b"2")),
+        ];
+        for input in [
+            &b"*6\r\n$4\r\nHSET\r\n$2\r\nns\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"[..],
+            &b"*6\r\n$5\r\nHMSET\r\n$2\r\nns\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"[..],
+        ] {
+            let (resp, _) = parse_resp_with_remaining(input).unwrap();
+            assert_eq!(
+                parse_command(resp).unwrap(),
+                RedisCommand::HMSet {
+                    namespace: "ns".to_string(),
+                    entries: expected.clone(),
+                }
+            );
+        }
+
+        let input = b"*5\r\n$4\r\nHSET\r\n$2\r\nns\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+
+        let input = b"*4\r\n$5\r\nHMGET\r\n$2\r\nns\r\n$1\r\na\r\n$1\r\nb\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HMGet {
+                namespace: "ns".to_string(),
+                keys: vec![Bytes::from("a"), Bytes::from("b")],
+            }
+        );
+
+        let input = b"*2\r\n$7\r\nHGETALL\r\n$2\r\nns\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::HGetAll {
+                namespace: "ns".to_string(),
+            }
+        );
+
+        let input = b"*3\r\n$4\r\nHLEN\r\n$2\r\nns\r\n$1\r\nx\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(matches!(parse_command(resp), Err(ParseError::Invalid(_))));
+    }
+
     #[test]
     fn test_parse_hdel_command() {
         let input = b"*3\r\n$4\r\nHDEL\r\n$9\r\nnamespace\r\n$5\r\nmykey\r\n";
@@ -476,7 +2181,7 @@ mod tests {
             command,
             RedisCommand::HDel {
                 namespace: "namespace".to_string(),
-                key: "mykey".to_string()
+                key: Bytes::from("mykey")
             }
         );
     }
@@ -490,7 +2195,7 @@ mod tests {
             command,
             RedisCommand::HExists {
                 namespace: "namespace".to_string(),
-                key: "mykey".to_string()
+                key: Bytes::from("mykey")
             }
         );
     }
@@ -527,7 +2232,7 @@ mod tests {
         assert_eq!(
             command,
             RedisCommand::ClusterKeySlot {
-                key: "mykey".to_string()
+                key: Bytes::from("mykey")
             }
         );
     }
@@ -548,7 +2253,7 @@ mod tests {
         assert_eq!(
             command,
             RedisCommand::Get {
-                key: "mykey".to_string()
+                key: Bytes::from("mykey")
             }
         );
     }
@@ -567,6 +2272,258 @@ mod tests {
         assert_eq!(serialized.as_ref(), b"-ERR something\r\n");
     }
 
+    #[test]
+    fn test_check_limits() {
+        let limits = ProtocolLimits {
+            max_bulk_len: 16,
+            max_multibulk_len: 3,
+            max_inline_len: 32,
+        };
+        let check = |input: &[u8]| check_limits(input, &limits);
+
+        assert_eq!(check(b""), Ok(()));
+        assert_eq!(check(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$16\r\n"), Ok(()));
+        // Rejected from the header, before the payload arrives
+        assert_eq!(
+            check(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$17\r\n"),
+            Err(LimitExceeded::Bulk)
+        );
+        assert_eq!(check(b"*1\r\n$-5\r\n"), Err(LimitExceeded::Bulk));
+        assert_eq!(check(b"*1\r\n$abc\r\n"), Err(LimitExceeded::Bulk));
+        assert_eq!(check(b"*4\r\n"), Err(LimitExceeded::Multibulk));
+        assert_eq!(check(b"*x\r\n"), Err(LimitExceeded::Multibulk));
+        // Incomplete headers are checked again once they end
+        assert_eq!(check(b"*1\r\n$99"), Ok(()));
+        assert_eq!(check(&[b'*'; 40]), Err(LimitExceeded::Inline));
+        assert_eq!(check(&[b'P'; 40]), Err(LimitExceeded::Inline));
+        assert_eq!(check(b"PING\r\n"), Ok(()));
+
+        assert_eq!(
+            LimitExceeded::Bulk.message(),
+            "ERR Protocol error: invalid bulk length"
+        );
+    }
+
+    #[test]
+    fn test_check_request_len() {
+        let limits = ProtocolLimits {
+            max_bulk_len: 4,
+            max_multibulk_len: 8,
+            max_inline_len: 6,
+        };
+        let check = |input: &[u8]| check_request_len(input, &limits);
+
+        // Two arguments of at most 6 + 2 + 4 + 2 bytes after the header
+        let mut request = b"*2\r\n".to_vec();
+        request.resize(4 + 2 * 14, b'x');
+        assert_eq!(check(&request), Ok(()));
+        request.push(b'x');
+        assert_eq!(check(&request), Err(LimitExceeded::Multibulk));
+        assert_eq!(check(b"PING"), Ok(()));
+        assert_eq!(check(b"PINGPONG\r\n"), Err(LimitExceeded::Inline));
+    }
+
+    #[test]
+    fn test_parse_auth_command() {
+        let input = b"*2\r\n$4\r\nAUTH\r\n$6\r\nsecret\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Auth {
+                username: None,
+                password: Bytes::from_static(b"secret"),
+            }
+        );
+
+        let input = b"*3\r\n$4\r\nauth\r\n$3\r\napp\r\n$6\r\nsecret\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        let command = parse_command(resp).unwrap();
+        assert!(command.allowed_before_auth());
+        assert_eq!(
+            command,
+            RedisCommand::Auth {
+                username: Some("app".to_string()),
+                password: Bytes::from_static(b"secret"),
+            }
+        );
+
+        let input = b"*1\r\n$4\r\nAUTH\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(parse_command(resp).is_err());
+    }
+
+    #[test]
+    fn test_parse_acl_commands() {
+        let input = b"*2\r\n$3\r\nacl\r\n$6\r\nwhoami\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        let command = parse_command(resp).unwrap();
+        assert_eq!(command, RedisCommand::AclWhoami);
+        assert_eq!(command.category(), CommandCategory::Connection);
+
+        let input =
+            b"*5\r\n$3\r\nACL\r\n$7\r\nSETUSER\r\n$4\r\nteam\r\n$2\r\non\r\n$9\r\n~team_a_*\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        let command = parse_command(resp).unwrap();
+        assert_eq!(command.category(), CommandCategory::Admin);
+        assert_eq!(
+            command,
+            RedisCommand::AclSetUser {
+                username: "team".to_string(),
+                rules: vec!["on".to_string(), "~team_a_*".to_string()],
+            }
+        );
+
+        let input = b"*2\r\n$3\r\nACL\r\n$7\r\nSETUSER\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(parse_command(resp).is_err());
+    }
+
+    #[test]
+    fn test_command_category_and_namespace() {
+        let hget = RedisCommand::HGet {
+            namespace: "team_a_users".to_string(),
+            key: Bytes::from_static(b"k"),
+        };
+        assert_eq!(hget.category(), CommandCategory::Read);
+        assert_eq!(hget.namespace(), Some("team_a_users"));
+
+        let del = RedisCommand::Del {
+            keys: vec![Bytes::from_static(b"k")],
+        };
+        assert_eq!(del.category(), CommandCategory::Write);
+        assert_eq!(del.namespace(), None);
+    }
+
+    #[test]
+    fn test_parse_hello_command() {
+        let input = b"*7\r\n$5\r\nhello\r\n$1\r\n3\r\n$4\r\nAUTH\r\n$7\r\ndefault\r\n$6\r\nsecret\r\n$7\r\nSETNAME\r\n$3\r\napp\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Hello {
+                protover: Some(3),
+                auth: Some(("default".to_string(), "secret".to_string())),
+                setname: Some("app".to_string()),
+            }
+        );
+
+        let input = b"*1\r\n$5\r\nHELLO\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert_eq!(
+            parse_command(resp).unwrap(),
+            RedisCommand::Hello {
+                protover: None,
+                auth: None,
+                setname: None,
+            }
+        );
+
+        let input = b"*3\r\n$5\r\nHELLO\r\n$1\r\n3\r\n$4\r\nAUTH\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(parse_command(resp).is_err());
+
+        let input = b"*2\r\n$5\r\nHELLO\r\n$5\r\nthree\r\n";
+        let (resp, _) = parse_resp_with_remaining(input).unwrap();
+        assert!(parse_command(resp).is_err());
+    }
+
+    #[test]
+    fn test_serialize_resp3_reply() {
+        let value = BytesFrame::Array(vec![BytesFrame::BulkString("a".into()), BytesFrame::Null]);
+        assert_eq!(
+            serialize_reply(&value, ProtocolVersion::Resp2).as_ref(),
+            b"*2\r\n$1\r\na\r\n$-1\r\n"
+        );
+        assert_eq!(
+            serialize_reply(&value, ProtocolVersion::Resp3).as_ref(),
+            b"*2\r\n$1\r\na\r\n_\r\n"
+        );
+
+        let entries = vec![(
+            BytesFrame::BulkString("proto".into()),
+            BytesFrame::Integer(3),
+        )];
+        assert_eq!(
+            serialize_map(&entries, ProtocolVersion::Resp2).as_ref(),
+            b"*2\r\n$5\r\nproto\r\n:3\r\n"
+        );
+        assert_eq!(
+            serialize_map(&entries, ProtocolVersion::Resp3).as_ref(),
+            b"%1\r\n$5\r\nproto\r\n:3\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_inline_commands() {
+        let mut buffer = bytes::BytesMut::from(&b"PING\r\n\r\n  set key  \"a b\\x41\\n\"\nGET"[..]);
+        assert_eq!(
+            parse_command(parse_resp_from_buffer(&mut buffer).unwrap()).unwrap(),
+            RedisCommand::Ping { message: None }
+        );
+        assert_eq!(
+            parse_command(parse_resp_from_buffer(&mut buffer).unwrap()).unwrap(),
+            RedisCommand::Set {
+                key: Bytes::from("key"),
+                value: Bytes::from("a bA\n"),
+                options: SetOptions::default(),
+            }
+        );
+        // The rest of the line has not arrived yet
+        assert!(matches!(
+            parse_resp_from_buffer(&mut buffer),
+            Err(ParseError::Incomplete)
+        ));
+        assert_eq!(&buffer[..], b"GET");
+
+        // Inline and RESP commands can follow each other
+        let mut buffer = bytes::BytesMut::from(&b"\n*1\r\n$4\r\nPING\r\n"[..]);
+        assert_eq!(
+            parse_command(parse_resp_from_buffer(&mut buffer).unwrap()).unwrap(),
+            RedisCommand::Ping { message: None }
+        );
+        assert!(buffer.is_empty());
+
+        assert_eq!(
+            split_inline_args(b"'it\\'s' \"\\\"q\\\"\"").unwrap(),
+            vec![Bytes::from("it's"), Bytes::from("\"q\"")]
+        );
+
+        for unbalanced in [&b"GET \"key\n"[..], b"GET 'key\n", b"GET \"key\"x\n"] {
+            let mut buffer = bytes::BytesMut::from(unbalanced);
+            assert!(matches!(
+                parse_resp_from_buffer(&mut buffer),
+                Err(ParseError::Invalid(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let get = RedisCommand::Get {
+            key: Bytes::from("k"),
+        };
+        let hlen = RedisCommand::HLen {
+            namespace: "ns".to_string(),
+        };
+        let set = RedisCommand::Set {
+            key: Bytes::from("k"),
+            value: Bytes::from("v"),
+            options: SetOptions::default(),
+        };
+        assert!(get.is_concurrent_read());
+        assert!(hlen.is_concurrent_read());
+        assert!(!set.is_concurrent_read());
+        assert!(!RedisCommand::Quit.is_concurrent_read());
+        assert!(
+            !RedisCommand::Hello {
+                protover: Some(3),
+                auth: None,
+                setname: None,
+            }
+            .is_concurrent_read()
+        );
+    }
+
     #[test]
     fn test_serialize_integer() {
         let value = BytesFrame::Integer(42);
@@ -0,0 +1,424 @@
+//! Keyspace iteration shared by SCAN, KEYS and HSCAN.
+//!
+//! A table (`blobs` or `blobs_{namespace}`) is spread over every shard, so
+//! iteration walks the shard pools in order and pages through each one by key.
+//! The cursor handed to clients encodes the shard index and the last key
+//! returned, which keeps it stable while keys are added or removed. It is
+//! made of decimal digits only, since clients expect a number.
+
+use crate::namespace::quote_identifier;
+use sqlx::SqlitePool;
+use std::fmt;
+
+/// Position of a SCAN-style iteration across all shards
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ScanCursor {
+    /// Shard currently being iterated
+    pub shard: usize,
+    /// Last key returned from that shard, `None` to start at its first key
+    pub after: Option<Vec<u8>>,
+}
+
+impl ScanCursor {
+    /// The cursor that starts an iteration and is returned once it is complete
+    pub const START: ScanCursor = ScanCursor {
+        shard: 0,
+        after: None,
+    };
+
+    /// Parse a cursor previously returned by `Display`.
+    ///
+    /// `"0"` is the start of the keyspace. Any other cursor is the number of
+    /// digits of the shard index, the shard index, then, if a key was
+    /// returned from that shard, `1` followed by every byte of the last key
+    /// as three digits. Shard 2 after the key `k` is `"121107"`.
+    pub fn parse(cursor: &str) -> Option<Self> {
+        if cursor == "0" {
+            return Some(Self::START);
+        }
+        if !cursor.bytes().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let width = cursor.get(..1)?.parse::<usize>().ok().filter(|&w| w > 0)?;
+        let shard = cursor.get(1..1 + width)?.parse().ok()?;
+        let after = match cursor.get(1 + width..)? {
+            "" => None,
+            key => {
+                let digits = key.strip_prefix('1')?;
+                if digits.len() % 3 != 0 {
+                    return None;
+                }
+                let bytes = (0..digits.len())
+                    .step_by(3)
+                    .map(|i| digits[i..i + 3].parse::<u8>().ok())
+                    .collect::<Option<Vec<u8>>>()?;
+                Some(bytes)
+            }
+        };
+        Some(ScanCursor { shard, after })
+    }
+}
+
+impl fmt::Display for ScanCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if *self == Self::START {
+            return write!(f, "0");
+        }
+        let shard = self.shard.to_string();
+        write!(f, "{}{}", shard.len(), shard)?;
+        if let Some(after) = &self.after {
+            write!(f, "1")?;
+            for byte in after {
+                write!(f, "{:03}", byte)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A key and, when requested, its stored (possibly compressed) data
+pub type ScanEntry = (Vec<u8>, Option<Vec<u8>>);
+
+/// Return up to `count` live rows of `table_name` starting at `cursor`,
+/// moving on to the next shard whenever one is exhausted.
+///
+/// Like Redis, `count` bounds the number of rows examined rather than the
+/// number returned, so a `pattern` can make a page come back short or empty.
+/// The returned cursor is `ScanCursor::START` once every shard has been walked.
+/// Shards that have no such table yet are skipped.
+pub async fn scan_table(
+    pools: &[SqlitePool],
+    table_name: &str,
+    mut cursor: ScanCursor,
+    count: usize,
+    pattern: Option<&[u8]>,
+    with_values: bool,
+    now_ms: i64,
+) -> Result<(ScanCursor, Vec<ScanEntry>), sqlx::Error> {
+    let query = format!(
+        "SELECT key, {} FROM {} WHERE (? IS NULL OR key > ?) AND (expires_at IS NULL OR expires_at > ?) ORDER BY key LIMIT ?",
+        if with_values { "data" } else { "NULL" },
+        quote_identifier(table_name)
+    );
+
+    let mut scanned = 0;
+    let mut entries = Vec::new();
+    while cursor.shard < pools.len() && scanned < count {
+        let limit = (count - scanned).min(i64::MAX as usize);
+        let rows = match sqlx::query_as::<_, (Vec<u8>, Option<Vec<u8>>)>(&query)
+            .bind(&cursor.after)
+            .bind(&cursor.after)
+            .bind(now_ms)
+            .bind(limit as i64)
+            .fetch_all(&pools[cursor.shard])
+            .await
+        {
+            Ok(rows) => rows,
+            Err(e) if is_missing_table(&e) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+
+        scanned += rows.len();
+        cursor = if rows.len() < limit {
+            ScanCursor {
+                shard: cursor.shard + 1,
+                after: None,
+            }
+        } else {
+            ScanCursor {
+                shard: cursor.shard,
+                after: rows.last().map(|(key, _)| key.clone()),
+            }
+        };
+
+        entries.extend(
+            rows.into_iter()
+                .filter(|(key, _)| pattern.is_none_or(|p| glob_match(p, key))),
+        );
+    }
+
+    if cursor.shard >= pools.len() {
+        cursor = ScanCursor::START;
+    }
+    Ok((cursor, entries))
+}
+
+/// Whether a query failed because the table does not exist. Namespaced tables
+/// are only created on a shard when the first key of the namespace lands there.
+pub fn is_missing_table(error: &sqlx::Error) -> bool {
+    matches!(error, sqlx::Error::Database(e) if e.message().contains("no such table"))
+}
+
+/// Glob-style matching with the same syntax as Redis `KEYS`/`SCAN MATCH`:
+/// `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` to escape a special character.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` seen and the text position it is matched up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() {
+            if pattern[p] == b'*' {
+                p += 1;
+                backtrack = Some((p, t));
+                continue;
+            }
+            if let Some(next) = match_single(pattern, p, text[t]) {
+                p = next;
+                t += 1;
+                continue;
+            }
+        }
+        // Let the last `*` absorb one more character and retry
+        match backtrack {
+            Some((star_p, star_t)) => {
+                p = star_p;
+                t = star_t + 1;
+                backtrack = Some((star_p, t));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Match one non-`*` token of the pattern at `p` against `c`, returning the
+// position of the next token on success.
+fn match_single(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    match pattern[p] {
+        b'?' => Some(p + 1),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == c).then_some(p + 2),
+        b'[' => {
+            let mut i = p + 1;
+            let negate = pattern.get(i) == Some(&b'^');
+            if negate {
+                i += 1;
+            }
+            let mut matched = false;
+            while i < pattern.len() && pattern[i] != b']' {
+                if pattern[i] == b'\\' && i + 1 < pattern.len() {
+                    matched |= pattern[i + 1] == c;
+                    i += 2;
+                } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']'
+                {
+                    let (start, end) = if pattern[i] <= pattern[i + 2] {
+                        (pattern[i], pattern[i + 2])
+                    } else {
+                        (pattern[i + 2], pattern[i])
+                    };
+                    matched |= (start..=end).contains(&c);
+                    i += 3;
+                } else {
+                    matched |= pattern[i] == c;
+                    i += 1;
+                }
+            }
+            // An unterminated class extends to the end of the pattern
+            (matched != negate).then_some((i + 1).min(pattern.len()))
+        }
+        literal => (literal == c).then_some(p + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("user:*", "user:1", true),
+            ("user:*", "session:1", false),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("a\\*b", "a*b", true),
+            ("a\\*b", "axb", false),
+            ("*a*b*c*", "xxaxxbxxcxx", true),
+            ("*a*b*c*", "xxaxxcxxbxx", false),
+            ("a*", "b", false),
+            ("", "", true),
+            ("", "a", false),
+        ];
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), text.as_bytes()),
+                *expected,
+                "pattern {:?} against {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        assert_eq!(ScanCursor::START.to_string(), "0");
+        assert_eq!(ScanCursor::parse("0"), Some(ScanCursor::START));
+
+        let cursors = [
+            ScanCursor {
+                shard: 2,
+                after: Some(b"k".to_vec()),
+            },
+            ScanCursor {
+                shard: 0,
+                after: Some(b"k".to_vec()),
+            },
+            // Start of a later shard, and after the empty key
+            ScanCursor {
+                shard: 12,
+                after: None,
+            },
+            ScanCursor {
+                shard: 3,
+                after: Some(Vec::new()),
+            },
+            // Keys are arbitrary bytes
+            ScanCursor {
+                shard: 3,
+                after: Some(vec![0x00, 0xff, b':', b' ']),
+            },
+        ];
+        for cursor in cursors {
+            let encoded = cursor.to_string();
+            assert!(encoded.bytes().all(|c| c.is_ascii_digit()), "{}", encoded);
+            assert!(!encoded.starts_with('0'), "{}", encoded);
+            assert_eq!(ScanCursor::parse(&encoded), Some(cursor));
+        }
+        assert_eq!(
+            ScanCursor {
+                shard: 2,
+                after: Some(b"k".to_vec()),
+            }
+            .to_string(),
+            "121107"
+        );
+
+        for invalid in ["", "-1", "1:6b", "00", "12107", "121256", "1210", "9123"] {
+            assert_eq!(ScanCursor::parse(invalid), None, "{:?}", invalid);
+        }
+    }
+
+    async fn shard_pool(
+        temp_dir: &TempDir,
+        shard: usize,
+        keys: &[(&str, Option<i64>)],
+    ) -> SqlitePool {
+        let db_path = temp_dir.path().join(format!("shard_{}.db", shard));
+        let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path.display()))
+            .unwrap()
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE blobs (key BLOB PRIMARY KEY, data BLOB, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, expires_at INTEGER, version INTEGER NOT NULL DEFAULT 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        for (key, expires_at) in keys {
+            sqlx::query("INSERT INTO blobs VALUES (?, ?, 0, 0, ?, 0)")
+                .bind(key.as_bytes())
+                .bind(key.as_bytes())
+                .bind(expires_at)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        pool
+    }
+
+    #[tokio::test]
+    async fn test_scan_walks_every_shard_once() {
+        let temp_dir = TempDir::new().unwrap();
+        let now = 1_000_000;
+        let pools = vec![
+            shard_pool(
+                &temp_dir,
+                0,
+                &[("a", None), ("c", None), ("e", Some(now - 1))],
+            )
+            .await,
+            shard_pool(&temp_dir, 1, &[]).await,
+            shard_pool(
+                &temp_dir,
+                2,
+                &[("b", Some(now + 1)), ("d", None), ("f", None)],
+            )
+            .await,
+        ];
+
+        let mut cursor = ScanCursor::START;
+        let mut seen = Vec::new();
+        let mut pages = 0;
+        loop {
+            let (next, entries) = scan_table(&pools, "blobs", cursor, 2, None, true, now)
+                .await
+                .unwrap();
+            assert!(entries.len() <= 2);
+            for (key, data) in entries {
+                assert_eq!(data.as_ref(), Some(&key));
+                seen.push(String::from_utf8(key).unwrap());
+            }
+            pages += 1;
+            if next == ScanCursor::START {
+                break;
+            }
+            cursor = next;
+        }
+
+        // The expired key is skipped and every live key is returned exactly once
+        seen.sort();
+        assert_eq!(seen, vec!["a", "b", "c", "d", "f"]);
+        assert!(pages >= 3);
+
+        // Patterns filter the examined rows and a missing table is empty
+        let (next, entries) = scan_table(
+            &pools,
+            "blobs",
+            ScanCursor::START,
+            usize::MAX,
+            Some(b"[a-c]"),
+            false,
+            now,
+        )
+        .await
+        .unwrap();
+        assert!(next == ScanCursor::START);
+        let keys: Vec<_> = entries
+            .into_iter()
+            .map(|(key, data)| {
+                assert!(data.is_none());
+                key
+            })
+            .collect();
+        assert_eq!(keys, vec![b"a".to_vec(), b"c".to_vec(), b"b".to_vec()]);
+
+        let (next, entries) = scan_table(
+            &pools,
+            "blobs_missing",
+            ScanCursor::START,
+            10,
+            None,
+            false,
+            now,
+        )
+        .await
+        .unwrap();
+        assert!(next == ScanCursor::START);
+        assert!(entries.is_empty());
+    }
+}
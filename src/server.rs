@@ -1,94 +1,232 @@
 use crate::AppState;
+use crate::acl::{Access, Denied};
+use crate::chunks::{self, ChunkManifest};
 use crate::cluster::ClusterManager;
+use crate::compression::codec::parse_frame;
+use crate::config::CompressionType;
+use crate::connection::Connection;
 use crate::metrics::Timer;
+use crate::namespace;
 use crate::redis::{
-    ParseError, RedisCommand, parse_command, parse_resp_with_remaining, serialize_frame,
+    ExpireCondition, ParseError, ProtocolVersion, RedisCommand, ScanOptions, SetCondition,
+    SetExpiry, SetOptions, check_limits, check_request_len, parse_command, parse_resp_from_buffer,
+    serialize_frame,
 };
-use crate::shard_manager::ShardWriteOperation;
-use bytes::Bytes;
+use crate::scan::{ScanCursor, is_missing_table, scan_table};
+use crate::shard_manager::{ExpireOutcome, ShardWriteOperation, TtlUpdate};
+use crate::tls::Tls;
+use bytes::{Bytes, BytesMut};
+use futures::TryStreamExt;
 use redis_protocol::resp2::types::BytesFrame;
+use sqlx::SqliteConnection;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpListener;
 use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
 
+/// Bytes read from a connection at a time
+const READ_SIZE: usize = 64 * 1024;
+
+/// How long a client may take to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Accept Redis connections on `addr`, over TLS when `tls` is given
 pub async fn run_redis_server(
     state: Arc<AppState>,
     addr: &str,
+    tls: Option<Arc<Tls>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let listener = TcpListener::bind(addr).await?;
-    tracing::info!("Blobasaur server listening on {}", addr);
+    if tls.is_some() {
+        tracing::info!("Blobasaur server listening on {} (TLS)", addr);
+    } else {
+        tracing::info!("Blobasaur server listening on {}", addr);
+    }
 
     loop {
         let (stream, addr) = listener.accept().await?;
-        tracing::info!("Accepted new connection from {}", addr);
-        tracing::debug!("New Blobasaur connection from {}", addr);
+        // The handshake runs on the connection's task, so the acceptor is
+        // taken now to use the certificates current at accept time
+        let acceptor = tls.as_ref().map(|tls| tls.acceptor());
+        spawn_connection(state.clone(), stream, addr.to_string(), acceptor);
+    }
+}
+
+/// Accept Redis connections on a Unix domain socket. A socket file left
+/// behind at `path` by an earlier run is replaced.
+#[cfg(unix)]
+pub async fn run_unix_server(
+    state: Arc<AppState>,
+    path: &str,
+    permissions: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    use std::os::unix::fs::FileTypeExt;
 
-        let state_clone = state.clone();
-        // Record new connection
-        state.metrics.record_connection();
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(format!("{} exists and is not a socket", path).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    let listener = bind_unix_socket(std::path::Path::new(path), permissions)?;
+    tracing::info!(
+        "Blobasaur server listening on {} (permissions {:o})",
+        path,
+        permissions
+    );
 
-        tokio::spawn(async move {
-            if let Err(e) = handle_connection(stream, state_clone.clone()).await {
-                tracing::error!("Error handling connection from {}: {}", addr, e);
-                state_clone.metrics.record_error("connection");
-            }
-            // Record dropped connection
-            state_clone.metrics.record_connection_dropped();
-        });
+    loop {
+        let (stream, _) = listener.accept().await?;
+        spawn_connection(state.clone(), stream, path.to_string(), None);
     }
 }
 
-async fn handle_connection(
-    mut stream: TcpStream,
+/// Bind a Unix socket at `path` with the given mode. The socket is created
+/// in a private directory next to `path` and renamed into place once its mode
+/// is set, so no other user can connect while it has the default mode.
+#[cfg(unix)]
+fn bind_unix_socket(
+    path: &std::path::Path,
+    permissions: u32,
+) -> std::io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    let name = path.file_name().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "socket path has no file name",
+        )
+    })?;
+    let private_dir = parent.join(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+
+    let temp_path = private_dir.join("socket");
+    let result = tokio::net::UnixListener::bind(&temp_path).and_then(|listener| {
+        std::fs::set_permissions(&temp_path, std::fs::Permissions::from_mode(permissions))?;
+        std::fs::rename(&temp_path, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&temp_path);
+    std::fs::remove_dir(&private_dir)?;
+    result
+}
+
+/// Serve a newly accepted client on its own task, after a TLS handshake if
+/// an acceptor is given
+fn spawn_connection<S>(state: Arc<AppState>, stream: S, peer: String, acceptor: Option<TlsAcceptor>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    tracing::info!("Accepted new connection from {}", peer);
+    tracing::debug!("New Blobasaur connection from {}", peer);
+
+    // Record new connection
+    state.metrics.record_connection();
+
+    let connections = state.connections.clone();
+    connections.spawn(async move {
+        let result = match acceptor {
+            Some(acceptor) => {
+                match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => handle_connection(stream, state.clone()).await,
+                    Ok(Err(e)) => Err(format!("TLS handshake failed: {}", e).into()),
+                    Err(_) => Err("TLS handshake timed out".into()),
+                }
+            }
+            None => handle_connection(stream, state.clone()).await,
+        };
+        if let Err(e) = result {
+            tracing::error!("Error handling connection from {}: {}", peer, e);
+            state.metrics.record_error("connection");
+        }
+        // Record dropped connection
+        state.metrics.record_connection_dropped();
+    });
+}
+
+async fn handle_connection<S>(
+    stream: S,
     state: Arc<AppState>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut buffer = Vec::new();
-    let mut temp_buffer = vec![0; 4096];
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let mut conn = Connection::new(Box::new(writer));
+    conn.user = state
+        .users
+        .read()
+        .unwrap()
+        .implicit_user()
+        .map(|user| user.name.clone());
+    let mut buffer = BytesMut::with_capacity(READ_SIZE);
+    let limits = state.cfg.protocol_limits();
 
     loop {
-        let n = match stream.read(&mut temp_buffer).await {
+        buffer.reserve(READ_SIZE);
+        // Commands already read are answered before shutdown closes the
+        // connection, only the wait for more is cut short
+        let read = tokio::select! {
+            read = reader.read_buf(&mut buffer) => read,
+            _ = state.shutdown.cancelled() => return Ok(()),
+        };
+        let n = match read {
             Ok(0) => return Ok(()), // Connection closed
             Ok(n) => n,
+            // TLS clients often close without sending close_notify
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
             Err(e) => {
                 tracing::error!("Failed to read from socket: {}", e);
                 return Err(Box::new(e));
             }
         };
 
-        buffer.extend_from_slice(&temp_buffer[..n]);
         tracing::debug!(
             "Read {} bytes from socket, buffer size is now {}",
             n,
             buffer.len()
         );
 
-        // Try to parse complete messages from the buffer
-        let mut remaining_data = &buffer[..];
-
-        while !remaining_data.is_empty() {
-            match parse_resp_with_remaining(remaining_data) {
-                Ok((resp_value, remaining)) => {
-                    remaining_data = remaining;
+        // Parse complete messages off the front of the buffer. They are
+        // handled together so pipelined replies go out in one write.
+        let mut requests = Vec::new();
+        let mut closing_error = None;
+        while !buffer.is_empty() {
+            if let Err(limit) = check_limits(&buffer, &limits) {
+                tracing::warn!("Closing connection: {}", limit.message());
+                state.metrics.record_limit_exceeded(limit);
+                closing_error = Some(limit.message().to_string());
+                break;
+            }
 
-                    // Parse and handle the command
+            match parse_resp_from_buffer(&mut buffer) {
+                Ok(resp_value) => {
+                    // Parse the command
                     match parse_command(resp_value) {
-                        Ok(command) => {
-                            if let Err(e) = handle_redis_command(&mut stream, &state, command).await
-                            {
-                                tracing::error!("Error handling command: {}", e);
-                                return Err(e);
-                            }
-                        }
+                        Ok(command) => requests.push(Request::Command(command)),
                         Err(ParseError::Invalid(msg)) => {
                             tracing::warn!("Invalid command: {}", msg);
                             let error_resp = BytesFrame::Error(format!("ERR {}", msg).into());
-                            stream.write_all(&serialize_frame(&error_resp)).await?;
+                            requests.push(Request::Reply(error_resp));
                         }
                         Err(e) => {
                             tracing::error!("Command parse error: {}", e);
                             let error_resp = BytesFrame::Error("ERR protocol error".into());
-                            stream.write_all(&serialize_frame(&error_resp)).await?;
+                            requests.push(Request::Reply(error_resp));
                         }
                     }
                 }
@@ -97,43 +235,122 @@ async fn handle_connection(
                     break;
                 }
                 Err(ParseError::Invalid(msg)) => {
-                    tracing::warn!("Protocol error: {}", msg);
-                    let error_resp = BytesFrame::Error(format!("ERR {}", msg).into());
-                    stream.write_all(&serialize_frame(&error_resp)).await?;
-                    // Skip one byte to try to recover
-                    if !remaining_data.is_empty() {
-                        remaining_data = &remaining_data[1..];
-                    }
+                    // The stream cannot be resynchronised, so close it like
+                    // Redis does
+                    tracing::warn!("Closing connection: protocol error: {}", msg);
+                    state.metrics.record_error("protocol");
+                    closing_error = Some(format!("ERR Protocol error: {}", msg));
+                    break;
                 }
             }
         }
 
-        // Update buffer to keep only unprocessed data
-        let remaining_len = remaining_data.len();
-        let processed_len = buffer.len() - remaining_len;
-        if processed_len > 0 {
-            buffer.drain(..processed_len);
+        // The error is turned into a string so it is not held across the
+        // flush, which would make the connection future not Send
+        let handled = handle_pipeline(&mut conn, &state, requests)
+            .await
+            .map_err(|e| e.to_string());
+        if let Err(e) = handled {
+            tracing::error!("Error handling command: {}", e);
+            // Still send the replies of the commands before it
+            let _ = conn.flush().await;
+            return Err(e.into());
+        }
+        if let Some(msg) = closing_error {
+            conn.write_frame(&BytesFrame::Error(msg.into())).await?;
+            conn.flush().await?;
+            return Ok(());
+        }
+        conn.flush().await?;
+
+        // Prevent buffer from growing too large. It only holds the start of
+        // one request now, which may not outgrow what its headers allow.
+        if let Err(limit) = check_request_len(&buffer, &limits) {
+            tracing::warn!("Closing connection: request too large");
+            state.metrics.record_limit_exceeded(limit);
+            conn.write_frame(&BytesFrame::Error(limit.message().into()))
+                .await?;
+            conn.flush().await?;
+            return Ok(());
+        }
+    }
+}
+
+/// A request parsed off a connection, waiting for its turn in the pipeline
+enum Request {
+    Command(RedisCommand),
+    /// Reply decided while parsing, such as a syntax error
+    Reply(BytesFrame),
+}
+
+/// Handle the requests of a pipeline in order. Runs of reads are handled
+/// concurrently, each on a detached connection, and their replies appended
+/// in the order of the requests. A read that defers its reply, such as a GET
+/// of a chunked value, runs again on the connection when its turn comes.
+async fn handle_pipeline(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    requests: Vec<Request>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut requests = requests.into_iter().peekable();
+    while let Some(request) = requests.next() {
+        let command = match request {
+            Request::Command(command) => command,
+            Request::Reply(response) => {
+                conn.write_frame(&response).await?;
+                continue;
+            }
+        };
+
+        let mut reads = Vec::new();
+        if command.is_concurrent_read() {
+            while let Some(Request::Command(next)) = requests.peek()
+                && next.is_concurrent_read()
+            {
+                let Some(Request::Command(next)) = requests.next() else {
+                    unreachable!();
+                };
+                reads.push(next);
+            }
+        }
+        if reads.is_empty() {
+            handle_redis_command(conn, state, command).await?;
+            continue;
         }
 
-        // Prevent buffer from growing too large
-        if buffer.len() > 1024 * 1024 {
-            tracing::warn!("Buffer too large, closing connection");
-            return Err("Buffer overflow".into());
+        reads.insert(0, command);
+        let results = futures::future::join_all(reads.iter().cloned().map(|command| {
+            let mut detached = conn.detached();
+            async move {
+                // Errors are turned into strings to keep the future Send
+                let result = handle_redis_command(&mut detached, state, command)
+                    .await
+                    .map_err(|e| e.to_string());
+                (detached, result)
+            }
+        }))
+        .await;
+        for (command, (detached, result)) in reads.into_iter().zip(results) {
+            if detached.is_deferred() && result.is_ok() {
+                handle_redis_command(conn, state, command).await?;
+                continue;
+            }
+            conn.append(detached).await?;
+            result?;
         }
     }
+    Ok(())
 }
 
-// Helper function to find the end of a complete message
-
 async fn handle_redis_command(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     command: RedisCommand,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let timer = Timer::start();
     let cmd_name = command.name();
 
-    let result = handle_redis_command_inner(stream, state, command).await;
+    let result = handle_redis_command_inner(conn, state, command).await;
 
     // Record command metrics
     state.metrics.record_command(&cmd_name, timer.start);
@@ -146,152 +363,624 @@ async fn handle_redis_command(
 }
 
 async fn handle_redis_command_inner(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     command: RedisCommand,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    if conn.user.is_none() && !command.allowed_before_auth() {
+        let response = BytesFrame::Error("NOAUTH Authentication required.".into());
+        conn.write_frame(&response).await?;
+        return Ok(());
+    }
+    if let Some(ref user) = conn.user
+        && let Err(denied) = check_permissions(state, user, &command)
+    {
+        tracing::debug!("Denied {} to user '{}'", command.name(), user);
+        state.metrics.record_permission_denied();
+        let response = BytesFrame::Error(denied.message(user, &command.name()).into());
+        conn.write_frame(&response).await?;
+        return Ok(());
+    }
+
     match command {
         RedisCommand::Get { key } => {
-            if let Some(ref cluster_manager) = state.cluster_manager {
-                if !cluster_manager.should_handle_locally(&key).await {
-                    if let Some(redirect) = cluster_manager.get_redirect_response(&key).await {
-                        let response = BytesFrame::Error(redirect.into());
-                        stream.write_all(&serialize_frame(&response)).await?;
-                        return Ok(());
-                    }
-                }
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
             }
-            handle_get(stream, state, key).await?;
+            handle_get(conn, state, key).await?;
         }
-        RedisCommand::Set { key, value } => {
-            if let Some(ref cluster_manager) = state.cluster_manager {
-                if !cluster_manager.should_handle_locally(&key).await {
-                    if let Some(redirect) = cluster_manager.get_redirect_response(&key).await {
-                        let response = BytesFrame::Error(redirect.into());
-                        stream.write_all(&serialize_frame(&response)).await?;
-                        return Ok(());
-                    }
-                }
+        RedisCommand::Set {
+            key,
+            value,
+            options,
+        } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            if reject_oversized(conn, state, [value.len()], false).await? {
+                return Ok(());
+            }
+            if options.is_plain() {
+                handle_set(conn, state, key, value).await?;
+            } else {
+                handle_set_with_options(conn, state, key, value, options).await?;
             }
-            handle_set(stream, state, key, value).await?;
         }
-        RedisCommand::Del { key } => {
-            if let Some(ref cluster_manager) = state.cluster_manager {
-                if !cluster_manager.should_handle_locally(&key).await {
-                    if let Some(redirect) = cluster_manager.get_redirect_response(&key).await {
-                        let response = BytesFrame::Error(redirect.into());
-                        stream.write_all(&serialize_frame(&response)).await?;
-                        return Ok(());
-                    }
+        RedisCommand::GetRange { key, start, end } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_getrange(conn, state, key, start, end).await?;
+        }
+        RedisCommand::SetRange { key, offset, value } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            if offset < 0 {
+                let response = BytesFrame::Error("ERR offset is out of range".into());
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+            handle_write_range(conn, state, key, Some(offset as u64), value, "SETRANGE").await?;
+        }
+        RedisCommand::Append { key, value } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_write_range(conn, state, key, None, value, "APPEND").await?;
+        }
+        RedisCommand::StrLen { key } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_strlen(conn, state, key).await?;
+        }
+        RedisCommand::Del { keys } => {
+            if redirect_keys_if_remote(conn, state, &keys).await? {
+                return Ok(());
+            }
+            handle_del(conn, state, keys).await?;
+        }
+        RedisCommand::Exists { keys } => {
+            if redirect_keys_if_remote(conn, state, &keys).await? {
+                return Ok(());
+            }
+            handle_exists(conn, state, keys).await?;
+        }
+        RedisCommand::MGet { keys } => {
+            if redirect_keys_if_remote(conn, state, &keys).await? {
+                return Ok(());
+            }
+            handle_mget(conn, state, keys).await?;
+        }
+        RedisCommand::MSet { entries } => {
+            for (key, _) in &entries {
+                if redirect_if_remote(conn, state, key).await? {
+                    return Ok(());
                 }
             }
-            handle_del(stream, state, key).await?;
+            if reject_oversized(conn, state, entries.iter().map(|(_, v)| v.len()), false).await? {
+                return Ok(());
+            }
+            handle_mset(conn, state, entries).await?;
         }
-        RedisCommand::Exists { key } => {
-            if let Some(ref cluster_manager) = state.cluster_manager {
-                if !cluster_manager.should_handle_locally(&key).await {
-                    if let Some(redirect) = cluster_manager.get_redirect_response(&key).await {
-                        let response = BytesFrame::Error(redirect.into());
-                        stream.write_all(&serialize_frame(&response)).await?;
-                        return Ok(());
-                    }
+        RedisCommand::MSetNx { entries } => {
+            for (key, _) in &entries {
+                if redirect_if_remote(conn, state, key).await? {
+                    return Ok(());
                 }
             }
-            handle_exists(stream, state, key).await?;
+            if reject_oversized(conn, state, entries.iter().map(|(_, v)| v.len()), false).await? {
+                return Ok(());
+            }
+            handle_msetnx(conn, state, entries).await?;
+        }
+        RedisCommand::Expire {
+            key,
+            seconds,
+            condition,
+        } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            let expires_at = now_ms.saturating_add(seconds.saturating_mul(1000));
+            handle_expire(conn, state, key, expires_at, condition).await?;
+        }
+        RedisCommand::PExpire {
+            key,
+            milliseconds,
+            condition,
+        } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            let expires_at = now_ms.saturating_add(milliseconds);
+            handle_expire(conn, state, key, expires_at, condition).await?;
+        }
+        RedisCommand::ExpireAt {
+            key,
+            timestamp,
+            condition,
+        } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            let expires_at = timestamp.saturating_mul(1000);
+            handle_expire(conn, state, key, expires_at, condition).await?;
+        }
+        RedisCommand::PExpireAt {
+            key,
+            timestamp_ms,
+            condition,
+        } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_expire(conn, state, key, timestamp_ms, condition).await?;
+        }
+        RedisCommand::Ttl { key } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_ttl(conn, state, key, false).await?;
+        }
+        RedisCommand::PTtl { key } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_ttl(conn, state, key, true).await?;
+        }
+        RedisCommand::Persist { key } => {
+            if redirect_if_remote(conn, state, &key).await? {
+                return Ok(());
+            }
+            handle_persist(conn, state, key).await?;
+        }
+        RedisCommand::Scan { cursor, options } => {
+            handle_scan(conn, state, cursor, options).await?;
+        }
+        RedisCommand::Keys { pattern } => {
+            handle_keys(conn, state, pattern).await?;
+        }
+        RedisCommand::HScan {
+            namespace,
+            cursor,
+            options,
+        } => {
+            handle_hscan(conn, state, namespace, cursor, options).await?;
         }
         RedisCommand::Ping { message } => {
-            handle_ping(stream, message).await?;
+            handle_ping(conn, message).await?;
         }
         RedisCommand::Info { section } => {
-            handle_info(stream, state, section).await?;
+            handle_info(conn, state, section).await?;
+        }
+        RedisCommand::Hello {
+            protover,
+            auth,
+            setname,
+        } => {
+            handle_hello(conn, state, protover, auth, setname).await?;
+        }
+        RedisCommand::Auth { username, password } => {
+            handle_auth(conn, state, username, password).await?;
+        }
+        RedisCommand::AclWhoami => {
+            handle_acl_whoami(conn).await?;
+        }
+        RedisCommand::AclList => {
+            handle_acl_list(conn, state).await?;
+        }
+        RedisCommand::AclSetUser { username, rules } => {
+            handle_acl_setuser(conn, state, username, rules).await?;
         }
         RedisCommand::Command => {
-            handle_command(stream).await?;
+            handle_command(conn).await?;
         }
         RedisCommand::HGet { namespace, key } => {
-            handle_hget(stream, state, namespace, key).await?;
+            handle_hget(conn, state, namespace, key).await?;
         }
         RedisCommand::HSet {
             namespace,
             key,
             value,
         } => {
-            handle_hset(stream, state, namespace, key, value).await?;
+            handle_hset(conn, state, namespace, key, value).await?;
         }
         RedisCommand::HDel { namespace, key } => {
-            handle_hdel(stream, state, namespace, key).await?;
+            handle_hdel(conn, state, namespace, key).await?;
         }
         RedisCommand::HExists { namespace, key } => {
-            handle_hexists(stream, state, namespace, key).await?;
+            handle_hexists(conn, state, namespace, key).await?;
+        }
+        RedisCommand::HMSet { namespace, entries } => {
+            handle_hmset(conn, state, namespace, entries).await?;
+        }
+        RedisCommand::HMGet { namespace, keys } => {
+            handle_hmget(conn, state, namespace, keys).await?;
+        }
+        RedisCommand::HLen { namespace } => {
+            handle_hlen(conn, state, namespace).await?;
+        }
+        RedisCommand::HKeys { namespace } => {
+            handle_hkeys(conn, state, namespace).await?;
+        }
+        RedisCommand::HGetAll { namespace } => {
+            handle_hgetall(conn, state, namespace).await?;
+        }
+        RedisCommand::HSetEx {
+            namespace,
+            fields,
+            expiry,
+            condition,
+        } => {
+            handle_hsetex(conn, state, namespace, fields, expiry, condition).await?;
+        }
+        RedisCommand::HExpire {
+            namespace,
+            seconds,
+            condition,
+            fields,
+        } => {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            let expires_at = now_ms.saturating_add(seconds.saturating_mul(1000));
+            handle_hexpire(conn, state, namespace, fields, expires_at, condition).await?;
+        }
+        RedisCommand::HPExpire {
+            namespace,
+            milliseconds,
+            condition,
+            fields,
+        } => {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            let expires_at = now_ms.saturating_add(milliseconds);
+            handle_hexpire(conn, state, namespace, fields, expires_at, condition).await?;
+        }
+        RedisCommand::HTtl { namespace, fields } => {
+            handle_httl(conn, state, namespace, fields, false).await?;
+        }
+        RedisCommand::HPTtl { namespace, fields } => {
+            handle_httl(conn, state, namespace, fields, true).await?;
+        }
+        RedisCommand::HPersist { namespace, fields } => {
+            handle_hpersist(conn, state, namespace, fields).await?;
         }
         RedisCommand::ClusterNodes => {
-            handle_cluster_nodes(stream, state).await?;
+            handle_cluster_nodes(conn, state).await?;
         }
         RedisCommand::ClusterInfo => {
-            handle_cluster_info(stream, state).await?;
+            handle_cluster_info(conn, state).await?;
         }
         RedisCommand::ClusterSlots => {
-            handle_cluster_slots(stream, state).await?;
+            handle_cluster_slots(conn, state).await?;
         }
         RedisCommand::ClusterAddSlots { slots } => {
-            handle_cluster_addslots(stream, state, slots).await?;
+            handle_cluster_addslots(conn, state, slots).await?;
         }
         RedisCommand::ClusterDelSlots { slots } => {
-            handle_cluster_delslots(stream, state, slots).await?;
+            handle_cluster_delslots(conn, state, slots).await?;
         }
         RedisCommand::ClusterKeySlot { key } => {
-            handle_cluster_keyslot(stream, key).await?;
+            handle_cluster_keyslot(conn, key).await?;
         }
         RedisCommand::Quit => {
             let response = BytesFrame::SimpleString("OK".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
             return Err("Client quit".into());
         }
         RedisCommand::Unknown(cmd) => {
             tracing::warn!("Unknown command: {}", cmd);
             let response = BytesFrame::Error(format!("ERR unknown command '{}'", cmd).into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         }
     }
     Ok(())
 }
 
-async fn compress_if_enabled(
+/// Check a command against the permissions of the user running it
+fn check_permissions(
+    state: &Arc<AppState>,
+    user: &str,
+    command: &RedisCommand,
+) -> Result<(), Denied> {
+    match state.users.read().unwrap().get(user) {
+        Some(user) => user.permissions.check(command),
+        None => Err(Denied::Command),
+    }
+}
+
+/// Whether the connection's user may read or write a namespace
+fn namespace_allowed(
+    conn: &Connection,
+    state: &Arc<AppState>,
+    namespace: &str,
+    access: Access,
+) -> bool {
+    let Some(ref user) = conn.user else {
+        return false;
+    };
+    state
+        .users
+        .read()
+        .unwrap()
+        .get(user)
+        .is_some_and(|user| user.permissions.allows_namespace(namespace, access))
+}
+
+/// Reply with a MOVED redirect when `key` is owned by another cluster node.
+/// Returns `true` if the redirect was sent and the command must not run locally.
+async fn redirect_if_remote(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: &[u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if let Some(ref cluster_manager) = state.cluster_manager
+        && !cluster_manager.should_handle_locally(key).await
+        && let Some(redirect) = cluster_manager.get_redirect_response(key).await
+    {
+        let response = BytesFrame::Error(redirect.into());
+        conn.write_frame(&response).await?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Same as `redirect_if_remote` for namespaced (hash) operations.
+async fn redirect_hash_if_remote(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: &str,
+    key: &[u8],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if let Some(ref cluster_manager) = state.cluster_manager
+        && !cluster_manager
+            .should_handle_hash_locally(namespace, key)
+            .await
+        && let Some(redirect) = cluster_manager
+            .get_hash_redirect_response(namespace, key)
+            .await
+    {
+        let response = BytesFrame::Error(redirect.into());
+        conn.write_frame(&response).await?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+/// Compress a value for storage, framed with the codec that wrote it. Values
+/// the compression policy skips are stored uncompressed. Large values are
+/// compressed on the compression pool so they do not hold up other connections.
+async fn encode_value(
     state: &Arc<AppState>,
     data: Bytes,
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
-    if state.cfg.is_compression() {
-        if let Some(compressor) = &state.compressor {
-            return Ok(compressor.compress(&data).await?.into());
-        }
-    }
-    Ok(data)
+    let len = data.len();
+    let (encoded, outcome) = if state.codec.compresses() && state.compression_pool.offloads(len) {
+        let codec = state.codec.clone();
+        state
+            .compression_pool
+            .run(move || futures::executor::block_on(codec.encode_with_outcome(&data)))
+            .await??
+    } else {
+        state.codec.encode_with_outcome(&data).await?
+    };
+    state
+        .metrics
+        .record_compression(outcome, len, encoded.len());
+    Ok(encoded.into())
 }
 
-async fn decompress_if_enabled(
+/// Decode a stored value, whichever codec wrote it. Large compressed values
+/// are decoded on the compression pool.
+async fn decode_value(
     state: &Arc<AppState>,
     data: Bytes,
 ) -> Result<Bytes, Box<dyn std::error::Error>> {
-    if state.cfg.is_compression() {
-        if let Some(compressor) = &state.compressor {
-            return Ok(compressor.decompress(&data).await?.into());
+    let uncompressed =
+        parse_frame(&data).is_some_and(|frame| frame.algorithm == CompressionType::None);
+    if uncompressed || !state.compression_pool.offloads(data.len()) {
+        return Ok(state.codec.decode(&data).await?.into());
+    }
+
+    let codec = state.codec.clone();
+    let decoded = state
+        .compression_pool
+        .run(move || futures::executor::block_on(codec.decode(&data)))
+        .await??;
+    Ok(decoded.into())
+}
+
+/// Reply with an error if one of the values, given by their lengths, is too
+/// large to store (see `Cfg::value_fits`). Every write checks its values here
+/// before encoding them.
+async fn reject_oversized(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    lens: impl IntoIterator<Item = usize>,
+    namespaced: bool,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if lens
+        .into_iter()
+        .all(|len| state.cfg.value_fits(len as u64, namespaced))
+    {
+        return Ok(false);
+    }
+    let response = BytesFrame::Error("ERR string exceeds maximum allowed size".into());
+    conn.write_frame(&response).await?;
+    Ok(true)
+}
+
+/// Encode a value for storage. Values larger than `chunk_size` are split into
+/// chunks: the returned data is then their manifest, to be stored in `blobs`.
+async fn encode_stored_value(
+    state: &Arc<AppState>,
+    value: Bytes,
+) -> Result<(Bytes, Vec<(u32, Bytes)>), Box<dyn std::error::Error>> {
+    let chunk_size = state.cfg.chunk_size();
+    if value.len() <= chunk_size {
+        return Ok((encode_value(state, value).await?, Vec::new()));
+    }
+    let manifest = ChunkManifest::new(value.len() as u64, chunk_size as u32);
+    let chunks = encode_chunks(state, &manifest, 0, value).await?;
+    Ok((manifest.encode(), chunks))
+}
+
+/// Encode the bytes of a chunked value starting at chunk `first`, one chunk
+/// at a time. The chunks are compressed concurrently on the compression pool.
+async fn encode_chunks(
+    state: &Arc<AppState>,
+    manifest: &ChunkManifest,
+    first: u32,
+    data: Bytes,
+) -> Result<Vec<(u32, Bytes)>, Box<dyn std::error::Error>> {
+    let chunk_size = manifest.chunk_size as usize;
+    let encoded = (0..data.len().div_ceil(chunk_size)).map(|i| {
+        let chunk = data.slice(i * chunk_size..((i + 1) * chunk_size).min(data.len()));
+        async move {
+            // Errors are turned into strings, which unlike boxed errors are Send
+            match encode_value(state, chunk).await {
+                Ok(encoded) => Ok((first + i as u32, encoded)),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+    });
+    Ok(futures::future::try_join_all(encoded).await?)
+}
+
+/// Decode the chunks of a value and join them
+async fn decode_chunks(
+    state: &Arc<AppState>,
+    chunks: Vec<Bytes>,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let decoded = chunks
+        .into_iter()
+        .map(|chunk| async move { decode_value(state, chunk).await.map_err(|e| e.to_string()) });
+    Ok(futures::future::try_join_all(decoded).await?.concat())
+}
+
+/// The live data stored in `blobs` for a key and its version
+async fn fetch_stored(
+    conn: &mut SqliteConnection,
+    key: &[u8],
+) -> Result<Option<(Bytes, i64)>, sqlx::Error> {
+    sqlx::query_as::<_, (Vec<u8>, i64)>(
+        "SELECT data, version FROM blobs WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(key)
+    .bind(chrono::Utc::now().timestamp_millis())
+    .fetch_optional(conn)
+    .await
+    .map(|row| row.map(|(data, version)| (data.into(), version)))
+}
+
+/// Decode a value read from `blobs`, assembling it from its chunks when it
+/// is stored as chunks. Returns `None` if the key was deleted in between.
+async fn load_value(
+    state: &Arc<AppState>,
+    key: &[u8],
+    data: Bytes,
+) -> Result<Option<Bytes>, Box<dyn std::error::Error>> {
+    if !chunks::is_chunked(&data) {
+        return Ok(Some(decode_value(state, data).await?));
+    }
+    // Read the manifest again with the chunks so both come from one snapshot
+    let mut tx = state.db_pools[state.get_shard(key)].begin().await?;
+    let Some((data, _)) = fetch_stored(&mut tx, key).await? else {
+        return Ok(None);
+    };
+    let value = match ChunkManifest::parse(&data) {
+        Some(manifest) => {
+            let encoded = chunks::read_chunks(&mut tx, key, 0..manifest.chunk_count()).await?;
+            decode_chunks(state, encoded).await?.into()
+        }
+        None => decode_value(state, data).await?,
+    };
+    tx.commit().await?;
+    Ok(Some(value))
+}
+
+/// Number of chunks read per transaction when streaming a chunked value
+const STREAM_BATCH_CHUNKS: u32 = 4;
+
+/// Reply to GET with a value stored as chunks, decoding one chunk at a time
+/// so the whole value is never held in memory. Chunks are read a few at a
+/// time, each batch in its own short transaction that checks the manifest
+/// and version are unchanged, so a slow client never holds a snapshot or a
+/// pooled connection and a concurrent write cannot tear the value. Once the
+/// length has been sent, any failure closes the connection.
+async fn write_chunked_value(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pool = &state.db_pools[state.get_shard(key)];
+    let (data, version) = match fetch_stored(&mut *pool.acquire().await?, key).await? {
+        Some(stored) => stored,
+        None => {
+            conn.write_frame(&BytesFrame::Null).await?;
+            return Ok(());
+        }
+    };
+    let Some(manifest) = ChunkManifest::parse(&data) else {
+        let response = BytesFrame::BulkString(decode_value(state, data).await?);
+        conn.write_frame(&response).await?;
+        return Ok(());
+    };
+
+    conn.write_all(format!("${}\r\n", manifest.len).as_bytes())
+        .await?;
+    let count = manifest.chunk_count();
+    let mut start = 0;
+    while start < count {
+        let end = (start + STREAM_BATCH_CHUNKS).min(count);
+        let mut tx = pool.begin().await?;
+        let current = fetch_stored(&mut tx, key).await?;
+        if current.as_ref().map(|(d, v)| (d.as_ref(), *v)) != Some((data.as_ref(), version)) {
+            return Err(format!("key {:?} changed while it was being sent", key).into());
+        }
+        let encoded = chunks::read_chunks(&mut tx, key, start..end).await?;
+        tx.commit().await?;
+
+        for (idx, encoded) in (start..end).zip(encoded) {
+            let chunk = decode_value(state, encoded).await?;
+            let range = manifest.chunk_range(idx);
+            if chunk.len() as u64 != range.end - range.start {
+                return Err(format!("chunk {} of key {:?} has the wrong length", idx, key).into());
+            }
+            conn.write_all(&chunk).await?;
         }
+        start = end;
+    }
+    conn.write_all(b"\r\n").await?;
+    Ok(())
+}
+
+/// The bytes of a value of `len` bytes selected by the inclusive, possibly
+/// negative, offsets of GETRANGE
+fn getrange_bounds(len: u64, start: i64, end: i64) -> Option<Range<u64>> {
+    let len = len as i64;
+    let start = if start < 0 { len + start } else { start }.max(0);
+    let end = if end < 0 { len + end } else { end }.max(0).min(len - 1);
+    if len == 0 || start > end {
+        return None;
     }
-    Ok(data)
+    Some(start as u64..end as u64 + 1)
 }
 
 async fn handle_get(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
-    key: String,
+    key: Bytes,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // First check inflight cache for pending writes
-    if let Some(data) = state.inflight_cache.get(&key).await {
-        // Decompress if needed
-        let data = decompress_if_enabled(state, data).await?;
+    if let Some(data) = state.inflight_cache.get(&key) {
+        // Decode with the codec that wrote it
+        let data = decode_value(state, data).await?;
 
-        let response = BytesFrame::BulkString(data.into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        let response = BytesFrame::BulkString(data);
+        conn.write_frame(&response).await?;
         state.metrics.record_cache_hit();
         return Ok(());
     }
@@ -302,28 +991,41 @@ async fn handle_get(
     match sqlx::query_as::<_, (Vec<u8>,)>(
         "SELECT data FROM blobs WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
     )
-    .bind(&key)
-    .bind(chrono::Utc::now().timestamp())
+    .bind(&key[..])
+    .bind(chrono::Utc::now().timestamp_millis())
     .fetch_optional(pool)
     .await
     {
+        Ok(Some(row)) if chunks::is_chunked(&row.0) => {
+            // Streamed on the real connection rather than collected whole
+            if conn.is_detached() {
+                conn.defer();
+                return Ok(());
+            }
+            if let Err(e) = write_chunked_value(conn, state, &key).await {
+                tracing::error!("Failed to GET chunked key {:?}: {}", key, e);
+                state.metrics.record_error("storage");
+                return Err(e);
+            }
+            state.metrics.record_cache_hit();
+        }
         Ok(Some(row)) => {
-            // Decompress if needed
-            let data = decompress_if_enabled(state, row.0.into()).await?;
+            // Decode with the codec that wrote it
+            let data = decode_value(state, row.0.into()).await?;
 
-            let response = BytesFrame::BulkString(data.into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            let response = BytesFrame::BulkString(data);
+            conn.write_frame(&response).await?;
             state.metrics.record_cache_hit();
         }
         Ok(None) => {
             let response = BytesFrame::Null;
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
             state.metrics.record_cache_miss();
         }
         Err(e) => {
-            tracing::error!("Failed to GET key {}: {}", key, e);
+            tracing::error!("Failed to GET key {:?}: {}", key, e);
             let response = BytesFrame::Error("ERR database error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
             state.metrics.record_error("storage");
         }
     }
@@ -332,38 +1034,48 @@ async fn handle_get(
 }
 
 async fn handle_set(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
-    key: String,
+    key: Bytes,
     value: Bytes,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    // Chunked values are always written synchronously, all chunks at once
+    if value.len() > state.cfg.chunk_size() {
+        return handle_set_with_options(conn, state, key, value, SetOptions::default()).await;
+    }
+
     let shard_index = state.get_shard(&key);
     let sender = &state.shard_senders[shard_index];
 
-    let value = compress_if_enabled(state, value).await?;
+    let value = encode_value(state, value).await?;
 
     // Check if async_write is enabled
     if state.cfg.async_write.unwrap_or(false) {
         // Store in inflight cache to prevent race conditions
+        let inflight_seq = state.inflight_cache.next_seq();
         state
             .inflight_cache
-            .insert(key.clone(), value.clone())
-            .await;
+            .insert(key.clone(), value.clone(), inflight_seq);
 
         // Async mode: respond immediately after queueing
-        let operation = ShardWriteOperation::SetAsync { key, data: value };
+        let operation = ShardWriteOperation::SetAsync {
+            key,
+            data: value,
+            inflight_seq,
+        };
 
-        if sender.send(operation).await.is_err() {
+        if let Err(e) = state.send_async(shard_index, operation).await {
             tracing::error!(
-                "Failed to send ASYNC SET operation to shard {}",
-                shard_index
+                "Failed to send ASYNC SET operation to shard {}: {}",
+                shard_index,
+                e
             );
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
             state.metrics.record_error("storage");
         } else {
             let response = BytesFrame::SimpleString("OK".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
             state.metrics.record_storage_operation();
         }
     } else {
@@ -379,25 +1091,25 @@ async fn handle_set(
         if sender.send(operation).await.is_err() {
             tracing::error!("Failed to send SET operation to shard {}", shard_index);
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
             state.metrics.record_error("storage");
         } else {
             match responder_rx.await {
                 Ok(Ok(())) => {
                     let response = BytesFrame::SimpleString("OK".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                     state.metrics.record_storage_operation();
                 }
                 Ok(Err(e)) => {
                     tracing::error!("Shard writer failed for SET: {}", e);
                     let response = BytesFrame::Error("ERR database error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                     state.metrics.record_error("storage");
                 }
                 Err(_) => {
                     tracing::error!("Shard writer task cancelled or panicked for SET ");
                     let response = BytesFrame::Error("ERR internal error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                     state.metrics.record_error("storage");
                 }
             }
@@ -407,140 +1119,1159 @@ async fn handle_set(
     Ok(())
 }
 
-async fn handle_del(
-    stream: &mut TcpStream,
+async fn handle_set_with_options(
+    conn: &mut Connection,
     state: &Arc<AppState>,
-    key: String,
+    key: Bytes,
+    value: Bytes,
+    options: SetOptions,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let shard_index = state.get_shard(&key);
+    let sender = &state.shard_senders[shard_index];
 
-    // First check if key exists
-    let pool = &state.db_pools[shard_index];
-    let exists = sqlx::query("SELECT 1 FROM blobs WHERE key = ?")
-        .bind(&key)
-        .fetch_optional(pool)
-        .await
-        .map(|row| row.is_some())
-        .unwrap_or(false);
+    let (value, chunks) = encode_stored_value(state, value).await?;
 
-    if !exists {
-        // Redis DEL returns the number of keys deleted
-        let response = BytesFrame::Integer(0);
-        stream.write_all(&serialize_frame(&response)).await?;
-        return Ok(());
-    }
+    let ttl = match options.expiry {
+        Some(SetExpiry::KeepTtl) => TtlUpdate::Keep,
+        Some(expiry) => {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            // to_unix_millis only returns None for KEEPTTL
+            TtlUpdate::At(expiry.to_unix_millis(now_ms).unwrap_or(now_ms))
+        }
+        None => TtlUpdate::Clear,
+    };
 
-    let sender = &state.shard_senders[shard_index];
-
-    // Check if async_write is enabled
-    if state.cfg.async_write.unwrap_or(false) {
-        // Remove from inflight cache immediately for delete operations
-        state.inflight_cache.invalidate(&key).await;
+    // Conditional writes always go through the writer synchronously, even with
+    // async_write enabled, since the reply depends on the stored state.
+    let (responder_tx, responder_rx) = oneshot::channel();
+    let operation = ShardWriteOperation::SetWithOptions {
+        key,
+        data: value,
+        chunks,
+        ttl,
+        condition: options.condition,
+        get: options.get,
+        responder: responder_tx,
+    };
 
-        // Async mode: respond immediately after queueing
-        let operation = ShardWriteOperation::DeleteAsync { key };
+    if sender.send(operation).await.is_err() {
+        tracing::error!("Failed to send SET operation to shard {}", shard_index);
+        let response = BytesFrame::Error("ERR internal error ".into());
+        conn.write_frame(&response).await?;
+        state.metrics.record_error("storage");
+        return Ok(());
+    }
 
-        if sender.send(operation).await.is_err() {
-            tracing::error!(
-                "Failed to send ASYNC DELETE operation to shard {}",
-                shard_index
-            );
+    match responder_rx.await {
+        Ok(Ok(outcome)) => {
+            let response = if options.get {
+                match outcome.previous {
+                    Some(previous) if chunks::is_chunked(&previous) => BytesFrame::BulkString(
+                        decode_chunks(state, outcome.previous_chunks).await?.into(),
+                    ),
+                    Some(previous) => BytesFrame::BulkString(decode_value(state, previous).await?),
+                    None => BytesFrame::Null,
+                }
+            } else if outcome.applied {
+                BytesFrame::SimpleString("OK".into())
+            } else {
+                BytesFrame::Null
+            };
+            conn.write_frame(&response).await?;
+            if outcome.applied {
+                state.metrics.record_storage_operation();
+            }
+        }
+        Ok(Err(e)) => {
+            tracing::error!("Shard writer failed for SET: {}", e);
+            let response = BytesFrame::Error("ERR database error ".into());
+            conn.write_frame(&response).await?;
+            state.metrics.record_error("storage");
+        }
+        Err(_) => {
+            tracing::error!("Shard writer task cancelled or panicked for SET ");
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
-        } else {
-            // Assume success for async mode
-            let response = BytesFrame::Integer(1);
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
+            state.metrics.record_error("storage");
         }
-    } else {
-        // Sync mode: wait for completion
-        let (responder_tx, responder_rx) = oneshot::channel();
+    }
 
-        let operation = ShardWriteOperation::Delete {
-            key,
-            responder: responder_tx,
-        };
+    Ok(())
+}
 
-        if sender.send(operation).await.is_err() {
-            tracing::error!("Failed to send DELETE operation to shard {}", shard_index);
-            let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
-        } else {
-            match responder_rx.await {
-                Ok(Ok(())) => {
-                    let response = BytesFrame::Integer(1);
-                    stream.write_all(&serialize_frame(&response)).await?;
-                }
-                Ok(Err(e)) => {
-                    tracing::error!("Shard writer failed for DELETE: {}", e);
+async fn handle_strlen(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: Bytes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let data = match state.inflight_cache.get(&key) {
+        Some(data) => Some(data),
+        None => {
+            let pool = &state.db_pools[state.get_shard(&key)];
+            let stored = match pool.acquire().await {
+                Ok(mut conn) => fetch_stored(&mut conn, &key).await,
+                Err(e) => Err(e),
+            };
+            match stored {
+                Ok(stored) => stored.map(|(data, _)| data),
+                Err(e) => {
+                    tracing::error!("Failed to STRLEN key {:?}: {}", key, e);
+                    state.metrics.record_error("storage");
                     let response = BytesFrame::Error("ERR database error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
-                }
-                Err(_) => {
-                    tracing::error!("Shard writer task cancelled or panicked for DELETE ");
-                    let response = BytesFrame::Error("ERR internal error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
+                    return Ok(());
                 }
             }
         }
+    };
+
+    // The manifest of a chunked value knows its length
+    let len = match data {
+        Some(data) => match ChunkManifest::parse(&data) {
+            Some(manifest) => manifest.len,
+            None => decode_value(state, data).await?.len() as u64,
+        },
+        None => 0,
+    };
+    let response = BytesFrame::Integer(len as i64);
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+async fn handle_getrange(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: Bytes,
+    start: i64,
+    end: i64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = match read_range(state, &key, start, end).await {
+        Ok(data) => BytesFrame::BulkString(data),
+        Err(e) => {
+            tracing::error!("Failed to GETRANGE key {:?}: {}", key, e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
+        }
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// The bytes GETRANGE selects. Only the chunks holding them are read from a
+/// chunked value.
+async fn read_range(
+    state: &Arc<AppState>,
+    key: &Bytes,
+    start: i64,
+    end: i64,
+) -> Result<Bytes, Box<dyn std::error::Error>> {
+    let slice = |value: Bytes| match getrange_bounds(value.len() as u64, start, end) {
+        Some(range) => value.slice(range.start as usize..range.end as usize),
+        None => Bytes::new(),
+    };
+
+    if let Some(data) = state.inflight_cache.get(key) {
+        return Ok(slice(decode_value(state, data).await?));
+    }
+
+    let mut tx = state.db_pools[state.get_shard(key)].begin().await?;
+    let Some((data, _)) = fetch_stored(&mut tx, key).await? else {
+        return Ok(Bytes::new());
+    };
+    let Some(manifest) = ChunkManifest::parse(&data) else {
+        return Ok(slice(decode_value(state, data).await?));
+    };
+    let Some(range) = getrange_bounds(manifest.len, start, end) else {
+        return Ok(Bytes::new());
+    };
+
+    let touched = manifest.chunks_for(range.start, range.end);
+    let base = manifest.chunk_range(touched.start).start;
+    let encoded = chunks::read_chunks(&mut tx, key, touched).await?;
+    tx.commit().await?;
+    let joined = decode_chunks(state, encoded).await?;
+    let (start, end) = ((range.start - base) as usize, (range.end - base) as usize);
+    if joined.len() < end {
+        return Err(format!("chunks of key {:?} are shorter than their manifest", key).into());
     }
+    Ok(Bytes::from(joined).slice(start..end))
+}
+
+/// Attempts at APPEND or SETRANGE before giving up on a key that keeps
+/// changing under it
+const RANGE_WRITE_ATTEMPTS: u32 = 5;
+
+/// Pause after a conflicting attempt, doubled after each one
+const RANGE_WRITE_BACKOFF: Duration = Duration::from_millis(5);
+
+/// Result of one attempt at APPEND or SETRANGE
+enum RangeWrite {
+    /// Written; the new length of the value
+    Written(u64),
+    /// The key changed since it was read
+    Conflict,
+    /// The value would exceed `max_value_size`
+    TooLarge,
+}
+
+/// SETRANGE, or APPEND when `offset` is `None`. The write is computed from
+/// the value as read and only stored if the key did not change meanwhile,
+/// otherwise it is computed again from a fresh read, a few times at most.
+async fn handle_write_range(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: Bytes,
+    offset: Option<u64>,
+    value: Bytes,
+    op_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut attempt = 0;
+    let response = loop {
+        match write_range(state, &key, offset, &value, op_name).await {
+            Ok(RangeWrite::Written(len)) => {
+                state.metrics.record_storage_operation();
+                break BytesFrame::Integer(len as i64);
+            }
+            Ok(RangeWrite::Conflict) if attempt + 1 < RANGE_WRITE_ATTEMPTS => {
+                tokio::time::sleep(RANGE_WRITE_BACKOFF * 2u32.pow(attempt)).await;
+                attempt += 1;
+            }
+            Ok(RangeWrite::Conflict) => {
+                tracing::warn!("Giving up {} of key {:?}: it keeps changing", op_name, key);
+                break BytesFrame::Error(
+                    format!(
+                        "ERR {} conflicted with concurrent writes, try again",
+                        op_name
+                    )
+                    .into(),
+                );
+            }
+            Ok(RangeWrite::TooLarge) => {
+                break BytesFrame::Error("ERR string exceeds maximum allowed size".into());
+            }
+            Err(response) => break response,
+        }
+    };
+    conn.write_frame(&response).await?;
 
     Ok(())
 }
 
-async fn handle_exists(
-    stream: &mut TcpStream,
+async fn write_range(
+    state: &Arc<AppState>,
+    key: &Bytes,
+    offset: Option<u64>,
+    value: &Bytes,
+    op_name: &str,
+) -> Result<RangeWrite, BytesFrame> {
+    let storage_error = |e: Box<dyn std::error::Error>| {
+        tracing::error!("Failed to {} key {:?}: {}", op_name, key, e);
+        state.metrics.record_error("storage");
+        BytesFrame::Error("ERR database error ".into())
+    };
+
+    let shard_index = state.get_shard(key);
+    let mut conn = state.db_pools[shard_index]
+        .acquire()
+        .await
+        .map_err(|e| storage_error(e.into()))?;
+    let stored = fetch_stored(&mut conn, key)
+        .await
+        .map_err(|e| storage_error(e.into()))?;
+    let manifest = stored
+        .as_ref()
+        .and_then(|(data, _)| ChunkManifest::parse(data));
+    // Values that are not chunked are small enough to be rewritten whole
+    let current = match (&stored, manifest) {
+        (Some((data, _)), None) => Some(
+            decode_value(state, data.clone())
+                .await
+                .map_err(storage_error)?,
+        ),
+        _ => None,
+    };
+
+    let len = match (manifest, &current) {
+        (Some(manifest), _) => manifest.len,
+        (None, Some(current)) => current.len() as u64,
+        (None, None) => 0,
+    };
+    // Like Redis, an empty SETRANGE never creates the key, an empty APPEND does
+    if value.is_empty() && (stored.is_some() || offset.is_some()) {
+        return Ok(RangeWrite::Written(len));
+    }
+    let offset = offset.unwrap_or(len);
+    let end = offset + value.len() as u64;
+    let new_len = len.max(end);
+    if !state.cfg.value_fits(new_len, false) {
+        return Ok(RangeWrite::TooLarge);
+    }
+
+    let (data, chunks) = match manifest {
+        // Only the chunks the write touches are read and rewritten
+        Some(manifest) => {
+            let updated = ChunkManifest {
+                len: new_len,
+                ..manifest
+            };
+            let touched = updated.chunks_for(offset.min(len), end);
+            let base = updated.chunk_range(touched.start).start;
+            let existing = touched.start..touched.end.min(manifest.chunk_count());
+            let encoded = chunks::read_chunks(&mut conn, key, existing)
+                .await
+                .map_err(|e| storage_error(e.into()))?;
+            let mut buffer = decode_chunks(state, encoded).await.map_err(storage_error)?;
+            // Zero padded like Redis when writing past the end
+            buffer.resize(
+                (updated.chunk_range(touched.end - 1).end - base) as usize,
+                0,
+            );
+            buffer[(offset - base) as usize..(end - base) as usize].copy_from_slice(value);
+            let chunks = encode_chunks(state, &updated, touched.start, buffer.into())
+                .await
+                .map_err(storage_error)?;
+            (updated.encode(), chunks)
+        }
+        None => {
+            let mut buffer = current.map(|c| c.to_vec()).unwrap_or_default();
+            buffer.resize(new_len as usize, 0);
+            buffer[offset as usize..end as usize].copy_from_slice(value);
+            encode_stored_value(state, buffer.into())
+                .await
+                .map_err(storage_error)?
+        }
+    };
+    drop(conn);
+
+    let (responder_tx, responder_rx) = oneshot::channel();
+    let operation = ShardWriteOperation::WriteRange {
+        key: key.clone(),
+        data,
+        chunks,
+        expected: stored,
+        responder: responder_tx,
+    };
+    queue_write(state, shard_index, operation, op_name).await?;
+    match wait_for_write(state, responder_rx, op_name).await? {
+        true => Ok(RangeWrite::Written(new_len)),
+        false => Ok(RangeWrite::Conflict),
+    }
+}
+
+async fn handle_expire(
+    conn: &mut Connection,
     state: &Arc<AppState>,
-    key: String,
+    key: Bytes,
+    expires_at: i64,
+    condition: Option<ExpireCondition>,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let shard_index = state.get_shard(&key);
+    let (responder_tx, responder_rx) = oneshot::channel();
+    let operation = ShardWriteOperation::Expire {
+        key,
+        expires_at,
+        condition,
+        responder: responder_tx,
+    };
+    send_expire_operation(conn, state, shard_index, operation, responder_rx, "EXPIRE").await
+}
+
+async fn handle_persist(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: Bytes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let shard_index = state.get_shard(&key);
+    let (responder_tx, responder_rx) = oneshot::channel();
+    let operation = ShardWriteOperation::Persist {
+        key,
+        responder: responder_tx,
+    };
+    send_expire_operation(conn, state, shard_index, operation, responder_rx, "PERSIST").await
+}
+
+/// Queue an EXPIRE/PERSIST write and reply with 1 if the key was changed, 0 otherwise.
+async fn send_expire_operation(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    shard_index: usize,
+    operation: ShardWriteOperation,
+    responder_rx: oneshot::Receiver<Result<ExpireOutcome, String>>,
+    op_name: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = if let Err(response) = queue_write(state, shard_index, operation, op_name).await
+    {
+        response
+    } else {
+        match wait_for_write(state, responder_rx, op_name).await {
+            Ok(ExpireOutcome::Updated | ExpireOutcome::Deleted) => BytesFrame::Integer(1),
+            Ok(ExpireOutcome::Missing | ExpireOutcome::Skipped) => BytesFrame::Integer(0),
+            Err(response) => response,
+        }
+    };
+    conn.write_frame(&response).await?;
+    Ok(())
+}
+
+/// Queue a write on a shard. On failure the error reply to send is returned.
+async fn queue_write(
+    state: &Arc<AppState>,
+    shard_index: usize,
+    operation: ShardWriteOperation,
+    op_name: &str,
+) -> Result<(), BytesFrame> {
+    let result = if operation.is_async() {
+        state.send_async(shard_index, operation).await
+    } else {
+        state.shard_senders[shard_index]
+            .send(operation)
+            .await
+            .map_err(|_| "shard writer stopped".to_string())
+    };
+    if let Err(e) = result {
+        tracing::error!(
+            "Failed to send {} operation to shard {}: {}",
+            op_name,
+            shard_index,
+            e
+        );
+        state.metrics.record_error("storage");
+        return Err(BytesFrame::Error("ERR internal error ".into()));
+    }
+    Ok(())
+}
+
+/// Wait for the result of a queued write. On failure the error reply to send is returned.
+async fn wait_for_write<T>(
+    state: &Arc<AppState>,
+    responder_rx: oneshot::Receiver<Result<T, String>>,
+    op_name: &str,
+) -> Result<T, BytesFrame> {
+    match responder_rx.await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => {
+            tracing::error!("Shard writer failed for {}: {}", op_name, e);
+            state.metrics.record_error("storage");
+            Err(BytesFrame::Error("ERR database error ".into()))
+        }
+        Err(_) => {
+            tracing::error!("Shard writer task cancelled or panicked for {} ", op_name);
+            state.metrics.record_error("storage");
+            Err(BytesFrame::Error("ERR internal error ".into()))
+        }
+    }
+}
+
+async fn handle_ttl(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    key: Bytes,
+    millis: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // A pending async write is always a plain SET, which carries no TTL
+    if state.inflight_cache.contains_key(&key) {
+        let response = BytesFrame::Integer(-1);
+        conn.write_frame(&response).await?;
+        return Ok(());
+    }
+
     let shard_index = state.get_shard(&key);
     let pool = &state.db_pools[shard_index];
+    let now_ms = chrono::Utc::now().timestamp_millis();
 
-    match sqlx::query(
-        "SELECT 1 FROM blobs WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+    let response = match sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT expires_at FROM blobs WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
     )
-    .bind(&key)
-    .bind(chrono::Utc::now().timestamp())
+    .bind(&key[..])
+    .bind(now_ms)
     .fetch_optional(pool)
     .await
     {
-        Ok(Some(_)) => {
-            let response = BytesFrame::Integer(1);
-            stream.write_all(&serialize_frame(&response)).await?;
+        Ok(Some((Some(expires_at),))) => BytesFrame::Integer(ttl_reply(expires_at, now_ms, millis)),
+        // Key exists but has no associated expire
+        Ok(Some((None,))) => BytesFrame::Integer(-1),
+        // Key does not exist
+        Ok(None) => BytesFrame::Integer(-2),
+        Err(e) => {
+            tracing::error!("Failed to read TTL for key {:?}: {}", key, e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
         }
-        Ok(None) => {
-            let response = BytesFrame::Integer(0);
-            stream.write_all(&serialize_frame(&response)).await?;
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// Remaining time to live for TTL/PTTL replies. Seconds are rounded like Redis does.
+fn ttl_reply(expires_at: i64, now_ms: i64, millis: bool) -> i64 {
+    let remaining = (expires_at - now_ms).max(0);
+    if millis {
+        remaining
+    } else {
+        (remaining + 500) / 1000
+    }
+}
+
+/// Maximum number of keys bound into a single `key IN (...)` lookup
+const MAX_KEYS_PER_QUERY: usize = 500;
+
+/// Look up the live rows of `keys` in `table_name`, querying every involved
+/// shard concurrently. Returns the data of each found key when `with_data` is
+/// set, `None` otherwise. Shards without the table have no rows.
+async fn fetch_live_rows(
+    state: &Arc<AppState>,
+    table_name: &str,
+    keys: &[Bytes],
+    with_data: bool,
+) -> Result<HashMap<Vec<u8>, Option<Vec<u8>>>, sqlx::Error> {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let mut by_shard: HashMap<usize, Vec<&Bytes>> = HashMap::new();
+    for key in keys {
+        by_shard.entry(state.get_shard(key)).or_default().push(key);
+    }
+
+    let lookups = by_shard.into_iter().map(|(shard_index, keys)| async move {
+        let pool = &state.db_pools[shard_index];
+        let mut rows = Vec::new();
+        for chunk in keys.chunks(MAX_KEYS_PER_QUERY) {
+            let query = format!(
+                "SELECT key, {} FROM {} WHERE key IN ({}) AND (expires_at IS NULL OR expires_at > ?)",
+                if with_data { "data" } else { "NULL" },
+                namespace::quote_identifier(table_name),
+                vec!["?"; chunk.len()].join(", ")
+            );
+            let mut query = sqlx::query_as::<_, (Vec<u8>, Option<Vec<u8>>)>(&query);
+            for key in chunk {
+                query = query.bind(&key[..]);
+            }
+            match query.bind(now_ms).fetch_all(pool).await {
+                Ok(found) => rows.extend(found),
+                Err(e) if is_missing_table(&e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok::<_, sqlx::Error>(rows)
+    });
+
+    let results = futures::future::try_join_all(lookups).await?;
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// Reply with a MOVED redirect if any of the keys is owned by another cluster node.
+async fn redirect_keys_if_remote(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    keys: &[Bytes],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    for key in keys {
+        if redirect_if_remote(conn, state, key).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Find which of `names` have a namespace table, grouped by shard
+async fn find_namespace_tables(
+    state: &Arc<AppState>,
+    names: &[String],
+) -> Result<BTreeMap<usize, Vec<String>>, sqlx::Error> {
+    // A name that is not a valid namespace cannot have a table
+    let names: Vec<&String> = names
+        .iter()
+        .filter(|name| namespace::validate(name).is_ok())
+        .collect();
+    let names = &names;
+    let lookups = state
+        .db_pools
+        .iter()
+        .enumerate()
+        .map(|(shard_index, pool)| async move {
+            let mut namespaces = Vec::new();
+            for chunk in names.chunks(MAX_KEYS_PER_QUERY) {
+                let query = format!(
+                    "SELECT name FROM sqlite_master WHERE type = 'table' AND name IN ({})",
+                    vec!["?"; chunk.len()].join(", ")
+                );
+                let mut query = sqlx::query_as::<_, (String,)>(&query);
+                for name in chunk {
+                    query = query.bind(namespace::table_name(name));
+                }
+                for (table_name,) in query.fetch_all(pool).await? {
+                    namespaces.extend(namespace::namespace_of(&table_name));
+                }
+            }
+            Ok::<_, sqlx::Error>((shard_index, namespaces))
+        });
+
+    let results = futures::future::try_join_all(lookups).await?;
+    Ok(results
+        .into_iter()
+        .filter(|(_, namespaces)| !namespaces.is_empty())
+        .collect())
+}
+
+/// DEL removes both the blob and the namespace of each name. A name counts as
+/// deleted if either held a live key. Namespaces are dropped synchronously,
+/// even with async_write, and in cluster mode only on the receiving node.
+async fn handle_del(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    mut keys: Vec<Bytes>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Redis DEL returns the number of keys deleted, so each key counts once
+    keys.sort();
+    keys.dedup();
+
+    // First check which keys exist (expired keys count as deleted already).
+    // A key with a pending async write exists even if it is not committed yet.
+    // Only a UTF-8 name can be a namespace
+    let names: Vec<String> = keys
+        .iter()
+        .filter_map(|key| std::str::from_utf8(key).ok().map(str::to_string))
+        .collect();
+    let lookup = futures::future::try_join(
+        fetch_live_rows(state, "blobs", &keys, false),
+        find_namespace_tables(state, &names),
+    )
+    .await;
+    let (stored, mut namespace_tables) = match lookup {
+        Ok(found) => found,
+        Err(e) => {
+            tracing::error!("Failed to check keys for DEL: {}", e);
+            let response = BytesFrame::Error("ERR database error ".into());
+            conn.write_frame(&response).await?;
+            state.metrics.record_error("storage");
+            return Ok(());
+        }
+    };
+
+    // A pending async HSET may target a shard whose table does not exist yet.
+    // Its write is queued ahead of the drop, so the drop still removes it.
+    for name in &names {
+        for field in state.inflight_hcache.fields(name) {
+            let namespaces = namespace_tables.entry(state.get_shard(&field)).or_default();
+            if !namespaces.contains(name) {
+                namespaces.push(name.clone());
+            }
+        }
+    }
+
+    // Dropping a namespace needs write access to it, not just to the key
+    if let Some(namespace) = namespace_tables
+        .values()
+        .flatten()
+        .find(|namespace| !namespace_allowed(conn, state, namespace, Access::Write))
+    {
+        tracing::debug!("Denied dropping namespace '{}'", namespace);
+        state.metrics.record_permission_denied();
+        let response = BytesFrame::Error(Denied::Namespace.message("", "DEL").into());
+        conn.write_frame(&response).await?;
+        return Ok(());
+    }
+
+    let mut deleted = HashSet::new();
+    let mut by_shard: BTreeMap<usize, Vec<Bytes>> = BTreeMap::new();
+    for key in keys {
+        if stored.contains_key(&key[..]) || state.inflight_cache.contains_key(&key) {
+            deleted.insert(key.clone());
+            by_shard.entry(state.get_shard(&key)).or_default().push(key);
+        }
+    }
+
+    let result = match delete_keys(state, by_shard).await {
+        Ok(()) => drop_namespaces(state, namespace_tables).await,
+        Err(response) => Err(response),
+    };
+    let response = match result {
+        Ok(dropped) => {
+            deleted.extend(dropped.into_iter().map(Bytes::from));
+            BytesFrame::Integer(deleted.len() as i64)
+        }
+        Err(response) => response,
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// Delete blobs, queueing one operation per shard. In sync mode this waits
+/// for all of them to be committed.
+async fn delete_keys(
+    state: &Arc<AppState>,
+    by_shard: BTreeMap<usize, Vec<Bytes>>,
+) -> Result<(), BytesFrame> {
+    // Check if async_write is enabled
+    if state.cfg.async_write.unwrap_or(false) {
+        for (shard_index, mut keys) in by_shard {
+            // Remove from inflight cache immediately for delete operations
+            for key in &keys {
+                state.inflight_cache.invalidate(key);
+            }
+            let operation = if keys.len() == 1 {
+                ShardWriteOperation::DeleteAsync {
+                    key: keys.remove(0),
+                }
+            } else {
+                ShardWriteOperation::DeleteManyAsync { keys }
+            };
+            queue_write(state, shard_index, operation, "ASYNC DELETE").await?;
+        }
+        return Ok(());
+    }
+
+    let mut pending = Vec::with_capacity(by_shard.len());
+    for (shard_index, mut keys) in by_shard {
+        let (responder_tx, responder_rx) = oneshot::channel();
+        let operation = if keys.len() == 1 {
+            ShardWriteOperation::Delete {
+                key: keys.remove(0),
+                responder: responder_tx,
+            }
+        } else {
+            ShardWriteOperation::DeleteMany {
+                keys,
+                responder: responder_tx,
+            }
+        };
+        queue_write(state, shard_index, operation, "DELETE").await?;
+        pending.push(responder_rx);
+    }
+    for responder_rx in pending {
+        wait_for_write(state, responder_rx, "DELETE").await?;
+    }
+    Ok(())
+}
+
+/// Drop namespace tables on the shards that have them. Returns the namespaces
+/// that held at least one live key.
+async fn drop_namespaces(
+    state: &Arc<AppState>,
+    tables: BTreeMap<usize, Vec<String>>,
+) -> Result<HashSet<String>, BytesFrame> {
+    let mut pending = Vec::new();
+    let mut dropped = HashSet::new();
+    for (shard_index, namespaces) in tables {
+        for namespace in namespaces {
+            let (responder_tx, responder_rx) = oneshot::channel();
+            let operation = ShardWriteOperation::DropNamespace {
+                namespace: namespace.clone(),
+                responder: responder_tx,
+            };
+            queue_write(state, shard_index, operation, "DROP NAMESPACE").await?;
+            pending.push((namespace.clone(), responder_rx));
+            dropped.insert(namespace);
+        }
+    }
+
+    // Pending async HSETs of a dropped namespace must not be served anymore
+    for namespace in &dropped {
+        state.inflight_hcache.invalidate_namespace(namespace);
+    }
+
+    let mut had_live_keys = HashSet::new();
+    for (namespace, responder_rx) in pending {
+        if wait_for_write(state, responder_rx, "DROP NAMESPACE").await? {
+            had_live_keys.insert(namespace);
+        }
+    }
+    Ok(had_live_keys)
+}
+
+async fn handle_exists(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    keys: Vec<Bytes>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = match fetch_live_rows(state, "blobs", &keys, false).await {
+        // Like Redis, a key mentioned several times is counted several times
+        Ok(stored) => BytesFrame::Integer(
+            keys.iter()
+                .filter(|key| {
+                    stored.contains_key(&key[..]) || state.inflight_cache.contains_key(key)
+                })
+                .count() as i64,
+        ),
+        Err(e) => {
+            tracing::error!("Failed to check EXISTS for keys {:?}: {}", keys, e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
         }
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+async fn handle_mget(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    keys: Vec<Bytes>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Pending async writes are served from the inflight cache, the rest from the shards
+    let mut values = Vec::with_capacity(keys.len());
+    let mut missing = Vec::new();
+    for key in &keys {
+        let value = state.inflight_cache.get(key);
+        if value.is_none() {
+            missing.push(key.clone());
+        }
+        values.push(value);
+    }
+
+    let stored = match fetch_live_rows(state, "blobs", &missing, true).await {
+        Ok(stored) => stored,
         Err(e) => {
-            tracing::error!("Failed to check EXISTS for key {}: {}", key, e);
+            tracing::error!("Failed to MGET keys {:?}: {}", missing, e);
             let response = BytesFrame::Error("ERR database error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
+            state.metrics.record_error("storage");
+            return Ok(());
+        }
+    };
+
+    let mut items = Vec::with_capacity(keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        let value = value.or_else(|| stored.get(&key[..]).cloned().flatten().map(Bytes::from));
+        let value = match value {
+            Some(data) => load_value(state, key, data).await?,
+            None => None,
+        };
+        match value {
+            Some(data) => {
+                items.push(BytesFrame::BulkString(data));
+                state.metrics.record_cache_hit();
+            }
+            None => {
+                items.push(BytesFrame::Null);
+                state.metrics.record_cache_miss();
+            }
+        }
+    }
+
+    let response = BytesFrame::Array(items);
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// Entries of a multi-key write owned by one shard, with the chunks of the
+/// values stored as chunks
+#[derive(Default)]
+struct ShardEntries {
+    entries: Vec<(Bytes, Bytes)>,
+    chunks: Vec<(Bytes, Vec<(u32, Bytes)>)>,
+}
+
+/// Compress the values of MSET/MSETNX and group them by owning shard
+async fn group_entries_by_shard(
+    state: &Arc<AppState>,
+    entries: Vec<(Bytes, Bytes)>,
+) -> Result<BTreeMap<usize, ShardEntries>, Box<dyn std::error::Error>> {
+    let mut by_shard: BTreeMap<usize, ShardEntries> = BTreeMap::new();
+    for (key, value) in entries {
+        let (value, chunks) = encode_stored_value(state, value).await?;
+        let shard = by_shard.entry(state.get_shard(&key)).or_default();
+        if !chunks.is_empty() {
+            shard.chunks.push((key.clone(), chunks));
+        }
+        shard.entries.push((key, value));
+    }
+    Ok(by_shard)
+}
+
+async fn handle_mset(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    entries: Vec<(Bytes, Bytes)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let by_shard = group_entries_by_shard(state, entries).await?;
+
+    // Like SET, values stored as chunks are always written synchronously
+    let chunked = by_shard.values().any(|shard| !shard.chunks.is_empty());
+    if state.cfg.async_write.unwrap_or(false) && !chunked {
+        // Async mode: respond immediately after queueing
+        for (shard_index, ShardEntries { entries, .. }) in by_shard {
+            // Store in inflight cache to prevent race conditions
+            let inflight_seq = state.inflight_cache.next_seq();
+            for (key, value) in &entries {
+                state
+                    .inflight_cache
+                    .insert(key.clone(), value.clone(), inflight_seq);
+            }
+            let operation = ShardWriteOperation::MSetAsync {
+                entries,
+                inflight_seq,
+            };
+            if let Err(response) = queue_write(state, shard_index, operation, "ASYNC MSET").await {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+        }
+    } else {
+        // Sync mode: queue one batch per shard, then wait for all of them
+        let mut pending = Vec::with_capacity(by_shard.len());
+        for (shard_index, ShardEntries { entries, chunks }) in by_shard {
+            let (responder_tx, responder_rx) = oneshot::channel();
+            let operation = ShardWriteOperation::MSet {
+                entries,
+                chunks,
+                responder: responder_tx,
+            };
+            if let Err(response) = queue_write(state, shard_index, operation, "MSET").await {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+            pending.push(responder_rx);
+        }
+        for responder_rx in pending {
+            if let Err(response) = wait_for_write(state, responder_rx, "MSET").await {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+        }
+    }
+
+    let response = BytesFrame::SimpleString("OK".into());
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// MSETNX is always synchronous since the reply depends on the stored state.
+/// The keys are checked and set in one transaction of their shard, so keys
+/// spanning shards are refused rather than set only in part.
+async fn handle_msetnx(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    entries: Vec<(Bytes, Bytes)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let shard_index = entries.first().map(|(key, _)| state.get_shard(key));
+    if entries
+        .iter()
+        .any(|(key, _)| Some(state.get_shard(key)) != shard_index)
+    {
+        let response =
+            BytesFrame::Error("CROSSSLOT Keys in request don't hash to the same shard".into());
+        conn.write_frame(&response).await?;
+        return Ok(());
+    }
+
+    // Keys with a pending async write already exist
+    if entries
+        .iter()
+        .any(|(key, _)| state.inflight_cache.contains_key(key))
+    {
+        let response = BytesFrame::Integer(0);
+        conn.write_frame(&response).await?;
+        return Ok(());
+    }
+
+    let by_shard = group_entries_by_shard(state, entries).await?;
+
+    let mut pending = Vec::with_capacity(by_shard.len());
+    for (shard_index, ShardEntries { entries, chunks }) in by_shard {
+        let (responder_tx, responder_rx) = oneshot::channel();
+        let operation = ShardWriteOperation::MSetNx {
+            entries,
+            chunks,
+            responder: responder_tx,
+        };
+        if let Err(response) = queue_write(state, shard_index, operation, "MSETNX").await {
+            conn.write_frame(&response).await?;
+            return Ok(());
+        }
+        pending.push(responder_rx);
+    }
+
+    let mut all_set = true;
+    for responder_rx in pending {
+        match wait_for_write(state, responder_rx, "MSETNX").await {
+            Ok(applied) => all_set &= applied,
+            Err(response) => {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
         }
     }
 
+    let response = BytesFrame::Integer(all_set as i64);
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// Number of keys examined per SCAN/HSCAN call when no COUNT is given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Parse a client supplied cursor, replying with an error if it is invalid
+async fn parse_scan_cursor(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    cursor: &str,
+) -> Result<Option<ScanCursor>, Box<dyn std::error::Error>> {
+    match ScanCursor::parse(cursor) {
+        Some(cursor) if cursor.shard < state.db_pools.len() => Ok(Some(cursor)),
+        _ => {
+            let response = BytesFrame::Error("ERR invalid cursor".into());
+            conn.write_frame(&response).await?;
+            Ok(None)
+        }
+    }
+}
+
+/// SCAN walks the shards of this node in order. In cluster mode it only
+/// covers the keys stored locally, like SCAN against a single Redis node.
+async fn handle_scan(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    cursor: String,
+    options: ScanOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(cursor) = parse_scan_cursor(conn, state, &cursor).await? else {
+        return Ok(());
+    };
+
+    let response = match scan_table(
+        &state.db_pools,
+        "blobs",
+        cursor,
+        options.count.unwrap_or(DEFAULT_SCAN_COUNT),
+        options.pattern.as_deref(),
+        false,
+        chrono::Utc::now().timestamp_millis(),
+    )
+    .await
+    {
+        Ok((next, entries)) => BytesFrame::Array(vec![
+            BytesFrame::BulkString(next.to_string().into()),
+            BytesFrame::Array(
+                entries
+                    .into_iter()
+                    .map(|(key, _)| BytesFrame::BulkString(key.into()))
+                    .collect(),
+            ),
+        ]),
+        Err(e) => {
+            tracing::error!("Failed to SCAN: {}", e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
+        }
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+async fn handle_keys(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    pattern: Bytes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let response = match scan_table(
+        &state.db_pools,
+        "blobs",
+        ScanCursor::START,
+        usize::MAX,
+        Some(&pattern[..]),
+        false,
+        chrono::Utc::now().timestamp_millis(),
+    )
+    .await
+    {
+        Ok((_, entries)) => BytesFrame::Array(
+            entries
+                .into_iter()
+                .map(|(key, _)| BytesFrame::BulkString(key.into()))
+                .collect(),
+        ),
+        Err(e) => {
+            tracing::error!("Failed to run KEYS {:?}: {}", pattern, e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
+        }
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// HSCAN iterates the keys of a namespace, which are spread over every shard.
+async fn handle_hscan(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    cursor: String,
+    options: ScanOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let table_name = namespace::table_name(&namespace);
+    let Some(cursor) = parse_scan_cursor(conn, state, &cursor).await? else {
+        return Ok(());
+    };
+
+    let (next, entries) = match scan_table(
+        &state.db_pools,
+        &table_name,
+        cursor,
+        options.count.unwrap_or(DEFAULT_SCAN_COUNT),
+        options.pattern.as_deref(),
+        !options.novalues,
+        chrono::Utc::now().timestamp_millis(),
+    )
+    .await
+    {
+        Ok(page) => page,
+        Err(e) => {
+            tracing::error!("Failed to HSCAN namespace {}: {}", namespace, e);
+            state.metrics.record_error("storage");
+            let response = BytesFrame::Error("ERR database error ".into());
+            conn.write_frame(&response).await?;
+            return Ok(());
+        }
+    };
+
+    let mut items = Vec::with_capacity(entries.len() * 2);
+    for (key, data) in entries {
+        items.push(BytesFrame::BulkString(key.into()));
+        if let Some(data) = data {
+            items.push(BytesFrame::BulkString(
+                decode_value(state, data.into()).await?,
+            ));
+        }
+    }
+
+    let response = BytesFrame::Array(vec![
+        BytesFrame::BulkString(next.to_string().into()),
+        BytesFrame::Array(items),
+    ]);
+    conn.write_frame(&response).await?;
+
     Ok(())
 }
 
 async fn handle_ping(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     message: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let response = match message {
         Some(msg) => BytesFrame::BulkString(msg.into_bytes().into()),
         None => BytesFrame::SimpleString("PONG".into()),
     };
-    stream.write_all(&serialize_frame(&response)).await?;
+    conn.write_frame(&response).await?;
     Ok(())
 }
 
 async fn handle_info(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     section: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let info = match section.as_deref() {
         Some("server") | None => {
+            let storage = state.cfg.storage();
             format!(
                 "# Server\r\n\
                  redis_version:blobasaur-0.1.0\r\n\
@@ -557,13 +2288,29 @@ async fn handle_info(
                  data_dir:{}\r\n\
                  async_write:{}\r\n\
                  batch_size:{}\r\n\
-                 batch_timeout_ms:{}\r\n",
+                 batch_timeout_ms:{}\r\n\
+                 \r\n\
+                 # Storage\r\n\
+                 durability:{}\r\n\
+                 cache_size_kib:{}\r\n\
+                 mmap_size:{}\r\n\
+                 page_size:{}\r\n\
+                 auto_vacuum:{}\r\n\
+                 wal_autocheckpoint:{}\r\n\
+                 busy_timeout_ms:{}\r\n",
                 std::process::id(),
                 state.cfg.num_shards,
                 state.cfg.data_dir,
                 state.cfg.async_write.unwrap_or(false),
                 state.cfg.batch_size.unwrap_or(1),
-                state.cfg.batch_timeout_ms.unwrap_or(0)
+                state.cfg.batch_timeout_ms.unwrap_or(0),
+                storage.durability().name(),
+                storage.cache_size_kib(),
+                storage.mmap_size(),
+                storage.page_size(),
+                storage.auto_vacuum().name(),
+                storage.wal_autocheckpoint(),
+                storage.busy_timeout().as_millis()
             )
         }
         Some("stats") => "# Stats\r\n\
@@ -595,83 +2342,265 @@ async fn handle_info(
         Some(s) => format!("# {}\r\n(section not implemented)\r\n", s),
     };
 
-    let response = BytesFrame::BulkString(info.into_bytes().into());
-    stream.write_all(&serialize_frame(&response)).await?;
+    write_info(conn, info).await?;
     Ok(())
 }
 
-async fn handle_command(stream: &mut TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-    // Return a minimal COMMAND response - just an empty array for now
-    // A full implementation would return detailed command information
-    let response = BytesFrame::Array(vec![]);
-    stream.write_all(&serialize_frame(&response)).await?;
+/// Reply with `field:value` lines, as a bulk string for RESP2 clients and as
+/// a map of fields to values for RESP3 ones
+async fn write_info(conn: &mut Connection, info: String) -> Result<(), Box<dyn std::error::Error>> {
+    match conn.protocol {
+        ProtocolVersion::Resp2 => {
+            let response = BytesFrame::BulkString(info.into_bytes().into());
+            conn.write_frame(&response).await?;
+        }
+        ProtocolVersion::Resp3 => {
+            let entries: Vec<_> = info
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(field, value)| {
+                    (
+                        BytesFrame::BulkString(field.to_string().into()),
+                        BytesFrame::BulkString(value.to_string().into()),
+                    )
+                })
+                .collect();
+            conn.write_map(&entries).await?;
+        }
+    }
     Ok(())
 }
 
-async fn handle_hget(
-    stream: &mut TcpStream,
+async fn handle_hello(
+    conn: &mut Connection,
     state: &Arc<AppState>,
-    namespace: String,
-    key: String,
+    protover: Option<i64>,
+    auth: Option<(String, String)>,
+    setname: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Check if we should handle this hash operation locally in a cluster
-    if let Some(ref cluster_manager) = state.cluster_manager {
-        if !cluster_manager
-            .should_handle_hash_locally(&namespace, &key)
-            .await
-        {
-            if let Some(redirect) = cluster_manager
-                .get_hash_redirect_response(&namespace, &key)
-                .await
-            {
-                let response = BytesFrame::Error(redirect.into());
-                stream.write_all(&serialize_frame(&response)).await?;
+    let protocol = match protover {
+        Some(protover) => match ProtocolVersion::from_number(protover) {
+            Some(protocol) => protocol,
+            None => {
+                let response = BytesFrame::Error("NOPROTO unsupported protocol version".into());
+                conn.write_frame(&response).await?;
                 return Ok(());
             }
+        },
+        None => conn.protocol,
+    };
+    // Nothing is changed unless the credentials are valid
+    let user = match auth {
+        Some((username, password)) => {
+            let authenticated = state
+                .users
+                .read()
+                .unwrap()
+                .authenticate(Some(&username), password.as_bytes())
+                .map(|user| user.name.clone());
+            match authenticated {
+                Ok(user) => Some(user),
+                Err(e) => {
+                    state.metrics.record_auth_failure();
+                    conn.write_frame(&BytesFrame::Error(e.message().into()))
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+        None if conn.user.is_none() => {
+            let response = BytesFrame::Error(
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the client and select the RESP protocol version at the same time".into(),
+            );
+            conn.write_frame(&response).await?;
+            return Ok(());
+        }
+        None => None,
+    };
+    if let Some(name) = setname {
+        if name.bytes().any(|b| !(b'!'..=b'~').contains(&b)) {
+            let response = BytesFrame::Error(
+                "ERR Client names cannot contain spaces, newlines or special characters.".into(),
+            );
+            conn.write_frame(&response).await?;
+            return Ok(());
+        }
+        conn.name = (!name.is_empty()).then_some(name);
+    }
+    conn.protocol = protocol;
+    if user.is_some() {
+        conn.user = user;
+    }
+
+    let mode = if state.cluster_manager.is_some() {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    let entries = [
+        ("server", BytesFrame::BulkString("blobasaur".into())),
+        (
+            "version",
+            BytesFrame::BulkString(env!("CARGO_PKG_VERSION").into()),
+        ),
+        ("proto", BytesFrame::Integer(protocol.number())),
+        ("id", BytesFrame::Integer(conn.id as i64)),
+        ("mode", BytesFrame::BulkString(mode.into())),
+        ("role", BytesFrame::BulkString("master".into())),
+        ("modules", BytesFrame::Array(vec![])),
+    ]
+    .map(|(field, value)| (BytesFrame::BulkString(field.into()), value));
+    conn.write_map(&entries).await?;
+    Ok(())
+}
+
+async fn handle_auth(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    username: Option<String>,
+    password: Bytes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let authenticated = state
+        .users
+        .read()
+        .unwrap()
+        .authenticate(username.as_deref(), &password)
+        .map(|user| user.name.clone());
+    match authenticated {
+        Ok(user) => {
+            conn.user = Some(user);
+            conn.write_frame(&BytesFrame::SimpleString("OK".into()))
+                .await?;
         }
+        Err(e) => {
+            tracing::warn!(
+                "Failed authentication as '{}' on connection {}",
+                username.as_deref().unwrap_or(crate::auth::DEFAULT_USER),
+                conn.id
+            );
+            state.metrics.record_auth_failure();
+            conn.write_frame(&BytesFrame::Error(e.message().into()))
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn handle_acl_whoami(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+    let user = conn.user.clone().unwrap_or_default();
+    conn.write_frame(&BytesFrame::BulkString(user.into()))
+        .await?;
+    Ok(())
+}
+
+async fn handle_acl_list(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let users: Vec<BytesFrame> = state
+        .users
+        .read()
+        .unwrap()
+        .list()
+        .into_iter()
+        .map(|user| BytesFrame::BulkString(user.describe().into()))
+        .collect();
+    conn.write_frame(&BytesFrame::Array(users)).await?;
+    Ok(())
+}
+
+async fn handle_acl_setuser(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    username: String,
+    rules: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = state.users.write().unwrap().set_user(&username, &rules);
+    let response = match result {
+        Ok(()) => {
+            tracing::info!(
+                "User '{}' changed by '{}'",
+                username,
+                conn.user.as_deref().unwrap_or("")
+            );
+            BytesFrame::SimpleString("OK".into())
+        }
+        Err(e) => BytesFrame::Error(format!("ERR {}", e).into()),
+    };
+    conn.write_frame(&response).await?;
+    Ok(())
+}
+
+async fn handle_command(conn: &mut Connection) -> Result<(), Box<dyn std::error::Error>> {
+    // Return a minimal COMMAND response - just an empty array for now
+    // A full implementation would return detailed command information
+    let response = BytesFrame::Array(vec![]);
+    conn.write_frame(&response).await?;
+    Ok(())
+}
+
+async fn handle_hget(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    key: Bytes,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Check if we should handle this hash operation locally in a cluster
+    if redirect_hash_if_remote(conn, state, &namespace, &key).await? {
+        return Ok(());
     }
 
     // First check inflight cache for pending writes
     let namespaced_key = state.namespaced_key(&namespace, &key);
-    if let Some(data) = state.inflight_hcache.get(&namespaced_key).await {
-        // Decompress if needed
-        let data = decompress_if_enabled(state, data).await?;
+    if let Some(data) = state.inflight_hcache.get(&namespaced_key) {
+        // Decode with the codec that wrote it
+        let data = decode_value(state, data).await?;
 
-        let response = BytesFrame::BulkString(data.into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        let response = BytesFrame::BulkString(data);
+        conn.write_frame(&response).await?;
         return Ok(());
     }
 
     let shard_index = state.get_shard(&key);
     let pool = &state.db_pools[shard_index];
-    let table_name = format!("blobs_{}", namespace);
+    let table_name = namespace::table_name(&namespace);
 
     let query = format!(
         "SELECT data FROM {} WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
-        table_name
+        namespace::quote_identifier(&table_name)
     );
 
     match sqlx::query_as::<_, (Vec<u8>,)>(&query)
-        .bind(&key)
-        .bind(chrono::Utc::now().timestamp())
+        .bind(&key[..])
+        .bind(chrono::Utc::now().timestamp_millis())
         .fetch_optional(pool)
         .await
     {
         Ok(Some(row)) => {
-            // Decompress if needed
-            let data = decompress_if_enabled(state, row.0.into()).await?;
+            // Decode with the codec that wrote it
+            let data = decode_value(state, row.0.into()).await?;
 
-            let response = BytesFrame::BulkString(data.into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            let response = BytesFrame::BulkString(data);
+            conn.write_frame(&response).await?;
         }
         Ok(None) => {
             let response = BytesFrame::Null;
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
+        }
+        // The namespace has never been written on this shard, or was dropped by DEL
+        Err(e) if is_missing_table(&e) => {
+            let response = BytesFrame::Null;
+            conn.write_frame(&response).await?;
         }
         Err(e) => {
-            tracing::error!("Failed to HGET namespace {} key {}: {}", namespace, key, e);
+            tracing::error!(
+                "Failed to HGET namespace {} key {:?}: {}",
+                namespace,
+                key,
+                e
+            );
             let response = BytesFrame::Error("ERR database error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         }
     }
 
@@ -679,61 +2608,54 @@ async fn handle_hget(
 }
 
 async fn handle_hset(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     namespace: String,
-    key: String,
+    key: Bytes,
     value: Bytes,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Check if we should handle this hash operation locally in a cluster
-    if let Some(ref cluster_manager) = state.cluster_manager {
-        if !cluster_manager
-            .should_handle_hash_locally(&namespace, &key)
-            .await
-        {
-            if let Some(redirect) = cluster_manager
-                .get_hash_redirect_response(&namespace, &key)
-                .await
-            {
-                let response = BytesFrame::Error(redirect.into());
-                stream.write_all(&serialize_frame(&response)).await?;
-                return Ok(());
-            }
-        }
+    if redirect_hash_if_remote(conn, state, &namespace, &key).await? {
+        return Ok(());
+    }
+    if reject_oversized(conn, state, [value.len()], true).await? {
+        return Ok(());
     }
 
     let shard_index = state.get_shard(&key);
     let sender = &state.shard_senders[shard_index];
 
-    // Compress data if storage compression is enabled
-    let value = compress_if_enabled(state, value).await?;
+    // Compress and frame the value for storage
+    let value = encode_value(state, value).await?;
 
     // Check if async_write is enabled
     if state.cfg.async_write.unwrap_or(false) {
         // Store in inflight cache to prevent race conditions
         let namespaced_key = state.namespaced_key(&namespace, &key);
+        let inflight_seq = state.inflight_hcache.next_seq();
         state
             .inflight_hcache
-            .insert(namespaced_key, value.clone())
-            .await;
+            .insert(namespaced_key, value.clone(), inflight_seq);
 
         // Async mode: respond immediately after queueing
         let operation = ShardWriteOperation::HSetAsync {
             namespace,
             key,
             data: value,
+            inflight_seq,
         };
 
-        if sender.send(operation).await.is_err() {
+        if let Err(e) = state.send_async(shard_index, operation).await {
             tracing::error!(
-                "Failed to send ASYNC HSET operation to shard {}",
-                shard_index
+                "Failed to send ASYNC HSET operation to shard {}: {}",
+                shard_index,
+                e
             );
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         } else {
             let response = BytesFrame::SimpleString("OK".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         }
     } else {
         // Sync mode: wait for completion
@@ -749,22 +2671,22 @@ async fn handle_hset(
         if sender.send(operation).await.is_err() {
             tracing::error!("Failed to send HSET operation to shard {}", shard_index);
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         } else {
             match responder_rx.await {
                 Ok(Ok(())) => {
                     let response = BytesFrame::SimpleString("OK".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                 }
                 Ok(Err(e)) => {
                     tracing::error!("Shard writer failed for HSET: {}", e);
                     let response = BytesFrame::Error("ERR database error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                 }
                 Err(_) => {
                     tracing::error!("Shard writer task cancelled or panicked for HSET ");
                     let response = BytesFrame::Error("ERR internal error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                 }
             }
         }
@@ -774,36 +2696,28 @@ async fn handle_hset(
 }
 
 async fn handle_hdel(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     namespace: String,
-    key: String,
+    key: Bytes,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Check if we should handle this hash operation locally in a cluster
-    if let Some(ref cluster_manager) = state.cluster_manager {
-        if !cluster_manager
-            .should_handle_hash_locally(&namespace, &key)
-            .await
-        {
-            if let Some(redirect) = cluster_manager
-                .get_hash_redirect_response(&namespace, &key)
-                .await
-            {
-                let response = BytesFrame::Error(redirect.into());
-                stream.write_all(&serialize_frame(&response)).await?;
-                return Ok(());
-            }
-        }
+    if redirect_hash_if_remote(conn, state, &namespace, &key).await? {
+        return Ok(());
     }
 
     let shard_index = state.get_shard(&key);
     let pool = &state.db_pools[shard_index];
-    let table_name = format!("blobs_{}", namespace);
+    let table_name = namespace::table_name(&namespace);
 
     // First check if key exists
-    let query = format!("SELECT 1 FROM {} WHERE key = ?", table_name);
+    let query = format!(
+        "SELECT 1 FROM {} WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+        namespace::quote_identifier(&table_name)
+    );
     let exists = sqlx::query(&query)
-        .bind(&key)
+        .bind(&key[..])
+        .bind(chrono::Utc::now().timestamp_millis())
         .fetch_optional(pool)
         .await
         .map(|row| row.is_some())
@@ -812,7 +2726,7 @@ async fn handle_hdel(
     if !exists {
         // Redis HDEL returns the number of keys deleted
         let response = BytesFrame::Integer(0);
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
         return Ok(());
     }
 
@@ -822,22 +2736,23 @@ async fn handle_hdel(
     if state.cfg.async_write.unwrap_or(false) {
         // Remove from inflight cache immediately for delete operations
         let namespaced_key = state.namespaced_key(&namespace, &key);
-        state.inflight_hcache.invalidate(&namespaced_key).await;
+        state.inflight_hcache.invalidate(&namespaced_key);
 
         // Async mode: respond immediately after queueing
         let operation = ShardWriteOperation::HDeleteAsync { namespace, key };
 
-        if sender.send(operation).await.is_err() {
+        if let Err(e) = state.send_async(shard_index, operation).await {
             tracing::error!(
-                "Failed to send ASYNC HDEL operation to shard {}",
-                shard_index
+                "Failed to send ASYNC HDEL operation to shard {}: {}",
+                shard_index,
+                e
             );
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         } else {
             // Assume success for async mode
             let response = BytesFrame::Integer(1);
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         }
     } else {
         // Sync mode: wait for completion
@@ -852,22 +2767,22 @@ async fn handle_hdel(
         if sender.send(operation).await.is_err() {
             tracing::error!("Failed to send HDEL operation to shard {}", shard_index);
             let response = BytesFrame::Error("ERR internal error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         } else {
             match responder_rx.await {
                 Ok(Ok(())) => {
                     let response = BytesFrame::Integer(1);
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                 }
                 Ok(Err(e)) => {
                     tracing::error!("Shard writer failed for HDEL: {}", e);
                     let response = BytesFrame::Error("ERR database error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                 }
                 Err(_) => {
                     tracing::error!("Shard writer task cancelled or panicked for HDEL ");
                     let response = BytesFrame::Error("ERR internal error ".into());
-                    stream.write_all(&serialize_frame(&response)).await?;
+                    conn.write_frame(&response).await?;
                 }
             }
         }
@@ -877,100 +2792,679 @@ async fn handle_hdel(
 }
 
 async fn handle_hexists(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     namespace: String,
-    key: String,
+    key: Bytes,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Check if we should handle this hash operation locally in a cluster
-    if let Some(ref cluster_manager) = state.cluster_manager {
-        if !cluster_manager
-            .should_handle_hash_locally(&namespace, &key)
-            .await
-        {
-            if let Some(redirect) = cluster_manager
-                .get_hash_redirect_response(&namespace, &key)
-                .await
-            {
-                let response = BytesFrame::Error(redirect.into());
-                stream.write_all(&serialize_frame(&response)).await?;
-                return Ok(());
-            }
-        }
+    if redirect_hash_if_remote(conn, state, &namespace, &key).await? {
+        return Ok(());
     }
 
     let shard_index = state.get_shard(&key);
     let pool = &state.db_pools[shard_index];
-    let table_name = format!("blobs_{}", namespace);
+    let table_name = namespace::table_name(&namespace);
 
     let query = format!(
         "SELECT 1 FROM {} WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
-        table_name
+        namespace::quote_identifier(&table_name)
     );
 
     match sqlx::query(&query)
-        .bind(&key)
-        .bind(chrono::Utc::now().timestamp())
+        .bind(&key[..])
+        .bind(chrono::Utc::now().timestamp_millis())
         .fetch_optional(pool)
         .await
     {
         Ok(Some(_)) => {
             let response = BytesFrame::Integer(1);
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
         }
         Ok(None) => {
             let response = BytesFrame::Integer(0);
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
+        }
+        Err(e) if is_missing_table(&e) => {
+            let response = BytesFrame::Integer(0);
+            conn.write_frame(&response).await?;
         }
         Err(e) => {
             tracing::error!(
-                "Failed to check HEXISTS for namespace {} key {}: {}",
+                "Failed to check HEXISTS for namespace {} key {:?}: {}",
                 namespace,
                 key,
                 e
             );
             let response = BytesFrame::Error("ERR database error ".into());
-            stream.write_all(&serialize_frame(&response)).await?;
+            conn.write_frame(&response).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn handle_hmset(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    entries: Vec<(Bytes, Bytes)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (key, _) in &entries {
+        if redirect_hash_if_remote(conn, state, &namespace, key).await? {
+            return Ok(());
+        }
+    }
+    if reject_oversized(conn, state, entries.iter().map(|(_, v)| v.len()), true).await? {
+        return Ok(());
+    }
+
+    // Values of a namespace fit in a chunk, so none is split
+    let by_shard = group_entries_by_shard(state, entries).await?;
+
+    // Check if async_write is enabled
+    if state.cfg.async_write.unwrap_or(false) {
+        // Async mode: respond immediately after queueing
+        for (shard_index, ShardEntries { entries, .. }) in by_shard {
+            // Store in inflight cache to prevent race conditions
+            let inflight_seq = state.inflight_hcache.next_seq();
+            for (key, value) in &entries {
+                let namespaced_key = state.namespaced_key(&namespace, key);
+                state
+                    .inflight_hcache
+                    .insert(namespaced_key, value.clone(), inflight_seq);
+            }
+            let operation = ShardWriteOperation::HMSetAsync {
+                namespace: namespace.clone(),
+                entries,
+                inflight_seq,
+            };
+            if let Err(response) = queue_write(state, shard_index, operation, "ASYNC HMSET").await {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+        }
+    } else {
+        // Sync mode: queue one batch per shard, then wait for all of them
+        let mut pending = Vec::with_capacity(by_shard.len());
+        for (shard_index, ShardEntries { entries, .. }) in by_shard {
+            let (responder_tx, responder_rx) = oneshot::channel();
+            let operation = ShardWriteOperation::HMSet {
+                namespace: namespace.clone(),
+                entries,
+                responder: responder_tx,
+            };
+            if let Err(response) = queue_write(state, shard_index, operation, "HMSET").await {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+            pending.push(responder_rx);
+        }
+        for responder_rx in pending {
+            if let Err(response) = wait_for_write(state, responder_rx, "HMSET").await {
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+        }
+    }
+
+    let response = BytesFrame::SimpleString("OK".into());
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+async fn handle_hmget(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    keys: Vec<Bytes>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if redirect_fields_if_remote(conn, state, &namespace, &keys).await? {
+        return Ok(());
+    }
+
+    // Pending async writes are served from the inflight cache, the rest from the shards
+    let mut values = Vec::with_capacity(keys.len());
+    let mut missing = Vec::new();
+    for key in &keys {
+        let namespaced_key = state.namespaced_key(&namespace, key);
+        let value = state.inflight_hcache.get(&namespaced_key);
+        if value.is_none() {
+            missing.push(key.clone());
+        }
+        values.push(value);
+    }
+
+    let table_name = namespace::table_name(&namespace);
+    let stored = match fetch_live_rows(state, &table_name, &missing, true).await {
+        Ok(stored) => stored,
+        Err(e) => {
+            tracing::error!("Failed to HMGET namespace {}: {}", namespace, e);
+            let response = BytesFrame::Error("ERR database error ".into());
+            conn.write_frame(&response).await?;
+            state.metrics.record_error("storage");
+            return Ok(());
+        }
+    };
+
+    let mut items = Vec::with_capacity(keys.len());
+    for (key, value) in keys.iter().zip(values) {
+        match value.or_else(|| stored.get(&key[..]).cloned().flatten().map(Bytes::from)) {
+            Some(data) => items.push(BytesFrame::BulkString(decode_value(state, data).await?)),
+            None => items.push(BytesFrame::Null),
+        }
+    }
+
+    let response = BytesFrame::Array(items);
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// Fields of a namespace with a pending async write that are not stored yet.
+/// HLEN, HKEYS and HGETALL add them to the stored fields.
+async fn unstored_pending_fields(
+    state: &Arc<AppState>,
+    namespace: &str,
+    pending: &HashMap<Bytes, Bytes>,
+) -> Result<Vec<(Bytes, Bytes)>, sqlx::Error> {
+    if pending.is_empty() {
+        return Ok(Vec::new());
+    }
+    let keys: Vec<Bytes> = pending.keys().cloned().collect();
+    let stored = fetch_live_rows(state, &namespace::table_name(namespace), &keys, false).await?;
+    Ok(pending
+        .iter()
+        .filter(|(key, _)| !stored.contains_key(&key[..]))
+        .map(|(key, data)| (key.clone(), data.clone()))
+        .collect())
+}
+
+/// Count the live keys of a namespace on every shard of this node
+async fn count_namespace(
+    state: &Arc<AppState>,
+    namespace: &str,
+    now_ms: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    let query = format!(
+        "SELECT COUNT(*) FROM {} WHERE expires_at IS NULL OR expires_at > ?",
+        namespace::quote_identifier(&namespace::table_name(namespace))
+    );
+
+    let counts = state.db_pools.iter().map(|pool| {
+        let query = &query;
+        async move {
+            match sqlx::query_as::<_, (i64,)>(query)
+                .bind(now_ms)
+                .fetch_one(pool)
+                .await
+            {
+                Ok((count,)) => Ok(count),
+                Err(e) if is_missing_table(&e) => Ok(0),
+                Err(e) => Err(e),
+            }
+        }
+    });
+    futures::future::try_join_all(counts).await
+}
+
+/// HLEN counts the live keys of a namespace on every shard of this node,
+/// including fields with a pending async write
+async fn handle_hlen(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pending: HashMap<Bytes, Bytes> = state
+        .inflight_hcache
+        .entries(&namespace)
+        .into_iter()
+        .collect();
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    let counted = futures::future::try_join(
+        count_namespace(state, &namespace, now_ms),
+        unstored_pending_fields(state, &namespace, &pending),
+    )
+    .await;
+    let response = match counted {
+        Ok((counts, unstored)) => {
+            BytesFrame::Integer(counts.into_iter().sum::<i64>() + unstored.len() as i64)
+        }
+        Err(e) => {
+            tracing::error!("Failed to HLEN namespace {}: {}", namespace, e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
+        }
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+async fn handle_hkeys(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pending: HashMap<Bytes, Bytes> = state
+        .inflight_hcache
+        .entries(&namespace)
+        .into_iter()
+        .collect();
+    let table_name = namespace::table_name(&namespace);
+    let scanned = futures::future::try_join(
+        scan_table(
+            &state.db_pools,
+            &table_name,
+            ScanCursor::START,
+            usize::MAX,
+            None,
+            false,
+            chrono::Utc::now().timestamp_millis(),
+        ),
+        unstored_pending_fields(state, &namespace, &pending),
+    )
+    .await;
+    let response = match scanned {
+        Ok(((_, entries), unstored)) => {
+            let mut keys: Vec<Bytes> = entries.into_iter().map(|(key, _)| key.into()).collect();
+            // A pending field committed in between is already scanned
+            let scanned: HashSet<Bytes> = keys.iter().cloned().collect();
+            keys.extend(
+                unstored
+                    .into_iter()
+                    .map(|(key, _)| key)
+                    .filter(|key| !scanned.contains(key)),
+            );
+            BytesFrame::Array(keys.into_iter().map(BytesFrame::BulkString).collect())
+        }
+        Err(e) => {
+            tracing::error!("Failed to HKEYS namespace {}: {}", namespace, e);
+            state.metrics.record_error("storage");
+            BytesFrame::Error("ERR database error ".into())
+        }
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// Flush the HGETALL reply buffer to the socket once it grows past this size
+const HGETALL_FLUSH_BYTES: usize = 64 * 1024;
+
+/// HGETALL streams the namespace to the client instead of collecting it in
+/// memory. The shards are counted first, then read one at a time, each in its
+/// own short read transaction, so a slow client holds at most one snapshot.
+/// A shard sends at most the rows it counted; fields with a pending async
+/// write are sent with their pending value, and those not stored yet come
+/// last. A failure after the header has been written, including a shard
+/// that lost rows in between, closes the connection.
+async fn handle_hgetall(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pending: HashMap<Bytes, Bytes> = state
+        .inflight_hcache
+        .entries(&namespace)
+        .into_iter()
+        .collect();
+    let table_name = namespace::quote_identifier(&namespace::table_name(&namespace));
+    let rows_query = format!(
+        "SELECT key, data FROM {} WHERE expires_at IS NULL OR expires_at > ?",
+        table_name
+    );
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    let counted = futures::future::try_join(
+        count_namespace(state, &namespace, now_ms),
+        unstored_pending_fields(state, &namespace, &pending),
+    )
+    .await;
+    let (counts, unstored) = match counted {
+        Ok(counted) => counted,
+        Err(e) => {
+            tracing::error!("Failed to HGETALL namespace {}: {}", namespace, e);
+            state.metrics.record_error("storage");
+            let response = BytesFrame::Error("ERR database error ".into());
+            conn.write_frame(&response).await?;
+            return Ok(());
+        }
+    };
+    let total = counts.iter().sum::<i64>() as usize + unstored.len();
+    let unstored_keys: HashSet<&Bytes> = unstored.iter().map(|(key, _)| key).collect();
+
+    let mut buffer = conn.protocol.map_header(total).into_bytes();
+    for (pool, count) in state.db_pools.iter().zip(counts) {
+        if count == 0 {
+            continue;
+        }
+        let mut tx = pool.begin().await?;
+        let mut rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(&rows_query)
+            .bind(now_ms)
+            .fetch(&mut *tx);
+        let mut sent = 0;
+        while sent < count {
+            let Some((key, data)) = rows.try_next().await? else {
+                return Err(format!("namespace {} changed during HGETALL", namespace).into());
+            };
+            let key = Bytes::from(key);
+            // Sent with the fields not stored yet, which it was not counted as
+            if unstored_keys.contains(&key) {
+                continue;
+            }
+            let data = pending.get(&key).cloned().unwrap_or_else(|| data.into());
+            let data = decode_value(state, data).await?;
+            buffer.extend_from_slice(&serialize_frame(&BytesFrame::BulkString(key)));
+            buffer.extend_from_slice(&serialize_frame(&BytesFrame::BulkString(data)));
+            sent += 1;
+            if buffer.len() >= HGETALL_FLUSH_BYTES {
+                conn.write_all(&buffer).await?;
+                buffer.clear();
+            }
+        }
+    }
+    for (key, data) in unstored {
+        let data = decode_value(state, data).await?;
+        buffer.extend_from_slice(&serialize_frame(&BytesFrame::BulkString(key)));
+        buffer.extend_from_slice(&serialize_frame(&BytesFrame::BulkString(data)));
+        if buffer.len() >= HGETALL_FLUSH_BYTES {
+            conn.write_all(&buffer).await?;
+            buffer.clear();
+        }
+    }
+    conn.write_all(&buffer).await?;
+
+    Ok(())
+}
+
+/// Reply with a MOVED redirect if any of the fields is owned by another cluster node.
+async fn redirect_fields_if_remote(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: &str,
+    fields: &[Bytes],
+) -> Result<bool, Box<dyn std::error::Error>> {
+    for field in fields {
+        if redirect_hash_if_remote(conn, state, namespace, field).await? {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Queue one write per field on the field's shard, then collect the results in
+/// field order. Fields on different shards are processed concurrently.
+async fn fan_out_field_writes<T>(
+    state: &Arc<AppState>,
+    fields: impl IntoIterator<Item = (Bytes, Bytes)>,
+    op_name: &str,
+    make_operation: impl Fn(Bytes, Bytes, oneshot::Sender<Result<T, String>>) -> ShardWriteOperation,
+) -> Result<Vec<T>, BytesFrame> {
+    let mut pending = Vec::new();
+    for (field, data) in fields {
+        let shard_index = state.get_shard(&field);
+        let (responder_tx, responder_rx) = oneshot::channel();
+        queue_write(
+            state,
+            shard_index,
+            make_operation(field, data, responder_tx),
+            op_name,
+        )
+        .await?;
+        pending.push(responder_rx);
+    }
+
+    let mut results = Vec::with_capacity(pending.len());
+    for responder_rx in pending {
+        results.push(wait_for_write(state, responder_rx, op_name).await?);
+    }
+    Ok(results)
+}
+
+/// HSETEX replies 1 if every field was set and 0 otherwise. FNX/FXX are
+/// checked per field, since fields of one namespace live on different shards.
+async fn handle_hsetex(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    fields: Vec<(Bytes, Bytes)>,
+    expiry: Option<SetExpiry>,
+    condition: Option<SetCondition>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for (field, _) in &fields {
+        if redirect_hash_if_remote(conn, state, &namespace, field).await? {
+            return Ok(());
+        }
+    }
+    if reject_oversized(conn, state, fields.iter().map(|(_, v)| v.len()), true).await? {
+        return Ok(());
+    }
+
+    let ttl = match expiry {
+        Some(SetExpiry::KeepTtl) => TtlUpdate::Keep,
+        Some(expiry) => {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+            TtlUpdate::At(expiry.to_unix_millis(now_ms).unwrap_or(now_ms))
+        }
+        None => TtlUpdate::Clear,
+    };
+
+    let mut compressed = Vec::with_capacity(fields.len());
+    for (field, value) in fields {
+        compressed.push((field, encode_value(state, value).await?));
+    }
+
+    let response =
+        match fan_out_field_writes(state, compressed, "HSETEX", |key, data, responder| {
+            ShardWriteOperation::HSetWithOptions {
+                namespace: namespace.clone(),
+                key,
+                data,
+                ttl,
+                condition,
+                responder,
+            }
+        })
+        .await
+        {
+            Ok(outcomes) => {
+                let applied = outcomes.iter().filter(|o| o.applied).count();
+                for _ in 0..applied {
+                    state.metrics.record_storage_operation();
+                }
+                BytesFrame::Integer((applied == outcomes.len()) as i64)
+            }
+            Err(response) => response,
+        };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// HEXPIRE/HPEXPIRE reply per field: -2 no such field, 0 condition not met,
+/// 1 expiry set, 2 field deleted because the time is in the past.
+async fn handle_hexpire(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    fields: Vec<Bytes>,
+    expires_at: i64,
+    condition: Option<ExpireCondition>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if redirect_fields_if_remote(conn, state, &namespace, &fields).await? {
+        return Ok(());
+    }
+
+    let fields = fields.into_iter().map(|field| (field, Bytes::new()));
+    let response = match fan_out_field_writes(state, fields, "HEXPIRE", |key, _, responder| {
+        ShardWriteOperation::HExpire {
+            namespace: namespace.clone(),
+            key,
+            expires_at,
+            condition,
+            responder,
+        }
+    })
+    .await
+    {
+        Ok(outcomes) => BytesFrame::Array(
+            outcomes
+                .into_iter()
+                .map(|outcome| {
+                    BytesFrame::Integer(match outcome {
+                        ExpireOutcome::Missing => -2,
+                        ExpireOutcome::Skipped => 0,
+                        ExpireOutcome::Updated => 1,
+                        ExpireOutcome::Deleted => 2,
+                    })
+                })
+                .collect(),
+        ),
+        Err(response) => response,
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// HPERSIST replies per field: -2 no such field, -1 no expiry, 1 expiry removed.
+async fn handle_hpersist(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    fields: Vec<Bytes>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if redirect_fields_if_remote(conn, state, &namespace, &fields).await? {
+        return Ok(());
+    }
+
+    let fields = fields.into_iter().map(|field| (field, Bytes::new()));
+    let response = match fan_out_field_writes(state, fields, "HPERSIST", |key, _, responder| {
+        ShardWriteOperation::HPersist {
+            namespace: namespace.clone(),
+            key,
+            responder,
+        }
+    })
+    .await
+    {
+        Ok(outcomes) => BytesFrame::Array(
+            outcomes
+                .into_iter()
+                .map(|outcome| {
+                    BytesFrame::Integer(match outcome {
+                        ExpireOutcome::Missing => -2,
+                        ExpireOutcome::Skipped => -1,
+                        ExpireOutcome::Updated | ExpireOutcome::Deleted => 1,
+                    })
+                })
+                .collect(),
+        ),
+        Err(response) => response,
+    };
+    conn.write_frame(&response).await?;
+
+    Ok(())
+}
+
+/// HTTL/HPTTL reply per field: -2 no such field, -1 no expiry, otherwise the
+/// remaining time to live.
+async fn handle_httl(
+    conn: &mut Connection,
+    state: &Arc<AppState>,
+    namespace: String,
+    fields: Vec<Bytes>,
+    millis: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if redirect_fields_if_remote(conn, state, &namespace, &fields).await? {
+        return Ok(());
+    }
+
+    let query = format!(
+        "SELECT expires_at FROM {} WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+        namespace::quote_identifier(&namespace::table_name(&namespace))
+    );
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    let mut replies = Vec::with_capacity(fields.len());
+    for field in fields {
+        // A pending async write is always a plain HSET, which carries no TTL
+        let namespaced_key = state.namespaced_key(&namespace, &field);
+        if state.inflight_hcache.contains_key(&namespaced_key) {
+            replies.push(BytesFrame::Integer(-1));
+            continue;
         }
+
+        let pool = &state.db_pools[state.get_shard(&field)];
+        let ttl = match sqlx::query_as::<_, (Option<i64>,)>(&query)
+            .bind(&field[..])
+            .bind(now_ms)
+            .fetch_optional(pool)
+            .await
+        {
+            Ok(Some((Some(expires_at),))) => ttl_reply(expires_at, now_ms, millis),
+            Ok(Some((None,))) => -1,
+            Ok(None) => -2,
+            // The namespace table is created lazily on a shard's first HSET
+            Err(e) if is_missing_table(&e) => -2,
+            Err(e) => {
+                tracing::error!(
+                    "Failed to read TTL for namespace {} key {:?}: {}",
+                    namespace,
+                    field,
+                    e
+                );
+                state.metrics.record_error("storage");
+                let response = BytesFrame::Error("ERR database error ".into());
+                conn.write_frame(&response).await?;
+                return Ok(());
+            }
+        };
+        replies.push(BytesFrame::Integer(ttl));
     }
 
+    let response = BytesFrame::Array(replies);
+    conn.write_frame(&response).await?;
+
     Ok(())
 }
 
 // Cluster command handlers
 async fn handle_cluster_nodes(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     tracing::info!("Handling CLUSTER NODES command ");
     if let Some(ref cluster_manager) = state.cluster_manager {
         let nodes_info = cluster_manager.get_cluster_nodes().await;
         let response = BytesFrame::BulkString(nodes_info.into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     } else {
         let response = BytesFrame::Error("ERR This instance has cluster support disabled ".into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     }
     Ok(())
 }
 
 async fn handle_cluster_info(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if let Some(ref cluster_manager) = state.cluster_manager {
         let cluster_info = cluster_manager.get_cluster_info().await;
-        let response = BytesFrame::BulkString(cluster_info.into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        write_info(conn, cluster_info).await?;
     } else {
         let response = BytesFrame::Error("ERR This instance has cluster support disabled ".into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     }
     Ok(())
 }
 
 async fn handle_cluster_slots(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     if let Some(ref cluster_manager) = state.cluster_manager {
@@ -999,7 +3493,7 @@ async fn handle_cluster_slots(
                         .and_then(|c| c.advertise_addr.clone())
                         .unwrap_or("127.0.0.1:6379".to_string());
                     let parts: Vec<&str> = advertise_addr.split(':').collect();
-                    let ip = parts.get(0).cloned().unwrap_or("127.0.0.1").to_string();
+                    let ip = parts.first().cloned().unwrap_or("127.0.0.1").to_string();
                     let port = parts
                         .get(1)
                         .and_then(|p| p.parse::<i64>().ok())
@@ -1026,7 +3520,7 @@ async fn handle_cluster_slots(
                 .and_then(|c| c.advertise_addr.clone())
                 .unwrap_or("127.0.0.1:6379".to_string());
             let parts: Vec<&str> = advertise_addr.split(':').collect();
-            let ip = parts.get(0).cloned().unwrap_or("127.0.0.1").to_string();
+            let ip = parts.first().cloned().unwrap_or("127.0.0.1").to_string();
             let port = parts
                 .get(1)
                 .and_then(|p| p.parse::<i64>().ok())
@@ -1045,16 +3539,16 @@ async fn handle_cluster_slots(
         }
 
         let response = BytesFrame::Array(slot_ranges);
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     } else {
         let response = BytesFrame::Error("ERR This instance has cluster support disabled ".into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     }
     Ok(())
 }
 
 async fn handle_cluster_addslots(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     slots: Vec<u16>,
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -1062,23 +3556,23 @@ async fn handle_cluster_addslots(
         match cluster_manager.add_slots(slots).await {
             Ok(()) => {
                 let response = BytesFrame::SimpleString("OK".into());
-                stream.write_all(&serialize_frame(&response)).await?;
+                conn.write_frame(&response).await?;
             }
             Err(e) => {
                 tracing::error!("Failed to add slots: {}", e);
                 let response = BytesFrame::Error("ERR failed to add slots ".into());
-                stream.write_all(&serialize_frame(&response)).await?;
+                conn.write_frame(&response).await?;
             }
         }
     } else {
         let response = BytesFrame::Error("ERR This instance has cluster support disabled ".into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     }
     Ok(())
 }
 
 async fn handle_cluster_delslots(
-    stream: &mut TcpStream,
+    conn: &mut Connection,
     state: &Arc<AppState>,
     slots: Vec<u16>,
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -1086,27 +3580,27 @@ async fn handle_cluster_delslots(
         match cluster_manager.remove_slots(slots).await {
             Ok(()) => {
                 let response = BytesFrame::SimpleString("OK".into());
-                stream.write_all(&serialize_frame(&response)).await?;
+                conn.write_frame(&response).await?;
             }
             Err(e) => {
                 tracing::error!("Failed to remove slots: {}", e);
                 let response = BytesFrame::Error("ERR failed to remove slots ".into());
-                stream.write_all(&serialize_frame(&response)).await?;
+                conn.write_frame(&response).await?;
             }
         }
     } else {
         let response = BytesFrame::Error("ERR This instance has cluster support disabled ".into());
-        stream.write_all(&serialize_frame(&response)).await?;
+        conn.write_frame(&response).await?;
     }
     Ok(())
 }
 
 async fn handle_cluster_keyslot(
-    stream: &mut TcpStream,
-    key: String,
+    conn: &mut Connection,
+    key: Bytes,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let slot = ClusterManager::calculate_slot(&key);
     let response = BytesFrame::Integer(slot as i64);
-    stream.write_all(&serialize_frame(&response)).await?;
+    conn.write_frame(&response).await?;
     Ok(())
 }
@@ -1,62 +1,307 @@
+use crate::chunks::{self, ChunkManifest};
+use crate::inflight::{InflightHashes, InflightValues};
+use crate::journal::{self, Journal};
 use crate::metrics::Metrics;
+use crate::namespace::{self, quote_identifier};
+use crate::redis::{ExpireCondition, SetCondition};
+use crate::scan::is_missing_table;
 use bytes::Bytes;
 use chrono::Utc;
-use moka::future::Cache;
 use sqlx::SqlitePool;
 use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
 use tokio::sync::{mpsc, oneshot};
 use tokio::time::{Duration, timeout};
+use tokio_util::sync::CancellationToken;
+
+/// Pause before retrying the asynchronous writes of a batch that could not
+/// be committed
+const BATCH_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How a conditional write treats the expiry already stored for a key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TtlUpdate {
+    /// Clear any existing expiry
+    Clear,
+    /// Keep the existing expiry (`KEEPTTL`)
+    Keep,
+    /// Expire at the given Unix time in milliseconds
+    At(i64),
+}
+
+/// Result of a `SetWithOptions` operation
+#[derive(Debug, Default)]
+pub struct SetOutcome {
+    /// Whether the value was written (false when an NX/XX condition failed)
+    pub applied: bool,
+    /// The live value stored before this write, if any
+    pub previous: Option<Bytes>,
+    /// Encoded chunks of `previous` when it is stored as chunks and the
+    /// previous value was asked for
+    pub previous_chunks: Vec<Bytes>,
+}
+
+/// Result of an `Expire` or `Persist` operation
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpireOutcome {
+    /// The key does not exist
+    Missing,
+    /// The key exists but the NX/XX/GT/LT condition was not met, or for
+    /// PERSIST it had no expiry to remove
+    Skipped,
+    /// The expiry was updated or removed
+    Updated,
+    /// The new expiry was in the past so the key was deleted
+    Deleted,
+}
+
+// Per-operation result collected while executing a batch
+enum WriteOutcome {
+    Done,
+    Set(SetOutcome),
+    Expire(ExpireOutcome),
+    Applied(bool),
+}
 
 // Message type for writer consumers
 pub enum ShardWriteOperation {
     Set {
-        key: String,
+        key: Bytes,
         data: Bytes,
         responder: oneshot::Sender<Result<(), String>>,
     },
+    /// Asynchronous writes carry the sequence number of their inflight
+    /// cache entries, which are removed once the write is committed. Writes
+    /// replayed from the journal have no entries and carry 0.
     SetAsync {
-        key: String,
+        key: Bytes,
         data: Bytes,
+        inflight_seq: u64,
     },
     Delete {
-        key: String,
+        key: Bytes,
         responder: oneshot::Sender<Result<(), String>>,
     },
     DeleteAsync {
-        key: String,
+        key: Bytes,
+    },
+    /// Plain SET of several keys owned by this shard (MSET). Large values
+    /// are written with the `chunks` of their manifest, by key.
+    MSet {
+        entries: Vec<(Bytes, Bytes)>,
+        chunks: Vec<(Bytes, Vec<(u32, Bytes)>)>,
+        responder: oneshot::Sender<Result<(), String>>,
+    },
+    MSetAsync {
+        entries: Vec<(Bytes, Bytes)>,
+        inflight_seq: u64,
+    },
+    /// Set every key only if none of them exists (MSETNX).
+    /// Responds with whether the keys were set.
+    MSetNx {
+        entries: Vec<(Bytes, Bytes)>,
+        chunks: Vec<(Bytes, Vec<(u32, Bytes)>)>,
+        responder: oneshot::Sender<Result<bool, String>>,
+    },
+    /// Delete several keys owned by this shard (multi-key DEL)
+    DeleteMany {
+        keys: Vec<Bytes>,
+        responder: oneshot::Sender<Result<(), String>>,
+    },
+    DeleteManyAsync {
+        keys: Vec<Bytes>,
+    },
+    /// SET with NX/XX, GET or an expiry. Always synchronous since the caller
+    /// needs to know whether the write was applied.
+    /// Large values are always written this way, with `data` holding the
+    /// manifest of their encoded `chunks`.
+    SetWithOptions {
+        key: Bytes,
+        data: Bytes,
+        chunks: Vec<(u32, Bytes)>,
+        ttl: TtlUpdate,
+        condition: Option<SetCondition>,
+        /// Read the chunks of the previous value (`SET ... GET`)
+        get: bool,
+        responder: oneshot::Sender<Result<SetOutcome, String>>,
+    },
+    /// Store the result of APPEND or SETRANGE unless the key changed since
+    /// the value it was computed from was read. Keeps the expiry.
+    /// Responds with whether the value was written.
+    WriteRange {
+        key: Bytes,
+        data: Bytes,
+        chunks: Vec<(u32, Bytes)>,
+        /// The live value and version that were read, `None` if the key did
+        /// not exist
+        expected: Option<(Bytes, i64)>,
+        responder: oneshot::Sender<Result<bool, String>>,
+    },
+    /// Set the absolute expiry (Unix milliseconds) of an existing key
+    Expire {
+        key: Bytes,
+        expires_at: i64,
+        condition: Option<ExpireCondition>,
+        responder: oneshot::Sender<Result<ExpireOutcome, String>>,
+    },
+    /// Remove the expiry of an existing key
+    Persist {
+        key: Bytes,
+        responder: oneshot::Sender<Result<ExpireOutcome, String>>,
     },
     HSet {
         namespace: String,
-        key: String,
+        key: Bytes,
         data: Bytes,
         responder: oneshot::Sender<Result<(), String>>,
     },
     HSetAsync {
         namespace: String,
-        key: String,
+        key: Bytes,
         data: Bytes,
+        inflight_seq: u64,
     },
     HDelete {
         namespace: String,
-        key: String,
+        key: Bytes,
         responder: oneshot::Sender<Result<(), String>>,
     },
     HDeleteAsync {
         namespace: String,
-        key: String,
+        key: Bytes,
+    },
+    /// Set several keys of a namespace owned by this shard (HMSET)
+    HMSet {
+        namespace: String,
+        entries: Vec<(Bytes, Bytes)>,
+        responder: oneshot::Sender<Result<(), String>>,
+    },
+    HMSetAsync {
+        namespace: String,
+        entries: Vec<(Bytes, Bytes)>,
+        inflight_seq: u64,
+    },
+    /// Drop this shard's table of a namespace (DEL namespace).
+    /// Responds with whether the table held any live key.
+    DropNamespace {
+        namespace: String,
+        responder: oneshot::Sender<Result<bool, String>>,
+    },
+    /// Namespaced counterpart of `SetWithOptions` (HSETEX)
+    HSetWithOptions {
+        namespace: String,
+        key: Bytes,
+        data: Bytes,
+        ttl: TtlUpdate,
+        condition: Option<SetCondition>,
+        responder: oneshot::Sender<Result<SetOutcome, String>>,
     },
+    /// Namespaced counterpart of `Expire` (HEXPIRE/HPEXPIRE)
+    HExpire {
+        namespace: String,
+        key: Bytes,
+        expires_at: i64,
+        condition: Option<ExpireCondition>,
+        responder: oneshot::Sender<Result<ExpireOutcome, String>>,
+    },
+    /// Namespaced counterpart of `Persist` (HPERSIST)
+    HPersist {
+        namespace: String,
+        key: Bytes,
+        responder: oneshot::Sender<Result<ExpireOutcome, String>>,
+    },
+}
+
+impl ShardWriteOperation {
+    /// Whether the client was answered before the write was committed
+    pub fn is_async(&self) -> bool {
+        matches!(
+            self,
+            ShardWriteOperation::SetAsync { .. }
+                | ShardWriteOperation::DeleteAsync { .. }
+                | ShardWriteOperation::MSetAsync { .. }
+                | ShardWriteOperation::DeleteManyAsync { .. }
+                | ShardWriteOperation::HSetAsync { .. }
+                | ShardWriteOperation::HDeleteAsync { .. }
+                | ShardWriteOperation::HMSetAsync { .. }
+        )
+    }
+
+    /// Whether the operation writes several keys, which must then be applied
+    /// together or not at all
+    fn is_multi_key(&self) -> bool {
+        matches!(
+            self,
+            ShardWriteOperation::MSet { .. }
+                | ShardWriteOperation::MSetAsync { .. }
+                | ShardWriteOperation::MSetNx { .. }
+                | ShardWriteOperation::DeleteMany { .. }
+                | ShardWriteOperation::DeleteManyAsync { .. }
+                | ShardWriteOperation::HMSet { .. }
+                | ShardWriteOperation::HMSetAsync { .. }
+        )
+    }
+}
+
+/// Apply the writes recovered from a shard's journal before the server
+/// starts serving, moving the watermark to `last_seq`
+pub async fn replay_journal(
+    shard_id: usize,
+    pool: &SqlitePool,
+    operations: Vec<ShardWriteOperation>,
+    last_seq: u64,
+    inflight_cache: &InflightValues,
+    inflight_hcache: &InflightHashes,
+) -> Result<(), String> {
+    let mut known_tables = load_existing_tables(pool, shard_id).await;
+    let mut batch = VecDeque::from(operations);
+    let failed = process_batch(
+        shard_id,
+        pool,
+        &mut batch,
+        &mut known_tables,
+        inflight_cache,
+        inflight_hcache,
+        Some(last_seq),
+    )
+    .await
+    .map_err(|e| format!("committing the journal of shard {}: {}", shard_id, e))?;
+    // Retrying a write that fails again would keep the server from starting
+    if failed > 0 {
+        tracing::error!(
+            "[Shard {}] {} journaled writes could not be applied",
+            shard_id,
+            failed
+        );
+    }
+    Ok(())
+}
+
+/// Sequence number of the last journaled write of a batch, `None` if the
+/// journal is off or the batch has no asynchronous write
+fn next_journal_seq(
+    journal_seq: Option<u64>,
+    batch: &VecDeque<ShardWriteOperation>,
+) -> Option<u64> {
+    let journaled = batch.iter().filter(|op| op.is_async()).count() as u64;
+    journal_seq
+        .filter(|_| journaled > 0)
+        .map(|seq| seq + journaled)
 }
 
 // Enhanced consumer with batching support
+#[allow(clippy::too_many_arguments)]
 pub async fn shard_writer_task(
     shard_id: usize,
     pool: SqlitePool,
     mut receiver: mpsc::Receiver<ShardWriteOperation>,
     batch_size: usize,
     batch_timeout_ms: u64,
-    inflight_cache: Cache<String, Bytes>,
-    inflight_hcache: Cache<String, Bytes>,
+    inflight_cache: InflightValues,
+    inflight_hcache: InflightHashes,
     metrics: Metrics,
+    shutdown: CancellationToken,
+    journal: Option<Arc<Journal>>,
 ) {
     // Load existing namespaced tables into memory
     let mut known_tables = load_existing_tables(&pool, shard_id).await;
@@ -70,12 +315,26 @@ pub async fn shard_writer_task(
     );
 
     let mut batch: VecDeque<ShardWriteOperation> = VecDeque::with_capacity(batch_size);
+    // Sequence number of the last asynchronous write received. The journal
+    // queues them in sequence order, so counting them is enough.
+    let mut journal_seq = journal.as_ref().map(|journal| journal.start_seq());
 
     loop {
+        // On shutdown no more operations are accepted, but those already
+        // queued are still written before the task stops
+        if shutdown.is_cancelled() && !receiver.is_closed() {
+            tracing::info!("Shard {} writer task draining its queue", shard_id);
+            receiver.close();
+        }
+
         // Collect operations for batching
         if batch.is_empty() {
             // If batch is empty, wait indefinitely for first operation
-            match receiver.recv().await {
+            let received = tokio::select! {
+                received = receiver.recv() => received,
+                _ = shutdown.cancelled(), if !receiver.is_closed() => continue,
+            };
+            match received {
                 Some(op) => {
                     batch.push_back(op);
                 }
@@ -103,49 +362,211 @@ pub async fn shard_writer_task(
 
         if !batch.is_empty() {
             let batch_start = std::time::Instant::now();
-            process_batch(
+            let batch_len = batch.len();
+            let batch_seq = next_journal_seq(journal_seq, &batch);
+            let result = process_batch(
                 shard_id,
                 &pool,
                 &mut batch,
                 &mut known_tables,
                 &inflight_cache,
                 &inflight_hcache,
+                batch_seq,
             )
             .await;
+            // A failed batch leaves its asynchronous writes queued for the
+            // next one, so its journal records must stay until they commit
+            if let (Some(journal), Some(seq), true) = (&journal, batch_seq, result.is_ok()) {
+                journal_seq = batch_seq;
+                journal.committed(seq).await;
+            }
             let batch_duration = batch_start.elapsed();
-            metrics.record_batch_operation(batch.len(), batch_duration);
+            metrics.record_batch_operation(batch_len, batch_duration);
+            if result.is_err() && !batch.is_empty() {
+                tokio::time::sleep(BATCH_RETRY_DELAY).await;
+            }
         }
     }
 
     // Process any remaining operations
     if !batch.is_empty() {
         let batch_start = std::time::Instant::now();
-        process_batch(
+        let batch_len = batch.len();
+        let batch_seq = next_journal_seq(journal_seq, &batch);
+        let result = process_batch(
             shard_id,
             &pool,
             &mut batch,
             &mut known_tables,
             &inflight_cache,
             &inflight_hcache,
+            batch_seq,
         )
         .await;
+        if let (Some(journal), Some(seq)) = (&journal, batch_seq)
+            && result.is_ok()
+        {
+            journal.committed(seq).await;
+        }
+        // Writes still failing are replayed from the journal on startup
         let batch_duration = batch_start.elapsed();
-        metrics.record_batch_operation(batch.len(), batch_duration);
+        metrics.record_batch_operation(batch_len, batch_duration);
     }
 
     tracing::info!("Shard {} writer task stopped", shard_id);
 }
 
+/// Periodically deletes expired rows from every table of a shard.
+///
+/// Reads already hide expired rows, this task only reclaims the space. Each
+/// DELETE re-checks `expires_at`, so a key rewritten by the shard writer in
+/// the meantime is never removed.
+pub async fn expiry_reaper_task(
+    shard_id: usize,
+    pool: SqlitePool,
+    interval: Duration,
+    batch_size: usize,
+    max_keys_per_pass: usize,
+    metrics: Metrics,
+    shutdown: CancellationToken,
+) {
+    tracing::info!(
+        "Shard {} expiry reaper started (interval={}ms, batch_size={}, max_keys_per_pass={})",
+        shard_id,
+        interval.as_millis(),
+        batch_size,
+        max_keys_per_pass
+    );
+
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => break,
+        }
+
+        let pass_start = std::time::Instant::now();
+        match reap_expired(
+            &pool,
+            Utc::now().timestamp_millis(),
+            batch_size,
+            max_keys_per_pass,
+        )
+        .await
+        {
+            Ok(stats) => {
+                if stats.keys > 0 {
+                    tracing::debug!(
+                        "[Shard {}] Reaped {} expired keys ({} bytes)",
+                        shard_id,
+                        stats.keys,
+                        stats.bytes
+                    );
+                }
+                metrics.record_expiry_pass(stats.keys, stats.bytes, pass_start.elapsed());
+            }
+            Err(e) => {
+                tracing::error!("[Shard {}] Expiry reaper pass failed: {}", shard_id, e);
+                metrics.record_error("storage");
+            }
+        }
+    }
+
+    tracing::info!("Shard {} expiry reaper stopped", shard_id);
+}
+
+/// Totals of a single reaper pass
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ReapStats {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+/// Delete rows that expired at or before `now_ms` from `blobs` and every
+/// `blobs_*` table, in statements of at most `batch_size` rows and stopping
+/// once `max_keys` rows have been removed. The bytes of chunked values
+/// include their chunks, which the delete trigger removes with the row.
+pub async fn reap_expired(
+    pool: &SqlitePool,
+    now_ms: i64,
+    batch_size: usize,
+    max_keys: usize,
+) -> Result<ReapStats, sqlx::Error> {
+    let tables = sqlx::query_as::<_, (String,)>(
+        "SELECT name FROM sqlite_master WHERE type='table' AND (name = 'blobs' OR name LIKE 'blobs_%')",
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut stats = ReapStats::default();
+
+    for (table_name,) in tables {
+        // Only plain keys are stored as chunks. RETURNING is evaluated
+        // before the trigger deletes them.
+        let returning = if table_name == "blobs" {
+            format!(
+                "length(data) + (SELECT COALESCE(SUM(length(c.data)), 0) FROM {} c WHERE c.key = blobs.key)",
+                chunks::CHUNK_TABLE
+            )
+        } else {
+            "length(data)".to_string()
+        };
+        // The subquery is served by the partial idx_*expires_at index
+        let query = format!(
+            "DELETE FROM {table} WHERE key IN (
+                SELECT key FROM {table} WHERE expires_at IS NOT NULL AND expires_at <= ? LIMIT ?
+            ) AND expires_at <= ?
+            RETURNING {returning}",
+            table = quote_identifier(&table_name)
+        );
+
+        while (stats.keys as usize) < max_keys {
+            let limit = batch_size.min(max_keys - stats.keys as usize);
+            let deleted = match sqlx::query_as::<_, (Option<i64>,)>(&query)
+                .bind(now_ms)
+                .bind(limit as i64)
+                .bind(now_ms)
+                .fetch_all(pool)
+                .await
+            {
+                Ok(deleted) => deleted,
+                // The namespace was dropped since the tables were listed
+                Err(e) if is_missing_table(&e) => break,
+                Err(e) => return Err(e),
+            };
+
+            stats.keys += deleted.len() as u64;
+            stats.bytes += deleted
+                .iter()
+                .map(|(len,)| len.unwrap_or(0) as u64)
+                .sum::<u64>();
+
+            if deleted.len() < limit {
+                break;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Write a batch in one transaction and answer its synchronous operations.
+/// Returns the number of asynchronous writes that failed, or the error if
+/// the transaction could not be committed. The asynchronous writes of a
+/// batch that could not be committed are left in `batch`.
 async fn process_batch(
     shard_id: usize,
     pool: &SqlitePool,
     batch: &mut VecDeque<ShardWriteOperation>,
     known_tables: &mut HashSet<String>,
-    inflight_cache: &Cache<String, Bytes>,
-    inflight_hcache: &Cache<String, Bytes>,
-) {
+    inflight_cache: &InflightValues,
+    inflight_hcache: &InflightHashes,
+    journal_seq: Option<u64>,
+) -> Result<usize, String> {
     if batch.is_empty() {
-        return;
+        return Ok(0);
     }
 
     let batch_size = batch.len();
@@ -160,86 +581,246 @@ async fn process_batch(
         Ok(tx) => tx,
         Err(e) => {
             tracing::error!("[Shard {}] Failed to start transaction: {}", shard_id, e);
-            // Send errors to all sync operations and clear batch
-            for operation in batch.drain(..) {
-                if let ShardWriteOperation::Set { responder, .. }
-                | ShardWriteOperation::Delete { responder, .. } = operation
-                {
-                    let _ = responder.send(Err(format!("Transaction start failed: {}", e)));
+            // Send errors to all sync operations and keep the async ones
+            // for the next batch
+            let error = format!("Transaction start failed: {}", e);
+            let operations: Vec<_> = batch.drain(..).collect();
+            for operation in operations {
+                if operation.is_async() {
+                    batch.push_back(operation);
+                    continue;
+                }
+                match operation {
+                    ShardWriteOperation::Set { responder, .. }
+                    | ShardWriteOperation::Delete { responder, .. }
+                    | ShardWriteOperation::HSet { responder, .. }
+                    | ShardWriteOperation::HDelete { responder, .. }
+                    | ShardWriteOperation::MSet { responder, .. }
+                    | ShardWriteOperation::DeleteMany { responder, .. }
+                    | ShardWriteOperation::HMSet { responder, .. } => {
+                        let _ = responder.send(Err(error.clone()));
+                    }
+                    ShardWriteOperation::MSetNx { responder, .. }
+                    | ShardWriteOperation::WriteRange { responder, .. }
+                    | ShardWriteOperation::DropNamespace { responder, .. } => {
+                        let _ = responder.send(Err(error.clone()));
+                    }
+                    ShardWriteOperation::SetWithOptions { responder, .. }
+                    | ShardWriteOperation::HSetWithOptions { responder, .. } => {
+                        let _ = responder.send(Err(error.clone()));
+                    }
+                    ShardWriteOperation::Expire { responder, .. }
+                    | ShardWriteOperation::Persist { responder, .. }
+                    | ShardWriteOperation::HExpire { responder, .. }
+                    | ShardWriteOperation::HPersist { responder, .. } => {
+                        let _ = responder.send(Err(error.clone()));
+                    }
+                    _ => {}
                 }
             }
-            return;
+            return Err(error);
         }
     };
 
-    let mut results: Vec<(usize, Result<(), String>)> = Vec::new();
-    let mut sync_operations: Vec<usize> = Vec::new();
+    let mut results: Vec<Result<WriteOutcome, String>> = Vec::with_capacity(batch_size);
 
     // Execute all operations in the transaction
-    for (idx, operation) in batch.iter().enumerate() {
+    for operation in batch.iter() {
+        // A multi-key write runs in a savepoint, so a key failing midway
+        // undoes the keys before it while the rest of the batch commits
+        let savepoint = operation.is_multi_key();
+        let begun = if savepoint {
+            sqlx::query("SAVEPOINT op")
+                .execute(&mut *tx)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        } else {
+            Ok(())
+        };
         let result = match operation {
+            _ if begun.is_err() => begun.clone().map(|()| WriteOutcome::Done),
             ShardWriteOperation::Set { key, data, .. }
-            | ShardWriteOperation::SetAsync { key, data } => {
-                match operation {
-                    ShardWriteOperation::Set { .. } => sync_operations.push(idx),
-                    _ => {}
-                }
-
+            | ShardWriteOperation::SetAsync { key, data, .. } => {
                 let now = Utc::now().timestamp();
 
                 // Check if record exists to determine if this is an insert or update
                 let exists = sqlx::query("SELECT 1 FROM blobs WHERE key = ?")
-                    .bind(key)
+                    .bind(&key[..])
                     .fetch_optional(&mut *tx)
                     .await
                     .map(|row| row.is_some())
                     .unwrap_or(false);
 
                 if exists {
-                    // Update existing record - only update data, updated_at, and version
-                    sqlx::query("UPDATE blobs SET data = ?, updated_at = ?, version = version + 1 WHERE key = ?")
+                    // Update existing record - a plain SET also discards any previous TTL
+                    sqlx::query("UPDATE blobs SET data = ?, updated_at = ?, expires_at = NULL, version = version + 1 WHERE key = ?")
                         .bind(&data[..])
                         .bind(now)
-                        .bind(key)
+                        .bind(&key[..])
                         .execute(&mut *tx)
                         .await
-                        .map(|_| ())
+                        .map(|_| WriteOutcome::Done)
                         .map_err(|e| {
-                            tracing::error!("[Shard {}] UPDATE error for key {}: {}", shard_id, key, e);
+                            tracing::error!("[Shard {}] UPDATE error for key {:?}: {}", shard_id, key, e);
                             e.to_string()
                         })
                 } else {
                     // Insert new record with metadata
                     sqlx::query("INSERT INTO blobs (key, data, created_at, updated_at, expires_at, version) VALUES (?, ?, ?, ?, NULL, 0)")
-                        .bind(key)
+                        .bind(&key[..])
                         .bind(&data[..])
                         .bind(now)
                         .bind(now)
                         .execute(&mut *tx)
                         .await
-                        .map(|_| ())
+                        .map(|_| WriteOutcome::Done)
                         .map_err(|e| {
-                            tracing::error!("[Shard {}] INSERT error for key {}: {}", shard_id, key, e);
+                            tracing::error!("[Shard {}] INSERT error for key {:?}: {}", shard_id, key, e);
                             e.to_string()
                         })
                 }
             }
             ShardWriteOperation::Delete { key, .. } | ShardWriteOperation::DeleteAsync { key } => {
-                match operation {
-                    ShardWriteOperation::Delete { .. } => sync_operations.push(idx),
-                    _ => {}
-                }
-
                 sqlx::query("DELETE FROM blobs WHERE key = ?")
-                    .bind(key)
+                    .bind(&key[..])
                     .execute(&mut *tx)
                     .await
-                    .map(|_| ())
+                    .map(|_| WriteOutcome::Done)
                     .map_err(|e| {
-                        tracing::error!("[Shard {}] DELETE error for key {}: {}", shard_id, key, e);
+                        tracing::error!(
+                            "[Shard {}] DELETE error for key {:?}: {}",
+                            shard_id,
+                            key,
+                            e
+                        );
                         e.to_string()
                     })
             }
+            ShardWriteOperation::MSet {
+                entries, chunks, ..
+            } => set_many(&mut tx, entries, chunks)
+                .await
+                .map(|_| WriteOutcome::Done)
+                .map_err(|e| {
+                    tracing::error!("[Shard {}] MSET error: {}", shard_id, e);
+                    e
+                }),
+            ShardWriteOperation::MSetAsync { entries, .. } => set_many(&mut tx, entries, &[])
+                .await
+                .map(|_| WriteOutcome::Done)
+                .map_err(|e| {
+                    tracing::error!("[Shard {}] MSET error: {}", shard_id, e);
+                    e
+                }),
+            ShardWriteOperation::MSetNx {
+                entries, chunks, ..
+            } => {
+                let mut any_exists = false;
+                let mut check = Ok(());
+                for (key, _) in entries {
+                    match fetch_live_row(&mut tx, "blobs", key).await {
+                        Ok(Some(_)) => {
+                            any_exists = true;
+                            break;
+                        }
+                        Ok(None) => {}
+                        Err(e) => {
+                            check = Err(e);
+                            break;
+                        }
+                    }
+                }
+                match check {
+                    Ok(()) if any_exists => Ok(WriteOutcome::Applied(false)),
+                    Ok(()) => set_many(&mut tx, entries, chunks)
+                        .await
+                        .map(|_| WriteOutcome::Applied(true)),
+                    Err(e) => Err(e),
+                }
+                .map_err(|e| {
+                    tracing::error!("[Shard {}] MSETNX error: {}", shard_id, e);
+                    e
+                })
+            }
+            ShardWriteOperation::DeleteMany { keys, .. }
+            | ShardWriteOperation::DeleteManyAsync { keys } => {
+                let mut result = Ok(WriteOutcome::Done);
+                for key in keys {
+                    if let Err(e) = sqlx::query("DELETE FROM blobs WHERE key = ?")
+                        .bind(&key[..])
+                        .execute(&mut *tx)
+                        .await
+                    {
+                        tracing::error!(
+                            "[Shard {}] DELETE error for key {:?}: {}",
+                            shard_id,
+                            key,
+                            e
+                        );
+                        result = Err(e.to_string());
+                        break;
+                    }
+                }
+                result
+            }
+            ShardWriteOperation::SetWithOptions {
+                key,
+                data,
+                chunks,
+                ttl,
+                condition,
+                get,
+                ..
+            } => set_value_with_options(&mut tx, key, data, chunks, *ttl, *condition, *get)
+                .await
+                .map(WriteOutcome::Set)
+                .map_err(|e| {
+                    tracing::error!("[Shard {}] SET error for key {:?}: {}", shard_id, key, e);
+                    e
+                }),
+            ShardWriteOperation::WriteRange {
+                key,
+                data,
+                chunks,
+                expected,
+                ..
+            } => write_range(&mut tx, key, data, chunks, expected.as_ref())
+                .await
+                .map(WriteOutcome::Applied)
+                .map_err(|e| {
+                    tracing::error!(
+                        "[Shard {}] SETRANGE error for key {:?}: {}",
+                        shard_id,
+                        key,
+                        e
+                    );
+                    e
+                }),
+            ShardWriteOperation::Expire {
+                key,
+                expires_at,
+                condition,
+                ..
+            } => set_expiry(&mut tx, "blobs", key, *expires_at, *condition)
+                .await
+                .map(WriteOutcome::Expire)
+                .map_err(|e| {
+                    tracing::error!("[Shard {}] EXPIRE error for key {:?}: {}", shard_id, key, e);
+                    e
+                }),
+            ShardWriteOperation::Persist { key, .. } => persist(&mut tx, "blobs", key)
+                .await
+                .map(WriteOutcome::Expire)
+                .map_err(|e| {
+                    tracing::error!(
+                        "[Shard {}] PERSIST error for key {:?}: {}",
+                        shard_id,
+                        key,
+                        e
+                    );
+                    e
+                }),
             ShardWriteOperation::HSet {
                 namespace,
                 key,
@@ -250,13 +831,9 @@ async fn process_batch(
                 namespace,
                 key,
                 data,
+                ..
             } => {
-                match operation {
-                    ShardWriteOperation::HSet { .. } => sync_operations.push(idx),
-                    _ => {}
-                }
-
-                let table_name = format!("blobs_{}", namespace);
+                let table_name = namespace::table_name(namespace);
 
                 // Ensure table exists
                 if let Err(e) =
@@ -268,30 +845,33 @@ async fn process_batch(
                     let now = Utc::now().timestamp();
 
                     // Check if record exists to determine if this is an insert or update
-                    let query = format!("SELECT 1 FROM {} WHERE key = ?", table_name);
+                    let query = format!(
+                        "SELECT 1 FROM {} WHERE key = ?",
+                        quote_identifier(&table_name)
+                    );
                     let exists = sqlx::query(&query)
-                        .bind(key)
+                        .bind(&key[..])
                         .fetch_optional(&mut *tx)
                         .await
                         .map(|row| row.is_some())
                         .unwrap_or(false);
 
                     if exists {
-                        // Update existing record
+                        // Update existing record - overwriting a field also discards its TTL
                         let update_query = format!(
-                            "UPDATE {} SET data = ?, updated_at = ?, version = version + 1 WHERE key = ?",
-                            table_name
+                            "UPDATE {} SET data = ?, updated_at = ?, expires_at = NULL, version = version + 1 WHERE key = ?",
+                            quote_identifier(&table_name)
                         );
                         sqlx::query(&update_query)
                             .bind(&data[..])
                             .bind(now)
-                            .bind(key)
+                            .bind(&key[..])
                             .execute(&mut *tx)
                             .await
-                            .map(|_| ())
+                            .map(|_| WriteOutcome::Done)
                             .map_err(|e| {
                                 tracing::error!(
-                                    "[Shard {}] HSET UPDATE error for namespace {} key {}: {}",
+                                    "[Shard {}] HSET UPDATE error for namespace {} key {:?}: {}",
                                     shard_id,
                                     namespace,
                                     key,
@@ -303,19 +883,19 @@ async fn process_batch(
                         // Insert new record
                         let insert_query = format!(
                             "INSERT INTO {} (key, data, created_at, updated_at, expires_at, version) VALUES (?, ?, ?, ?, NULL, 0)",
-                            table_name
+                            quote_identifier(&table_name)
                         );
                         sqlx::query(&insert_query)
-                            .bind(key)
+                            .bind(&key[..])
                             .bind(&data[..])
                             .bind(now)
                             .bind(now)
                             .execute(&mut *tx)
                             .await
-                            .map(|_| ())
+                            .map(|_| WriteOutcome::Done)
                             .map_err(|e| {
                                 tracing::error!(
-                                    "[Shard {}] HSET INSERT error for namespace {} key {}: {}",
+                                    "[Shard {}] HSET INSERT error for namespace {} key {:?}: {}",
                                     shard_id,
                                     namespace,
                                     key,
@@ -328,24 +908,22 @@ async fn process_batch(
             }
             ShardWriteOperation::HDelete { namespace, key, .. }
             | ShardWriteOperation::HDeleteAsync { namespace, key } => {
-                match operation {
-                    ShardWriteOperation::HDelete { .. } => sync_operations.push(idx),
-                    _ => {}
-                }
-
-                let table_name = format!("blobs_{}", namespace);
+                let table_name = namespace::table_name(namespace);
 
                 // Only delete if table exists
                 if known_tables.contains(&table_name) {
-                    let delete_query = format!("DELETE FROM {} WHERE key = ?", table_name);
+                    let delete_query = format!(
+                        "DELETE FROM {} WHERE key = ?",
+                        quote_identifier(&table_name)
+                    );
                     sqlx::query(&delete_query)
-                        .bind(key)
+                        .bind(&key[..])
                         .execute(&mut *tx)
                         .await
-                        .map(|_| ())
+                        .map(|_| WriteOutcome::Done)
                         .map_err(|e| {
                             tracing::error!(
-                                "[Shard {}] HDEL error for namespace {} key {}: {}",
+                                "[Shard {}] HDEL error for namespace {} key {:?}: {}",
                                 shard_id,
                                 namespace,
                                 key,
@@ -355,52 +933,221 @@ async fn process_batch(
                         })
                 } else {
                     // Table doesn't exist, operation succeeds (key doesn't exist)
-                    Ok(())
+                    Ok(WriteOutcome::Done)
+                }
+            }
+            ShardWriteOperation::HMSet {
+                namespace, entries, ..
+            }
+            | ShardWriteOperation::HMSetAsync {
+                namespace, entries, ..
+            } => {
+                let table_name = namespace::table_name(namespace);
+                let mut result =
+                    ensure_namespaced_table_exists(&mut tx, &table_name, known_tables).await;
+                for (key, data) in entries {
+                    if result.is_err() {
+                        break;
+                    }
+                    result =
+                        set_with_options(&mut tx, &table_name, key, data, TtlUpdate::Clear, None)
+                            .await
+                            .map(|_| ());
+                }
+                result.map(|_| WriteOutcome::Done).map_err(|e| {
+                    tracing::error!(
+                        "[Shard {}] HMSET error for namespace {}: {}",
+                        shard_id,
+                        namespace,
+                        e
+                    );
+                    e
+                })
+            }
+            ShardWriteOperation::DropNamespace { namespace, .. } => {
+                let table_name = namespace::table_name(namespace);
+                // Forget the table even if the commit fails, the next HSET
+                // recreates it with CREATE TABLE IF NOT EXISTS
+                known_tables.remove(&table_name);
+                drop_namespace_table(&mut tx, &table_name)
+                    .await
+                    .map(WriteOutcome::Applied)
+                    .map_err(|e| {
+                        tracing::error!(
+                            "[Shard {}] Failed to drop namespace {}: {}",
+                            shard_id,
+                            namespace,
+                            e
+                        );
+                        e
+                    })
+            }
+            ShardWriteOperation::HSetWithOptions {
+                namespace,
+                key,
+                data,
+                ttl,
+                condition,
+                ..
+            } => {
+                let table_name = namespace::table_name(namespace);
+                match ensure_namespaced_table_exists(&mut tx, &table_name, known_tables).await {
+                    Ok(()) => set_with_options(&mut tx, &table_name, key, data, *ttl, *condition)
+                        .await
+                        .map(WriteOutcome::Set),
+                    Err(e) => Err(e),
+                }
+                .map_err(|e| {
+                    tracing::error!(
+                        "[Shard {}] HSETEX error for namespace {} key {:?}: {}",
+                        shard_id,
+                        namespace,
+                        key,
+                        e
+                    );
+                    e
+                })
+            }
+            ShardWriteOperation::HExpire {
+                namespace,
+                key,
+                expires_at,
+                condition,
+                ..
+            } => {
+                let table_name = namespace::table_name(namespace);
+                if known_tables.contains(&table_name) {
+                    set_expiry(&mut tx, &table_name, key, *expires_at, *condition)
+                        .await
+                        .map(WriteOutcome::Expire)
+                        .map_err(|e| {
+                            tracing::error!(
+                                "[Shard {}] HEXPIRE error for namespace {} key {:?}: {}",
+                                shard_id,
+                                namespace,
+                                key,
+                                e
+                            );
+                            e
+                        })
+                } else {
+                    Ok(WriteOutcome::Expire(ExpireOutcome::Missing))
                 }
             }
+            ShardWriteOperation::HPersist { namespace, key, .. } => {
+                let table_name = namespace::table_name(namespace);
+                if known_tables.contains(&table_name) {
+                    persist(&mut tx, &table_name, key)
+                        .await
+                        .map(WriteOutcome::Expire)
+                        .map_err(|e| {
+                            tracing::error!(
+                                "[Shard {}] HPERSIST error for namespace {} key {:?}: {}",
+                                shard_id,
+                                namespace,
+                                key,
+                                e
+                            );
+                            e
+                        })
+                } else {
+                    Ok(WriteOutcome::Expire(ExpireOutcome::Missing))
+                }
+            }
+        };
+        let result = if savepoint && begun.is_ok() {
+            end_savepoint(&mut tx, result).await
+        } else {
+            result
         };
 
-        results.push((idx, result));
+        results.push(result);
     }
 
+    let failed_async = batch
+        .iter()
+        .zip(&results)
+        .filter(|(operation, result)| operation.is_async() && result.is_err())
+        .count();
+
+    // The watermark commits with the batch, so a restart never replays a
+    // write that is already in the database
+    let watermark = match journal_seq {
+        Some(seq) => journal::store_watermark(&mut tx, seq).await,
+        None => Ok(()),
+    };
+
     // Commit transaction
-    let commit_result = tx.commit().await.map_err(|e| {
+    let commit_result = match watermark {
+        Ok(()) => tx.commit().await,
+        Err(e) => Err(e),
+    }
+    .map_err(|e| {
         tracing::error!("[Shard {}] Transaction commit failed: {}", shard_id, e);
         e.to_string()
     });
 
-    // Clean up inflight cache entries after successful database commit.
-    // This prevents memory leaks and ensures the cache doesn't grow indefinitely.
-    // The inflight cache is used to prevent race conditions in async write mode:
-    // when a SET returns OK immediately, subsequent GET requests check the cache
-    // first before hitting the database. Once the write is committed, we can
-    // safely remove the entry from the cache since it's now in the database.
+    // Once a write is committed, reads find it in the database, so its
+    // inflight entries are removed. An entry replaced by a later write of
+    // the same key stays until that write is committed too. Deletes drop
+    // their keys' entries when they are queued, so any entry left belongs to
+    // a later write.
     if commit_result.is_ok() {
         for operation in batch.iter() {
             match operation {
-                ShardWriteOperation::SetAsync { key, .. } => {
-                    inflight_cache.invalidate(key).await;
+                ShardWriteOperation::SetAsync {
+                    key, inflight_seq, ..
+                } => {
+                    inflight_cache.remove_committed(key, *inflight_seq);
                 }
-                ShardWriteOperation::DeleteAsync { key } => {
-                    // Also clean up any SET operations that might have been overridden
-                    inflight_cache.invalidate(key).await;
+                ShardWriteOperation::MSetAsync {
+                    entries,
+                    inflight_seq,
+                } => {
+                    for (key, _) in entries {
+                        inflight_cache.remove_committed(key, *inflight_seq);
+                    }
                 }
-                ShardWriteOperation::HSetAsync { namespace, key, .. } => {
-                    let namespaced_key = format!("{}:{}", namespace, key);
-                    inflight_hcache.invalidate(&namespaced_key).await;
+                ShardWriteOperation::HMSetAsync {
+                    namespace,
+                    entries,
+                    inflight_seq,
+                } => {
+                    for (key, _) in entries {
+                        inflight_hcache
+                            .remove_committed(&(namespace.clone(), key.clone()), *inflight_seq);
+                    }
                 }
-                ShardWriteOperation::HDeleteAsync { namespace, key } => {
-                    // Also clean up any HSET operations that might have been overridden
-                    let namespaced_key = format!("{}:{}", namespace, key);
-                    inflight_hcache.invalidate(&namespaced_key).await;
+                ShardWriteOperation::HSetAsync {
+                    namespace,
+                    key,
+                    inflight_seq,
+                    ..
+                } => {
+                    inflight_hcache
+                        .remove_committed(&(namespace.clone(), key.clone()), *inflight_seq);
                 }
-                _ => {} // Only async operations use the inflight cache
+                _ => {} // Only async writes have inflight entries
             }
         }
     }
 
-    // Send responses to synchronous operations
-    for operation in batch.drain(..) {
+    // Send responses to synchronous operations. Async operations have no
+    // one to answer, so when the commit fails they are kept in the batch
+    // and retried with the next one.
+    let operations: Vec<_> = batch.drain(..).collect();
+    for (operation, result) in operations.into_iter().zip(results) {
+        if operation.is_async() {
+            if let Err(e) = &commit_result {
+                tracing::error!(
+                    "[Shard {}] ASYNC operation failed due to commit error, retrying: {}",
+                    shard_id,
+                    e
+                );
+                batch.push_back(operation);
+            }
+            continue;
+        }
         match operation {
             ShardWriteOperation::Set { responder, .. }
             | ShardWriteOperation::Delete { responder, .. }
@@ -412,20 +1159,388 @@ async fn process_batch(
                 };
                 let _ = responder.send(final_result);
             }
+            ShardWriteOperation::MSet { responder, .. }
+            | ShardWriteOperation::DeleteMany { responder, .. }
+            | ShardWriteOperation::HMSet { responder, .. } => {
+                let final_result = match (&commit_result, result) {
+                    (Ok(_), Ok(_)) => Ok(()),
+                    (Ok(_), Err(e)) => Err(e),
+                    (Err(e), _) => Err(e.clone()),
+                };
+                let _ = responder.send(final_result);
+            }
+            ShardWriteOperation::MSetNx { responder, .. }
+            | ShardWriteOperation::WriteRange { responder, .. }
+            | ShardWriteOperation::DropNamespace { responder, .. } => {
+                let final_result = match (&commit_result, result) {
+                    (Ok(_), Ok(WriteOutcome::Applied(applied))) => Ok(applied),
+                    (Ok(_), Ok(_)) => Ok(false),
+                    (Ok(_), Err(e)) => Err(e),
+                    (Err(e), _) => Err(e.clone()),
+                };
+                let _ = responder.send(final_result);
+            }
+            ShardWriteOperation::SetWithOptions { responder, .. }
+            | ShardWriteOperation::HSetWithOptions { responder, .. } => {
+                let final_result = match (&commit_result, result) {
+                    (Ok(_), Ok(WriteOutcome::Set(outcome))) => Ok(outcome),
+                    (Ok(_), Ok(_)) => Ok(SetOutcome::default()),
+                    (Ok(_), Err(e)) => Err(e),
+                    (Err(e), _) => Err(e.clone()),
+                };
+                let _ = responder.send(final_result);
+            }
+            ShardWriteOperation::Expire { responder, .. }
+            | ShardWriteOperation::Persist { responder, .. }
+            | ShardWriteOperation::HExpire { responder, .. }
+            | ShardWriteOperation::HPersist { responder, .. } => {
+                let final_result = match (&commit_result, result) {
+                    (Ok(_), Ok(WriteOutcome::Expire(outcome))) => Ok(outcome),
+                    (Ok(_), Ok(_)) => Ok(ExpireOutcome::Missing),
+                    (Ok(_), Err(e)) => Err(e),
+                    (Err(e), _) => Err(e.clone()),
+                };
+                let _ = responder.send(final_result);
+            }
             ShardWriteOperation::SetAsync { .. }
             | ShardWriteOperation::DeleteAsync { .. }
             | ShardWriteOperation::HSetAsync { .. }
-            | ShardWriteOperation::HDeleteAsync { .. } => {
-                // Async operations don't need responses, but log commit errors
-                if let Err(e) = &commit_result {
-                    tracing::error!(
-                        "[Shard {}] ASYNC operation failed due to commit error: {}",
-                        shard_id,
-                        e
-                    );
-                }
-            }
+            | ShardWriteOperation::HDeleteAsync { .. }
+            | ShardWriteOperation::MSetAsync { .. }
+            | ShardWriteOperation::DeleteManyAsync { .. }
+            | ShardWriteOperation::HMSetAsync { .. } => {} // Handled above
+        }
+    }
+
+    commit_result.map(|_| failed_async)
+}
+
+// Fetch the live (non-expired) data and expiry for a key
+async fn fetch_live_row(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    table_name: &str,
+    key: &[u8],
+) -> Result<Option<(Vec<u8>, Option<i64>)>, String> {
+    let query = format!(
+        "SELECT data, expires_at FROM {} WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+        quote_identifier(table_name)
+    );
+    sqlx::query_as::<_, (Vec<u8>, Option<i64>)>(&query)
+        .bind(key)
+        .bind(Utc::now().timestamp_millis())
+        .fetch_optional(&mut **tx)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+// Conditionally write a value together with its expiry
+async fn set_with_options(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    table_name: &str,
+    key: &[u8],
+    data: &Bytes,
+    ttl: TtlUpdate,
+    condition: Option<SetCondition>,
+) -> Result<SetOutcome, String> {
+    let current = fetch_live_row(tx, table_name, key).await?;
+    let previous = current.as_ref().map(|(data, _)| Bytes::from(data.clone()));
+
+    let allowed = match condition {
+        Some(SetCondition::Nx) => current.is_none(),
+        Some(SetCondition::Xx) => current.is_some(),
+        None => true,
+    };
+    if !allowed {
+        return Ok(SetOutcome {
+            applied: false,
+            previous,
+            previous_chunks: Vec::new(),
+        });
+    }
+
+    let now = Utc::now().timestamp();
+    match (current, ttl) {
+        (Some(_), TtlUpdate::Keep) => {
+            let query = format!(
+                "UPDATE {} SET data = ?, updated_at = ?, version = version + 1 WHERE key = ?",
+                quote_identifier(table_name)
+            );
+            sqlx::query(&query)
+                .bind(&data[..])
+                .bind(now)
+                .bind(key)
+                .execute(&mut **tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        (Some(_), ttl) => {
+            let expires_at = match ttl {
+                TtlUpdate::At(ts) => Some(ts),
+                _ => None,
+            };
+            let query = format!(
+                "UPDATE {} SET data = ?, updated_at = ?, expires_at = ?, version = version + 1 WHERE key = ?",
+                quote_identifier(table_name)
+            );
+            sqlx::query(&query)
+                .bind(&data[..])
+                .bind(now)
+                .bind(expires_at)
+                .bind(key)
+                .execute(&mut **tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        (None, ttl) => {
+            let expires_at = match ttl {
+                TtlUpdate::At(ts) => Some(ts),
+                _ => None,
+            };
+            // REPLACE also overwrites a row that exists but has already expired
+            let query = format!(
+                "INSERT OR REPLACE INTO {} (key, data, created_at, updated_at, expires_at, version) VALUES (?, ?, ?, ?, ?, 0)",
+                quote_identifier(table_name)
+            );
+            sqlx::query(&query)
+                .bind(key)
+                .bind(&data[..])
+                .bind(now)
+                .bind(now)
+                .bind(expires_at)
+                .execute(&mut **tx)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+
+    Ok(SetOutcome {
+        applied: true,
+        previous,
+        previous_chunks: Vec::new(),
+    })
+}
+
+// SET of the plain keyspace: `set_with_options`, plus the chunks of large
+// values on both sides of the write
+#[allow(clippy::too_many_arguments)]
+async fn set_value_with_options(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &[u8],
+    data: &Bytes,
+    chunks: &[(u32, Bytes)],
+    ttl: TtlUpdate,
+    condition: Option<SetCondition>,
+    get: bool,
+) -> Result<SetOutcome, String> {
+    // The chunks of the previous value are gone once it is overwritten
+    let previous_chunks = if get {
+        match fetch_live_row(tx, "blobs", key).await? {
+            Some((previous, _)) => match ChunkManifest::parse(&previous) {
+                Some(manifest) => chunks::read_chunks(tx, key, 0..manifest.chunk_count())
+                    .await
+                    .map_err(|e| e.to_string())?,
+                None => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    } else {
+        Vec::new()
+    };
+
+    let mut outcome = set_with_options(tx, "blobs", key, data, ttl, condition).await?;
+    if outcome.applied
+        && let Some(manifest) = ChunkManifest::parse(data)
+    {
+        chunks::write_chunks(tx, key, &manifest, chunks)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    outcome.previous_chunks = previous_chunks;
+    Ok(outcome)
+}
+
+// Write the result of APPEND or SETRANGE if the live value and its version
+// are still the ones it was computed from
+async fn write_range(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    key: &[u8],
+    data: &Bytes,
+    chunks: &[(u32, Bytes)],
+    expected: Option<&(Bytes, i64)>,
+) -> Result<bool, String> {
+    let current = sqlx::query_as::<_, (Vec<u8>, i64)>(
+        "SELECT data, version FROM blobs WHERE key = ? AND (expires_at IS NULL OR expires_at > ?)",
+    )
+    .bind(key)
+    .bind(Utc::now().timestamp_millis())
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let unchanged = match (current, expected) {
+        (None, None) => true,
+        (Some((data, version)), Some((expected_data, expected_version))) => {
+            version == *expected_version && data[..] == expected_data[..]
+        }
+        _ => false,
+    };
+    if !unchanged {
+        return Ok(false);
+    }
+
+    set_with_options(tx, "blobs", key, data, TtlUpdate::Keep, None).await?;
+    if let Some(manifest) = ChunkManifest::parse(data) {
+        chunks::write_chunks(tx, key, &manifest, chunks)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(true)
+}
+
+// Set the expiry of a live key, honouring the EXPIRE NX/XX/GT/LT flags.
+// An expiry in the past deletes the key, as Redis does.
+async fn set_expiry(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    table_name: &str,
+    key: &[u8],
+    expires_at: i64,
+    condition: Option<ExpireCondition>,
+) -> Result<ExpireOutcome, String> {
+    let Some((_, current)) = fetch_live_row(tx, table_name, key).await? else {
+        return Ok(ExpireOutcome::Missing);
+    };
+
+    // A key without an expiry is treated as having an infinite TTL for GT/LT
+    let allowed = match condition {
+        Some(ExpireCondition::Nx) => current.is_none(),
+        Some(ExpireCondition::Xx) => current.is_some(),
+        Some(ExpireCondition::Gt) => current.is_some_and(|c| expires_at > c),
+        Some(ExpireCondition::Lt) => current.is_none_or(|c| expires_at < c),
+        None => true,
+    };
+    if !allowed {
+        return Ok(ExpireOutcome::Skipped);
+    }
+
+    if expires_at <= Utc::now().timestamp_millis() {
+        let query = format!("DELETE FROM {} WHERE key = ?", quote_identifier(table_name));
+        sqlx::query(&query)
+            .bind(key)
+            .execute(&mut **tx)
+            .await
+            .map_err(|e| e.to_string())?;
+        return Ok(ExpireOutcome::Deleted);
+    }
+
+    let query = format!(
+        "UPDATE {} SET expires_at = ?, updated_at = ? WHERE key = ?",
+        quote_identifier(table_name)
+    );
+    sqlx::query(&query)
+        .bind(expires_at)
+        .bind(Utc::now().timestamp())
+        .bind(key)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(ExpireOutcome::Updated)
+}
+
+// Drop a namespace table, reporting whether it held any live key
+async fn drop_namespace_table(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    table_name: &str,
+) -> Result<bool, String> {
+    let query = format!(
+        "SELECT EXISTS(SELECT 1 FROM {} WHERE expires_at IS NULL OR expires_at > ?)",
+        quote_identifier(table_name)
+    );
+    let had_live_keys = match sqlx::query_as::<_, (bool,)>(&query)
+        .bind(Utc::now().timestamp_millis())
+        .fetch_one(&mut **tx)
+        .await
+    {
+        Ok((had_live_keys,)) => had_live_keys,
+        Err(e) if is_missing_table(&e) => return Ok(false),
+        Err(e) => return Err(e.to_string()),
+    };
+
+    sqlx::query(&format!(
+        "DROP TABLE IF EXISTS {}",
+        quote_identifier(table_name)
+    ))
+    .execute(&mut **tx)
+    .await
+    .map_err(|e| e.to_string())?;
+
+    Ok(had_live_keys)
+}
+
+// Plain SET of several keys, discarding any previous expiry
+async fn set_many(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    entries: &[(Bytes, Bytes)],
+    chunks: &[(Bytes, Vec<(u32, Bytes)>)],
+) -> Result<(), String> {
+    // The chunks are in the order of the chunked entries, so a key set twice
+    // gets the chunks of each of its values
+    let mut chunks = chunks.iter();
+    for (key, data) in entries {
+        set_with_options(tx, "blobs", key, data, TtlUpdate::Clear, None).await?;
+        if let Some(manifest) = ChunkManifest::parse(data) {
+            let Some((_, value_chunks)) = chunks.next() else {
+                return Err(format!("missing chunks of {:?}", key));
+            };
+            chunks::write_chunks(tx, key, &manifest, value_chunks)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+// Keep the writes of a multi-key operation if it succeeded, undo them if it
+// failed
+async fn end_savepoint(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    result: Result<WriteOutcome, String>,
+) -> Result<WriteOutcome, String> {
+    if result.is_err() {
+        sqlx::query("ROLLBACK TO op")
+            .execute(&mut **tx)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    sqlx::query("RELEASE op")
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| e.to_string())?;
+    result
+}
+
+// Remove the expiry of a live key
+async fn persist(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    table_name: &str,
+    key: &[u8],
+) -> Result<ExpireOutcome, String> {
+    match fetch_live_row(tx, table_name, key).await? {
+        Some((_, Some(_))) => {
+            let query = format!(
+                "UPDATE {} SET expires_at = NULL, updated_at = ? WHERE key = ?",
+                quote_identifier(table_name)
+            );
+            sqlx::query(&query)
+                .bind(Utc::now().timestamp())
+                .bind(key)
+                .execute(&mut **tx)
+                .await
+                .map_err(|e| e.to_string())?;
+            Ok(ExpireOutcome::Updated)
         }
+        Some(_) => Ok(ExpireOutcome::Skipped),
+        None => Ok(ExpireOutcome::Missing),
     }
 }
 
@@ -444,7 +1559,7 @@ async fn load_existing_tables(pool: &SqlitePool, shard_id: usize) -> HashSet<Str
     {
         Ok(rows) => {
             for (table_name,) in rows {
-                tables.insert(table_name);
+                tables.insert(rename_legacy_table(pool, shard_id, table_name).await);
             }
             tracing::info!(
                 "[Shard {}] Loaded {} existing namespaced tables",
@@ -460,6 +1575,40 @@ async fn load_existing_tables(pool: &SqlitePool, shard_id: usize) -> HashSet<Str
     tables
 }
 
+// Tables created before namespaces were encoded keep uppercase letters in
+// their name, rename them to the name the namespace maps to now
+async fn rename_legacy_table(pool: &SqlitePool, shard_id: usize, table_name: String) -> String {
+    let Some(namespace) = namespace::legacy_namespace_of(&table_name) else {
+        return table_name;
+    };
+    let new_name = namespace::table_name(namespace);
+    let query = format!(
+        "ALTER TABLE {} RENAME TO {}",
+        quote_identifier(&table_name),
+        quote_identifier(&new_name)
+    );
+    match sqlx::query(&query).execute(pool).await {
+        Ok(_) => {
+            tracing::info!(
+                "[Shard {}] Renamed table {} to {}",
+                shard_id,
+                table_name,
+                new_name
+            );
+            new_name
+        }
+        Err(e) => {
+            tracing::error!(
+                "[Shard {}] Failed to rename table {}: {}",
+                shard_id,
+                table_name,
+                e
+            );
+            table_name
+        }
+    }
+}
+
 // Ensure a namespaced table exists, creating it if necessary
 async fn ensure_namespaced_table_exists(
     tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
@@ -472,23 +1621,23 @@ async fn ensure_namespaced_table_exists(
 
     let create_query = format!(
         "CREATE TABLE IF NOT EXISTS {} (
-            key TEXT PRIMARY KEY,
+            key BLOB PRIMARY KEY,
             data BLOB,
             created_at INTEGER NOT NULL,
             updated_at INTEGER NOT NULL,
             expires_at INTEGER,
             version INTEGER NOT NULL DEFAULT 0
         )",
-        table_name
+        quote_identifier(table_name)
     );
 
     match sqlx::query(&create_query).execute(&mut **tx).await {
         Ok(_) => {
             // Create index on expires_at for efficient expiry queries
             let index_query = format!(
-                "CREATE INDEX IF NOT EXISTS idx_{}_expires_at ON {}(expires_at) WHERE expires_at IS NOT NULL",
-                table_name.replace("blobs_", ""),
-                table_name
+                "CREATE INDEX IF NOT EXISTS {} ON {}(expires_at) WHERE expires_at IS NOT NULL",
+                quote_identifier(&namespace::expires_at_index_name(table_name)),
+                quote_identifier(table_name)
             );
 
             match sqlx::query(&index_query).execute(&mut **tx).await {
@@ -516,3 +1665,1027 @@ async fn ensure_namespaced_table_exists(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::sqlite::SqliteConnectOptions;
+    use std::str::FromStr;
+    use tempfile::TempDir;
+
+    async fn setup_writer(temp_dir: &TempDir) -> (SqlitePool, mpsc::Sender<ShardWriteOperation>) {
+        let (pool, sender, _) =
+            setup_writer_with_shutdown(temp_dir, CancellationToken::new(), None).await;
+        (pool, sender)
+    }
+
+    async fn setup_writer_with_shutdown(
+        temp_dir: &TempDir,
+        shutdown: CancellationToken,
+        journal: Option<Arc<Journal>>,
+    ) -> (
+        SqlitePool,
+        mpsc::Sender<ShardWriteOperation>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        setup_writer_with_inflight(temp_dir, shutdown, journal, InflightValues::new()).await
+    }
+
+    async fn setup_writer_with_inflight(
+        temp_dir: &TempDir,
+        shutdown: CancellationToken,
+        journal: Option<Arc<Journal>>,
+        inflight: InflightValues,
+    ) -> (
+        SqlitePool,
+        mpsc::Sender<ShardWriteOperation>,
+        tokio::task::JoinHandle<()>,
+    ) {
+        let db_path = temp_dir.path().join("shard_0.db");
+        let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path.display()))
+            .unwrap()
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS blobs (
+                key BLOB PRIMARY KEY,
+                data BLOB,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                expires_at INTEGER,
+                version INTEGER NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        chunks::create_chunk_table(&pool).await.unwrap();
+        journal::create_watermark_table(&pool).await.unwrap();
+
+        let (sender, receiver) = mpsc::channel(16);
+        let writer = tokio::spawn(shard_writer_task(
+            0,
+            pool.clone(),
+            receiver,
+            1,
+            0,
+            inflight,
+            InflightHashes::new(),
+            Metrics::new(),
+            shutdown,
+            journal,
+        ));
+        (pool, sender, writer)
+    }
+
+    async fn set_with(
+        sender: &mpsc::Sender<ShardWriteOperation>,
+        key: &str,
+        data: &'static [u8],
+        ttl: TtlUpdate,
+        condition: Option<SetCondition>,
+    ) -> SetOutcome {
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::SetWithOptions {
+                key: Bytes::copy_from_slice(key.as_bytes()),
+                data: Bytes::from_static(data),
+                chunks: Vec::new(),
+                ttl,
+                condition,
+                get: false,
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap()
+    }
+
+    async fn expires_at(pool: &SqlitePool, key: &str) -> Option<i64> {
+        sqlx::query_as::<_, (Option<i64>,)>("SELECT expires_at FROM blobs WHERE key = ?")
+            .bind(key.as_bytes())
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn test_set_conditions_and_keepttl() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let future = Utc::now().timestamp_millis() + 60_000;
+
+        // XX on a missing key is not applied
+        let outcome = set_with(
+            &sender,
+            "k",
+            b"v1",
+            TtlUpdate::Clear,
+            Some(SetCondition::Xx),
+        )
+        .await;
+        assert!(!outcome.applied);
+
+        // NX on a missing key writes the value with its TTL
+        let outcome = set_with(
+            &sender,
+            "k",
+            b"v1",
+            TtlUpdate::At(future),
+            Some(SetCondition::Nx),
+        )
+        .await;
+        assert!(outcome.applied);
+        assert!(outcome.previous.is_none());
+        assert_eq!(expires_at(&pool, "k").await, Some(future));
+
+        // NX on an existing key is rejected but still reports the previous value
+        let outcome = set_with(
+            &sender,
+            "k",
+            b"v2",
+            TtlUpdate::Clear,
+            Some(SetCondition::Nx),
+        )
+        .await;
+        assert!(!outcome.applied);
+        assert_eq!(outcome.previous, Some(Bytes::from_static(b"v1")));
+
+        // KEEPTTL overwrites the value but keeps the expiry
+        let outcome = set_with(&sender, "k", b"v3", TtlUpdate::Keep, Some(SetCondition::Xx)).await;
+        assert!(outcome.applied);
+        assert_eq!(expires_at(&pool, "k").await, Some(future));
+
+        // A write without expiry clears it
+        set_with(&sender, "k", b"v4", TtlUpdate::Clear, None).await;
+        assert_eq!(expires_at(&pool, "k").await, None);
+    }
+
+    async fn chunk_count(pool: &SqlitePool, key: &str) -> i64 {
+        sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM blob_chunks WHERE key = ?")
+            .bind(key.as_bytes())
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .0
+    }
+
+    #[tokio::test]
+    async fn test_chunked_values() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+
+        let set_chunked = |len: u64, get: bool| {
+            let manifest = ChunkManifest::new(len, 2);
+            let chunks = (0..manifest.chunk_count())
+                .map(|idx| (idx, Bytes::from(vec![b'a' + idx as u8; 2])))
+                .collect();
+            let (tx, rx) = oneshot::channel();
+            let op = ShardWriteOperation::SetWithOptions {
+                key: Bytes::from_static(b"big"),
+                data: manifest.encode(),
+                chunks,
+                ttl: TtlUpdate::Clear,
+                condition: None,
+                get,
+                responder: tx,
+            };
+            (op, rx, manifest)
+        };
+
+        let (op, rx, first) = set_chunked(6, false);
+        sender.send(op).await.unwrap();
+        assert!(rx.await.unwrap().unwrap().applied);
+        assert_eq!(chunk_count(&pool, "big").await, 3);
+
+        // A shorter chunked value drops the chunks past its end, and SET GET
+        // returns the chunks of the value it replaced
+        let (op, rx, second) = set_chunked(4, true);
+        sender.send(op).await.unwrap();
+        let outcome = rx.await.unwrap().unwrap();
+        assert_eq!(outcome.previous, Some(first.encode()));
+        assert_eq!(outcome.previous_chunks.len(), 3);
+        assert_eq!(chunk_count(&pool, "big").await, 2);
+
+        // A range write computed from an outdated value is not applied
+        let write_range = |expected: Option<(Bytes, i64)>| {
+            let (tx, rx) = oneshot::channel();
+            let op = ShardWriteOperation::WriteRange {
+                key: Bytes::from_static(b"big"),
+                data: Bytes::from_static(b"small"),
+                chunks: Vec::new(),
+                expected,
+                responder: tx,
+            };
+            (op, rx)
+        };
+        let (op, rx) = write_range(Some((first.encode(), 0)));
+        sender.send(op).await.unwrap();
+        assert!(!rx.await.unwrap().unwrap());
+        let (op, rx) = write_range(None);
+        sender.send(op).await.unwrap();
+        assert!(!rx.await.unwrap().unwrap());
+        assert_eq!(chunk_count(&pool, "big").await, 2);
+
+        // Replacing the value with one that is not chunked deletes the chunks
+        let (op, rx) = write_range(Some((second.encode(), 1)));
+        sender.send(op).await.unwrap();
+        assert!(rx.await.unwrap().unwrap());
+        assert_eq!(chunk_count(&pool, "big").await, 0);
+
+        // So does deleting the key
+        let (op, rx, _) = set_chunked(6, false);
+        sender.send(op).await.unwrap();
+        rx.await.unwrap().unwrap();
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::Delete {
+                key: Bytes::from_static(b"big"),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(chunk_count(&pool, "big").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_expired_key_is_treated_as_missing() {
+        let temp_dir = TempDir::new().unwrap();
+        let (_pool, sender) = setup_writer(&temp_dir).await;
+        let past = Utc::now().timestamp_millis() - 1_000;
+
+        set_with(&sender, "k", b"old", TtlUpdate::At(past), None).await;
+
+        let outcome = set_with(
+            &sender,
+            "k",
+            b"new",
+            TtlUpdate::Clear,
+            Some(SetCondition::Nx),
+        )
+        .await;
+        assert!(outcome.applied);
+        assert!(outcome.previous.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reap_expired_is_bounded() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let now = Utc::now().timestamp_millis();
+
+        for i in 0..5 {
+            set_with(
+                &sender,
+                &format!("old{}", i),
+                b"1234",
+                TtlUpdate::At(now - 1),
+                None,
+            )
+            .await;
+        }
+        set_with(&sender, "live", b"1234", TtlUpdate::At(now + 60_000), None).await;
+        set_with(&sender, "forever", b"1234", TtlUpdate::Clear, None).await;
+        sqlx::query(
+            "CREATE TABLE blobs_ns (key BLOB PRIMARY KEY, data BLOB, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, expires_at INTEGER, version INTEGER NOT NULL DEFAULT 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO blobs_ns VALUES ('field', x'00', 0, 0, ?, 0)")
+            .bind(now - 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // The per-pass limit caps the first pass
+        let stats = reap_expired(&pool, now, 2, 3).await.unwrap();
+        assert_eq!(stats, ReapStats { keys: 3, bytes: 12 });
+
+        let stats = reap_expired(&pool, now, 2, 100).await.unwrap();
+        assert_eq!(stats, ReapStats { keys: 3, bytes: 9 });
+
+        let remaining: Vec<(Vec<u8>,)> = sqlx::query_as("SELECT key FROM blobs ORDER BY key")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(remaining, vec![(b"forever".to_vec(),), (b"live".to_vec(),)]);
+    }
+
+    #[tokio::test]
+    async fn test_reap_expired_counts_chunks() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let now = Utc::now().timestamp_millis();
+
+        let manifest = ChunkManifest::new(6, 2);
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::SetWithOptions {
+                key: Bytes::from_static(b"big"),
+                data: manifest.encode(),
+                chunks: (0..3).map(|idx| (idx, Bytes::from_static(b"ab"))).collect(),
+                ttl: TtlUpdate::At(now - 1),
+                condition: None,
+                get: false,
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().unwrap().applied);
+
+        let stats = reap_expired(&pool, now, 10, 100).await.unwrap();
+        assert_eq!(
+            stats,
+            ReapStats {
+                keys: 1,
+                bytes: chunks::MANIFEST_LEN as u64 + 6
+            }
+        );
+        assert_eq!(chunk_count(&pool, "big").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_persist() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let now = Utc::now().timestamp_millis();
+
+        let expire = |key: &str, at: i64, condition: Option<ExpireCondition>| {
+            let (tx, rx) = oneshot::channel();
+            let op = ShardWriteOperation::Expire {
+                key: Bytes::copy_from_slice(key.as_bytes()),
+                expires_at: at,
+                condition,
+                responder: tx,
+            };
+            (op, rx)
+        };
+
+        // Missing key
+        let (op, rx) = expire("k", now + 10_000, None);
+        sender.send(op).await.unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Missing);
+
+        set_with(&sender, "k", b"v", TtlUpdate::Clear, None).await;
+
+        // GT never applies to a key without TTL, LT always does
+        let (op, rx) = expire("k", now + 10_000, Some(ExpireCondition::Gt));
+        sender.send(op).await.unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Skipped);
+        let (op, rx) = expire("k", now + 10_000, Some(ExpireCondition::Lt));
+        sender.send(op).await.unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Updated);
+        assert_eq!(expires_at(&pool, "k").await, Some(now + 10_000));
+
+        // PERSIST removes the TTL once
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::Persist {
+                key: Bytes::from("k"),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Updated);
+        assert_eq!(expires_at(&pool, "k").await, None);
+
+        // An expiry in the past deletes the key
+        let (op, rx) = expire("k", now - 1, None);
+        sender.send(op).await.unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Deleted);
+        let count: (i64,) = sqlx::query_as("SELECT COUNT(*) FROM blobs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count.0, 0);
+    }
+
+    #[tokio::test]
+    async fn test_namespaced_field_expiry() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let now = Utc::now().timestamp_millis();
+
+        let hexpire = |key: &str, at: i64| {
+            let (tx, rx) = oneshot::channel();
+            let op = ShardWriteOperation::HExpire {
+                namespace: "ns".to_string(),
+                key: Bytes::copy_from_slice(key.as_bytes()),
+                expires_at: at,
+                condition: None,
+                responder: tx,
+            };
+            (op, rx)
+        };
+
+        // The namespace table does not exist yet
+        let (op, rx) = hexpire("f", now + 10_000);
+        sender.send(op).await.unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Missing);
+
+        // HSETEX creates the table and stores the TTL
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::HSetWithOptions {
+                namespace: "ns".to_string(),
+                key: Bytes::from("f"),
+                data: Bytes::from_static(b"v"),
+                ttl: TtlUpdate::At(now + 10_000),
+                condition: Some(SetCondition::Nx),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().unwrap().applied);
+        let field_expiry = || async {
+            sqlx::query_as::<_, (Option<i64>,)>("SELECT expires_at FROM blobs_ns WHERE key = x'66'")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .0
+        };
+        assert_eq!(field_expiry().await, Some(now + 10_000));
+
+        let (op, rx) = hexpire("f", now + 20_000);
+        sender.send(op).await.unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Updated);
+        assert_eq!(field_expiry().await, Some(now + 20_000));
+
+        // A plain HSET overwrites the value and discards the TTL
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::HSet {
+                namespace: "ns".to_string(),
+                key: Bytes::from("f"),
+                data: Bytes::from_static(b"v2"),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(field_expiry().await, None);
+
+        // HPERSIST on a field without TTL is skipped
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::HPersist {
+                namespace: "ns".to_string(),
+                key: Bytes::from("f"),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert_eq!(rx.await.unwrap().unwrap(), ExpireOutcome::Skipped);
+    }
+
+    #[tokio::test]
+    async fn test_multi_key_operations() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let entries = |keys: &[&str]| -> Vec<(Bytes, Bytes)> {
+            keys.iter()
+                .map(|key| {
+                    (
+                        Bytes::copy_from_slice(key.as_bytes()),
+                        Bytes::from_static(b"v"),
+                    )
+                })
+                .collect()
+        };
+        let count = || async {
+            sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM blobs")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .0
+        };
+
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::MSet {
+                entries: entries(&["a", "b"]),
+                chunks: Vec::new(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(count().await, 2);
+
+        // MSETNX sets nothing if any key exists
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::MSetNx {
+                entries: entries(&["b", "c"]),
+                chunks: Vec::new(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert!(!rx.await.unwrap().unwrap());
+        assert_eq!(count().await, 2);
+
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::MSetNx {
+                entries: entries(&["c", "d"]),
+                chunks: Vec::new(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().unwrap());
+        assert_eq!(count().await, 4);
+
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::DeleteMany {
+                keys: vec![Bytes::from("a"), Bytes::from("c"), Bytes::from("missing")],
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(count().await, 2);
+
+        // A key failing midway undoes the keys before it
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::MSet {
+                entries: vec![
+                    (Bytes::from("e"), Bytes::from("v")),
+                    (Bytes::from("f"), ChunkManifest::new(10, 4).encode()),
+                ],
+                chunks: Vec::new(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().is_err());
+        assert_eq!(count().await, 2);
+
+        // The writer carries on with the next write
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::MSet {
+                entries: entries(&["e"]),
+                chunks: Vec::new(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        assert_eq!(count().await, 3);
+    }
+
+    #[tokio::test]
+    async fn test_hmset_and_drop_namespace() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let table_exists = || async {
+            sqlx::query_as::<_, (bool,)>(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'blobs_ns')",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .0
+        };
+        let drop_namespace = || async {
+            let (tx, rx) = oneshot::channel();
+            sender
+                .send(ShardWriteOperation::DropNamespace {
+                    namespace: "ns".to_string(),
+                    responder: tx,
+                })
+                .await
+                .unwrap();
+            rx.await.unwrap().unwrap()
+        };
+
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::HMSet {
+                namespace: "ns".to_string(),
+                entries: vec![
+                    (Bytes::from("a"), Bytes::from_static(b"1")),
+                    (Bytes::from("b"), Bytes::from_static(b"2")),
+                ],
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM blobs_ns")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        assert!(drop_namespace().await);
+        assert!(!table_exists().await);
+        assert!(!drop_namespace().await);
+
+        // The writer recreates the table on the next write
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::HSet {
+                namespace: "ns".to_string(),
+                key: Bytes::from("a"),
+                data: Bytes::from_static(b"1"),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+        assert!(table_exists().await);
+
+        // A namespace holding only expired keys is dropped but reports no live keys
+        sqlx::query("UPDATE blobs_ns SET expires_at = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert!(!drop_namespace().await);
+        assert!(!table_exists().await);
+    }
+
+    #[tokio::test]
+    async fn test_hostile_namespaces() {
+        let temp_dir = TempDir::new().unwrap();
+        let (pool, sender) = setup_writer(&temp_dir).await;
+
+        for namespace in ["x\"; DROP TABLE blobs; --", "a b", "users:1", "Users"] {
+            let (tx, rx) = oneshot::channel();
+            sender
+                .send(ShardWriteOperation::HSet {
+                    namespace: namespace.to_string(),
+                    key: Bytes::from("k"),
+                    data: Bytes::from(namespace.to_string()),
+                    responder: tx,
+                })
+                .await
+                .unwrap();
+            rx.await.unwrap().unwrap();
+
+            let query = format!(
+                "SELECT data FROM {} WHERE key = x'6b'",
+                quote_identifier(&namespace::table_name(namespace))
+            );
+            let (data,): (Vec<u8>,) = sqlx::query_as(&query).fetch_one(&pool).await.unwrap();
+            assert_eq!(data, namespace.as_bytes());
+        }
+
+        // `users` and `Users` must not share a table
+        let (tables,): (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name LIKE 'blobs_%'",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(tables, 4);
+
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::DropNamespace {
+                namespace: "x\"; DROP TABLE blobs; --".to_string(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        assert!(rx.await.unwrap().unwrap());
+        sqlx::query("SELECT COUNT(*) FROM blobs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_legacy_table_is_renamed() {
+        let temp_dir = TempDir::new().unwrap();
+        let db_path = temp_dir.path().join("shard_0.db");
+        let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path.display()))
+            .unwrap()
+            .create_if_missing(true);
+        let legacy = SqlitePool::connect_with(options).await.unwrap();
+        sqlx::query(
+            "CREATE TABLE blobs_Users (key TEXT PRIMARY KEY, data BLOB, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, expires_at INTEGER, version INTEGER NOT NULL DEFAULT 0)",
+        )
+        .execute(&legacy)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO blobs_Users VALUES ('k', x'01', 0, 0, NULL, 0)")
+            .execute(&legacy)
+            .await
+            .unwrap();
+        legacy.close().await;
+
+        let (pool, sender) = setup_writer(&temp_dir).await;
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::HSet {
+                namespace: "Users".to_string(),
+                key: Bytes::from("k2"),
+                data: Bytes::from_static(b"\x02"),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+
+        let tables: Vec<(String,)> = sqlx::query_as(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name LIKE 'blobs_%'",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(tables, vec![("blobs_$55sers".to_string(),)]);
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM \"blobs_$55sers\"")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queued_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let shutdown = CancellationToken::new();
+        let (pool, sender, writer) =
+            setup_writer_with_shutdown(&temp_dir, shutdown.clone(), None).await;
+
+        for i in 0..10 {
+            sender
+                .send(ShardWriteOperation::SetAsync {
+                    key: Bytes::from(format!("k{}", i)),
+                    data: Bytes::from_static(b"v"),
+                    inflight_seq: 0,
+                })
+                .await
+                .unwrap();
+        }
+        shutdown.cancel();
+        writer.await.unwrap();
+
+        // Every write queued before the shutdown is stored, later ones are refused
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM blobs")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 10);
+        assert!(
+            sender
+                .send(ShardWriteOperation::SetAsync {
+                    key: Bytes::from_static(b"late"),
+                    data: Bytes::from_static(b"v"),
+                    inflight_seq: 0,
+                })
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pending_writes_are_not_evicted() {
+        let temp_dir = TempDir::new().unwrap();
+        let inflight = InflightValues::new();
+        let (pool, sender, _) =
+            setup_writer_with_inflight(&temp_dir, CancellationToken::new(), None, inflight.clone())
+                .await;
+        let keys: Vec<Bytes> = (0..20_000)
+            .map(|i| Bytes::from(format!("k{}", i)))
+            .collect();
+
+        let (tx, rx) = oneshot::channel();
+        sender
+            .send(ShardWriteOperation::MSet {
+                entries: keys
+                    .iter()
+                    .map(|key| (key.clone(), Bytes::from_static(b"old")))
+                    .collect(),
+                chunks: Vec::new(),
+                responder: tx,
+            })
+            .await
+            .unwrap();
+        rx.await.unwrap().unwrap();
+
+        // More writes are pending than the old 10,000 entry cache could hold
+        for batch in keys.chunks(1_000) {
+            let entries: Vec<(Bytes, Bytes)> = batch
+                .iter()
+                .map(|key| (key.clone(), Bytes::from_static(b"new")))
+                .collect();
+            let inflight_seq = inflight.next_seq();
+            for (key, value) in &entries {
+                inflight.insert(key.clone(), value.clone(), inflight_seq);
+            }
+            sender
+                .send(ShardWriteOperation::MSetAsync {
+                    entries,
+                    inflight_seq,
+                })
+                .await
+                .unwrap();
+        }
+
+        // Every key reads back its new value, pending or committed
+        for key in &keys {
+            let value = match inflight.get(key) {
+                Some(value) => value.to_vec(),
+                None => {
+                    sqlx::query_as::<_, (Vec<u8>,)>("SELECT data FROM blobs WHERE key = ?")
+                        .bind(&key[..])
+                        .fetch_one(&pool)
+                        .await
+                        .unwrap()
+                        .0
+                }
+            };
+            assert_eq!(value, b"new", "stale value for {:?}", key);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_commit_keeps_later_pending_write() {
+        let temp_dir = TempDir::new().unwrap();
+        let inflight = InflightValues::new();
+        let (pool, sender, _) =
+            setup_writer_with_inflight(&temp_dir, CancellationToken::new(), None, inflight.clone())
+                .await;
+        let key = Bytes::from_static(b"k");
+        let stored = || async {
+            sqlx::query_as::<_, (Vec<u8>,)>("SELECT data FROM blobs WHERE key = ?")
+                .bind(&key[..])
+                .fetch_optional(&pool)
+                .await
+                .unwrap()
+                .map(|(data,)| data)
+        };
+        let set_async = |data: &'static [u8], inflight_seq| ShardWriteOperation::SetAsync {
+            key: key.clone(),
+            data: Bytes::from_static(data),
+            inflight_seq,
+        };
+
+        // Two async SETs of one key, the second still queued when the first
+        // is committed
+        let first = inflight.next_seq();
+        inflight.insert(key.clone(), Bytes::from_static(b"v1"), first);
+        let second = inflight.next_seq();
+        inflight.insert(key.clone(), Bytes::from_static(b"v2"), second);
+        sender.send(set_async(b"v1", first)).await.unwrap();
+        // A synchronous write is answered once the batches before it are done
+        set_with(&sender, "barrier", b"v", TtlUpdate::Clear, None).await;
+
+        // Reads between the two commits still see the second value
+        assert_eq!(stored().await.unwrap(), b"v1");
+        assert_eq!(inflight.get(&key).unwrap(), "v2");
+
+        sender.send(set_async(b"v2", second)).await.unwrap();
+        set_with(&sender, "barrier", b"v", TtlUpdate::Clear, None).await;
+        assert_eq!(stored().await.unwrap(), b"v2");
+        assert!(!inflight.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_failed_batch_is_retried() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let journal_cfg = crate::config::JournalConfig {
+            enabled: true,
+            fsync: Some(false),
+            segment_size: None,
+        };
+        let journal = Arc::new(Journal::create(data_dir, 0, &journal_cfg, 0).await.unwrap());
+        let shutdown = CancellationToken::new();
+        let (pool, sender, writer) =
+            setup_writer_with_shutdown(&temp_dir, shutdown.clone(), Some(journal.clone())).await;
+
+        // Fail every commit while the table has a row
+        sqlx::query("CREATE TABLE fail_commit (x INTEGER)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO fail_commit VALUES (1)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(&format!(
+            "CREATE TRIGGER fail_watermark BEFORE INSERT ON {}
+             WHEN EXISTS (SELECT 1 FROM fail_commit)
+             BEGIN SELECT RAISE(ABORT, 'injected failure'); END",
+            journal::WATERMARK_TABLE
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        for (key, data) in [(&b"k1"[..], &b"v1"[..]), (b"k2", b"v2")] {
+            journal
+                .send(
+                    &sender,
+                    ShardWriteOperation::SetAsync {
+                        key: Bytes::copy_from_slice(key),
+                        data: Bytes::copy_from_slice(data),
+                        inflight_seq: 0,
+                    },
+                )
+                .await
+                .unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // The failed writes are still journaled
+        assert_eq!(journal::load_watermark(&pool).await.unwrap(), 0);
+        assert_eq!(Journal::recover(data_dir, 0, 0).await.unwrap().len(), 2);
+
+        sqlx::query("DELETE FROM fail_commit")
+            .execute(&pool)
+            .await
+            .unwrap();
+        set_with(&sender, "barrier", b"v", TtlUpdate::Clear, None).await;
+        shutdown.cancel();
+        writer.await.unwrap();
+
+        for (key, data) in [(&b"k1"[..], &b"v1"[..]), (b"k2", b"v2")] {
+            let (stored,): (Vec<u8>,) = sqlx::query_as("SELECT data FROM blobs WHERE key = ?")
+                .bind(key)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(stored, data);
+        }
+        assert_eq!(journal::load_watermark(&pool).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_committed_writes() {
+        let temp_dir = TempDir::new().unwrap();
+        let data_dir = temp_dir.path().to_str().unwrap();
+        let journal_cfg = crate::config::JournalConfig {
+            enabled: true,
+            fsync: Some(false),
+            segment_size: None,
+        };
+        let journal = Arc::new(Journal::create(data_dir, 0, &journal_cfg, 0).await.unwrap());
+        let shutdown = CancellationToken::new();
+        let (pool, sender, writer) =
+            setup_writer_with_shutdown(&temp_dir, shutdown.clone(), Some(journal.clone())).await;
+
+        // An asynchronous write followed by a synchronous one that is never
+        // journaled
+        journal
+            .send(
+                &sender,
+                ShardWriteOperation::SetAsync {
+                    key: Bytes::from_static(b"k"),
+                    data: Bytes::from_static(b"v1"),
+                    inflight_seq: 0,
+                },
+            )
+            .await
+            .unwrap();
+        let future = Utc::now().timestamp_millis() + 60_000;
+        set_with(&sender, "k", b"v2", TtlUpdate::At(future), None).await;
+        shutdown.cancel();
+        writer.await.unwrap();
+
+        // The committed write is behind the watermark and is not replayed
+        // over the newer value
+        let watermark = journal::load_watermark(&pool).await.unwrap();
+        assert_eq!(watermark, 1);
+        assert!(
+            Journal::recover(data_dir, 0, watermark)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+
+        // Writes after the watermark are replayed and move it
+        let records = vec![ShardWriteOperation::SetAsync {
+            key: Bytes::from_static(b"k"),
+            data: Bytes::from_static(b"v3"),
+            inflight_seq: 0,
+        }];
+        replay_journal(
+            0,
+            &pool,
+            records,
+            2,
+            &InflightValues::new(),
+            &InflightHashes::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(journal::load_watermark(&pool).await.unwrap(), 2);
+        let (data,): (Vec<u8>,) = sqlx::query_as("SELECT data FROM blobs WHERE key = ?")
+            .bind(&b"k"[..])
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(data, b"v3");
+    }
+}
@@ -0,0 +1,148 @@
+//! TLS for the Redis listener.
+//!
+//! The certificate, key and client CA are read from the paths in `[tls]`
+//! when the server starts and again on every SIGHUP, so certificates can be
+//! rotated without a restart. Connections accepted after a reload use the
+//! new certificates; established connections keep their session. If the new
+//! files cannot be loaded the previous certificates stay in use.
+
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use std::sync::{Arc, RwLock};
+use tokio_rustls::TlsAcceptor;
+
+use crate::config::{ClientAuth, TlsConfig};
+
+/// TLS settings of the listener, reloadable at runtime
+pub struct Tls {
+    cfg: TlsConfig,
+    server_config: RwLock<Arc<rustls::ServerConfig>>,
+}
+
+impl Tls {
+    pub fn load(cfg: &TlsConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            cfg: cfg.clone(),
+            server_config: RwLock::new(build_server_config(cfg)?),
+        })
+    }
+
+    /// Acceptor for a new connection, with the current certificates
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config.read().unwrap().clone())
+    }
+
+    /// Read the certificate, key and client CA files again
+    pub fn reload(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let server_config = build_server_config(&self.cfg)?;
+        *self.server_config.write().unwrap() = server_config;
+        Ok(())
+    }
+}
+
+/// Reload the certificates whenever the process receives SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_sighup(tls: Arc<Tls>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            tracing::error!("Failed to listen for SIGHUP, TLS reload is disabled: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        match tls.reload() {
+            Ok(()) => tracing::info!("Reloaded TLS certificates"),
+            Err(e) => tracing::error!(
+                "Failed to reload TLS certificates, keeping the current ones: {}",
+                e
+            ),
+        }
+    }
+}
+
+fn build_server_config(
+    cfg: &TlsConfig,
+) -> Result<Arc<rustls::ServerConfig>, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(&cfg.cert_file)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("reading certificates from {}: {}", cfg.cert_file, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates found in {}", cfg.cert_file).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(&cfg.key_file)
+        .map_err(|e| format!("reading private key from {}: {}", cfg.key_file, e))?;
+
+    let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match cfg.client_ca_file {
+        Some(ref ca_file) => builder.with_client_cert_verifier(client_verifier(
+            ca_file,
+            cfg.client_auth(),
+            provider,
+        )?),
+        None => builder.with_no_client_auth(),
+    };
+    let server_config = builder.with_single_cert(certs, key)?;
+    Ok(Arc::new(server_config))
+}
+
+fn client_verifier(
+    ca_file: &str,
+    client_auth: ClientAuth,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, Box<dyn std::error::Error>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca_file)
+        .map_err(|e| format!("reading client CA from {}: {}", ca_file, e))?
+    {
+        roots.add(cert.map_err(|e| format!("reading client CA from {}: {}", ca_file, e))?)?;
+    }
+
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    let verifier = match client_auth {
+        ClientAuth::Required => builder.build()?,
+        ClientAuth::Optional => builder.allow_unauthenticated().build()?,
+    };
+    Ok(verifier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_files_are_reported() {
+        let cfg = TlsConfig {
+            addr: None,
+            cert_file: "/nonexistent/server.crt".to_string(),
+            key_file: "/nonexistent/server.key".to_string(),
+            client_ca_file: None,
+            client_auth: None,
+        };
+        let err = Tls::load(&cfg).err().unwrap().to_string();
+        assert!(err.contains("/nonexistent/server.crt"), "{}", err);
+    }
+
+    #[test]
+    fn test_empty_certificate_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert_file = dir.path().join("server.crt");
+        std::fs::write(&cert_file, "").unwrap();
+        let cfg = TlsConfig {
+            addr: None,
+            cert_file: cert_file.to_string_lossy().to_string(),
+            key_file: "/nonexistent/server.key".to_string(),
+            client_ca_file: None,
+            client_auth: None,
+        };
+        let err = Tls::load(&cfg).err().unwrap().to_string();
+        assert!(err.starts_with("no certificates found"), "{}", err);
+    }
+}
@@ -0,0 +1,199 @@
+//! Offline training of zstd dictionaries from stored values.
+//!
+//! Small values with a shared structure, such as JSON documents, barely
+//! compress on their own. `DictionaryTrainer` samples random rows from the
+//! `blobs*` tables of every shard, trains a dictionary from their decoded
+//! values and saves it as the next version in the data directory. Servers
+//! configured with `use_dictionary = true` compress new values with the latest
+//! version after a restart; `shard recompress` rewrites existing ones.
+
+use crate::chunks;
+use crate::compression::{DictionaryStore, ValueCodec, train_dictionary};
+use crate::config::CompressionConfig;
+use crate::namespace::quote_identifier;
+use miette::{Context, Result};
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Values sampled across all shards when no sample count is given
+pub const DEFAULT_SAMPLES: usize = 10_000;
+
+// Helper function to convert sqlx errors to miette errors
+fn sqlx_to_miette(err: sqlx::Error, context: &str) -> miette::Error {
+    miette::miette!("{}: {}", context, err)
+}
+
+/// A dictionary saved by a training run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TrainedDictionary {
+    pub id: u32,
+    pub size: usize,
+    pub samples: usize,
+}
+
+pub struct DictionaryTrainer {
+    shard_count: usize,
+    data_dir: String,
+    /// Decodes the sampled values, whichever codec wrote them
+    reader: ValueCodec,
+    dictionaries: DictionaryStore,
+    samples: usize,
+    max_size: usize,
+}
+
+impl DictionaryTrainer {
+    pub fn new(
+        shard_count: usize,
+        data_dir: String,
+        current: Option<&CompressionConfig>,
+        samples: usize,
+        max_size: usize,
+    ) -> Result<Self> {
+        if shard_count == 0 {
+            return Err(miette::miette!("Shard count must be greater than 0"));
+        }
+        if samples == 0 {
+            return Err(miette::miette!("Sample count must be greater than 0"));
+        }
+        if max_size < 256 {
+            return Err(miette::miette!(
+                "Dictionary size must be at least 256 bytes"
+            ));
+        }
+
+        let dictionaries = DictionaryStore::load(&data_dir)
+            .map_err(|e| miette::miette!("Failed to load zstd dictionaries: {}", e))?;
+
+        Ok(DictionaryTrainer {
+            shard_count,
+            reader: ValueCodec::with_dictionaries(current, &dictionaries),
+            dictionaries,
+            data_dir,
+            samples,
+            max_size,
+        })
+    }
+
+    async fn create_connection_pool(&self, shard_id: usize) -> Result<SqlitePool> {
+        let db_path = format!("{}/shard_{}.db", self.data_dir, shard_id);
+        if !Path::new(&db_path).exists() {
+            return Err(miette::miette!("Shard file {} does not exist", db_path));
+        }
+
+        let connect_options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path))
+            .map_err(|e| sqlx_to_miette(e, "Failed to parse connection string"))?
+            .busy_timeout(std::time::Duration::from_millis(5000));
+
+        SqlitePool::connect_with(connect_options)
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to connect to database"))
+    }
+
+    /// Sample every shard, train a dictionary and save it as the next version
+    pub async fn run(mut self) -> Result<TrainedDictionary> {
+        tracing::info!(
+            "Sampling {} values from {} shards in {}",
+            self.samples,
+            self.shard_count,
+            self.data_dir
+        );
+
+        let per_shard = self.samples.div_ceil(self.shard_count);
+        let mut samples = Vec::with_capacity(self.samples);
+        for shard_id in 0..self.shard_count {
+            let pool = self.create_connection_pool(shard_id).await?;
+            let sampled = self
+                .sample_shard(&pool, shard_id, per_shard)
+                .await
+                .wrap_err(format!("Failed to sample shard {}", shard_id))?;
+            pool.close().await;
+
+            tracing::info!("Shard {}: sampled {} values", shard_id, sampled.len());
+            samples.extend(sampled);
+        }
+
+        if samples.is_empty() {
+            return Err(miette::miette!(
+                "No values to train a dictionary from in {}",
+                self.data_dir
+            ));
+        }
+
+        let dictionary = train_dictionary(&samples, self.max_size).map_err(|e| {
+            miette::miette!(
+                "Failed to train a dictionary from {} values: {}",
+                samples.len(),
+                e
+            )
+        })?;
+        let size = dictionary.len();
+        let id = self
+            .dictionaries
+            .save(&self.data_dir, dictionary)
+            .map_err(|e| miette::miette!("Failed to save dictionary: {}", e))?;
+
+        tracing::info!(
+            "Saved zstd dictionary {} ({} bytes) trained from {} values",
+            id,
+            size,
+            samples.len()
+        );
+
+        Ok(TrainedDictionary {
+            id,
+            size,
+            samples: samples.len(),
+        })
+    }
+
+    async fn sample_shard(
+        &self,
+        pool: &SqlitePool,
+        shard_id: usize,
+        limit: usize,
+    ) -> Result<Vec<Vec<u8>>> {
+        let tables = sqlx::query_as::<_, (String,)>(
+            "SELECT name FROM sqlite_master WHERE type='table' AND (name = 'blobs' OR name LIKE 'blobs_%') ORDER BY name",
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(|e| sqlx_to_miette(e, "Failed to query table names"))?;
+        if tables.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Sample uniformly across the tables of the shard
+        let union = tables
+            .iter()
+            .map(|(name,)| format!("SELECT data FROM {}", quote_identifier(name)))
+            .collect::<Vec<_>>()
+            .join(" UNION ALL ");
+        let query = format!(
+            "SELECT data FROM ({}) WHERE data IS NOT NULL ORDER BY RANDOM() LIMIT ?",
+            union
+        );
+        let rows = sqlx::query_as::<_, (Vec<u8>,)>(&query)
+            .bind(limit as i64)
+            .fetch_all(pool)
+            .await
+            .map_err(|e| sqlx_to_miette(e, "Failed to sample rows"))?;
+
+        let mut samples = Vec::with_capacity(rows.len());
+        for (data,) in rows {
+            // Large values stored as chunks are no use to a dictionary
+            if chunks::is_chunked(&data) {
+                continue;
+            }
+            match self.reader.decode(&data).await {
+                Ok(decoded) if !decoded.is_empty() => samples.push(decoded),
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("Skipping a value of shard {}: {}", shard_id, e);
+                }
+            }
+        }
+        Ok(samples)
+    }
+}
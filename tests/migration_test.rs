@@ -6,6 +6,7 @@
 //! 3. Handles both regular and namespaced tables
 //! 4. Properly calculates old and new shard assignments
 
+use blobasaur::namespace::{self, quote_identifier};
 use miette::Result;
 use mpchash::HashRing;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode};
@@ -143,7 +144,10 @@ async fn count_records_in_shards(
                 .is_some();
 
         if table_exists {
-            let query = format!("SELECT COUNT(*) as count FROM {}", table_name);
+            let query = format!(
+                "SELECT COUNT(*) as count FROM {}",
+                quote_identifier(table_name)
+            );
             let row = sqlx::query(&query).fetch_one(&pool).await?;
             let count: i64 = row.get("count");
             total_count += count as usize;
@@ -168,8 +172,14 @@ async fn verify_data_integrity(
         let db_path = temp_dir.path().join(format!("shard_{}.db", expected_shard));
         let pool = create_test_pool(db_path.to_str().unwrap()).await?;
 
-        let query = format!("SELECT data FROM {} WHERE key = ?", table_name);
-        let row = sqlx::query(&query).bind(key).fetch_optional(&pool).await?;
+        let query = format!(
+            "SELECT data FROM {} WHERE key = ?",
+            quote_identifier(table_name)
+        );
+        let row = sqlx::query(&query)
+            .bind(key.as_bytes())
+            .fetch_optional(&pool)
+            .await?;
 
         if let Some(row) = row {
             let data: Vec<u8> = row.get("data");
@@ -260,6 +270,109 @@ async fn test_migration_basic() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_migration_applies_storage_settings() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let test_keys = vec!["key1", "key2", "key3", "key4", "key5", "key6"];
+    create_test_data(&temp_dir, 2, &test_keys).await?;
+
+    let storage = blobasaur::config::StorageConfig {
+        page_size: Some(8192),
+        auto_vacuum: Some(blobasaur::config::AutoVacuum::Incremental),
+        ..Default::default()
+    };
+    blobasaur::migration::MigrationManager::new(
+        2,
+        3,
+        temp_dir.path().to_str().unwrap().to_string(),
+    )?
+    .with_storage(storage)
+    .run_migration()
+    .await?;
+
+    // The shard created by the migration uses the configured settings
+    let db_path = temp_dir.path().join("shard_2.db");
+    let pool = create_test_pool(db_path.to_str().unwrap()).await?;
+    let (page_size,): (i64,) = sqlx::query_as("PRAGMA page_size").fetch_one(&pool).await?;
+    let (auto_vacuum,): (i64,) = sqlx::query_as("PRAGMA auto_vacuum")
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(page_size, 8192);
+    assert_eq!(auto_vacuum, 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migration_with_hostile_namespace() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let old_shard_count = 2;
+    let new_shard_count = 3;
+    let test_keys = vec!["key1", "key2", "key3", "key4", "key5", "key6"];
+    let table_name = namespace::table_name("x\"; DROP TABLE blobs; --");
+
+    create_test_data(&temp_dir, old_shard_count, &test_keys).await?;
+    for shard_id in 0..old_shard_count {
+        let db_path = temp_dir.path().join(format!("shard_{}.db", shard_id));
+        let pool = create_test_pool(db_path.to_str().unwrap()).await?;
+        sqlx::query(&format!(
+            "CREATE TABLE {} (
+                key TEXT PRIMARY KEY,
+                data BLOB,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                expires_at INTEGER,
+                version INTEGER NOT NULL DEFAULT 0
+            )",
+            quote_identifier(&table_name)
+        ))
+        .execute(&pool)
+        .await?;
+        for key in &test_keys {
+            if get_shard_for_key(key, old_shard_count) == shard_id {
+                sqlx::query(&format!(
+                    "INSERT INTO {} (key, data, created_at, updated_at, version) VALUES (?, ?, 0, 0, 1)",
+                    quote_identifier(&table_name)
+                ))
+                .bind(key)
+                .bind(format!("hostile_{}", key).as_bytes())
+                .execute(&pool)
+                .await?;
+            }
+        }
+        pool.close().await;
+    }
+
+    let migration_manager = blobasaur::migration::MigrationManager::new(
+        old_shard_count,
+        new_shard_count,
+        temp_dir.path().to_str().unwrap().to_string(),
+    )?;
+    migration_manager.run_migration().await?;
+
+    assert_eq!(
+        count_records_in_shards(&temp_dir, new_shard_count, &table_name).await?,
+        test_keys.len()
+    );
+    assert_eq!(
+        count_records_in_shards(&temp_dir, new_shard_count, "blobs").await?,
+        test_keys.len()
+    );
+    assert!(
+        verify_data_integrity(
+            &temp_dir,
+            new_shard_count,
+            &test_keys,
+            &table_name,
+            "hostile_"
+        )
+        .await?
+    );
+    migration_manager.verify_migration().await?;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_migration_scale_up() -> Result<(), Box<dyn std::error::Error>> {
     let temp_dir = TempDir::new()?;
@@ -293,7 +406,10 @@ async fn test_migration_scale_up() -> Result<(), Box<dyn std::error::Error>> {
         let pool = create_test_pool(db_path.to_str().unwrap()).await?;
 
         let count_query = "SELECT COUNT(*) as count FROM blobs WHERE key = ?";
-        let row = sqlx::query(count_query).bind(key).fetch_one(&pool).await?;
+        let row = sqlx::query(count_query)
+            .bind(key.as_bytes())
+            .fetch_one(&pool)
+            .await?;
         let count: i64 = row.get("count");
 
         assert_eq!(
@@ -409,12 +525,12 @@ async fn test_migration_verification_failure() -> Result<(), Box<dyn std::error:
 
         // Get the record from the correct shard
         let row = sqlx::query("SELECT key, data, created_at, updated_at, expires_at, version FROM blobs WHERE key = ?")
-            .bind(key_to_corrupt)
+            .bind(key_to_corrupt.as_bytes())
             .fetch_optional(&correct_pool)
             .await?;
 
         if let Some(row) = row {
-            let key: String = row.get("key");
+            let key: Vec<u8> = row.get("key");
             let data: Vec<u8> = row.get("data");
             let created_at: i64 = row.get("created_at");
             let updated_at: i64 = row.get("updated_at");
@@ -434,7 +550,7 @@ async fn test_migration_verification_failure() -> Result<(), Box<dyn std::error:
 
             // Delete it from the correct shard
             sqlx::query("DELETE FROM blobs WHERE key = ?")
-                .bind(key_to_corrupt)
+                .bind(key_to_corrupt.as_bytes())
                 .execute(&correct_pool)
                 .await?;
         }
@@ -456,7 +572,7 @@ async fn test_migration_verification_failure() -> Result<(), Box<dyn std::error:
 #[tokio::test]
 async fn test_migration_consistent_hashing() -> Result<(), Box<dyn std::error::Error>> {
     // Test that keys distribute differently between different shard counts
-    let keys = vec!["key1", "key2", "key3", "key4", "key5"];
+    let keys = ["key1", "key2", "key3", "key4", "key5"];
 
     let old_distribution: Vec<usize> = keys.iter().map(|k| get_shard_for_key(k, 2)).collect();
     let new_distribution: Vec<usize> = keys.iter().map(|k| get_shard_for_key(k, 3)).collect();
@@ -498,3 +614,106 @@ async fn test_migration_manager_validation() {
     let result = blobasaur::migration::MigrationManager::new(2, 3, "/tmp".to_string());
     assert!(result.is_ok(), "Should succeed with valid configuration");
 }
+
+#[tokio::test]
+async fn test_upgrade_text_keys() -> Result<(), Box<dyn std::error::Error>> {
+    let temp_dir = TempDir::new()?;
+    let test_keys = vec!["key1", "key2", "user:123"];
+    create_test_data(&temp_dir, 1, &test_keys).await?;
+
+    let db_path = temp_dir.path().join("shard_0.db");
+    let pool = create_test_pool(db_path.to_str().unwrap()).await?;
+    assert_eq!(blobasaur::migration::upgrade_text_keys(&pool).await?, 2);
+    // Already converted tables are left alone
+    assert_eq!(blobasaur::migration::upgrade_text_keys(&pool).await?, 0);
+
+    for table_name in ["blobs", "blobs_users"] {
+        let (key_type,): (String,) =
+            sqlx::query_as("SELECT type FROM pragma_table_info(?) WHERE name = 'key'")
+                .bind(table_name)
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(key_type, "BLOB");
+
+        let (index_count,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND name = ?")
+                .bind(namespace::expires_at_index_name(table_name))
+                .fetch_one(&pool)
+                .await?;
+        assert_eq!(index_count, 1);
+    }
+    pool.close().await;
+
+    // Existing keys are found by their bytes
+    assert!(verify_data_integrity(&temp_dir, 1, &test_keys, "blobs", "data_for_").await?);
+    assert!(
+        verify_data_integrity(&temp_dir, 1, &test_keys, "blobs_users", "user_data_for_").await?
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_migration_moves_chunks() -> Result<(), Box<dyn std::error::Error>> {
+    use blobasaur::chunks::{self, ChunkManifest};
+
+    let temp_dir = TempDir::new()?;
+    let test_keys: Vec<String> = (0..20).map(|i| format!("big{}", i)).collect();
+
+    let db_path = temp_dir.path().join("shard_0.db");
+    let pool = create_test_pool(db_path.to_str().unwrap()).await?;
+    sqlx::query(
+        "CREATE TABLE blobs (key BLOB PRIMARY KEY, data BLOB, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, expires_at INTEGER, version INTEGER NOT NULL DEFAULT 0)",
+    )
+    .execute(&pool)
+    .await?;
+    chunks::create_chunk_table(&pool).await?;
+    for key in &test_keys {
+        let manifest = ChunkManifest::new(10, 4);
+        sqlx::query("INSERT INTO blobs (key, data, created_at, updated_at) VALUES (?, ?, 0, 0)")
+            .bind(key.as_bytes())
+            .bind(&manifest.encode()[..])
+            .execute(&pool)
+            .await?;
+        for idx in 0..manifest.chunk_count() {
+            sqlx::query("INSERT INTO blob_chunks (key, idx, data) VALUES (?, ?, ?)")
+                .bind(key.as_bytes())
+                .bind(idx as i64)
+                .bind(format!("{}:{}", key, idx).into_bytes())
+                .execute(&pool)
+                .await?;
+        }
+    }
+    pool.close().await;
+
+    blobasaur::migration::MigrationManager::new(
+        1,
+        3,
+        temp_dir.path().to_str().unwrap().to_string(),
+    )?
+    .run_migration()
+    .await?;
+
+    // Every chunk lives in the shard of its key, and only there
+    for shard_id in 0..3 {
+        let db_path = temp_dir.path().join(format!("shard_{}.db", shard_id));
+        let pool = create_test_pool(db_path.to_str().unwrap()).await?;
+        let rows: Vec<(Vec<u8>, i64, Vec<u8>)> =
+            sqlx::query_as("SELECT key, idx, data FROM blob_chunks ORDER BY key, idx")
+                .fetch_all(&pool)
+                .await?;
+        let expected: Vec<&String> = test_keys
+            .iter()
+            .filter(|key| get_shard_for_key(key, 3) == shard_id)
+            .collect();
+        assert_eq!(rows.len(), expected.len() * 3, "shard {}", shard_id);
+        for (key, idx, data) in rows {
+            let key = String::from_utf8(key)?;
+            assert_eq!(get_shard_for_key(&key, 3), shard_id);
+            assert_eq!(data, format!("{}:{}", key, idx).into_bytes());
+        }
+        pool.close().await;
+    }
+
+    Ok(())
+}
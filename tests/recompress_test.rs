@@ -0,0 +1,301 @@
+//! Tests for offline recompression of existing shards
+//!
+//! These tests verify that recompression:
+//! 1. Rewrites values of every `blobs*` table with the target codec
+//! 2. Still reads values written before values were tagged with their codec
+//! 3. Resumes after an interrupted run instead of starting over
+//! 4. Rewrites the chunks of large values
+
+use blobasaur::chunks::{self, ChunkManifest};
+use blobasaur::compression::codec::parse_frame;
+use blobasaur::compression::{DictionaryStore, ValueCodec};
+use blobasaur::config::{CompressionConfig, CompressionType};
+use blobasaur::recompress::RecompressManager;
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::str::FromStr;
+use tempfile::TempDir;
+
+fn compression(algorithm: CompressionType, level: u32) -> CompressionConfig {
+    CompressionConfig {
+        enabled: true,
+        algorithm,
+        level: Some(level),
+        min_size: None,
+        min_savings_percent: None,
+        use_dictionary: None,
+    }
+}
+
+async fn create_shard(temp_dir: &TempDir, shard_id: usize) -> SqlitePool {
+    let db_path = temp_dir.path().join(format!("shard_{}.db", shard_id));
+    let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path.display()))
+        .unwrap()
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await.unwrap();
+    for table in ["blobs", "blobs_users"] {
+        sqlx::query(&format!(
+            "CREATE TABLE {} (key BLOB PRIMARY KEY, data BLOB, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, expires_at INTEGER, version INTEGER NOT NULL DEFAULT 0)",
+            table
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+    }
+    pool
+}
+
+async fn insert(pool: &SqlitePool, table: &str, key: &str, data: &[u8]) {
+    sqlx::query(&format!(
+        "INSERT INTO {} (key, data, created_at, updated_at) VALUES (?, ?, 0, 0)",
+        table
+    ))
+    .bind(key.as_bytes())
+    .bind(data)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn stored(pool: &SqlitePool, table: &str, key: &str) -> Vec<u8> {
+    let (data,): (Vec<u8>,) = sqlx::query_as(&format!("SELECT data FROM {} WHERE key = ?", table))
+        .bind(key.as_bytes())
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    data
+}
+
+fn value(key: &str) -> Vec<u8> {
+    format!("value of {} ", key).repeat(50).into_bytes()
+}
+
+#[tokio::test]
+async fn test_recompress_mixed_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let gzip = compression(CompressionType::Gzip, 6);
+    let gzip_codec = ValueCodec::with_dictionaries(Some(&gzip), &DictionaryStore::default());
+    let plain_codec = ValueCodec::with_dictionaries(None, &DictionaryStore::default());
+
+    let mut keys = Vec::new();
+    for shard_id in 0..2 {
+        let pool = create_shard(&temp_dir, shard_id).await;
+        for i in 0..7 {
+            let key = format!("s{}k{}", shard_id, i);
+            let data = value(&key);
+            // Legacy gzip values without a header, framed gzip and framed plain values
+            let stored = match i % 3 {
+                0 => {
+                    use blobasaur::compression::{Compressor, GzipCompressor};
+                    GzipCompressor::new(Some(6)).compress(&data).await.unwrap()
+                }
+                1 => gzip_codec.encode(&data).await.unwrap(),
+                _ => plain_codec.encode(&data).await.unwrap(),
+            };
+            insert(&pool, "blobs", &key, &stored).await;
+            insert(&pool, "blobs_users", &key, &stored).await;
+            keys.push((shard_id, key));
+        }
+        pool.close().await;
+    }
+
+    // The server was configured with gzip when the legacy values were written
+    let manager = RecompressManager::new(
+        2,
+        temp_dir.path().to_str().unwrap().to_string(),
+        Some(&gzip),
+        compression(CompressionType::Zstd, 9),
+        3,
+    )
+    .unwrap();
+    let reports = manager.run().await.unwrap();
+
+    assert_eq!(reports.len(), 2);
+    for report in &reports {
+        assert_eq!(report.rows, 14);
+        assert_eq!(report.skipped, 0);
+        assert!(report.bytes_saved() > 0, "{:?}", report);
+    }
+
+    let reader = ValueCodec::with_dictionaries(None, &DictionaryStore::default());
+    for (shard_id, key) in &keys {
+        let db_path = temp_dir.path().join(format!("shard_{}.db", shard_id));
+        let pool = SqlitePool::connect(&format!("sqlite:{}", db_path.display()))
+            .await
+            .unwrap();
+        for table in ["blobs", "blobs_users"] {
+            let data = stored(&pool, table, key).await;
+            assert_eq!(
+                parse_frame(&data).map(|frame| frame.algorithm),
+                Some(CompressionType::Zstd)
+            );
+            assert_eq!(reader.decode(&data).await.unwrap(), value(key));
+        }
+
+        // Progress is removed once the run completes
+        let (progress,): (i64,) =
+            sqlx::query_as("SELECT COUNT(*) FROM sqlite_master WHERE name = 'recompress_progress'")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!(progress, 0);
+        pool.close().await;
+    }
+}
+
+#[tokio::test]
+async fn test_recompress_resumes() {
+    let temp_dir = TempDir::new().unwrap();
+    let plain_codec = ValueCodec::with_dictionaries(None, &DictionaryStore::default());
+    let pool = create_shard(&temp_dir, 0).await;
+    for key in ["a", "b", "c", "d"] {
+        insert(
+            &pool,
+            "blobs",
+            key,
+            &plain_codec.encode(&value(key)).await.unwrap(),
+        )
+        .await;
+    }
+
+    // An earlier run to lz4 level 4 was interrupted after "b"
+    sqlx::query(
+        "CREATE TABLE recompress_progress (
+            table_name TEXT PRIMARY KEY,
+            target TEXT NOT NULL,
+            last_key BLOB,
+            done INTEGER NOT NULL DEFAULT 0,
+            row_count INTEGER NOT NULL DEFAULT 0,
+            bytes_before INTEGER NOT NULL DEFAULT 0,
+            bytes_after INTEGER NOT NULL DEFAULT 0,
+            skipped INTEGER NOT NULL DEFAULT 0
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO recompress_progress VALUES ('blobs', 'lz4:4', CAST('b' AS BLOB), 0, 2, 100, 50, 0)",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    pool.close().await;
+
+    let manager = RecompressManager::new(
+        1,
+        temp_dir.path().to_str().unwrap().to_string(),
+        None,
+        compression(CompressionType::Lz4, 4),
+        10,
+    )
+    .unwrap();
+    let reports = manager.run().await.unwrap();
+    // The two rows of the earlier run are counted along with the two rewritten now
+    assert_eq!(reports[0].rows, 4);
+
+    let db_path = temp_dir.path().join("shard_0.db");
+    let pool = SqlitePool::connect(&format!("sqlite:{}", db_path.display()))
+        .await
+        .unwrap();
+    for (key, algorithm) in [
+        ("a", CompressionType::None),
+        ("b", CompressionType::None),
+        ("c", CompressionType::Lz4),
+        ("d", CompressionType::Lz4),
+    ] {
+        let data = stored(&pool, "blobs", key).await;
+        assert_eq!(
+            parse_frame(&data).map(|frame| frame.algorithm),
+            Some(algorithm)
+        );
+    }
+    pool.close().await;
+
+    // A run with another target starts over
+    let manager = RecompressManager::new(
+        1,
+        temp_dir.path().to_str().unwrap().to_string(),
+        None,
+        compression(CompressionType::Zstd, 3),
+        10,
+    )
+    .unwrap();
+    let reports = manager.run().await.unwrap();
+    assert_eq!(reports[0].rows, 4);
+}
+
+#[test]
+fn test_recompress_validation() {
+    let target = compression(CompressionType::Zstd, 30);
+    assert!(RecompressManager::new(1, "/tmp".to_string(), None, target, 10).is_err());
+
+    let target = compression(CompressionType::Zstd, 9);
+    assert!(RecompressManager::new(0, "/tmp".to_string(), None, target.clone(), 10).is_err());
+    assert!(RecompressManager::new(1, "/tmp".to_string(), None, target.clone(), 0).is_err());
+    assert!(RecompressManager::new(1, "/tmp".to_string(), None, target, 10).is_ok());
+}
+
+#[tokio::test]
+async fn test_recompress_chunked_values() {
+    let temp_dir = TempDir::new().unwrap();
+    let plain_codec = ValueCodec::with_dictionaries(None, &DictionaryStore::default());
+    let pool = create_shard(&temp_dir, 0).await;
+    chunks::create_chunk_table(&pool).await.unwrap();
+
+    let data = value("big").repeat(4);
+    let manifest = ChunkManifest::new(data.len() as u64, 1024);
+    insert(&pool, "blobs", "big", &manifest.encode()).await;
+    for idx in 0..manifest.chunk_count() {
+        let range = manifest.chunk_range(idx);
+        let chunk = plain_codec
+            .encode(&data[range.start as usize..range.end as usize])
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO blob_chunks (key, idx, data) VALUES (?, ?, ?)")
+            .bind(&b"big"[..])
+            .bind(idx as i64)
+            .bind(&chunk[..])
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    pool.close().await;
+
+    let manager = RecompressManager::new(
+        1,
+        temp_dir.path().to_str().unwrap().to_string(),
+        None,
+        compression(CompressionType::Zstd, 9),
+        10,
+    )
+    .unwrap();
+    let reports = manager.run().await.unwrap();
+    assert_eq!(reports[0].rows, 1);
+    assert_eq!(reports[0].skipped, 0);
+    assert!(reports[0].bytes_saved() > 0, "{:?}", reports[0]);
+
+    // The manifest is kept and every chunk is rewritten with the target codec
+    let db_path = temp_dir.path().join("shard_0.db");
+    let pool = SqlitePool::connect(&format!("sqlite:{}", db_path.display()))
+        .await
+        .unwrap();
+    assert_eq!(stored(&pool, "blobs", "big").await, manifest.encode());
+    let rows: Vec<(Vec<u8>,)> =
+        sqlx::query_as("SELECT data FROM blob_chunks WHERE key = ? ORDER BY idx")
+            .bind(&b"big"[..])
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+    assert_eq!(rows.len(), manifest.chunk_count() as usize);
+    let mut restored = Vec::new();
+    for (chunk,) in rows {
+        assert_eq!(
+            parse_frame(&chunk).map(|frame| frame.algorithm),
+            Some(CompressionType::Zstd)
+        );
+        restored.extend(plain_codec.decode(&chunk).await.unwrap());
+    }
+    assert_eq!(restored, data);
+    pool.close().await;
+}
@@ -0,0 +1,138 @@
+//! Tests for training zstd dictionaries from stored values
+//!
+//! These tests verify that training:
+//! 1. Samples the `blobs*` tables of every shard and saves a new dictionary version
+//! 2. Produces a dictionary that recompression can use for existing values
+
+use blobasaur::compression::codec::parse_frame;
+use blobasaur::compression::{DictionaryStore, ValueCodec};
+use blobasaur::config::{CompressionConfig, CompressionType};
+use blobasaur::recompress::RecompressManager;
+use blobasaur::train::DictionaryTrainer;
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::str::FromStr;
+use tempfile::TempDir;
+
+async fn create_shard(temp_dir: &TempDir, shard_id: usize, values: &[(String, Vec<u8>)]) {
+    let db_path = temp_dir.path().join(format!("shard_{}.db", shard_id));
+    let options = SqliteConnectOptions::from_str(&format!("sqlite:{}", db_path.display()))
+        .unwrap()
+        .create_if_missing(true);
+    let pool = SqlitePool::connect_with(options).await.unwrap();
+    for table in ["blobs", "blobs_docs"] {
+        sqlx::query(&format!(
+            "CREATE TABLE {} (key BLOB PRIMARY KEY, data BLOB, created_at INTEGER NOT NULL, updated_at INTEGER NOT NULL, expires_at INTEGER, version INTEGER NOT NULL DEFAULT 0)",
+            table
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+        for (key, data) in values {
+            sqlx::query(&format!(
+                "INSERT INTO {} (key, data, created_at, updated_at) VALUES (?, ?, 0, 0)",
+                table
+            ))
+            .bind(key.as_bytes())
+            .bind(data)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+    }
+    pool.close().await;
+}
+
+fn document(i: usize) -> Vec<u8> {
+    format!(
+        r#"{{"id":{},"type":"order","status":"shipped","customer":{{"name":"customer {}","country":"NL"}},"items":[{{"sku":"SKU-{}","quantity":{}}}]}}"#,
+        i,
+        i % 37,
+        i % 101,
+        i % 5
+    )
+    .into_bytes()
+}
+
+#[tokio::test]
+async fn test_train_and_recompress_with_dictionary() {
+    let temp_dir = TempDir::new().unwrap();
+    let codec = ValueCodec::with_dictionaries(None, &DictionaryStore::default());
+    for shard_id in 0..2 {
+        let mut values = Vec::new();
+        for i in 0..300 {
+            let n = shard_id * 1000 + i;
+            values.push((
+                format!("doc{}", n),
+                codec.encode(&document(n)).await.unwrap(),
+            ));
+        }
+        create_shard(&temp_dir, shard_id, &values).await;
+    }
+    let data_dir = temp_dir.path().to_str().unwrap().to_string();
+
+    let trained = DictionaryTrainer::new(2, data_dir.clone(), None, 400, 4096)
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    assert_eq!(trained.id, 1);
+    assert_eq!(trained.samples, 400);
+    assert!(trained.size > 0 && trained.size <= 4096);
+
+    // Training again saves the next version
+    let trained = DictionaryTrainer::new(2, data_dir.clone(), None, 400, 4096)
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    assert_eq!(trained.id, 2);
+
+    let target = CompressionConfig {
+        enabled: true,
+        algorithm: CompressionType::Zstd,
+        level: Some(3),
+        min_size: Some(0),
+        min_savings_percent: None,
+        use_dictionary: Some(true),
+    };
+    let reports = RecompressManager::new(2, data_dir.clone(), None, target, 100)
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+    for report in &reports {
+        assert_eq!(report.rows, 600);
+        assert!(report.bytes_saved() > 0, "{:?}", report);
+    }
+
+    let reader = ValueCodec::with_dictionaries(None, &DictionaryStore::load(&data_dir).unwrap());
+    let db_path = temp_dir.path().join("shard_1.db");
+    let pool = SqlitePool::connect(&format!("sqlite:{}", db_path.display()))
+        .await
+        .unwrap();
+    let (data,): (Vec<u8>,) = sqlx::query_as("SELECT data FROM blobs_docs WHERE key = ?")
+        .bind(&b"doc1007"[..])
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    let frame = parse_frame(&data).unwrap();
+    assert_eq!(frame.algorithm, CompressionType::Zstd);
+    assert_eq!(frame.dictionary, Some(2));
+    assert_eq!(reader.decode(&data).await.unwrap(), document(1007));
+    pool.close().await;
+}
+
+#[tokio::test]
+async fn test_train_validation() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().to_str().unwrap().to_string();
+    assert!(DictionaryTrainer::new(0, data_dir.clone(), None, 10, 4096).is_err());
+    assert!(DictionaryTrainer::new(1, data_dir.clone(), None, 0, 4096).is_err());
+    assert!(DictionaryTrainer::new(1, data_dir.clone(), None, 10, 16).is_err());
+
+    // Nothing to sample from
+    create_shard(&temp_dir, 0, &[]).await;
+    let trainer = DictionaryTrainer::new(1, data_dir, None, 10, 4096).unwrap();
+    assert!(trainer.run().await.is_err());
+}